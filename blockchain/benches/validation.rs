@@ -0,0 +1,78 @@
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+use blockchain::clock::MockClock;
+use blockchain::script::{Multisig, SigningKey};
+use blockchain::{Block, BlockChain, ChainParams, InputTx, OutPoint, OutputTx, SimpleTx, Trans, TxIn};
+
+const TXS: u32 = 4000;
+
+/// A chain whose tip holds `TXS` coins locked by a 2-of-3 multisig, the first half paid to the
+/// policy and the rest to its script hash, and a block spending all of them with two signatures.
+fn setup() -> (BlockChain, Block){
+    let keys: Vec<SigningKey> = (1..=3u8).map(|i| SigningKey::from_seed(&[i; 32])).collect();
+    let policy = Multisig::new(2, keys.iter().map(|k| k.public_key()).collect()).unwrap();
+    let addr = |i: u32| if i < TXS / 2 { policy.address() } else { policy.p2sh_address() };
+
+    let mut params = ChainParams::regtest();
+    params.coinbase_maturity = 1;
+    let g = params.genesis_timestamp;
+    let mut chain = BlockChain::with_clock(params, Arc::new(MockClock::new(g + 1_000_000)));
+    let b1 = chain.create_block(g + 100, "Alice".to_string(), vec![]).unwrap();
+    let cb = OutPoint::new(b1.txs()[0].txid(), 0);
+    let reward = b1.txs()[0].output.0[0].val.val;
+    chain.add_block(b1).unwrap();
+
+    let value = reward / TXS as u64;
+    let fan_out = SimpleTx{
+        input: InputTx(vec![TxIn::new(cb, "Alice".to_string(), reward.into())]),
+        output: OutputTx((0..TXS).map(|i| Trans{addr: addr(i), val: value.into()}).collect()),
+        lock_time: 0,
+        witness: vec![],
+    };
+    let fan_id = fan_out.txid();
+    let b2 = chain.create_block(g + 200, "Miner".to_string(), vec![fan_out]).unwrap();
+    chain.add_block(b2).unwrap();
+
+    let spends = (0..TXS).map(|i| {
+        let mut tx = SimpleTx{
+            input: InputTx(vec![TxIn::new(OutPoint::new(fan_id, i), addr(i), value.into())]),
+            output: OutputTx(vec![Trans{addr: format!("Bob{}", i), val: (value - 100).into()}]),
+            lock_time: 0,
+            witness: vec![],
+        };
+        let mut items = vec![keys[0].sign(&tx, 0).to_vec(), Vec::new(), keys[2].sign(&tx, 0).to_vec()];
+        if i >= TXS / 2{
+            items.push(policy.to_bytes());
+        }
+        tx.witness = vec![items];
+        tx
+    }).collect();
+    let b3 = chain.create_block(g + 300, "Miner".to_string(), spends).unwrap();
+    (chain, b3)
+}
+
+fn bench_connect(c: &mut Criterion) {
+    let (chain, block) = setup();
+    let threads = rayon::current_num_threads();
+    for &n in [1, threads].iter(){
+        let mut chain = chain.clone();
+        chain.set_validation_threads(n);
+        c.bench_function(&format!("connect {} txs, {} threads", TXS, n), |b| b.iter_batched(
+            || (chain.clone(), block.clone()),
+            |(mut chain, block)| chain.add_block(block).unwrap(),
+            BatchSize::LargeInput,
+        ));
+        if threads == 1{
+            break
+        }
+    }
+}
+
+criterion_group!{
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = bench_connect
+}
+criterion_main!(benches);
@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use blockchain::Block;
+
+// a decoded block encodes back to the same bytes
+fuzz_target!(|data: &[u8]| {
+    let block: Option<Block> = Block::from_bytes(data);
+    if let Some(block) = block{
+        assert_eq!(data, &block.to_bytes()[..]);
+    }
+});
@@ -0,0 +1,14 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use blockchain::encode::Reader;
+use blockchain::BlockHeader;
+
+// a decoded header encodes back to the bytes it was read from
+fuzz_target!(|data: &[u8]| {
+    let mut r = Reader::new(data);
+    if let Some(header) = BlockHeader::decode(&mut r){
+        let used = data.len() - r.remaining();
+        assert_eq!(&data[..used], &header.to_bytes()[..]);
+    }
+});
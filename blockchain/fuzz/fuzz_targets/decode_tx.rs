@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use blockchain::SimpleTx;
+
+// either serialization decodes back to the bytes, the txid ignores the witness
+fuzz_target!(|data: &[u8]| {
+    if let Some(tx) = SimpleTx::from_bytes(data){
+        assert_eq!(data, &tx.to_witness_bytes()[..]);
+        let stripped = SimpleTx::from_bytes(&tx.to_bytes()).unwrap();
+        assert_eq!(tx.txid(), stripped.txid());
+    }
+});
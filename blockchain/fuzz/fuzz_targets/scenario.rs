@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use blockchain::scenario::Scenario;
+
+// the input seeds a short scenario, every step checks the invariants
+fuzz_target!(|data: &[u8]| {
+    let mut seed = [0; 8];
+    let n = data.len().min(8);
+    seed[..n].copy_from_slice(&data[..n]);
+    Scenario::new(u64::from_be_bytes(seed)).run(20).unwrap();
+});
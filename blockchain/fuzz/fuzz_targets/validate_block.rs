@@ -0,0 +1,28 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use blockchain::scenario::{self, Scenario};
+use blockchain::{Block, BlockChain};
+
+thread_local!{
+    /// The node of a short scenario, with forks and spent coins to build on.
+    static CHAIN: BlockChain = {
+        let mut s = Scenario::new(0);
+        s.run(30).unwrap();
+        s.node().clone()
+    };
+}
+
+// any block is either rejected without a change or keeps the invariants
+fuzz_target!(|data: &[u8]| {
+    let block: Block = match Block::from_bytes(data){
+        Some(block) => block,
+        None => return,
+    };
+    let mut chain = CHAIN.with(|c| c.clone());
+    let tip = chain.tip().hash();
+    match chain.add_block(block){
+        Ok(()) => scenario::check_invariants(&chain).unwrap(),
+        Err(_) => assert_eq!(tip, chain.tip().hash()),
+    }
+});
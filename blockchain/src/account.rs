@@ -0,0 +1,524 @@
+//! Account based state, an alternative to the UTXO set.
+//!
+//! Every address has a balance and a nonce. A transfer names the nonce of its sender and is
+//! only valid with the current one, so that it cannot be replayed. It is signed by the key
+//! the address of the sender is derived from. The first transaction of a block pays the
+//! reward and commits to the state root after the block, the merkle root of all accounts
+//! sorted by address.
+//!
+//! Blocks are the same `Block` and `BlockHeader` as in the UTXO model, carrying `AccountTx`,
+//! so they are mined and serialized by the same code. `AccountChain` is a `BlockChain` with
+//! `AccountState` as its state model, branches and events work as with the UTXO set.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::block::{Block, BlockTx};
+use crate::clock::Clock;
+use crate::consensus::ConsensusParams;
+use crate::encode::{self, Reader};
+use crate::error::ChainError;
+use crate::mkt::{self, HashVal};
+use crate::params::ChainParams;
+use crate::script::{self, SigningKey};
+use crate::state::StateModel;
+use crate::BlockChain;
+
+/// Prefix of the address of an account, followed by the hex public key controlling it.
+pub const ACCOUNT_PREFIX: &str = "acct:";
+
+/// Address of the account controlled by `public_key`.
+pub fn address(public_key: &[u8; 32]) -> String{
+    format!("{}{}", ACCOUNT_PREFIX, encode::to_hex(public_key))
+}
+
+fn public_key_of(addr: &str) -> Option<[u8; 32]>{
+    if !addr.starts_with(ACCOUNT_PREFIX){
+        return None
+    }
+    let bytes = encode::from_hex(&addr[ACCOUNT_PREFIX.len()..])?;
+    if bytes.len() != 32{
+        return None
+    }
+    let mut key = [0; 32];
+    key.copy_from_slice(&bytes);
+    Some(key)
+}
+
+/// A payment from an account, valid once at its nonce.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transfer{
+    /// Address of the key signing the transfer.
+    pub from: String,
+    pub to: String,
+    pub value: u64,
+    /// Paid to the producer of the block.
+    pub fee: u64,
+    /// Number of transfers sent by `from` before this one.
+    pub nonce: u64,
+    /// Signature of `sighash()`.
+    pub signature: [u8; 64],
+}
+
+impl Transfer{
+    /// A transfer from the account of `key`, signed by it.
+    pub fn new(key: &SigningKey, to: &str, value: u64, fee: u64, nonce: u64) -> Transfer{
+        let from = address(&key.public_key());
+        let mut t = Transfer{ from, to: to.to_string(), value, fee, nonce, signature: [0; 64] };
+        t.signature = key.sign_hash(&t.sighash());
+        t
+    }
+
+    fn encode_unsigned(&self, buf: &mut Vec<u8>){
+        encode::write_var_bytes(buf, self.from.as_bytes());
+        encode::write_var_bytes(buf, self.to.as_bytes());
+        buf.write_u64::<BigEndian>(self.value).unwrap();
+        buf.write_u64::<BigEndian>(self.fee).unwrap();
+        buf.write_u64::<BigEndian>(self.nonce).unwrap();
+    }
+
+    /// Message signed by the sender: SHA-256 of the transfer without its signature.
+    pub fn sighash(&self) -> [u8; 32]{
+        let mut buf = Vec::new();
+        self.encode_unsigned(&mut buf);
+        mkt::sha256(&buf)
+    }
+
+    /// The signature is made by the key of `from`.
+    pub fn verify(&self) -> Result<(), ChainError>{
+        let key = public_key_of(&self.from).ok_or(ChainError::BadSignature)?;
+        if !script::check_signature(&key, &self.sighash(), &self.signature){
+            return Err(ChainError::BadSignature)
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AccountTx{
+    /// First transaction of a block, paying the subsidy and the fees.
+    Reward{ height: u64, to: String, value: u64, state_root: [u8; 32] },
+    Transfer(Transfer),
+}
+
+const REWARD_TAG: u8 = 0;
+const TRANSFER_TAG: u8 = 1;
+
+fn read_string(r: &mut Reader) -> Option<String>{
+    String::from_utf8(r.read_var_bytes()?.to_vec()).ok()
+}
+
+impl AccountTx{
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<AccountTx>{
+        let mut r = Reader::new(bytes);
+        let tx = AccountTx::decode_from(&mut r)?;
+        if !r.is_empty(){
+            return None
+        }
+        Some(tx)
+    }
+}
+
+impl BlockTx for AccountTx{
+    fn txid(&self) -> [u8; 32]{
+        mkt::sha256d(&self.to_bytes())
+    }
+
+    fn is_coinbase(&self) -> bool{
+        match self{
+            AccountTx::Reward{ .. } => true,
+            AccountTx::Transfer(_) => false,
+        }
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>){
+        match self{
+            AccountTx::Reward{ height, to, value, state_root } => {
+                buf.push(REWARD_TAG);
+                buf.write_u64::<BigEndian>(*height).unwrap();
+                encode::write_var_bytes(buf, to.as_bytes());
+                buf.write_u64::<BigEndian>(*value).unwrap();
+                buf.extend(state_root);
+            },
+            AccountTx::Transfer(t) => {
+                buf.push(TRANSFER_TAG);
+                t.encode_unsigned(buf);
+                buf.extend(&t.signature[..]);
+            },
+        }
+    }
+
+    fn decode_from(r: &mut Reader) -> Option<AccountTx>{
+        match r.read_u8()?{
+            REWARD_TAG => Some(AccountTx::Reward{
+                height: r.read_u64()?,
+                to: read_string(r)?,
+                value: r.read_u64()?,
+                state_root: r.read_hash()?,
+            }),
+            TRANSFER_TAG => Some(AccountTx::Transfer(Transfer{
+                from: read_string(r)?,
+                to: read_string(r)?,
+                value: r.read_u64()?,
+                fee: r.read_u64()?,
+                nonce: r.read_u64()?,
+                signature: {
+                    let mut sig = [0; 64];
+                    sig.copy_from_slice(r.read_bytes(64)?);
+                    sig
+                },
+            })),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Account{
+    pub balance: u64,
+    /// Transfers sent so far, the nonce of the next one.
+    pub nonce: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AccountState{
+    accounts: BTreeMap<String, Account>,
+}
+
+impl AccountState{
+    pub fn new() -> AccountState{
+        AccountState::default()
+    }
+
+    /// A state where `balances` have been allocated, e.g. at genesis.
+    pub fn with_balances(balances: impl IntoIterator<Item=(String, u64)>) -> Result<AccountState, ChainError>{
+        let mut state = AccountState::new();
+        for (addr, value) in balances{
+            state.credit(&addr, value)?;
+        }
+        Ok(state)
+    }
+
+    /// The account of `addr`, empty if it never received anything.
+    pub fn account(&self, addr: &str) -> Account{
+        self.accounts.get(addr).cloned().unwrap_or_default()
+    }
+
+    pub fn balance(&self, addr: &str) -> u64{
+        self.account(addr).balance
+    }
+
+    pub fn nonce(&self, addr: &str) -> u64{
+        self.account(addr).nonce
+    }
+
+    pub fn accounts(&self) -> impl Iterator<Item=(&String, &Account)>{
+        self.accounts.iter()
+    }
+
+    pub fn credit(&mut self, addr: &str, value: u64) -> Result<(), ChainError>{
+        let account = self.accounts.entry(addr.to_string()).or_default();
+        account.balance = account.balance.checked_add(value).ok_or(ChainError::ValueOutOfRange)?;
+        Ok(())
+    }
+
+    /// Move `value` and take `fee` from the sender, returns the fee.
+    /// Nothing changes if the signature, the nonce or the balance does not allow it.
+    pub fn apply_transfer(&mut self, t: &Transfer) -> Result<u64, ChainError>{
+        t.verify()?;
+        let sender = self.account(&t.from);
+        if t.nonce != sender.nonce{
+            return Err(ChainError::BadNonce{ expected: sender.nonce, got: t.nonce })
+        }
+        let total = t.value.checked_add(t.fee).ok_or(ChainError::ValueOutOfRange)?;
+        let balance = sender.balance.checked_sub(total).ok_or(ChainError::InsufficientFunds)?;
+        self.accounts.insert(t.from.clone(), Account{ balance, nonce: sender.nonce + 1 });
+        self.credit(&t.to, t.value)?;
+        Ok(t.fee)
+    }
+
+    /// Merkle root of the accounts sorted by address.
+    /// Every leaf hashes an address, its balance and its nonce.
+    pub fn state_root(&self) -> [u8; 32]{
+        let leaves = self.accounts.iter()
+            .filter(|(_, a)| **a != Account::default())
+            .map(|(addr, a)| {
+                let mut buf = Vec::new();
+                encode::write_var_bytes(&mut buf, addr.as_bytes());
+                buf.write_u64::<BigEndian>(a.balance).unwrap();
+                buf.write_u64::<BigEndian>(a.nonce).unwrap();
+                HashVal(mkt::sha256(&buf))
+            })
+            .collect();
+        mkt::build_tree(leaves).root().0
+    }
+
+    /// Apply the block at `height`. Its reward may claim `subsidy` and the fees, and must
+    /// commit to the resulting state root. On error the state is unchanged.
+    pub fn apply_block(&mut self, block: &Block<AccountTx>, height: usize, subsidy: u64) -> Result<(), ChainError>{
+        let mut next = self.clone();
+        let (to, value, state_root) = match block.txs().first(){
+            Some(AccountTx::Reward{ height: h, to, value, state_root }) => {
+                if *h != height as u64{
+                    return Err(ChainError::BadCoinbaseHeight)
+                }
+                (to, *value, state_root)
+            },
+            _ => return Err(ChainError::MissingCoinbase),
+        };
+        let mut fees = 0u64;
+        for tx in block.txs()[1..].iter(){
+            match tx{
+                AccountTx::Transfer(t) => {
+                    let fee = next.apply_transfer(t)?;
+                    fees = fees.checked_add(fee).ok_or(ChainError::ValueOutOfRange)?;
+                },
+                AccountTx::Reward{ .. } => return Err(ChainError::UnexpectedCoinbase),
+            }
+        }
+        if value > subsidy.saturating_add(fees){
+            return Err(ChainError::BadCoinbaseValue)
+        }
+        next.credit(to, value)?;
+        if next.state_root() != *state_root{
+            return Err(ChainError::BadStateRoot)
+        }
+        *self = next;
+        Ok(())
+    }
+}
+
+impl StateModel for AccountState{
+    type Tx = AccountTx;
+    /// The accounts touched by the block as they were before it.
+    type Undo = Vec<(String, Account)>;
+
+    fn connect(&mut self, chain: &AccountChain, block: &Block<AccountTx>, height: usize) -> Result<Self::Undo, ChainError>{
+        if block.header().merkle_root() != &block.compute_merkle_root(){
+            return Err(ChainError::BadMerkleRoot)
+        }
+        if block.is_mutated(){
+            return Err(ChainError::MutatedBlock)
+        }
+        let touched: BTreeSet<&String> = block.txs().iter().flat_map(|tx| match tx{
+            AccountTx::Reward{ to, .. } => vec![to],
+            AccountTx::Transfer(t) => vec![&t.from, &t.to],
+        }).collect();
+        let undo = touched.into_iter().map(|addr| (addr.clone(), self.account(addr))).collect();
+        self.apply_block(block, height, chain.params().block_subsidy(height).val)?;
+        Ok(undo)
+    }
+
+    fn disconnect(&mut self, _block: &Block<AccountTx>, undo: Self::Undo){
+        for (addr, account) in undo{
+            if account == Account::default(){
+                self.accounts.remove(&addr);
+            }else{
+                self.accounts.insert(addr, account);
+            }
+        }
+    }
+}
+
+/// A chain of account blocks, checked, reorganized and announced like the UTXO chain.
+pub type AccountChain = BlockChain<AccountState>;
+
+impl BlockChain<AccountState>{
+    /// A chain whose genesis allocates `balances`.
+    pub fn with_balances(params: ChainParams, clock: Arc<dyn Clock>, balances: Vec<(String, u64)>) -> Result<AccountChain, ChainError>{
+        let state = AccountState::with_balances(balances)?;
+        let reward = AccountTx::Reward{
+            height: 0,
+            to: params.genesis_addr.clone(),
+            value: 0,
+            state_root: state.state_root(),
+        };
+        let genesis = Block::genesis_block(params.genesis_timestamp, params.pow_limit, reward);
+        Ok(BlockChain::with_genesis(params, clock, genesis, state))
+    }
+
+    /// Mine a block on the tip with `transfers`, paying the subsidy and the fees to `addr`.
+    pub fn create_block(&self, timestamp: u64, addr: String, transfers: Vec<Transfer>) -> Result<Block<AccountTx>, ChainError>{
+        if let ConsensusParams::ProofOfStake(_) = self.params().consensus{
+            return Err(ChainError::BadSeal)
+        }
+        let height = self.height() + 1;
+        let mut state = self.state().clone();
+        let mut value = self.params().block_subsidy(height).val;
+        for t in transfers.iter(){
+            let fee = state.apply_transfer(t)?;
+            value = value.checked_add(fee).ok_or(ChainError::ValueOutOfRange)?;
+        }
+        state.credit(&addr, value)?;
+        let reward = AccountTx::Reward{ height: height as u64, to: addr, value, state_root: state.state_root() };
+        let txs = std::iter::once(reward).chain(transfers.into_iter().map(AccountTx::Transfer));
+        let mut block = Block::pack(self.tip().header(), timestamp, txs);
+        block.header_mut().set_bits(self.next_bits());
+        if !block.mine(self.params().pow_limit){
+            return Err(ChainError::HighHash)
+        }
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod account_test{
+    use super::*;
+    use crate::block::MAX_FUTURE_BLOCK_TIME;
+    use crate::clock::MockClock;
+    use crate::events::ChainEvent;
+
+    fn key(seed: u8) -> (SigningKey, String){
+        let key = SigningKey::from_seed(&[seed; 32]);
+        let addr = address(&key.public_key());
+        (key, addr)
+    }
+
+    #[test]
+    fn test_transfer_nonces() {
+        let (alice, a) = key(1);
+        let mut state = AccountState::with_balances(vec![(a.clone(), 100)]).unwrap();
+        let t = Transfer::new(&alice, "Bob", 50, 1, 0);
+        assert_eq!(Ok(1), state.apply_transfer(&t));
+        assert_eq!(Account{ balance: 49, nonce: 1 }, state.account(&a));
+        assert_eq!(50, state.balance("Bob"));
+
+        // the same transfer cannot be replayed
+        assert_eq!(Err(ChainError::BadNonce{ expected: 1, got: 0 }), state.apply_transfer(&t));
+        assert_eq!(Err(ChainError::BadNonce{ expected: 1, got: 2 }), state.apply_transfer(&Transfer::new(&alice, "Bob", 1, 1, 2)));
+        assert_eq!(Err(ChainError::InsufficientFunds), state.apply_transfer(&Transfer::new(&alice, "Bob", 49, 1, 1)));
+        assert_eq!(Err(ChainError::ValueOutOfRange), state.apply_transfer(&Transfer::new(&alice, "Bob", u64::MAX, 1, 1)));
+        assert_eq!(Account{ balance: 49, nonce: 1 }, state.account(&a));
+        assert_eq!(Account::default(), state.account("Carol"));
+    }
+
+    #[test]
+    fn test_transfer_signature() {
+        let (alice, a) = key(1);
+        let (mallory, _) = key(2);
+        let mut state = AccountState::with_balances(vec![(a.clone(), 100), ("Alice".to_string(), 100)]).unwrap();
+
+        // signed by another key
+        let mut t = Transfer::new(&mallory, "Mallory", 50, 1, 0);
+        t.from = a.clone();
+        assert_eq!(Err(ChainError::BadSignature), state.apply_transfer(&t));
+        // changed after it was signed
+        let mut t = Transfer::new(&alice, "Bob", 50, 1, 0);
+        t.to = "Mallory".to_string();
+        assert_eq!(Err(ChainError::BadSignature), state.apply_transfer(&t));
+        // an address not derived from a key cannot send
+        let mut t = Transfer::new(&alice, "Bob", 50, 1, 0);
+        t.from = "Alice".to_string();
+        assert_eq!(Err(ChainError::BadSignature), state.apply_transfer(&t));
+        assert_eq!(100, state.balance(&a));
+        assert_eq!(100, state.balance("Alice"));
+
+        let t = Transfer::new(&alice, "Bob", 50, 1, 0);
+        assert_eq!(Some(AccountTx::Transfer(t.clone())), AccountTx::from_bytes(&AccountTx::Transfer(t).to_bytes()));
+    }
+
+    #[test]
+    fn test_state_root() {
+        let (alice, a) = key(1);
+        let x = AccountState::with_balances(vec![(a.clone(), 1), ("Bob".to_string(), 2)]).unwrap();
+        let y = AccountState::with_balances(vec![("Bob".to_string(), 2), (a.clone(), 1)]).unwrap();
+        assert_eq!(x.state_root(), y.state_root());
+        // empty accounts are not committed
+        let mut c = y.clone();
+        c.credit("Carol", 0).unwrap();
+        assert_eq!(x.state_root(), c.state_root());
+
+        c.credit("Carol", 1).unwrap();
+        assert_ne!(x.state_root(), c.state_root());
+        // nonces are committed too
+        let mut d = x.clone();
+        d.apply_transfer(&Transfer::new(&alice, &a, 1, 0, 0)).unwrap();
+        assert_eq!(x.balance(&a), d.balance(&a));
+        assert_ne!(x.state_root(), d.state_root());
+    }
+
+    fn test_chain(a: &str) -> AccountChain{
+        let params = ChainParams::regtest();
+        let g = params.genesis_timestamp;
+        AccountChain::with_balances(params, Arc::new(MockClock::new(g + 1_000_000)), vec![(a.to_string(), 1000)]).unwrap()
+    }
+
+    #[test]
+    fn test_account_chain() {
+        let (alice, a) = key(1);
+        let mut chain = test_chain(&a);
+        let g = chain.params().genesis_timestamp;
+        let subsidy = chain.params().block_subsidy(1).val;
+
+        let block = chain.create_block(g + 100, "Miner".to_string(), vec![Transfer::new(&alice, "Bob", 300, 1, 0)]).unwrap();
+        let decoded = Block::<AccountTx>::from_bytes(&block.to_bytes()).unwrap();
+        assert_eq!(block.hash(), decoded.hash());
+        assert_eq!(block.txs(), decoded.txs());
+        chain.add_block(decoded).unwrap();
+        assert_eq!(699, chain.state().balance(&a));
+        assert_eq!(300, chain.state().balance("Bob"));
+        assert_eq!(subsidy + 1, chain.state().balance("Miner"));
+
+        // a replayed transfer makes the block invalid
+        let mut replay = chain.create_block(g + 200, "Miner".to_string(), vec![]).unwrap();
+        replay.txs_mut().push(AccountTx::Transfer(Transfer::new(&alice, "Bob", 300, 1, 0)));
+        replay.update_merkle_root();
+        assert!(replay.mine(chain.params().pow_limit));
+        assert_eq!(Err(ChainError::BadNonce{ expected: 1, got: 0 }), chain.add_block(replay));
+
+        // the reward commits to the state after the block
+        let mut wrong = chain.create_block(g + 200, "Miner".to_string(), vec![Transfer::new(&alice, "Bob", 300, 1, 1)]).unwrap();
+        if let AccountTx::Reward{ ref mut value, .. } = wrong.txs_mut()[0]{
+            *value -= 1;
+        }
+        wrong.update_merkle_root();
+        assert!(wrong.mine(chain.params().pow_limit));
+        assert_eq!(Err(ChainError::BadStateRoot), chain.add_block(wrong));
+
+        // the header rules of the chain apply
+        let max = g + 1_000_000 + MAX_FUTURE_BLOCK_TIME;
+        let future = chain.create_block(max + 1, "Miner".to_string(), vec![]).unwrap();
+        assert_eq!(Err(ChainError::TimeTooNew{ timestamp: max + 1, max }), chain.add_block(future));
+        assert_eq!(1, chain.height());
+        assert_eq!(1, chain.state().nonce(&a));
+    }
+
+    #[test]
+    fn test_account_reorg() {
+        let (alice, a) = key(1);
+        let mut chain = test_chain(&a);
+        let g = chain.params().genesis_timestamp;
+        let mut fork = chain.clone();
+        let t = Transfer::new(&alice, "Bob", 300, 1, 0);
+        let b1 = chain.create_block(g + 100, "Miner".to_string(), vec![t.clone()]).unwrap();
+        chain.add_block(b1.clone()).unwrap();
+        assert_eq!(300, chain.state().balance("Bob"));
+
+        let events = chain.subscribe();
+        for ts in [g + 100, g + 200].iter(){
+            let block = fork.create_block(*ts, "Other".to_string(), vec![]).unwrap();
+            fork.add_block(block.clone()).unwrap();
+            chain.add_block(block).unwrap();
+        }
+        assert_eq!(fork.tip().hash(), chain.tip().hash());
+        // the transfer is undone and can be mined again
+        assert_eq!(1000, chain.state().balance(&a));
+        assert_eq!(0, chain.state().nonce(&a));
+        assert_eq!(Account::default(), chain.state().account("Bob"));
+        assert_eq!(0, chain.state().balance("Miner"));
+        assert_eq!(fork.state().state_root(), chain.state().state_root());
+        assert!(events.try_iter().any(|e| matches!(e, ChainEvent::BlockDisconnected{ height: 1, ref block } if block.hash() == b1.hash())));
+
+        let again = chain.create_block(g + 300, "Miner".to_string(), vec![t]).unwrap();
+        chain.add_block(again).unwrap();
+        assert_eq!(300, chain.state().balance("Bob"));
+    }
+}
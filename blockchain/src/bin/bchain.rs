@@ -0,0 +1,212 @@
+//! Command line interface of a local node.
+//!
+//! ```text
+//! bchain --datadir ./data init --network regtest
+//! bchain --datadir ./pruned init --network regtest --prune 288
+//! bchain --datadir ./data mine 101 Alice
+//! bchain --datadir ./data send Alice Bob 1000 --fee 10
+//! bchain --datadir ./data block 1
+//! bchain --datadir ./data tx <txid>
+//! bchain --datadir ./data balance
+//! bchain --datadir ./data estimatefee 6
+//! ```
+//! Amounts are in satoshis. `block` and `tx` print JSON.
+
+use std::process;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use serde_json::{json, Value};
+
+use blockchain::encode::{from_hex, to_hex};
+use blockchain::node::{Node, NodeError};
+use blockchain::{Block, Network, SimpleTx};
+
+fn main() {
+    let matches = App::new("bchain")
+        .version("0.1.0")
+        .author("Tsumida")
+        .about("Run a local block chain")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("datadir")
+            .short("d")
+            .long("datadir")
+            .value_name("DIR")
+            .default_value(".bchain")
+            .takes_value(true))
+        .subcommand(SubCommand::with_name("init")
+            .about("Initialize the data directory")
+            .arg(Arg::with_name("network")
+                .long("network")
+                .possible_values(&["mainnet", "testnet", "regtest"])
+                .default_value("regtest"))
+            .arg(Arg::with_name("prune")
+                .long("prune")
+                .value_name("BLOCKS")
+                .help("Only keep the last BLOCKS blocks")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("mine")
+            .about("Mine blocks on regtest, including the mempool")
+            .arg(Arg::with_name("count").required(true))
+            .arg(Arg::with_name("address").required(true)))
+        .subcommand(SubCommand::with_name("send")
+            .about("Create a transaction and submit it to the mempool")
+            .arg(Arg::with_name("from").required(true))
+            .arg(Arg::with_name("to").required(true))
+            .arg(Arg::with_name("amount").required(true))
+            .arg(Arg::with_name("fee")
+                .long("fee")
+                .default_value("0")))
+        .subcommand(SubCommand::with_name("submit")
+            .about("Submit a hex encoded transaction to the mempool")
+            .arg(Arg::with_name("hex").required(true)))
+        .subcommand(SubCommand::with_name("block")
+            .about("Print a block by height or hash")
+            .arg(Arg::with_name("id").required(true)))
+        .subcommand(SubCommand::with_name("tx")
+            .about("Print a transaction of the chain or the mempool")
+            .arg(Arg::with_name("txid").required(true)))
+        .subcommand(SubCommand::with_name("estimatefee")
+            .about("Estimate the fee rate, in satoshis per 1000 vbytes, to confirm within a number of blocks")
+            .arg(Arg::with_name("target").required(true)))
+        .subcommand(SubCommand::with_name("balance")
+            .about("Show confirmed balances")
+            .arg(Arg::with_name("address")))
+        .get_matches();
+
+    if let Err(e) = run(&matches){
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<(), String>{
+    let dir = matches.value_of("datadir").unwrap();
+    match matches.subcommand(){
+        ("init", Some(m)) => {
+            let network = Network::from_name(m.value_of("network").unwrap()).unwrap();
+            let node = match m.value_of("prune"){
+                Some(keep) => Node::init_pruned(dir, network, parse_u64(keep)? as usize),
+                None => Node::init(dir, network),
+            }.map_err(err)?;
+            println!("{}", to_hex(&node.chain().tip().hash()));
+        },
+        ("mine", Some(m)) => {
+            let count = parse_u64(m.value_of("count").unwrap())? as usize;
+            let mut node = Node::open(dir).map_err(err)?;
+            for hash in node.mine(count, m.value_of("address").unwrap()).map_err(err)?{
+                println!("{}", to_hex(&hash));
+            }
+        },
+        ("send", Some(m)) => {
+            let mut node = Node::open(dir).map_err(err)?;
+            let tx = node.create_tx(
+                m.value_of("from").unwrap(),
+                m.value_of("to").unwrap(),
+                parse_u64(m.value_of("amount").unwrap())?,
+                parse_u64(m.value_of("fee").unwrap())?,
+            ).map_err(err)?;
+            let txid = node.submit(tx).map_err(err)?;
+            println!("{}", to_hex(&txid));
+        },
+        ("submit", Some(m)) => {
+            let tx = from_hex(m.value_of("hex").unwrap())
+                .and_then(|b| SimpleTx::from_bytes(&b))
+                .ok_or("malformed transaction")?;
+            let mut node = Node::open(dir).map_err(err)?;
+            let txid = node.submit(tx).map_err(err)?;
+            println!("{}", to_hex(&txid));
+        },
+        ("block", Some(m)) => {
+            let node = Node::open(dir).map_err(err)?;
+            let id = m.value_of("id").unwrap();
+            let height = match id.parse::<usize>(){
+                Ok(h) => h,
+                Err(_) => node.chain().height_of(&parse_hash(id)?).ok_or("unknown block")?,
+            };
+            if height < node.chain().prune_height(){
+                return Err("block was pruned".to_string())
+            }
+            let block = node.chain().block(height).ok_or("unknown block")?;
+            println!("{}", serde_json::to_string_pretty(&block_json(block, height)).unwrap());
+        },
+        ("tx", Some(m)) => {
+            let node = Node::open(dir).map_err(err)?;
+            let (tx, height) = node.find_tx(&parse_hash(m.value_of("txid").unwrap())?)
+                .ok_or("unknown transaction")?;
+            let mut v = tx_json(&tx);
+            v["height"] = json!(height);
+            println!("{}", serde_json::to_string_pretty(&v).unwrap());
+        },
+        ("estimatefee", Some(m)) => {
+            let node = Node::open(dir).map_err(err)?;
+            let target = parse_u64(m.value_of("target").unwrap())? as usize;
+            let est = node.estimate_fee(target).ok_or("not enough data")?;
+            println!("{}", json!({ "fee_rate": est.fee_rate, "confidence": est.confidence }));
+        },
+        ("balance", Some(m)) => {
+            let node = Node::open(dir).map_err(err)?;
+            let balances = node.balances();
+            match m.value_of("address"){
+                Some(addr) => println!("{}", balances.get(addr).map_or(0, |v| v.val)),
+                None => for (addr, val) in balances.iter(){
+                    println!("{} {}", addr, val.val);
+                },
+            }
+        },
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn err(e: NodeError) -> String{
+    e.to_string()
+}
+
+fn parse_u64(s: &str) -> Result<u64, String>{
+    s.parse().map_err(|_| format!("invalid number {:?}", s))
+}
+
+fn parse_hash(s: &str) -> Result<[u8; 32], String>{
+    let bytes = from_hex(s).filter(|b| b.len() == 32).ok_or_else(|| format!("invalid hash {:?}", s))?;
+    let mut h = [0; 32];
+    h.copy_from_slice(&bytes);
+    Ok(h)
+}
+
+fn block_json(block: &Block, height: usize) -> Value{
+    let h = block.header();
+    json!({
+        "hash": to_hex(&block.hash()),
+        "height": height,
+        "version": h.version(),
+        "prev_block": to_hex(h.prev_block()),
+        "merkle_root": to_hex(h.merkle_root()),
+        "timestamp": h.timestamp(),
+        "bits": format!("{:08x}", h.bits()),
+        "nonce": h.nonce(),
+        "txs": block.txs().iter().map(tx_json).collect::<Vec<_>>(),
+    })
+}
+
+fn tx_json(tx: &SimpleTx) -> Value{
+    json!({
+        "txid": to_hex(&tx.txid()),
+        "wtxid": to_hex(&tx.wtxid()),
+        "lock_time": tx.lock_time,
+        "inputs": tx.input.0.iter().map(|txin| json!({
+            "txid": to_hex(&txin.prev_out.txid),
+            "index": txin.prev_out.index,
+            "address": txin.addr,
+            "value": txin.val.val,
+            "sequence": txin.sequence,
+        })).collect::<Vec<_>>(),
+        "outputs": tx.output.0.iter().map(|out| json!({
+            "address": out.addr,
+            "value": out.val.val,
+        })).collect::<Vec<_>>(),
+        "witness": tx.witness.iter()
+            .map(|w| w.iter().map(|item| to_hex(item)).collect::<Vec<_>>())
+            .collect::<Vec<_>>(),
+        "hex": to_hex(&tx.to_witness_bytes()),
+    })
+}
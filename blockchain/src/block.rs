@@ -2,12 +2,40 @@
 //! 
 //! 
 
+use std::collections::HashSet;
+
+use byteorder::{WriteBytesExt, BigEndian};
 use merkletree::merkle::{MerkleTree};
 //use digest::{Input, FixedOutput};
 //use sha2::Sha256;
 
-use crate::transaction::{Transaction, TxAddr, CoinValue};
-use crate::mkt::{HashVal, HashAlgorithm};
+use crate::encode::{self, Reader};
+use crate::error::ChainError;
+use crate::mkt::{self, HashVal, HashAlgorithm};
+use crate::pow;
+use crate::SimpleTx;
+
+use std::fmt;
+
+/// Number of previous blocks whose timestamps give the median time past.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+/// A block may be at most this many seconds ahead of the network-adjusted time.
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+
+/// Version bit of headers sealed by a validator instead of a nonce.
+pub const STAKE_SEAL_FLAG: u32 = 1 << 31;
+
+/// Signature of a proof of stake block, it takes the place of the nonce.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StakeSeal{
+    /// Public key of the validator.
+    pub validator: [u8; 32],
+    /// Output and proof of the validator's VRF for the slot of the block.
+    pub vrf_output: [u8; 32],
+    pub vrf_proof: [u8; 64],
+    /// Signature of `BlockHeader::signing_bytes`.
+    pub signature: [u8; 64],
+}
 
 #[derive(Debug, Clone)]
 pub struct BlockHeader{
@@ -17,6 +45,7 @@ pub struct BlockHeader{
     timestamp: u64,      // 64
     bits: u32,          
     nonce: u32, 
+    seal: Option<StakeSeal>,
 }
 
 impl BlockHeader{
@@ -29,61 +58,422 @@ impl BlockHeader{
         self.timestamp = u64::max(self.timestamp, ts);
         self
     }
+
+    pub fn set_bits(&mut self, bits: u32) -> &mut Self{
+        self.bits = bits;
+        self
+    }
+
+    pub fn set_nonce(&mut self, nonce: u32) -> &mut Self{
+        self.nonce = nonce;
+        self
+    }
+
+    /// Replace the nonce with a validator's seal.
+    pub fn set_seal(&mut self, seal: StakeSeal) -> &mut Self{
+        self.version |= STAKE_SEAL_FLAG;
+        self.nonce = 0;
+        self.seal = Some(seal);
+        self
+    }
+
+    pub fn timestamp(&self) -> u64{
+        self.timestamp
+    }
+
+    pub fn bits(&self) -> u32{
+        self.bits
+    }
+
+    pub fn nonce(&self) -> u32{
+        self.nonce
+    }
+
+    pub fn merkle_root(&self) -> &[u8; 32]{
+        &self.merkle_root
+    }
+
+    pub fn prev_block(&self) -> &[u8; 32]{
+        &self.prev_block
+    }
+
+    pub fn version(&self) -> u32{
+        self.version
+    }
+
+    pub fn seal(&self) -> Option<&StakeSeal>{
+        self.seal.as_ref()
+    }
+
+    /// Sealed headers end with the seal instead of the nonce.
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut v = self.signing_bytes();
+        match self.seal.as_ref(){
+            Some(seal) => v.extend(&seal.signature[..]),
+            None => v.write_u32::<BigEndian>(self.nonce).unwrap(),
+        }
+        v
+    }
+
+    /// The bytes signed by the validator: the header without the signature.
+    /// Unsealed headers stop before the nonce.
+    pub fn signing_bytes(&self) -> Vec<u8>{
+        let mut v = Vec::with_capacity(84);
+        v.write_u32::<BigEndian>(self.version).unwrap();
+        v.extend(&self.prev_block);
+        v.extend(&self.merkle_root);
+        v.write_u64::<BigEndian>(self.timestamp).unwrap();
+        v.write_u32::<BigEndian>(self.bits).unwrap();
+        if let Some(seal) = self.seal.as_ref(){
+            v.extend(&seal.validator);
+            v.extend(&seal.vrf_output);
+            v.extend(&seal.vrf_proof[..]);
+        }
+        v
+    }
+
+    pub fn decode(r: &mut Reader) -> Option<BlockHeader>{
+        let version = r.read_u32()?;
+        let prev_block = r.read_hash()?;
+        let merkle_root = r.read_hash()?;
+        let timestamp = r.read_u64()?;
+        let bits = r.read_u32()?;
+        let (nonce, seal) = if version & STAKE_SEAL_FLAG == 0{
+            (r.read_u32()?, None)
+        }else{
+            let validator = r.read_hash()?;
+            let vrf_output = r.read_hash()?;
+            let mut vrf_proof = [0; 64];
+            vrf_proof.copy_from_slice(r.read_bytes(64)?);
+            let mut signature = [0; 64];
+            signature.copy_from_slice(r.read_bytes(64)?);
+            (0, Some(StakeSeal{ validator, vrf_output, vrf_proof, signature }))
+        };
+        Some(BlockHeader{ version, prev_block, merkle_root, timestamp, bits, nonce, seal })
+    }
+
+    /// Double SHA-256 of the header.
+    pub fn hash(&self) -> [u8; 32]{
+        mkt::sha256d(&self.to_bytes())
+    }
+
+    /// A timestamp must be greater than the median time past of the previous blocks
+    /// and at most `MAX_FUTURE_BLOCK_TIME` ahead of the network-adjusted time.
+    pub fn check_timestamp(&self, median_time_past: u64, adjusted_time: u64) -> Result<(), ChainError>{
+        if self.timestamp <= median_time_past{
+            return Err(ChainError::TimeTooOld{
+                timestamp: self.timestamp,
+                median_time_past,
+            })
+        }
+        let max = adjusted_time.saturating_add(MAX_FUTURE_BLOCK_TIME);
+        if self.timestamp > max{
+            return Err(ChainError::TimeTooNew{
+                timestamp: self.timestamp,
+                max,
+            })
+        }
+        Ok(())
+    }
+}
+
+/// What a block needs from its transactions, so that other state models can reuse blocks.
+pub trait BlockTx: Clone + fmt::Debug{
+    /// Hash committed in the merkle root.
+    fn txid(&self) -> [u8; 32];
+
+    /// Hash including the witness, committed in the coinbase.
+    fn wtxid(&self) -> [u8; 32]{
+        self.txid()
+    }
+
+    /// The first transaction of a block, paying the producer.
+    fn is_coinbase(&self) -> bool;
+
+    fn has_witness(&self) -> bool{
+        false
+    }
+
+    /// Commit to the witness root, called on the coinbase when another transaction has a witness.
+    fn set_witness_commitment(&mut self, _root: &[u8; 32]){}
+
+    fn encode_into(&self, buf: &mut Vec<u8>);
+
+    fn decode_from(r: &mut Reader) -> Option<Self>;
+}
+
+impl BlockTx for SimpleTx{
+    fn txid(&self) -> [u8; 32]{
+        SimpleTx::txid(self)
+    }
+
+    fn wtxid(&self) -> [u8; 32]{
+        SimpleTx::wtxid(self)
+    }
+
+    fn is_coinbase(&self) -> bool{
+        SimpleTx::is_coinbase(self)
+    }
+
+    fn has_witness(&self) -> bool{
+        SimpleTx::has_witness(self)
+    }
+
+    fn set_witness_commitment(&mut self, root: &[u8; 32]){
+        SimpleTx::set_witness_commitment(self, root)
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>){
+        self.encode(buf, SimpleTx::has_witness(self))
+    }
+
+    fn decode_from(r: &mut Reader) -> Option<SimpleTx>{
+        SimpleTx::decode(r)
+    }
+}
+
+/// Merkle root of the wtxids of `txs`. The coinbase counts as zero since it holds the commitment.
+pub fn witness_root<T: BlockTx>(txs: &[T]) -> [u8; 32]{
+    let leaves = txs.iter()
+        .map(|tx| if tx.is_coinbase() { HashVal::default() } else { HashVal(tx.wtxid()) })
+        .collect();
+    mkt::build_tree(leaves).root().0
+}
+
+/// Median of the last `MEDIAN_TIME_SPAN` timestamps, 0 for an empty slice.
+pub fn median_time_past(timestamps: &[u64]) -> u64{
+    let start = timestamps.len().saturating_sub(MEDIAN_TIME_SPAN);
+    let mut ts = timestamps[start..].to_vec();
+    if ts.is_empty(){
+        return 0
+    }
+    ts.sort();
+    ts[ts.len() / 2]
 }
 
 #[derive(Clone, Debug)]
-pub struct BlockData{
+pub struct BlockData<T = SimpleTx>{
     mkt: MerkleTree<HashVal, HashAlgorithm, merkletree::store::VecStore<HashVal>>, //
+    txs: Vec<T>,
 }
     
+/// A header and its transactions, `SimpleTx` of the UTXO model by default.
 #[derive(Clone, Debug)]
-pub struct Block{
+pub struct Block<T = SimpleTx>{
     header: BlockHeader,
-    data: BlockData,
+    data: BlockData<T>,
     // data
 }
 
-impl Block{
-    /// Create a genesis_block.
-    pub fn genesis_block(ts: u64) -> Block{
-        let mkt = MerkleTree::new(vec![HashVal([0; 32]); 2]).unwrap();
+impl<T: BlockTx> Block<T>{
+    fn new(prev_block: [u8; 32], ts: u64, txs: Vec<T>) -> Block<T>{
+        let hashes = txs.iter().map(|tx| HashVal(tx.txid())).collect();
+        let mkt = mkt::build_tree(hashes);
+
         Block{
             header: BlockHeader{
                 version: 0,
-                prev_block: [0; 32],
+                prev_block,
                 merkle_root: mkt.root().0,
                 timestamp: ts,
                 bits: 0,
                 nonce: 0,
+                seal: None,
             },
             data: BlockData{
                 mkt: mkt,
+                txs,
             }
         }
     }
 
-    /// Pack transactions into a block
-    pub fn pack<A, V>(ts: u64, txs: impl Iterator<Item=Transaction<A, V>>) -> Block
-        where A: TxAddr + AsRef<[u8]>, V: CoinValue 
-    {
-        unimplemented!()
-        /*
-        let hashes = vec![];
-        let mkt = MerkleTree::new(hashes).unwrap();
+    /// Create a genesis_block. Its proof of work is never checked.
+    pub fn genesis_block(ts: u64, bits: u32, coinbase: T) -> Block<T>{
+        let mut block = Block::new([0; 32], ts, vec![coinbase]);
+        block.header.set_bits(bits);
+        block
+    }
 
-        Block{
-            header: BlockHeader{
-                version: 0,
-                prev_block: [0; 32],
-                merkle_root: mkt.root().0,
-                timestamp: ts,
-                bits: 0,
-                nonce: 0,
-            },
-            data: BlockData{
-                mkt: mkt,
+    /// Pack transactions into a block on top of `prev`.
+    /// If any transaction carries witness data, the witness root is committed in the coinbase.
+    pub fn pack(prev: &BlockHeader, ts: u64, txs: impl Iterator<Item=T>) -> Block<T>{
+        let mut txs: Vec<T> = txs.collect();
+        if txs.iter().any(|tx| tx.has_witness()) && txs.first().is_some_and(|tx| tx.is_coinbase()){
+            let root = witness_root(&txs);
+            txs[0].set_witness_commitment(&root);
+        }
+        Block::new(prev.hash(), ts, txs)
+    }
+
+    /// Merkle root of the transactions, to be compared with the header.
+    pub fn compute_merkle_root(&self) -> [u8; 32]{
+        let hashes = self.data.txs.iter().map(|tx| HashVal(tx.txid())).collect();
+        mkt::build_tree(hashes).root().0
+    }
+
+    /// True if a txid appears twice. Such a block is invalid, but it may share its merkle root,
+    /// hence its hash, with a valid block, so its hash must not be remembered as invalid.
+    pub fn is_mutated(&self) -> bool{
+        let mut seen = HashSet::with_capacity(self.data.txs.len());
+        !self.data.txs.iter().all(|tx| seen.insert(tx.txid()))
+    }
+
+    /// Rebuild the merkle tree and root after the transactions changed.
+    pub fn update_merkle_root(&mut self){
+        let hashes = self.data.txs.iter().map(|tx| HashVal(tx.txid())).collect();
+        self.data.mkt = mkt::build_tree(hashes);
+        self.header.set_merkle_root(self.data.mkt.root().0);
+    }
+
+    pub fn check_proof_of_work(&self, pow_limit: u32) -> bool{
+        pow::check_proof_of_work(&self.hash(), self.header.bits, pow_limit)
+    }
+
+    /// Search a nonce meeting the target in `bits`, false if there is none.
+    pub fn mine(&mut self, pow_limit: u32) -> bool{
+        for nonce in 0..=u32::MAX{
+            self.header.nonce = nonce;
+            if self.check_proof_of_work(pow_limit){
+                return true
             }
-        }*/
+        }
+        false
+    }
+
+    /// Header followed by the transactions with their witnesses.
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut buf = self.header.to_bytes();
+        encode::write_varint(&mut buf, self.data.txs.len() as u64);
+        for tx in self.data.txs.iter(){
+            tx.encode_into(&mut buf);
+        }
+        buf
+    }
+
+    /// Decode a whole buffer. The header is kept as it is, even if it does not match the transactions.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Block<T>>{
+        let mut r = Reader::new(bytes);
+        let header = BlockHeader::decode(&mut r)?;
+        let n = r.read_count(10)?;
+        let txs = (0..n).map(|_| T::decode_from(&mut r)).collect::<Option<Vec<_>>>()?;
+        if !r.is_empty(){
+            return None
+        }
+        Some(Block::from_parts(header, txs))
+    }
+
+    /// Assemble a block received in pieces. The header is kept as it is.
+    pub fn from_parts(header: BlockHeader, txs: Vec<T>) -> Block<T>{
+        let mut block = Block::new(header.prev_block, header.timestamp, txs);
+        block.header = header;
+        block
+    }
+
+    pub fn txs(&self) -> &[T]{
+        &self.data.txs
+    }
+
+    /// Transactions can be changed without updating the header, e.g. to build invalid blocks.
+    pub(crate) fn txs_mut(&mut self) -> &mut Vec<T>{
+        &mut self.data.txs
+    }
+
+    pub fn header(&self) -> &BlockHeader{
+        &self.header
+    }
+
+    pub(crate) fn header_mut(&mut self) -> &mut BlockHeader{
+        &mut self.header
+    }
+
+    pub fn hash(&self) -> [u8; 32]{
+        self.header.hash()
     }
 }
 
+#[cfg(test)]
+mod block_test{
+    use super::*;
+
+    #[test]
+    fn test_median_time_past() {
+        assert_eq!(0, median_time_past(&[]));
+        assert_eq!(5, median_time_past(&[5]));
+        assert_eq!(3, median_time_past(&[9, 1, 3]));
+        // only the last 11 count
+        let ts: Vec<u64> = (0..20).collect();
+        assert_eq!(14, median_time_past(&ts));
+    }
+
+    #[test]
+    fn test_mine() {
+        let params = crate::ChainParams::regtest();
+        let genesis = params.genesis_block();
+        let mut b = Block::pack(genesis.header(), 1, Vec::<SimpleTx>::new().into_iter());
+        b.header.set_bits(0x1f7fffff);
+        assert!(b.mine(params.pow_limit));
+        assert!(b.check_proof_of_work(params.pow_limit));
+        assert_eq!(0, b.hash()[0]);
+        // easier than the limit allows
+        assert!(!b.check_proof_of_work(0x1e7fffff));
+    }
+
+    #[test]
+    fn test_block_bytes() {
+        let genesis = crate::ChainParams::regtest().genesis_block();
+        let mut tx = SimpleTx::coinbase(2, 50.into(), "Bob".to_string());
+        tx.witness = vec![vec![b"w".to_vec()]];
+        let cb = SimpleTx::coinbase(1, 50.into(), "Alice".to_string());
+        let b = Block::pack(genesis.header(), 7, vec![cb, tx].into_iter());
+
+        let bytes = b.to_bytes();
+        let decoded: Block = Block::from_bytes(&bytes).unwrap();
+        assert_eq!(b.hash(), decoded.hash());
+        assert_eq!(b.txs()[1].wtxid(), decoded.txs()[1].wtxid());
+        assert!(Block::<SimpleTx>::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn test_mutated() {
+        let genesis = crate::ChainParams::regtest().genesis_block();
+        let txs: Vec<_> = (1..=5).map(|h| SimpleTx::coinbase(h, 50.into(), "Alice".to_string())).collect();
+        let b = Block::pack(genesis.header(), 7, txs.clone().into_iter());
+        assert!(!b.is_mutated());
+
+        // repeating the last transaction up to 8 leaves keeps the root and the hash
+        for n in 1..=3{
+            let mut repeated = txs.clone();
+            repeated.extend(vec![txs[4].clone(); n]);
+            let mutated = Block::from_parts(b.header().clone(), repeated);
+            assert_eq!(b.compute_merkle_root(), mutated.compute_merkle_root());
+            assert_eq!(b.hash(), mutated.hash());
+            assert!(mutated.is_mutated());
+        }
+        let mut repeated = txs.clone();
+        repeated.extend(vec![txs[4].clone(); 4]);
+        assert_ne!(b.compute_merkle_root(), Block::from_parts(b.header().clone(), repeated).compute_merkle_root());
+
+        let mut inner = txs.clone();
+        inner.insert(1, txs[1].clone());
+        assert!(Block::from_parts(b.header().clone(), inner).is_mutated());
+    }
+
+    #[test]
+    fn test_check_timestamp() {
+        let mut h = crate::ChainParams::regtest().genesis_block().header;
+        h.timestamp = 1000;
+        assert!(h.check_timestamp(999, 0).is_ok());
+        assert_eq!(
+            Err(ChainError::TimeTooOld{ timestamp: 1000, median_time_past: 1000 }),
+            h.check_timestamp(1000, 0)
+        );
+
+        h.timestamp = 5000 + MAX_FUTURE_BLOCK_TIME;
+        assert!(h.check_timestamp(0, 5000).is_ok());
+        h.timestamp += 1;
+        assert_eq!(
+            Err(ChainError::TimeTooNew{ timestamp: 5001 + MAX_FUTURE_BLOCK_TIME, max: 5000 + MAX_FUTURE_BLOCK_TIME }),
+            h.check_timestamp(0, 5000)
+        );
+    }
+}
@@ -0,0 +1,604 @@
+//! Compact block filters in the style of BIP157/BIP158.
+//!
+//! Unlike bloom filters the node does not learn what a light client watches. For every block the
+//! node builds a Golomb-Rice coded set (GCS) of the addresses paid by its outputs and of the
+//! outpoints spent by its inputs. Filters are chained by filter headers, so a client holding the
+//! headers can tell whether a downloaded filter is genuine. The client tests its own addresses and
+//! coins against each filter and fetches only the blocks that match.
+//!
+//! Elements are hashed with SipHash-2-4 keyed by the first 16 bytes of the block hash, mapped to
+//! `[0, N * M)` and coded with the basic filter parameters of BIP158. The count in front of the
+//! filter is a CompactSize as in BIP158, not the crate's big-endian varint.
+
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hasher;
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, LittleEndian};
+use siphasher::sip::SipHasher24;
+
+use crate::block::{Block, BlockHeader};
+use crate::consensus::{self, Consensus};
+use crate::encode::{self, Reader};
+use crate::mkt;
+use crate::spv::outpoint_key;
+use crate::{BlockChain, ChainError, ChainParams, OutPoint, SimpleValue, Trans};
+
+/// Bits of the remainder of every coded delta.
+pub const FILTER_P: u8 = 19;
+/// Inverse of the false positive rate.
+pub const FILTER_M: u64 = 784_931;
+
+/// Writes bits from the most significant bit of every byte.
+struct BitWriter{
+    buf: Vec<u8>,
+    bits: usize,
+}
+
+impl BitWriter{
+    fn write_bit(&mut self, bit: bool){
+        if self.bits.is_multiple_of(8){
+            self.buf.push(0);
+        }
+        if bit{
+            *self.buf.last_mut().unwrap() |= 0x80 >> (self.bits % 8);
+        }
+        self.bits += 1;
+    }
+
+    fn write_bits(&mut self, value: u64, n: u8){
+        for i in (0..n).rev(){
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a>{
+    data: &'a [u8],
+    bits: usize,
+}
+
+impl<'a> BitReader<'a>{
+    fn read_bit(&mut self) -> Option<bool>{
+        let byte = self.data.get(self.bits / 8)?;
+        let bit = byte & (0x80 >> (self.bits % 8)) != 0;
+        self.bits += 1;
+        Some(bit)
+    }
+
+    fn read_bits(&mut self, n: u8) -> Option<u64>{
+        let mut value = 0;
+        for _ in 0..n{
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Some(value)
+    }
+}
+
+/// Map an element uniformly to `[0, f)`.
+fn hash_to_range(keys: (u64, u64), item: &[u8], f: u64) -> u64{
+    let mut hasher = SipHasher24::new_with_keys(keys.0, keys.1);
+    hasher.write(item);
+    ((hasher.finish() as u128 * f as u128) >> 64) as u64
+}
+
+fn filter_keys(block_hash: &[u8; 32]) -> (u64, u64){
+    (LittleEndian::read_u64(&block_hash[0..8]), LittleEndian::read_u64(&block_hash[8..16]))
+}
+
+/// The basic filter of a block: the element count then the coded deltas.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockFilter{
+    pub content: Vec<u8>,
+}
+
+impl BlockFilter{
+    /// Filter of a set of elements. Duplicates are counted once.
+    pub fn new<'a>(block_hash: &[u8; 32], elements: impl IntoIterator<Item=&'a [u8]>) -> BlockFilter{
+        let elements: BTreeSet<&[u8]> = elements.into_iter().collect();
+        let n = elements.len() as u64;
+        let keys = filter_keys(block_hash);
+        let mut values: Vec<u64> = elements.iter().map(|e| hash_to_range(keys, e, n * FILTER_M)).collect();
+        values.sort();
+
+        let mut content = Vec::new();
+        encode::write_compact_size(&mut content, n);
+        let mut w = BitWriter{ bits: content.len() * 8, buf: content };
+        let mut last = 0;
+        for v in values{
+            let delta = v - last;
+            last = v;
+            for _ in 0..(delta >> FILTER_P){
+                w.write_bit(true);
+            }
+            w.write_bit(false);
+            w.write_bits(delta, FILTER_P);
+        }
+        BlockFilter{ content: w.buf }
+    }
+
+    /// Addresses paid by the block and the outpoints it spends.
+    /// Unspendable outputs, like the witness commitment, are left out.
+    pub fn from_block(block: &Block) -> BlockFilter{
+        let mut elements: Vec<Vec<u8>> = Vec::new();
+        for tx in block.txs(){
+            for out in tx.output.0.iter(){
+                if !out.is_unspendable() && !out.addr.is_empty(){
+                    elements.push(out.addr.as_bytes().to_vec());
+                }
+            }
+            if !tx.is_coinbase(){
+                elements.extend(tx.input.0.iter().map(|txin| outpoint_key(&txin.prev_out)));
+            }
+        }
+        BlockFilter::new(&block.hash(), elements.iter().map(|e| e.as_slice()))
+    }
+
+    /// Number of elements, `None` if the filter is malformed.
+    pub fn len(&self) -> Option<u64>{
+        Reader::new(&self.content).read_compact_size()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.len() == Some(0)
+    }
+
+    /// Header of this filter, committing to the header of the previous one.
+    pub fn filter_header(&self, prev_header: &[u8; 32]) -> [u8; 32]{
+        let mut buf = mkt::sha256d(&self.content).to_vec();
+        buf.extend(prev_header);
+        mkt::sha256d(&buf)
+    }
+
+    /// Whether any of `queries` may be in the filter of the block with `block_hash`.
+    /// False positives happen once in `FILTER_M` queries, a malformed filter matches nothing.
+    pub fn match_any<'a>(&self, block_hash: &[u8; 32], queries: impl IntoIterator<Item=&'a [u8]>) -> bool{
+        let mut r = Reader::new(&self.content);
+        let n = match r.read_compact_size(){
+            Some(n) if n > 0 => n,
+            _ => return false,
+        };
+        let keys = filter_keys(block_hash);
+        let f = n.saturating_mul(FILTER_M);
+        let mut targets: Vec<u64> = queries.into_iter().map(|q| hash_to_range(keys, q, f)).collect();
+        if targets.is_empty(){
+            return false
+        }
+        targets.sort();
+
+        let mut bits = BitReader{ data: &self.content, bits: (self.content.len() - r.remaining()) * 8 };
+        let mut value = 0u64;
+        let mut next = 0;
+        for _ in 0..n{
+            let mut q = 0u64;
+            loop{
+                match bits.read_bit(){
+                    Some(true) => q += 1,
+                    Some(false) => break,
+                    None => return false,
+                }
+            }
+            let rem = match bits.read_bits(FILTER_P){
+                Some(rem) => rem,
+                None => return false,
+            };
+            value = value.saturating_add((q << FILTER_P) + rem);
+            while targets[next] < value{
+                next += 1;
+                if next == targets.len(){
+                    return false
+                }
+            }
+            if targets[next] == value{
+                return true
+            }
+        }
+        false
+    }
+}
+
+/// Filters and filter headers of the active chain, kept by a full node to serve light clients.
+#[derive(Default)]
+pub struct FilterIndex{
+    // (block hash, filter, filter header) by height
+    entries: Vec<([u8; 32], BlockFilter, [u8; 32])>,
+}
+
+impl FilterIndex{
+    /// Index of every block of `chain`, which must not be pruned.
+    pub fn new(chain: &BlockChain) -> Result<FilterIndex, ChainError>{
+        let mut index = FilterIndex::default();
+        index.sync(chain)?;
+        Ok(index)
+    }
+
+    /// Follow the active chain of `chain`, dropping the filters of disconnected blocks.
+    /// Fails if blocks without a filter yet were pruned, the filters of pruned blocks are kept.
+    pub fn sync(&mut self, chain: &BlockChain) -> Result<(), ChainError>{
+        while let Some((hash, _, _)) = self.entries.last(){
+            let height = self.entries.len() - 1;
+            if chain.header(height).map(|h| h.hash()) == Some(*hash){
+                break
+            }
+            self.entries.pop();
+        }
+        if self.entries.len() < chain.prune_height(){
+            return Err(ChainError::BlockPruned{ height: self.entries.len() })
+        }
+        for height in self.entries.len()..=chain.height(){
+            let block = chain.block(height).unwrap();
+            let filter = BlockFilter::from_block(block);
+            let prev = self.entries.last().map(|e| e.2).unwrap_or([0; 32]);
+            let header = filter.filter_header(&prev);
+            self.entries.push((block.hash(), filter, header));
+        }
+        Ok(())
+    }
+
+    pub fn height(&self) -> usize{
+        self.entries.len() - 1
+    }
+
+    /// Answer to `getcfilters`.
+    pub fn filter(&self, height: usize) -> Option<&BlockFilter>{
+        self.entries.get(height).map(|e| &e.1)
+    }
+
+    /// Answer to `getcfheaders`.
+    pub fn filter_header(&self, height: usize) -> Option<&[u8; 32]>{
+        self.entries.get(height).map(|e| &e.2)
+    }
+}
+
+/// A wallet that downloads the filters of every block and only the matching blocks.
+/// Headers are checked against their seal and their parent, difficulty changes are not.
+pub struct FilterClient{
+    consensus: Arc<dyn Consensus>,
+    headers: Vec<BlockHeader>,
+    filter_headers: Vec<[u8; 32]>,
+    addrs: Vec<String>,
+    coins: HashMap<OutPoint, Trans<String, SimpleValue>>,
+}
+
+impl FilterClient{
+    /// A client at the genesis block of `params`, watching `addrs`.
+    pub fn new(params: &ChainParams, addrs: Vec<String>) -> FilterClient{
+        let genesis = params.genesis_block();
+        FilterClient{
+            consensus: consensus::engine(params),
+            headers: vec![genesis.header().clone()],
+            filter_headers: vec![BlockFilter::from_block(&genesis).filter_header(&[0; 32])],
+            addrs,
+            coins: HashMap::new(),
+        }
+    }
+
+    pub fn height(&self) -> usize{
+        self.headers.len() - 1
+    }
+
+    pub fn balance(&self) -> u64{
+        self.coins.values().map(|out| out.val.val).sum()
+    }
+
+    /// Elements tested against filters: the watched addresses and the outpoints of the coins.
+    pub fn watched(&self) -> Vec<Vec<u8>>{
+        self.addrs.iter().map(|a| a.as_bytes().to_vec())
+            .chain(self.coins.keys().map(outpoint_key))
+            .collect()
+    }
+
+    /// Append a header and the filter header of its block.
+    pub fn add_header(&mut self, header: BlockHeader, filter_header: [u8; 32]) -> Result<(), ChainError>{
+        if header.prev_block() != &self.headers.last().unwrap().hash(){
+            return Err(ChainError::BadPrevBlock)
+        }
+        self.consensus.check_seal(&header)?;
+        self.headers.push(header);
+        self.filter_headers.push(filter_header);
+        Ok(())
+    }
+
+    /// Check the filter of the block at `height` against the filter headers and test the
+    /// watched elements. True if the block has to be fetched.
+    pub fn check_filter(&self, height: usize, filter: &BlockFilter) -> Result<bool, ChainError>{
+        let header = self.headers.get(height).ok_or(ChainError::UnexpectedBlock)?;
+        if height == 0 || filter.filter_header(&self.filter_headers[height - 1]) != self.filter_headers[height]{
+            return Err(ChainError::BadFilterHeader)
+        }
+        let watched = self.watched();
+        Ok(filter.match_any(&header.hash(), watched.iter().map(|e| e.as_slice())))
+    }
+
+    /// Apply a fetched block to the coins of the wallet.
+    pub fn process_block(&mut self, height: usize, block: &Block) -> Result<(), ChainError>{
+        if self.headers.get(height).map(|h| h.hash()) != Some(block.hash()){
+            return Err(ChainError::UnexpectedBlock)
+        }
+        if &block.compute_merkle_root() != block.header().merkle_root(){
+            return Err(ChainError::BadMerkleRoot)
+        }
+        for tx in block.txs(){
+            let txid = tx.txid();
+            if !tx.is_coinbase(){
+                for txin in tx.input.0.iter(){
+                    self.coins.remove(&txin.prev_out);
+                }
+            }
+            for (i, out) in tx.output.0.iter().enumerate(){
+                if self.addrs.contains(&out.addr){
+                    self.coins.insert(OutPoint::new(txid, i as u32), out.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Catch up with a full node: headers, then filters, then the matching blocks.
+    /// Returns the heights of the fetched blocks.
+    pub fn sync(&mut self, chain: &BlockChain, index: &FilterIndex) -> Result<Vec<usize>, ChainError>{
+        let mut fetched = Vec::new();
+        for height in self.headers.len()..=index.height(){
+            let block = chain.block(height).ok_or(ChainError::UnexpectedBlock)?;
+            self.add_header(block.header().clone(), *index.filter_header(height).unwrap())?;
+            if self.check_filter(height, index.filter(height).unwrap())?{
+                self.process_block(height, block)?;
+                fetched.push(height);
+            }
+        }
+        Ok(fetched)
+    }
+}
+
+#[cfg(test)]
+mod cfilter_test{
+    use super::*;
+    use std::sync::Arc;
+    use crate::clock::MockClock;
+    use crate::encode::from_hex;
+    use crate::transaction::*;
+    use crate::{SimpleTx, COIN};
+
+    /// Hashes in the vectors are displayed in reverse byte order.
+    fn rev_hash(s: &str) -> [u8; 32]{
+        let mut h = [0; 32];
+        h.copy_from_slice(&from_hex(s).unwrap());
+        h.reverse();
+        h
+    }
+
+    #[test]
+    fn test_bip158_vectors() {
+        // testnet blocks: hash, elements, previous filter header, filter, filter header.
+        // The elements are the output scripts of the block, but the empty and OP_RETURN ones,
+        // and the scripts of the outputs it spends.
+        let vectors: &[(&str, &[&str], &str, &str, &str)] = &[
+            // block 0, genesis block
+            (
+                "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943",
+                &[
+                    "4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac",
+                ],
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "019dfca8",
+                "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750",
+            ),
+            // block 2
+            (
+                "000000006c02c8ea6e4ff69651f7fcde348fb9d557a06e6957b65552002a7820",
+                &[
+                    "21038a7f6ef1c8ca0c588aa53fa860128077c9e6c11e6830f4d7ee4e763a56b7718fac",
+                ],
+                "d7bdac13a59d745b1add0d2ce852f1a0442e8945fc1bf3848d3cbffd88c24fe1",
+                "0174a170",
+                "186afd11ef2b5e7e3504f2e8cbf8df28a1fd251fe53d60dff8b1467d1b386cf0",
+            ),
+            // block 3
+            (
+                "000000008b896e272758da5297bcd98fdc6d97c9b765ecec401e286dc1fdbe10",
+                &[
+                    "2103f6d9ff4c12959445ca5549c811683bf9c88e637b222dd2e0311154c4c85cf423ac",
+                ],
+                "186afd11ef2b5e7e3504f2e8cbf8df28a1fd251fe53d60dff8b1467d1b386cf0",
+                "016cf7a0",
+                "8d63aadf5ab7257cb6d2316a57b16f517bff1c6388f124ec4c04af1212729d2a",
+            ),
+            // block 15007, tx has non-standard OP_RETURN output followed by opcodes
+            (
+                "0000000038c44c703bae0f98cdd6bf30922326340a5996cc692aaae8bacf47ad",
+                &[
+                    "2103f268e9ae07e0f8cb2f6e901d87c510d650b97230c0365b021df8f467363cafb1ac",
+                ],
+                "18b5c2b0146d2d09d24fb00ff5b52bd0742f36c9e65527abdb9de30c027a4748",
+                "013c3710",
+                "07384b01311867949e0c046607c66b7a766d338474bb67f66c8ae9dbd454b20e",
+            ),
+            // block 49291, tx pays to empty output script
+            (
+                "0000000018b07dca1b28b4b5a119f6d6e71698ce1ed96f143f54179ce177a19c",
+                &[
+                    "2102971dd6034ed0cf52450b608d196c07d6345184fcb14deb277a6b82d526a6163dac",
+                    "512103b9d1d0e2b4355ec3cdef7c11a5c0beff9e8b8d8372ab4b4e0aaf30e80173001951ae",
+                    "52210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f8179821021d69e2b68c3960903b702af7829fadcd80bd89b158150c85c4a75b2c8cb9c39452ae",
+                    "52210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f8179821022adb62335f41eb4e27056ac37d462cda5ad783fa8e0e526ed79c752475db285d52ae",
+                    "522102a7ae1e0971fc1689bd66d2a7296da3a1662fd21a53c9e38979e0f090a375c12d21022adb62335f41eb4e27056ac37d462cda5ad783fa8e0e526ed79c752475db285d52ae",
+                    "5221033423007d8f263819a2e42becaaf5b06f34cb09919e06304349d950668209eaed21021d69e2b68c3960903b702af7829fadcd80bd89b158150c85c4a75b2c8cb9c39452ae",
+                    "522103f1848b40621c5d48471d9784c8174ca060555891ace6d2b03c58eece946b1a9121020ee5d32b54d429c152fdc7b1db84f2074b0564d35400d89d11870f9273ec140c52ae",
+                    "76a91445db0b779c0b9fa207f12a8218c94fc77aff504588ac",
+                    "76a9149144761ebaccd5b4bbdc2a35453585b5637b2f8588ac",
+                    "76a914f4fa1cc7de742d135ea82c17adf0bb9cf5f4fb8388ac",
+                ],
+                "ed47705334f4643892ca46396eb3f4196a5e30880589e4009ef38eae895d4a13",
+                "0afbc2920af1b027f31f87b592276eb4c32094bb4d3697021b4c6380",
+                "b6d98692cec5145f67585f3434ec3c2b3030182e1cb3ec58b855c5c164dfaaa3",
+            ),
+            // block 180480, tx spends from empty output script
+            (
+                "00000000fd3ceb2404ff07a785c7fdcc76619edc8ed61bd25134eaa22084366a",
+                &[
+                    "2102e769e60137a4df6b0df8ebd387cca44c4c57ae74cc0114a8e8317c8f3bfd85e9ac",
+                    "2103bb52138972c48a132fc1f637858c5189607dd0f7fe40c4f20f6ad65f2d389ba4ac",
+                    "76a914001fa7459a6cfc64bdc178ba7e7a21603bb2568f88ac",
+                    "76a9142903b138c24be9e070b3e73ec495d77a204615e788ac",
+                    "76a9142a0307cd925dbb66b534c4db33003dd18c57015788ac",
+                    "76a91433a1941fd9a37b9821d376f5a51bd4b52fa50e2888ac",
+                    "76a9143b8d051d37a07ea1042067e93efe63dbf73920b988ac",
+                    "76a9146d10f3f592699265d10b106eda37c3ce793f7a8588ac",
+                    "76a9147779b7fba1c1e06b717069b80ca170e8b04458a488ac",
+                    "76a914797fb8777d7991d8284d88bfd421ce520f0f843188ac",
+                    "76a914ae19d27efe12f5a886dc79af37ad6805db6f922d88ac",
+                    "76a914e4374e8155d0865742ca12b8d4d14d41b57d682f88ac",
+                    "76a914f6039952bc2b307aeec5371bfb96b66078ec17f688ac",
+                ],
+                "d34ef98386f413769502808d4bac5f20f8dfd5bffc9eedafaa71de0eb1f01489",
+                "0db414c859a07e8205876354a210a75042d0463404913d61a8e068e58a3ae2aa080026",
+                "c582d51c0ca365e3fcf36c51cb646d7f83a67e867cb4743fd2128e3e022b700c",
+            ),
+            // block 926485, duplicate pushdata 913bcc2be49cb534c20474c4dee1e9c4c317e7eb
+            (
+                "000000000000015d6077a411a8f5cc95caf775ccf11c54e27df75ce58d187313",
+                &[
+                    "52534b424c4f434b3acd16772ad61a3c5f00287480b720f6035d5e54c9efc71be94bb5e3727f109090",
+                    "76a9143ebc40e411ed3c76f86711507ab952300890397288ac",
+                    "76a91450333046115eaa0ac9e0216565f945070e44573988ac",
+                    "76a914876fbb82ec05caa6af7a3b5e5a983aae6c6cc6d688ac",
+                    "76a914913bcc2be49cb534c20474c4dee1e9c4c317e7eb88ac",
+                    "76a914c01a7ca16b47be50cbdbc60724f701d52d75156688ac",
+                    "a9148fc37ad460fdfbd2b44fe446f6e3071a4f64faa687",
+                    "a914b7e6f7ff8658b2d1fb107e3d7be7af4742e6b1b387",
+                    "a914feb8a29635c56d9cd913122f90678756bf23887687",
+                ],
+                "8f13b9a9c85611635b47906c3053ac53cfcec7211455d4cb0d63dc9acc13d472",
+                "09027acea61b6cc3fb33f5d52f7d088a6b2f75d234e89ca800",
+                "546c574a0472144bcaf9b6aeabf26372ad87c7af7d1ee0dbfae5e099abeae49c",
+            ),
+            // block 987876, coinbase tx has unparseable output script
+            (
+                "0000000000000c00901f2049055e2a437c819d79a3d54fd63e6af796cd7b8a79",
+                &[
+                    "76a914c486de584a735ec2f22da7cd9681614681f92173d83d0aa68688ac",
+                ],
+                "fe4d230dbb0f4fec9bed23a5283e08baf996e3f32b93f52c7de1f641ddfd04ad",
+                "010c0b40",
+                "0965a544743bbfa36f254446e75630c09404b3d164a261892372977538928ed5",
+            ),
+            // block 1263442, includes witness data
+            (
+                "000000006f27ddfe1dd680044a34548f41bed47eba9e6f0b310da21423bc5f33",
+                &[
+                    "001446c29eabe8208a33aa1023c741fa79aa92e881ff",
+                    "002027a5000c7917f785d8fc6e5a55adfca8717ecb973ebb7743849ff956d896a7ed",
+                    "76a914f2c25ac3d59f3d674b1d1d0a25c27339aaac0ba688ac",
+                ],
+                "31d66d516a9eda7de865df29f6ef6cb8e4bf9309e5dac899968a9a62a5df61e3",
+                "0385acb4f0fe889ef0",
+                "4e6d564c2a2452065c205dd7eb2791124e0c4e0dbb064c410c24968572589dec",
+            ),
+            // block 1414221, empty data
+            (
+                "0000000000000027b2b3b3381f114f674f481544ff2be37ae3788d7e078383b1",
+                &[],
+                "5e5e12d90693c8e936f01847859404c67482439681928353ca1296982042864e",
+                "00",
+                "021e8882ef5a0ed932edeebbecfeda1d7ce528ec7b3daa27641acf1189d7b5dc",
+            ),
+        ];
+        for &(hash, elements, prev, content, header) in vectors{
+            let hash = rev_hash(hash);
+            let elements: Vec<Vec<u8>> = elements.iter().map(|e| from_hex(e).unwrap()).collect();
+            let filter = BlockFilter::new(&hash, elements.iter().map(|e| e.as_slice()));
+            assert_eq!(from_hex(content).unwrap(), filter.content);
+            assert_eq!(Some(elements.len() as u64), filter.len());
+            assert_eq!(rev_hash(header), filter.filter_header(&rev_hash(prev)));
+            assert!(elements.iter().all(|e| filter.match_any(&hash, vec![e.as_slice()])));
+        }
+
+        // above 252 elements the count is 0xfd then two little-endian bytes
+        let hash = mkt::sha256(b"block");
+        let elements: Vec<Vec<u8>> = (0..300u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let filter = BlockFilter::new(&hash, elements.iter().map(|e| e.as_slice()));
+        assert_eq!(&[0xfd, 0x2c, 0x01], &filter.content[..3]);
+        assert_eq!(Some(300), filter.len());
+        assert!(elements.iter().all(|e| filter.match_any(&hash, vec![e.as_slice()])));
+    }
+
+    #[test]
+    fn test_match_any() {
+        let hash = mkt::sha256(b"block");
+        let elements: Vec<Vec<u8>> = (0..1000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let filter = BlockFilter::new(&hash, elements.iter().map(|e| e.as_slice()));
+        assert_eq!(Some(1000), filter.len());
+        // about P + 2.5 bits per element
+        assert!(filter.content.len() < 1000 * 22 / 8);
+        for e in elements.iter(){
+            assert!(filter.match_any(&hash, vec![e.as_slice()]));
+        }
+        let others: Vec<Vec<u8>> = (1000..11000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let hits = others.iter().filter(|e| filter.match_any(&hash, vec![e.as_slice()])).count();
+        assert!(hits <= 1);
+        assert!(filter.match_any(&hash, others.iter().chain(elements.last()).map(|e| e.as_slice())));
+        // another block hash keys the hashes differently
+        assert!(!elements.iter().all(|e| filter.match_any(&[0; 32], vec![e.as_slice()])));
+    }
+
+    #[test]
+    fn test_filter_client() {
+        let params = ChainParams::regtest();
+        let g = params.genesis_timestamp;
+        let mut chain = crate::test_chain(Arc::new(MockClock::new(g + 1_000_000)));
+        let mut cb = SimpleTx::coinbase(1, COIN.into(), "Alice".to_string());
+        cb.output.0.push(Trans{addr: "Carol".to_string(), val: COIN.into()});
+        let to_carol = OutPoint::new(cb.txid(), 1);
+        chain.add_block(crate::mine_block(&chain, g + 100, vec![cb])).unwrap();
+        for i in 2..5u64{
+            let block = chain.create_block(g + 100 * i, "Miner".to_string(), vec![]).unwrap();
+            chain.add_block(block).unwrap();
+        }
+        let spend = SimpleTx{
+            input: InputTx(vec![TxIn::new(to_carol, "Carol".to_string(), COIN.into())]),
+            output: OutputTx(vec![Trans{addr: "Dave".to_string(), val: (COIN - 1000).into()}]),
+            lock_time: 0,
+            witness: vec![],
+        };
+
+        let mut index = FilterIndex::new(&chain).unwrap();
+        let mut client = FilterClient::new(&params, vec!["Carol".to_string()]);
+        assert_eq!(Ok(vec![1]), client.sync(&chain, &index));
+        assert_eq!(4, client.height());
+        assert_eq!(COIN, client.balance());
+
+        let block = chain.create_block(g + 500, "Miner".to_string(), vec![spend]).unwrap();
+        chain.add_block(block).unwrap();
+        index.sync(&chain).unwrap();
+        // the spend is found through the outpoint of the coin
+        assert_eq!(Ok(vec![5]), client.sync(&chain, &index));
+        assert_eq!(0, client.balance());
+
+        let fake = BlockFilter::new(&chain.tip().hash(), vec![&b"Carol"[..]]);
+        assert_eq!(Err(ChainError::BadFilterHeader), client.check_filter(5, &fake));
+        assert_eq!(Ok(false), client.check_filter(3, index.filter(3).unwrap()));
+    }
+
+    #[test]
+    fn test_sync_pruned() {
+        let g = ChainParams::regtest().genesis_timestamp;
+        let mut chain = crate::test_chain(Arc::new(MockClock::new(g + 1_000_000)));
+        let mut index = FilterIndex::new(&chain).unwrap();
+        for i in 1..6u64{
+            let block = chain.create_block(g + 100 * i, "Miner".to_string(), vec![]).unwrap();
+            chain.add_block(block).unwrap();
+        }
+        index.sync(&chain).unwrap();
+        let filters: Vec<BlockFilter> = (0..=5).map(|h| index.filter(h).unwrap().clone()).collect();
+        assert_eq!(4, chain.prune(2));
+        assert_eq!(Err(ChainError::BlockPruned{ height: 0 }), FilterIndex::new(&chain).map(|_| ()));
+
+        // the filters of the pruned blocks stay
+        let block = chain.create_block(g + 600, "Miner".to_string(), vec![]).unwrap();
+        chain.add_block(block).unwrap();
+        index.sync(&chain).unwrap();
+        assert_eq!(6, index.height());
+        assert!((0..=5).all(|h| index.filter(h) == Some(&filters[h])));
+        assert_eq!(Some(&BlockFilter::from_block(chain.tip())), index.filter(6));
+    }
+}
@@ -0,0 +1,146 @@
+//! Clocks used by the timestamp rules of blocks.
+//!
+//! `BlockChain` never reads the system time directly, it asks a `Clock`.
+//! Tests inject a `MockClock` so that they can move time by hand.
+
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Peers whose clocks differ from ours by more than this are ignored.
+pub const MAX_TIME_ADJUSTMENT: i64 = 70 * 60;
+/// At most this many time samples from peers are kept.
+pub const MAX_TIME_SAMPLES: usize = 200;
+/// Offsets from peers are not used until this many samples are collected.
+pub const MIN_TIME_SAMPLES: usize = 5;
+
+/// Source of unix time in seconds.
+pub trait Clock: Debug + Send + Sync{
+    fn now(&self) -> u64;
+}
+
+/// Wall clock of the local machine.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock{
+    fn now(&self) -> u64{
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// Clock that only moves when told to.
+#[derive(Debug, Default)]
+pub struct MockClock{
+    now: AtomicU64,
+}
+
+impl MockClock{
+    pub fn new(ts: u64) -> MockClock{
+        MockClock{
+            now: AtomicU64::new(ts),
+        }
+    }
+
+    pub fn set(&self, ts: u64){
+        self.now.store(ts, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, secs: u64){
+        self.now.fetch_add(secs, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock{
+    fn now(&self) -> u64{
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+/// Network-adjusted time: the local clock plus the median offset reported by peers.
+#[derive(Debug)]
+pub struct NetworkClock{
+    local: Arc<dyn Clock>,
+    offsets: Mutex<Vec<i64>>,
+}
+
+impl NetworkClock{
+    pub fn new(local: Arc<dyn Clock>) -> NetworkClock{
+        NetworkClock{
+            local,
+            offsets: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Record the time announced by a peer.
+    pub fn add_sample(&self, peer_time: u64){
+        let offset = peer_time as i64 - self.local.now() as i64;
+        let mut offsets = self.offsets.lock().unwrap();
+        if offsets.len() >= MAX_TIME_SAMPLES{
+            offsets.remove(0);
+        }
+        offsets.push(offset);
+    }
+
+    /// Median offset of peers, 0 if there are too few samples or it is too large.
+    pub fn offset(&self) -> i64{
+        let mut offsets = self.offsets.lock().unwrap().clone();
+        if offsets.len() < MIN_TIME_SAMPLES{
+            return 0
+        }
+        offsets.sort();
+        let median = offsets[offsets.len() / 2];
+        if median.abs() > MAX_TIME_ADJUSTMENT{
+            0
+        }else{
+            median
+        }
+    }
+}
+
+impl Clock for NetworkClock{
+    fn now(&self) -> u64{
+        let t = self.local.now() as i64 + self.offset();
+        if t < 0 { 0 } else { t as u64 }
+    }
+}
+
+#[cfg(test)]
+mod clock_test{
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let c = MockClock::new(100);
+        c.advance(20);
+        assert_eq!(120, c.now());
+        c.set(7);
+        assert_eq!(7, c.now());
+    }
+
+    #[test]
+    fn test_network_clock_median() {
+        let local = Arc::new(MockClock::new(1_000_000));
+        let nc = NetworkClock::new(local.clone());
+
+        for &dt in [10, 20, 30, 40].iter(){
+            nc.add_sample(1_000_000 + dt);
+        }
+        // too few samples
+        assert_eq!(1_000_000, nc.now());
+
+        nc.add_sample(1_000_050);
+        assert_eq!(30, nc.offset());
+        assert_eq!(1_000_030, nc.now());
+
+        // a median beyond the limit is ignored
+        for _ in 0..10{
+            nc.add_sample(1_000_000 + 2 * MAX_TIME_ADJUSTMENT as u64);
+        }
+        assert_eq!(0, nc.offset());
+    }
+}
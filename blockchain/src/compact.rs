@@ -0,0 +1,311 @@
+//! Compact block relay in the style of BIP152.
+//!
+//! A compact block carries the header, a 6 byte short id for every transaction and the
+//! transactions the receiver cannot have, at least the coinbase. Short ids are SipHash-2-4
+//! of the wtxid, keyed with the hash of the header and a random nonce so that collisions
+//! cannot be prepared in advance.
+//! The receiver fills the block from its mempool and requests the rest with `GetBlockTxn`.
+//! The messages work with the transactions of either state model, `SimpleTx` by default.
+
+use std::collections::HashMap;
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use siphasher::sip::SipHasher24;
+use std::hash::Hasher;
+
+use crate::block::{Block, BlockHeader, BlockTx};
+use crate::encode::{self, Reader};
+use crate::mkt;
+use crate::SimpleTx;
+
+/// Bytes of a short transaction id.
+pub const SHORT_ID_LEN: usize = 6;
+
+/// A transaction sent in full with the compact block.
+#[derive(Clone, Debug)]
+pub struct PrefilledTx<T = SimpleTx>{
+    pub index: usize,
+    pub tx: T,
+}
+
+#[derive(Clone, Debug)]
+pub struct CompactBlock<T = SimpleTx>{
+    pub header: BlockHeader,
+    pub nonce: u64,
+    /// Short ids of the transactions that are not prefilled, in block order.
+    pub short_ids: Vec<u64>,
+    pub prefilled: Vec<PrefilledTx<T>>,
+}
+
+/// Keys of SipHash, derived from the header and the nonce.
+fn short_id_keys(header: &BlockHeader, nonce: u64) -> (u64, u64){
+    let mut buf = header.to_bytes();
+    buf.write_u64::<BigEndian>(nonce).unwrap();
+    let h = mkt::sha256(&buf);
+    (BigEndian::read_u64(&h[0..8]), BigEndian::read_u64(&h[8..16]))
+}
+
+fn short_id(keys: (u64, u64), wtxid: &[u8; 32]) -> u64{
+    let mut hasher = SipHasher24::new_with_keys(keys.0, keys.1);
+    hasher.write(wtxid);
+    hasher.finish() & 0xffff_ffff_ffff
+}
+
+impl<T: BlockTx> CompactBlock<T>{
+    /// Compact form of `block`, only the coinbase is prefilled.
+    pub fn from_block(block: &Block<T>, nonce: u64) -> CompactBlock<T>{
+        let keys = short_id_keys(block.header(), nonce);
+        let mut short_ids = Vec::with_capacity(block.txs().len());
+        let mut prefilled = Vec::new();
+        for (i, tx) in block.txs().iter().enumerate(){
+            if i == 0{
+                prefilled.push(PrefilledTx{ index: 0, tx: tx.clone() });
+            }else{
+                short_ids.push(short_id(keys, &tx.wtxid()));
+            }
+        }
+        CompactBlock{ header: block.header().clone(), nonce, short_ids, prefilled }
+    }
+
+    pub fn hash(&self) -> [u8; 32]{
+        self.header.hash()
+    }
+
+    pub fn tx_count(&self) -> usize{
+        self.short_ids.len() + self.prefilled.len()
+    }
+
+    /// Header, nonce, short ids and the prefilled transactions.
+    /// Indexes of prefilled transactions are differentially encoded.
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut buf = self.header.to_bytes();
+        buf.write_u64::<BigEndian>(self.nonce).unwrap();
+        encode::write_varint(&mut buf, self.short_ids.len() as u64);
+        for id in self.short_ids.iter(){
+            buf.extend(&id.to_be_bytes()[8 - SHORT_ID_LEN..]);
+        }
+        encode::write_varint(&mut buf, self.prefilled.len() as u64);
+        let mut next = 0;
+        for p in self.prefilled.iter(){
+            encode::write_varint(&mut buf, (p.index - next) as u64);
+            next = p.index + 1;
+            p.tx.encode_into(&mut buf);
+        }
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<CompactBlock<T>>{
+        let mut r = Reader::new(bytes);
+        let header = BlockHeader::decode(&mut r)?;
+        let nonce = r.read_u64()?;
+        let n = r.read_count(SHORT_ID_LEN)?;
+        let mut short_ids = Vec::with_capacity(n);
+        for _ in 0..n{
+            short_ids.push(BigEndian::read_uint(r.read_bytes(SHORT_ID_LEN)?, SHORT_ID_LEN));
+        }
+        let n = r.read_count(11)?;
+        let mut prefilled = Vec::with_capacity(n);
+        let mut next = 0usize;
+        for _ in 0..n{
+            let index = next.checked_add(r.read_varint()? as usize)?;
+            prefilled.push(PrefilledTx{ index, tx: T::decode_from(&mut r)? });
+            next = index.checked_add(1)?;
+        }
+        if !r.is_empty() || next > short_ids.len() + prefilled.len(){
+            return None
+        }
+        Some(CompactBlock{ header, nonce, short_ids, prefilled })
+    }
+}
+
+/// Request for the transactions at `indexes` of a block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GetBlockTxn{
+    pub block_hash: [u8; 32],
+    pub indexes: Vec<usize>,
+}
+
+/// Answer to `GetBlockTxn`, the transactions in the requested order.
+#[derive(Clone, Debug)]
+pub struct BlockTxn<T = SimpleTx>{
+    pub block_hash: [u8; 32],
+    pub txs: Vec<T>,
+}
+
+impl GetBlockTxn{
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut buf = self.block_hash.to_vec();
+        encode::write_varint(&mut buf, self.indexes.len() as u64);
+        for &i in self.indexes.iter(){
+            encode::write_varint(&mut buf, i as u64);
+        }
+        buf
+    }
+
+    /// Transactions of `block` asked for, `None` if an index is out of range.
+    pub fn answer<T: BlockTx>(&self, block: &Block<T>) -> Option<BlockTxn<T>>{
+        let txs = self.indexes.iter()
+            .map(|&i| block.txs().get(i).cloned())
+            .collect::<Option<Vec<_>>>()?;
+        Some(BlockTxn{ block_hash: self.block_hash, txs })
+    }
+}
+
+impl<T: BlockTx> BlockTxn<T>{
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut buf = self.block_hash.to_vec();
+        encode::write_varint(&mut buf, self.txs.len() as u64);
+        for tx in self.txs.iter(){
+            tx.encode_into(&mut buf);
+        }
+        buf
+    }
+}
+
+/// Why a compact block could not be turned into a block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompactError{
+    /// Prefilled indexes overlap or point past the end.
+    BadPrefilled,
+    /// The response is for another block or has the wrong number of transactions.
+    BadResponse,
+    /// The rebuilt block does not match the merkle root, short ids collided.
+    /// The full block has to be requested.
+    MerkleMismatch,
+}
+
+/// A compact block being rebuilt by a receiver.
+#[derive(Clone, Debug)]
+pub struct PartialBlock<T = SimpleTx>{
+    header: BlockHeader,
+    txs: Vec<Option<T>>,
+}
+
+impl<T: BlockTx> PartialBlock<T>{
+    /// Place the prefilled transactions and look up the others in `pool`, e.g. the mempool.
+    /// A short id matching several transactions of the pool is left missing.
+    pub fn new<'a>(cmpct: &CompactBlock<T>, pool: impl IntoIterator<Item=&'a T>) -> Result<PartialBlock<T>, CompactError> where T: 'a{
+        let mut txs: Vec<Option<T>> = vec![None; cmpct.tx_count()];
+        for p in cmpct.prefilled.iter(){
+            match txs.get_mut(p.index){
+                Some(slot @ None) => *slot = Some(p.tx.clone()),
+                _ => return Err(CompactError::BadPrefilled),
+            }
+        }
+
+        let keys = short_id_keys(&cmpct.header, cmpct.nonce);
+        let mut by_id: HashMap<u64, Option<&T>> = HashMap::new();
+        for tx in pool{
+            by_id.entry(short_id(keys, &tx.wtxid()))
+                .and_modify(|e| *e = None)
+                .or_insert(Some(tx));
+        }
+
+        let mut ids = cmpct.short_ids.iter();
+        for slot in txs.iter_mut().filter(|slot| slot.is_none()){
+            let id = ids.next().ok_or(CompactError::BadPrefilled)?;
+            *slot = by_id.get(id).cloned().flatten().cloned();
+        }
+        Ok(PartialBlock{ header: cmpct.header.clone(), txs })
+    }
+
+    /// Indexes of the transactions not found in the mempool.
+    pub fn missing(&self) -> Vec<usize>{
+        self.txs.iter().enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    pub fn request(&self) -> GetBlockTxn{
+        GetBlockTxn{ block_hash: self.header.hash(), indexes: self.missing() }
+    }
+
+    /// Complete the block with the response to `request()`.
+    pub fn fill(mut self, resp: BlockTxn<T>) -> Result<Block<T>, CompactError>{
+        let missing = self.missing();
+        if resp.block_hash != self.header.hash() || resp.txs.len() != missing.len(){
+            return Err(CompactError::BadResponse)
+        }
+        for (i, tx) in missing.into_iter().zip(resp.txs){
+            self.txs[i] = Some(tx);
+        }
+        self.into_block()
+    }
+
+    /// The block if nothing is missing.
+    pub fn into_block(self) -> Result<Block<T>, CompactError>{
+        let txs = self.txs.into_iter().collect::<Option<Vec<_>>>().ok_or(CompactError::BadResponse)?;
+        let block = Block::from_parts(self.header, txs);
+        if block.compute_merkle_root() != *block.header().merkle_root(){
+            return Err(CompactError::MerkleMismatch)
+        }
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod compact_test{
+    use super::*;
+    use crate::mempool::Mempool;
+    use crate::transaction::*;
+
+    fn tx(i: u32) -> SimpleTx{
+        SimpleTx{
+            input: InputTx(vec![TxIn::new(OutPoint::new([i as u8 + 1; 32], i), "Alice".to_string(), 10.into())]),
+            output: OutputTx(vec![Trans{addr: "Bob".to_string(), val: 10.into()}]),
+            lock_time: 0,
+            witness: vec![],
+        }
+    }
+
+    fn block(n: u32) -> Block{
+        let genesis = crate::ChainParams::regtest().genesis_block();
+        let cb = SimpleTx::coinbase(1, 50.into(), "Alice".to_string());
+        Block::pack(genesis.header(), 1, std::iter::once(cb).chain((0..n).map(tx)))
+    }
+
+    #[test]
+    fn test_compact_bytes() {
+        let b = block(3);
+        let cmpct = CompactBlock::from_block(&b, 42);
+        assert_eq!(3, cmpct.short_ids.len());
+        assert!(cmpct.short_ids.iter().all(|id| id >> 48 == 0));
+
+        let decoded = CompactBlock::<SimpleTx>::from_bytes(&cmpct.to_bytes()).unwrap();
+        assert_eq!(cmpct.short_ids, decoded.short_ids);
+        assert_eq!(b.hash(), decoded.hash());
+        assert_eq!(0, decoded.prefilled[0].index);
+        assert!(b.to_bytes().len() > cmpct.to_bytes().len());
+
+        // another nonce gives other ids
+        assert_ne!(cmpct.short_ids, CompactBlock::from_block(&b, 43).short_ids);
+    }
+
+    #[test]
+    fn test_rebuild_from_mempool() {
+        let b = block(3);
+        let cmpct = CompactBlock::from_block(&b, 7);
+
+        // the mempool only knows two of the transactions, accept() would need a chain
+        let mut mempool = Mempool::new();
+        mempool.insert_unchecked(b.txs()[1].clone());
+        mempool.insert_unchecked(b.txs()[3].clone());
+
+        let partial = PartialBlock::new(&cmpct, mempool.txs()).unwrap();
+        assert_eq!(vec![2], partial.missing());
+        let req = partial.request();
+        let resp = req.answer(&b).unwrap();
+        let empty = BlockTxn{ block_hash: b.hash(), txs: vec![] };
+        assert_eq!(Some(CompactError::BadResponse), partial.clone().fill(empty).err());
+
+        let rebuilt = partial.fill(resp).unwrap();
+        assert_eq!(b.hash(), rebuilt.hash());
+        assert_eq!(b.compute_merkle_root(), rebuilt.compute_merkle_root());
+
+        // a wrong transaction is caught by the merkle root
+        let partial = PartialBlock::new(&cmpct, mempool.txs()).unwrap();
+        let bad = BlockTxn{ block_hash: b.hash(), txs: vec![tx(9)] };
+        assert_eq!(Some(CompactError::MerkleMismatch), partial.fill(bad).err());
+    }
+}
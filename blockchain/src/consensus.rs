@@ -0,0 +1,115 @@
+//! Rules deciding who may extend the chain.
+//!
+//! `BlockChain` leaves the checks of the header seal and the weight of a branch to an
+//! engine chosen by `ChainParams::consensus`. Everything else, transactions, timestamps and
+//! the coinbase, is checked the same way with either engine.
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::block::BlockHeader;
+use crate::error::ChainError;
+use crate::params::ChainParams;
+use crate::pos::{ProofOfStake, StakeParams};
+use crate::pow;
+
+#[derive(Clone, Debug)]
+pub enum ConsensusParams{
+    ProofOfWork,
+    ProofOfStake(StakeParams),
+}
+
+/// What an engine sees of the chain a header extends.
+#[derive(Clone, Copy, Debug)]
+pub struct HeaderContext<'a>{
+    /// Header of the tip.
+    pub prev: &'a BlockHeader,
+    /// Difficulty required for the next block.
+    pub bits: u32,
+    /// Current time adjusted by the offsets of peers.
+    pub adjusted_time: u64,
+}
+
+pub trait Consensus: fmt::Debug + Send + Sync{
+    /// Checks needing only the header, done before a block is kept on a side branch.
+    fn check_seal(&self, header: &BlockHeader) -> Result<(), ChainError>;
+
+    /// Checks of a header extending the tip of a chain.
+    fn check_header(&self, ctx: &HeaderContext, header: &BlockHeader) -> Result<(), ChainError>;
+
+    /// Weight of a block, the branch with the most is the active chain.
+    fn block_work(&self, header: &BlockHeader) -> u128;
+
+    /// Blocks this deep in the active chain are final, `None` if any branch with more work
+    /// is followed.
+    fn finality_depth(&self) -> Option<usize>{
+        None
+    }
+
+    /// Slot and producer of a block, for engines where a producer may only sign once per slot.
+    fn slot_owner(&self, _header: &BlockHeader) -> Option<(u64, [u8; 32])>{
+        None
+    }
+}
+
+/// The engine configured in `params`.
+pub fn engine(params: &ChainParams) -> Arc<dyn Consensus>{
+    match params.consensus{
+        ConsensusParams::ProofOfWork => Arc::new(ProofOfWork{ pow_limit: params.pow_limit }),
+        ConsensusParams::ProofOfStake(ref stake) => Arc::new(ProofOfStake::new(params, stake.clone())),
+    }
+}
+
+/// Nakamoto consensus: a nonce meeting the target, the most work wins.
+#[derive(Clone, Debug)]
+pub struct ProofOfWork{
+    pub pow_limit: u32,
+}
+
+impl Consensus for ProofOfWork{
+    fn check_seal(&self, header: &BlockHeader) -> Result<(), ChainError>{
+        if header.seal().is_some(){
+            return Err(ChainError::BadSeal)
+        }
+        if !pow::check_proof_of_work(&header.hash(), header.bits(), self.pow_limit){
+            return Err(ChainError::HighHash)
+        }
+        Ok(())
+    }
+
+    fn check_header(&self, ctx: &HeaderContext, header: &BlockHeader) -> Result<(), ChainError>{
+        if header.bits() != ctx.bits{
+            return Err(ChainError::BadDifficulty)
+        }
+        self.check_seal(header)
+    }
+
+    fn block_work(&self, header: &BlockHeader) -> u128{
+        pow::block_work(header.bits())
+    }
+}
+
+/// Two headers signed by one producer for the same slot.
+#[derive(Clone, Debug)]
+pub struct DoubleSign{
+    pub slot: u64,
+    pub validator: [u8; 32],
+    pub first: BlockHeader,
+    pub second: BlockHeader,
+}
+
+impl DoubleSign{
+    /// Both headers are validly sealed by `validator` for `slot` and differ.
+    pub fn verify(&self, consensus: &dyn Consensus) -> Result<(), ChainError>{
+        for header in [&self.first, &self.second].iter(){
+            consensus.check_seal(header).map_err(|_| ChainError::BadEvidence)?;
+            if consensus.slot_owner(header) != Some((self.slot, self.validator)){
+                return Err(ChainError::BadEvidence)
+            }
+        }
+        if self.first.hash() == self.second.hash(){
+            return Err(ChainError::BadEvidence)
+        }
+        Ok(())
+    }
+}
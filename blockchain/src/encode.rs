@@ -0,0 +1,216 @@
+//! Helpers for the binary encoding of transactions and blocks.
+//!
+//! Integers are big-endian like the rest of the crate.
+//! Lengths and counts use a variable length integer in the style of Bitcoin's CompactSize:
+//! values below 0xfd take one byte, larger values a marker byte (0xfd, 0xfe, 0xff)
+//! followed by 2, 4 or 8 bytes.
+//! Formats taken from Bitcoin, like the block filters, use the little-endian CompactSize itself.
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
+
+fn write_var<B: ByteOrder>(buf: &mut Vec<u8>, n: u64){
+    if n < 0xfd{
+        buf.push(n as u8);
+    }else if n <= 0xffff{
+        buf.push(0xfd);
+        buf.write_u16::<B>(n as u16).unwrap();
+    }else if n <= 0xffff_ffff{
+        buf.push(0xfe);
+        buf.write_u32::<B>(n as u32).unwrap();
+    }else{
+        buf.push(0xff);
+        buf.write_u64::<B>(n).unwrap();
+    }
+}
+
+pub fn write_varint(buf: &mut Vec<u8>, n: u64){
+    write_var::<BigEndian>(buf, n)
+}
+
+/// Bitcoin's CompactSize, little-endian.
+pub fn write_compact_size(buf: &mut Vec<u8>, n: u64){
+    write_var::<LittleEndian>(buf, n)
+}
+
+/// Length-prefixed bytes.
+pub fn write_var_bytes(buf: &mut Vec<u8>, bytes: &[u8]){
+    write_varint(buf, bytes.len() as u64);
+    buf.extend(bytes);
+}
+
+pub fn to_hex(bytes: &[u8]) -> String{
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>>{
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()){
+        return None
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Cursor over an encoded message. Every read returns `None` instead of panicking
+/// when the input is too short or not canonical.
+pub struct Reader<'a>{
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a>{
+    pub fn new(buf: &'a [u8]) -> Reader<'a>{
+        Reader{ buf, pos: 0 }
+    }
+
+    pub fn remaining(&self) -> usize{
+        self.buf.len() - self.pos
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.remaining() == 0
+    }
+
+    /// Look at the next byte without consuming it.
+    pub fn peek_u8(&self) -> Option<u8>{
+        self.buf.get(self.pos).cloned()
+    }
+
+    pub fn read_bytes(&mut self, n: usize) -> Option<&'a [u8]>{
+        if n > self.remaining(){
+            return None
+        }
+        let b = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Some(b)
+    }
+
+    pub fn read_u8(&mut self) -> Option<u8>{
+        self.read_bytes(1).map(|b| b[0])
+    }
+
+    pub fn read_u16(&mut self) -> Option<u16>{
+        self.read_bytes(2).map(BigEndian::read_u16)
+    }
+
+    pub fn read_u32(&mut self) -> Option<u32>{
+        self.read_bytes(4).map(BigEndian::read_u32)
+    }
+
+    pub fn read_u64(&mut self) -> Option<u64>{
+        self.read_bytes(8).map(BigEndian::read_u64)
+    }
+
+    pub fn read_hash(&mut self) -> Option<[u8; 32]>{
+        let mut h = [0; 32];
+        h.copy_from_slice(self.read_bytes(32)?);
+        Some(h)
+    }
+
+    fn read_var<B: ByteOrder>(&mut self) -> Option<u64>{
+        let n = match self.read_u8()?{
+            0xfd => {
+                let n = self.read_bytes(2).map(B::read_u16)? as u64;
+                if n < 0xfd { return None }
+                n
+            },
+            0xfe => {
+                let n = self.read_bytes(4).map(B::read_u32)? as u64;
+                if n <= 0xffff { return None }
+                n
+            },
+            0xff => {
+                let n = self.read_bytes(8).map(B::read_u64)?;
+                if n <= 0xffff_ffff { return None }
+                n
+            },
+            n => n as u64,
+        };
+        Some(n)
+    }
+
+    /// Reject encodings that are not the shortest form.
+    pub fn read_varint(&mut self) -> Option<u64>{
+        self.read_var::<BigEndian>()
+    }
+
+    /// Little-endian CompactSize, shortest form only.
+    pub fn read_compact_size(&mut self) -> Option<u64>{
+        self.read_var::<LittleEndian>()
+    }
+
+    /// Read a count of items, each at least `min_item_size` bytes long.
+    /// Counts that could not fit in the rest of the input are rejected
+    /// so that callers can allocate for them.
+    pub fn read_count(&mut self, min_item_size: usize) -> Option<usize>{
+        let n = self.read_varint()?;
+        if n.saturating_mul(min_item_size.max(1) as u64) > self.remaining() as u64{
+            return None
+        }
+        Some(n as usize)
+    }
+
+    pub fn read_var_bytes(&mut self) -> Option<&'a [u8]>{
+        let n = self.read_count(1)?;
+        self.read_bytes(n)
+    }
+}
+
+#[cfg(test)]
+mod encode_test{
+    use super::*;
+
+    #[test]
+    fn test_varint() {
+        for &n in [0u64, 1, 0xfc, 0xfd, 0xffff, 0x10000, 0xffff_ffff, 0x1_0000_0000, u64::MAX].iter(){
+            let mut buf = Vec::new();
+            write_varint(&mut buf, n);
+            let mut r = Reader::new(&buf);
+            assert_eq!(Some(n), r.read_varint());
+            assert!(r.is_empty());
+        }
+        // non-canonical
+        assert_eq!(None, Reader::new(&[0xfd, 0x00, 0x10]).read_varint());
+        assert_eq!(None, Reader::new(&[0xfe, 0x00, 0x00, 0xff, 0xff]).read_varint());
+        // truncated
+        assert_eq!(None, Reader::new(&[0xfd, 0x01]).read_varint());
+    }
+
+    #[test]
+    fn test_compact_size() {
+        for &n in [0u64, 0xfc, 0xfd, 0xffff, 0x10000, 0xffff_ffff, 0x1_0000_0000, u64::MAX].iter(){
+            let mut buf = Vec::new();
+            write_compact_size(&mut buf, n);
+            let mut r = Reader::new(&buf);
+            assert_eq!(Some(n), r.read_compact_size());
+            assert!(r.is_empty());
+        }
+        let mut buf = Vec::new();
+        write_compact_size(&mut buf, 0x1234);
+        assert_eq!(vec![0xfd, 0x34, 0x12], buf);
+        assert_eq!(None, Reader::new(&[0xfd, 0x10, 0x00]).read_compact_size());
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!("00ff1a", to_hex(&[0, 255, 26]));
+        assert_eq!(Some(vec![0, 255, 26]), from_hex("00ff1a"));
+        assert_eq!(Some(vec![0xab]), from_hex("AB"));
+        assert_eq!(None, from_hex("abc"));
+        assert_eq!(None, from_hex("zz"));
+    }
+
+    #[test]
+    fn test_var_bytes() {
+        let mut buf = Vec::new();
+        write_var_bytes(&mut buf, b"abc");
+        buf.push(7);
+        let mut r = Reader::new(&buf);
+        assert_eq!(Some(&b"abc"[..]), r.read_var_bytes());
+        assert_eq!(Some(7), r.read_u8());
+        assert_eq!(None, r.read_u8());
+
+        // the length is larger than the input
+        assert_eq!(None, Reader::new(&[5, 1, 2]).read_var_bytes());
+    }
+}
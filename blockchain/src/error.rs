@@ -0,0 +1,141 @@
+use std::fmt;
+
+use crate::encode::to_hex as hex;
+use crate::transaction::OutPoint;
+
+/// Reasons for rejecting a block or a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainError{
+    /// `prev_block` of the header is not the hash of the tip.
+    BadPrevBlock,
+    /// Timestamp is not greater than the median time past.
+    TimeTooOld{ timestamp: u64, median_time_past: u64 },
+    /// Timestamp is too far ahead of the network-adjusted time.
+    TimeTooNew{ timestamp: u64, max: u64 },
+    /// A coinbase transaction that is not the first of a block.
+    UnexpectedCoinbase,
+    NoInputs,
+    /// `lock_time` has not been reached.
+    NonFinal,
+    /// A relative lock-time (BIP68) of an input has not been reached.
+    SequenceLocked,
+    /// The output does not exist or has been spent.
+    MissingInput(OutPoint),
+    /// The address or value of an input differs from the output it spends.
+    InputMismatch(OutPoint),
+    DoubleSpend(OutPoint),
+    AlreadyInMempool,
+    /// An amount or a sum of amounts overflows or exceeds the money supply.
+    ValueOutOfRange,
+    /// Outputs spend more than the inputs provide.
+    InsufficientFunds,
+    /// Witness data is present but does not match the inputs.
+    BadWitness,
+    /// The coinbase commitment is missing or differs from the witness root.
+    BadWitnessCommitment,
+    /// `bits` differs from the difficulty required by the chain.
+    BadDifficulty,
+    /// The header hash does not meet its target.
+    HighHash,
+    /// The merkle root of the header does not commit to the transactions.
+    BadMerkleRoot,
+    /// The transactions repeat a txid, another block with the same header and hash may be valid.
+    MutatedBlock,
+    /// The first transaction of a block is not a coinbase.
+    MissingCoinbase,
+    /// The coinbase does not commit to the height of its block.
+    BadCoinbaseHeight,
+    /// The coinbase claims more than the subsidy and the fees.
+    BadCoinbaseValue,
+    /// A coinbase output spent before it reached maturity.
+    ImmatureCoinbase(OutPoint),
+    /// The block is already in the active chain or a side branch.
+    DuplicateBlock,
+    /// The header is not sealed the way the consensus of the chain requires,
+    /// or the seal's signatures do not verify.
+    BadSeal,
+    /// The slot is not after the slot of the previous block.
+    BadSlot,
+    /// The VRF output of the validator is above its threshold for the slot.
+    NotSlotLeader,
+    /// Evidence of a double signed slot does not hold.
+    BadEvidence,
+    /// A transfer does not carry the current nonce of its sender.
+    BadNonce{ expected: u64, got: u64 },
+    /// The state root committed by the block differs from the state after it.
+    BadStateRoot,
+    /// A bloom filter or an element added to it exceeds the limits.
+    BadFilter,
+    /// A partial merkle tree is malformed or does not cover the transactions sent with it.
+    BadMerkleBlock,
+    /// A compact filter does not match the chain of filter headers.
+    BadFilterHeader,
+    /// A block is not the one at its height in the header chain.
+    UnexpectedBlock,
+    /// The block at a checkpoint height is not the checkpoint.
+    CheckpointMismatch{ height: usize },
+    /// The block forks from the active chain below the checkpoint at `height`.
+    ForkBeforeCheckpoint{ height: usize },
+    /// Switching to the branch forking at `fork` needs blocks that were pruned,
+    /// or would disconnect final blocks.
+    ReorgTooDeep{ fork: usize },
+    /// The transactions of the block at `height` were pruned.
+    BlockPruned{ height: usize },
+    /// The witness of an input does not match the policy of the address it spends.
+    BadScript,
+    /// A signature is invalid or fewer keys than required signed.
+    BadSignature,
+}
+
+impl fmt::Display for ChainError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        match self{
+            ChainError::BadPrevBlock => write!(f, "previous block is not the tip"),
+            ChainError::TimeTooOld{ timestamp, median_time_past } => write!(
+                f, "block timestamp {} is not after median time past {}", timestamp, median_time_past
+            ),
+            ChainError::TimeTooNew{ timestamp, max } => write!(
+                f, "block timestamp {} is later than {}", timestamp, max
+            ),
+            ChainError::UnexpectedCoinbase => write!(f, "coinbase is not the first transaction"),
+            ChainError::NoInputs => write!(f, "transaction has no inputs"),
+            ChainError::NonFinal => write!(f, "transaction is not final"),
+            ChainError::SequenceLocked => write!(f, "relative lock-time not reached"),
+            ChainError::MissingInput(op) => write!(f, "missing input {}:{}", hex(&op.txid), op.index),
+            ChainError::InputMismatch(op) => write!(f, "input {}:{} differs from the output", hex(&op.txid), op.index),
+            ChainError::DoubleSpend(op) => write!(f, "{}:{} is already spent", hex(&op.txid), op.index),
+            ChainError::AlreadyInMempool => write!(f, "transaction already in mempool"),
+            ChainError::ValueOutOfRange => write!(f, "value out of range"),
+            ChainError::InsufficientFunds => write!(f, "outputs exceed inputs"),
+            ChainError::BadWitness => write!(f, "bad witness"),
+            ChainError::BadWitnessCommitment => write!(f, "bad witness commitment"),
+            ChainError::BadDifficulty => write!(f, "incorrect proof of work target"),
+            ChainError::HighHash => write!(f, "proof of work failed"),
+            ChainError::BadMerkleRoot => write!(f, "merkle root mismatch"),
+            ChainError::MutatedBlock => write!(f, "block repeats a transaction"),
+            ChainError::MissingCoinbase => write!(f, "first transaction is not a coinbase"),
+            ChainError::BadCoinbaseHeight => write!(f, "coinbase does not commit to the block height"),
+            ChainError::BadCoinbaseValue => write!(f, "coinbase pays too much"),
+            ChainError::ImmatureCoinbase(op) => write!(f, "coinbase {}:{} is not mature", hex(&op.txid), op.index),
+            ChainError::DuplicateBlock => write!(f, "block already known"),
+            ChainError::BadSeal => write!(f, "bad block seal"),
+            ChainError::BadSlot => write!(f, "slot is not after the previous block"),
+            ChainError::NotSlotLeader => write!(f, "validator does not lead the slot"),
+            ChainError::BadEvidence => write!(f, "invalid double sign evidence"),
+            ChainError::BadNonce{ expected, got } => write!(f, "nonce {} differs from the account nonce {}", got, expected),
+            ChainError::BadStateRoot => write!(f, "state root mismatch"),
+            ChainError::BadFilter => write!(f, "bloom filter too large"),
+            ChainError::BadMerkleBlock => write!(f, "invalid merkle block"),
+            ChainError::BadFilterHeader => write!(f, "filter does not match its header"),
+            ChainError::UnexpectedBlock => write!(f, "block is not in the header chain"),
+            ChainError::CheckpointMismatch{ height } => write!(f, "block differs from the checkpoint at height {}", height),
+            ChainError::ForkBeforeCheckpoint{ height } => write!(f, "block forks below the checkpoint at height {}", height),
+            ChainError::ReorgTooDeep{ fork } => write!(f, "branch forks at height {} below the pruned or final blocks", fork),
+            ChainError::BlockPruned{ height } => write!(f, "block at height {} was pruned", height),
+            ChainError::BadScript => write!(f, "witness does not match the spending policy"),
+            ChainError::BadSignature => write!(f, "missing or invalid signature"),
+        }
+    }
+}
+
+impl std::error::Error for ChainError{}
@@ -0,0 +1,99 @@
+//! Notifications of chain and mempool changes.
+//!
+//! Subscribers get a channel receiving every event published after they subscribed.
+//! `BlockChain::subscribe_from` first replays the active chain from a height, so a service
+//! that remembers the last height it processed can catch up after a restart.
+//! Events are sent while the chain is being changed; a subscriber that falls behind only
+//! buffers them, it never blocks the chain.
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use crate::block::{Block, BlockTx};
+use crate::SimpleTx;
+
+/// Why a transaction left the mempool without being mined.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EvictReason{
+    /// A block spent one of its inputs.
+    Conflict,
+    /// It became invalid after a reorganization.
+    Reorg,
+    /// A conflicting transaction paying more took its place.
+    Replaced,
+}
+
+/// Events of a chain whose blocks carry `T`, `SimpleTx` of the UTXO model by default.
+#[derive(Clone, Debug)]
+pub enum ChainEvent<T = SimpleTx>{
+    BlockConnected{ height: usize, block: Arc<Block<T>> },
+    /// The block at `height` was removed from the active chain by a reorganization.
+    BlockDisconnected{ height: usize, block: Arc<Block<T>> },
+    /// Sent once after a block or a whole reorganization was applied.
+    TipChanged{ height: usize, hash: [u8; 32] },
+    /// `height` is the tip height when the transaction was accepted.
+    TxAccepted{ tx: Arc<T>, height: usize },
+    TxEvicted{ txid: [u8; 32], reason: EvictReason },
+}
+
+/// Subscribers of a chain. Clones share the subscribers.
+#[derive(Clone, Debug)]
+pub struct EventBus<T = SimpleTx>{
+    subscribers: Arc<Mutex<Vec<Sender<ChainEvent<T>>>>>,
+}
+
+impl<T> Default for EventBus<T>{
+    fn default() -> EventBus<T>{
+        EventBus{ subscribers: Arc::new(Mutex::new(Vec::new())) }
+    }
+}
+
+impl EventBus{
+    pub fn new() -> EventBus{
+        EventBus::default()
+    }
+}
+
+impl<T: BlockTx> EventBus<T>{
+    pub fn subscribe(&self) -> Receiver<ChainEvent<T>>{
+        let (tx, rx) = channel();
+        self.add(tx);
+        rx
+    }
+
+    /// Add a sender, e.g. after replaying past events into it.
+    pub fn add(&self, sender: Sender<ChainEvent<T>>){
+        self.subscribers.lock().unwrap().push(sender);
+    }
+
+    /// Send to every subscriber, dropping those whose receiver is gone.
+    pub fn publish(&self, event: ChainEvent<T>){
+        self.subscribers.lock().unwrap().retain(|s| s.send(event.clone()).is_ok());
+    }
+
+    pub fn subscriber_count(&self) -> usize{
+        self.subscribers.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod events_test{
+    use super::*;
+
+    #[test]
+    fn test_publish() {
+        let bus = EventBus::new();
+        let a = bus.subscribe();
+        let b = bus.subscribe();
+        bus.publish(ChainEvent::TipChanged{ height: 1, hash: [1; 32] });
+        drop(b);
+        bus.publish(ChainEvent::TipChanged{ height: 2, hash: [2; 32] });
+
+        let heights: Vec<usize> = a.try_iter().map(|e| match e{
+            ChainEvent::TipChanged{ height, .. } => height,
+            _ => unreachable!(),
+        }).collect();
+        assert_eq!(vec![1, 2], heights);
+        assert_eq!(1, bus.subscriber_count());
+    }
+}
@@ -0,0 +1,346 @@
+//! Fee estimation from the history of confirmed transactions.
+//!
+//! Transactions entering the mempool are tracked with their fee rate and the height at which
+//! they were seen. When a block is connected, the tracked transactions it contains are counted
+//! in the bucket of their fee rate, together with the number of blocks they waited.
+//! Counters decay with every block so that recent blocks weigh more.
+//!
+//! `estimate(target)` looks for the lowest fee rate whose transactions, and those paying more,
+//! confirmed within `target` blocks at least `SUCCESS_THRESHOLD` of the time.
+//! Fee rates are in satoshis per 1000 virtual bytes.
+
+use std::collections::HashMap;
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::block::Block;
+use crate::encode::{self, Reader};
+use crate::transaction::CoinValue;
+use crate::SimpleTx;
+
+/// Longest confirmation target that can be estimated.
+pub const MAX_TARGET: usize = 25;
+/// Lower bound of the first bucket, 1 satoshi per virtual byte.
+pub const MIN_BUCKET_FEE_RATE: u64 = 1000;
+pub const MAX_BUCKET_FEE_RATE: u64 = 10_000_000;
+/// Each bucket starts this much above the previous one.
+pub const BUCKET_SPACING: f64 = 1.1;
+/// Weight of the history kept at every block.
+pub const DECAY: f64 = 0.998;
+/// Share of transactions that must have confirmed in time.
+pub const SUCCESS_THRESHOLD: f64 = 0.85;
+/// Decayed number of transactions needed before a group of buckets is judged.
+pub const SUFFICIENT_TXS: f64 = 2.0;
+
+const FORMAT_VERSION: u8 = 1;
+
+/// A fee rate and the share of transactions paying at least as much
+/// that confirmed within the target.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FeeEstimate{
+    pub fee_rate: u64,
+    pub confidence: f64,
+}
+
+#[derive(Clone, Debug)]
+struct TrackedTx{
+    height: usize,
+    bucket: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct FeeEstimator{
+    /// Lower bounds of the buckets.
+    buckets: Vec<u64>,
+    /// Transactions that confirmed or gave up, per bucket.
+    total: Vec<f64>,
+    /// `confirmed[t][b]`: transactions of bucket `b` confirmed within `t + 1` blocks.
+    confirmed: Vec<Vec<f64>>,
+    tracked: HashMap<[u8; 32], TrackedTx>,
+    best_height: usize,
+}
+
+impl Default for FeeEstimator{
+    fn default() -> FeeEstimator{
+        FeeEstimator::new()
+    }
+}
+
+impl FeeEstimator{
+    pub fn new() -> FeeEstimator{
+        let mut buckets = Vec::new();
+        let mut rate = MIN_BUCKET_FEE_RATE as f64;
+        while rate <= MAX_BUCKET_FEE_RATE as f64{
+            buckets.push(rate as u64);
+            rate *= BUCKET_SPACING;
+        }
+        let n = buckets.len();
+        FeeEstimator{
+            buckets,
+            total: vec![0.0; n],
+            confirmed: vec![vec![0.0; n]; MAX_TARGET],
+            tracked: HashMap::new(),
+            best_height: 0,
+        }
+    }
+
+    /// Height of the last connected block.
+    pub fn best_height(&self) -> usize{
+        self.best_height
+    }
+
+    /// Fee per 1000 virtual bytes, `None` if the input values are out of range.
+    pub fn fee_rate(tx: &SimpleTx) -> Option<u64>{
+        let fee = tx.value_in()?.checked_sub(&tx.value_out()?)?;
+        Some(fee.val.saturating_mul(1000) / tx.vsize() as u64)
+    }
+
+    fn bucket_of(&self, fee_rate: u64) -> usize{
+        match self.buckets.binary_search(&fee_rate){
+            Ok(i) => i,
+            Err(0) => 0,
+            Err(i) => i - 1,
+        }
+    }
+
+    /// Start tracking a transaction accepted into the mempool at `height`, the tip height.
+    pub fn track_tx(&mut self, tx: &SimpleTx, height: usize){
+        if let Some(rate) = FeeEstimator::fee_rate(tx){
+            let bucket = self.bucket_of(rate);
+            self.tracked.insert(tx.txid(), TrackedTx{ height, bucket });
+        }
+    }
+
+    /// Stop tracking a transaction that left the mempool without being mined.
+    pub fn untrack_tx(&mut self, txid: &[u8; 32]){
+        self.tracked.remove(txid);
+    }
+
+    /// Record the tracked transactions confirmed by the block at `height`.
+    /// Blocks at or below the best height, e.g. replayed at startup, are ignored.
+    pub fn block_connected(&mut self, height: usize, block: &Block){
+        if height <= self.best_height{
+            return
+        }
+        self.best_height = height;
+        for t in self.total.iter_mut(){
+            *t *= DECAY;
+        }
+        for row in self.confirmed.iter_mut(){
+            for c in row.iter_mut(){
+                *c *= DECAY;
+            }
+        }
+
+        for tx in block.txs(){
+            if let Some(entry) = self.tracked.remove(&tx.txid()){
+                let blocks = height.saturating_sub(entry.height).max(1);
+                self.total[entry.bucket] += 1.0;
+                for t in blocks..=MAX_TARGET{
+                    self.confirmed[t - 1][entry.bucket] += 1.0;
+                }
+            }
+        }
+        // waited longer than any target, counted as failures
+        let expired: Vec<[u8; 32]> = self.tracked.iter()
+            .filter(|(_, t)| height.saturating_sub(t.height) > MAX_TARGET)
+            .map(|(txid, _)| *txid)
+            .collect();
+        for txid in expired{
+            let bucket = self.tracked.remove(&txid).unwrap().bucket;
+            self.total[bucket] += 1.0;
+        }
+    }
+
+    /// Forget the best height when the block at `height` leaves the active chain,
+    /// so that the blocks of the new branch are counted even at the same height.
+    /// Its transactions are tracked again once they are back in the mempool.
+    pub fn block_disconnected(&mut self, height: usize){
+        self.best_height = self.best_height.min(height.saturating_sub(1));
+    }
+
+    /// Lowest fee rate expected to confirm within `target` blocks.
+    pub fn estimate(&self, target: usize) -> Option<FeeEstimate>{
+        if target == 0 || target > MAX_TARGET{
+            return None
+        }
+        let confirmed = &self.confirmed[target - 1];
+        let mut best = None;
+        let (mut group_conf, mut group_total) = (0.0, 0.0);
+        let (mut pass_conf, mut pass_total) = (0.0, 0.0);
+        // from the highest fee rate down, groups of buckets with enough data
+        for b in (0..self.buckets.len()).rev(){
+            group_conf += confirmed[b];
+            group_total += self.total[b];
+            if group_total < SUFFICIENT_TXS{
+                continue
+            }
+            if group_conf / group_total < SUCCESS_THRESHOLD{
+                break
+            }
+            pass_conf += group_conf;
+            pass_total += group_total;
+            best = Some(FeeEstimate{ fee_rate: self.buckets[b], confidence: pass_conf / pass_total });
+            group_conf = 0.0;
+            group_total = 0.0;
+        }
+        best
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut buf = vec![FORMAT_VERSION];
+        buf.write_u64::<BigEndian>(self.best_height as u64).unwrap();
+        encode::write_varint(&mut buf, self.buckets.len() as u64);
+        for row in std::iter::once(&self.total).chain(self.confirmed.iter()){
+            for v in row.iter(){
+                buf.write_u64::<BigEndian>(v.to_bits()).unwrap();
+            }
+        }
+        encode::write_varint(&mut buf, self.tracked.len() as u64);
+        for (txid, t) in self.tracked.iter(){
+            buf.extend(txid);
+            buf.write_u64::<BigEndian>(t.height as u64).unwrap();
+            encode::write_varint(&mut buf, t.bucket as u64);
+        }
+        buf
+    }
+
+    /// Restore a saved state. `None` if it is malformed or was saved with other buckets.
+    pub fn from_bytes(bytes: &[u8]) -> Option<FeeEstimator>{
+        let mut est = FeeEstimator::new();
+        let mut r = Reader::new(bytes);
+        if r.read_u8()? != FORMAT_VERSION{
+            return None
+        }
+        est.best_height = r.read_u64()? as usize;
+        let n = est.buckets.len();
+        if r.read_varint()? != n as u64{
+            return None
+        }
+        for row in std::iter::once(&mut est.total).chain(est.confirmed.iter_mut()){
+            for v in row.iter_mut(){
+                *v = f64::from_bits(r.read_u64()?);
+            }
+        }
+        let count = r.read_count(41)?;
+        for _ in 0..count{
+            let txid = r.read_hash()?;
+            let height = r.read_u64()? as usize;
+            let bucket = r.read_varint()? as usize;
+            if bucket >= n{
+                return None
+            }
+            est.tracked.insert(txid, TrackedTx{ height, bucket });
+        }
+        if !r.is_empty(){
+            return None
+        }
+        Some(est)
+    }
+}
+
+#[cfg(test)]
+mod fees_test{
+    use super::*;
+    use crate::transaction::*;
+
+    fn tx(i: u32, fee: u64) -> SimpleTx{
+        SimpleTx{
+            input: InputTx(vec![TxIn::new(OutPoint::new([1; 32], i), "Alice".to_string(), 100_000.into())]),
+            output: OutputTx(vec![Trans{addr: "Bob".to_string(), val: (100_000 - fee).into()}]),
+            lock_time: 0,
+            witness: vec![],
+        }
+    }
+
+    fn block(txs: Vec<SimpleTx>) -> Block{
+        let genesis = crate::ChainParams::regtest().genesis_block();
+        Block::pack(genesis.header(), 1, txs.into_iter())
+    }
+
+    /// Every block confirms two transactions paying 50000 right away
+    /// and two paying 5000 that waited 5 blocks.
+    fn history() -> FeeEstimator{
+        let mut est = FeeEstimator::new();
+        let mut i = 0;
+        let mut low = Vec::new();
+        for height in 1..=60{
+            let mut txs = Vec::new();
+            for _ in 0..2{
+                let t = tx(i, 50_000);
+                est.track_tx(&t, height - 1);
+                txs.push(t);
+                let t = tx(i + 1, 5_000);
+                est.track_tx(&t, height - 1);
+                low.push((height - 1, t));
+                i += 2;
+            }
+            while low.first().is_some_and(|(h, _)| h + 5 <= height){
+                txs.push(low.remove(0).1);
+            }
+            est.block_connected(height, &block(txs));
+        }
+        est
+    }
+
+    #[test]
+    fn test_estimate() {
+        let est = history();
+        let high = FeeEstimator::fee_rate(&tx(0, 50_000)).unwrap();
+        let low = FeeEstimator::fee_rate(&tx(0, 5_000)).unwrap();
+
+        let fast = est.estimate(1).unwrap();
+        assert!(fast.fee_rate > low && fast.fee_rate <= high);
+        assert!(fast.confidence >= SUCCESS_THRESHOLD);
+
+        let slow = est.estimate(5).unwrap();
+        assert!(slow.fee_rate <= low);
+        assert!(slow.confidence > 0.99);
+
+        assert_eq!(None, est.estimate(0));
+        assert_eq!(None, est.estimate(MAX_TARGET + 1));
+        assert_eq!(None, FeeEstimator::new().estimate(1));
+    }
+
+    #[test]
+    fn test_persist() {
+        let est = history();
+        let restored = FeeEstimator::from_bytes(&est.to_bytes()).unwrap();
+        assert_eq!(est.best_height(), restored.best_height());
+        assert_eq!(est.tracked.len(), restored.tracked.len());
+        for target in 1..=MAX_TARGET{
+            assert_eq!(est.estimate(target), restored.estimate(target));
+        }
+        // replayed blocks are not counted twice
+        let mut replayed = restored.clone();
+        replayed.block_connected(1, &block(vec![]));
+        assert_eq!(est.estimate(1), replayed.estimate(1));
+
+        assert!(FeeEstimator::from_bytes(&[]).is_none());
+        let bytes = est.to_bytes();
+        assert!(FeeEstimator::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn test_reorg_same_height() {
+        let mut est = history();
+        let (a, b) = (tx(1000, 50_000), tx(1001, 50_000));
+        est.track_tx(&a, 60);
+        est.track_tx(&b, 60);
+        est.block_connected(61, &block(vec![a.clone()]));
+        assert!(!est.tracked.contains_key(&a.txid()));
+
+        // a competing block at the same height replaces it
+        est.block_disconnected(61);
+        assert_eq!(60, est.best_height());
+        est.track_tx(&a, 60);
+        est.block_connected(61, &block(vec![b.clone()]));
+        assert_eq!(61, est.best_height());
+        assert!(!est.tracked.contains_key(&b.txid()));
+        assert!(est.tracked.contains_key(&a.txid()));
+
+        // transactions seen above the height of a block do not underflow
+        est.track_tx(&tx(1002, 50_000), 70);
+        est.block_connected(62, &block(vec![]));
+    }
+}
@@ -3,7 +3,7 @@
 //! 
 //! 首先BTC本质上是一个分布式状态机，状态即UTXO的集合：
 //! 
-//! ```
+//! ```text
 //!     Net：               P2P, Gossip
 //!                             |
 //!                         BlockChecker
@@ -34,50 +34,789 @@
 //! # V1
 //! 没有签名的区块链
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::iter::{FromIterator, Iterator};
 use std::io::{Read, Write};
 use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver};
 
 use merkletree::merkle::{Element, MerkleTree};
 use mkt::HashAlgorithm;
 use digest::{Input, FixedOutput};
 use sha2::Sha256;
-use byteorder::{ WriteBytesExt, BigEndian};
+use byteorder::{ ByteOrder, WriteBytesExt, BigEndian};
+use rayon::prelude::*;
 
 mod block;
 mod transaction;
 mod mkt;
+mod error;
+mod utxo;
+pub mod encode;
+pub mod pow;
+pub mod clock;
+pub mod mempool;
+pub mod params;
+pub mod node;
+pub mod compact;
+pub mod net;
+pub mod fees;
+pub mod events;
+pub mod consensus;
+pub mod pos;
+pub mod account;
+pub mod spv;
+pub mod cfilter;
+pub mod script;
+pub mod scenario;
+pub mod state;
 //use mkt::*;
 use block::*;
+pub use block::{Block, BlockHeader, BlockTx, StakeSeal};
+use clock::{Clock, NetworkClock, SystemClock};
+pub use error::ChainError;
+pub use params::{ChainParams, Network};
+pub use events::{ChainEvent, EventBus, EvictReason};
+use consensus::{Consensus, ConsensusParams, DoubleSign, HeaderContext};
+pub use state::StateModel;
 use mkt::HashVal;
 use transaction::*;
+pub use transaction::{InputTx, OutPoint, OutputTx, Trans, Transaction, TxIn, CoinValue, TxAddr};
+pub use utxo::{UtxoEntry, UtxoSet};
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 /// Simple block chain for exploration.
-pub struct BlockChain{
-    chain: Vec<Block>, 
+/// Blocks on other branches are kept, the branch with the most work is the active chain.
+/// The state after the tip is the UTXO set by default, see `StateModel`.
+pub struct BlockChain<S: StateModel = UtxoSet<String, SimpleValue>>{
+    chain: Vec<Block<S::Tx>>, 
+    /// Height of every block of `chain` by hash.
+    heights: HashMap<[u8; 32], usize>,
+    state: S,
+    /// Data to disconnect each block of `chain` again, e.g. the outputs it spent.
+    undo: Vec<S::Undo>,
+    /// Blocks below this height only keep their header, their undo data is dropped too.
+    pruned: usize,
+    /// Blocks that are not in the active chain.
+    side: HashMap<[u8; 32], Block<S::Tx>>,
+    clock: Arc<dyn Clock>,
+    params: ChainParams,
+    consensus: Arc<dyn Consensus>,
+    /// Header of every block seen for a slot, by slot and producer, from the last final slot on.
+    signed_slots: HashMap<(u64, [u8; 32]), BlockHeader>,
+    double_signs: Vec<DoubleSign>,
+    /// Headers received ahead of their blocks, with their height.
+    headers: HashMap<[u8; 32], (usize, BlockHeader)>,
+    /// The assume-valid block and its ancestors, once its header is known.
+    assumed_valid: HashSet<[u8; 32]>,
+    /// Threads checking transactions, the global pool of rayon if `None`.
+    pool: Option<Arc<rayon::ThreadPool>>,
+    events: EventBus<S::Tx>,
+}
+
+/// A clone starts without subscribers.
+impl<S: StateModel> Clone for BlockChain<S>{
+    fn clone(&self) -> BlockChain<S>{
+        BlockChain{
+            chain: self.chain.clone(),
+            heights: self.heights.clone(),
+            state: self.state.clone(),
+            undo: self.undo.clone(),
+            pruned: self.pruned,
+            side: self.side.clone(),
+            clock: self.clock.clone(),
+            params: self.params.clone(),
+            consensus: self.consensus.clone(),
+            signed_slots: self.signed_slots.clone(),
+            double_signs: self.double_signs.clone(),
+            headers: self.headers.clone(),
+            assumed_valid: self.assumed_valid.clone(),
+            pool: self.pool.clone(),
+            events: EventBus::default(),
+        }
+    }
 }
 
 impl BlockChain{
-    pub fn new() -> BlockChain{
-        let genesis_block = Block::genesis_block(0);
+    pub fn new(params: ChainParams) -> BlockChain{
+        BlockChain::with_clock(params, Arc::new(NetworkClock::new(Arc::new(SystemClock))))
+    }
+
+    /// Create a chain that reads the time from `clock`.
+    /// The outputs of the genesis block are not spendable.
+    pub fn with_clock(params: ChainParams, clock: Arc<dyn Clock>) -> BlockChain{
+        let genesis_block = params.genesis_block();
+        BlockChain::with_genesis(params, clock, genesis_block, UtxoSet::new())
+    }
+
+    /// Rebuild a pruned chain from the headers of the pruned blocks after genesis, the blocks
+    /// that are kept with their undo data and the UTXO set of the tip.
+    /// Only the links between the headers are checked.
+    pub fn restore(
+        params: ChainParams,
+        clock: Arc<dyn Clock>,
+        headers: Vec<BlockHeader>,
+        blocks: Vec<(Block, BlockUndo)>,
+        utxo: UtxoSet<String, SimpleValue>,
+    ) -> Result<BlockChain, ChainError>{
+        let mut chain = BlockChain::with_clock(params, clock);
+        if !headers.is_empty(){
+            // the tip is never pruned
+            if blocks.is_empty(){
+                return Err(ChainError::UnexpectedBlock)
+            }
+            chain.pruned = headers.len() + 1;
+            chain.chain[0].txs_mut().clear();
+        }
+        let blocks = headers.into_iter().map(|h| (Block::from_parts(h, vec![]), vec![])).chain(blocks);
+        for (block, undo) in blocks{
+            if block.header().prev_block() != &chain.tip().hash(){
+                return Err(ChainError::BadPrevBlock)
+            }
+            chain.heights.insert(block.hash(), chain.chain.len());
+            chain.chain.push(block);
+            chain.undo.push(undo);
+        }
+        chain.state = utxo;
+        Ok(chain)
+    }
+
+    pub fn utxo(&self) -> &UtxoSet<String, SimpleValue>{
+        &self.state
+    }
+}
+
+impl<S: StateModel> BlockChain<S>{
+    /// A chain starting at `genesis`, with `state` after it. The genesis block is not checked.
+    pub fn with_genesis(params: ChainParams, clock: Arc<dyn Clock>, genesis: Block<S::Tx>, state: S) -> BlockChain<S>{
+        let mut heights = HashMap::new();
+        heights.insert(genesis.hash(), 0);
         BlockChain{
-            chain: vec![genesis_block], // ts
+            chain: vec![genesis],
+            heights,
+            state,
+            undo: vec![S::Undo::default()],
+            pruned: 0,
+            side: HashMap::new(),
+            clock,
+            consensus: consensus::engine(&params),
+            params,
+            signed_slots: HashMap::new(),
+            double_signs: Vec::new(),
+            headers: HashMap::new(),
+            assumed_valid: HashSet::new(),
+            pool: None,
+            events: EventBus::default(),
+        }
+    }
+
+    /// Check the transactions of blocks on `threads` threads, e.g. 1 to validate serially.
+    pub fn set_validation_threads(&mut self, threads: usize){
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        self.pool = Some(Arc::new(pool));
+    }
+
+    /// Subscribers of this chain, e.g. to be shared with a mempool.
+    pub fn events(&self) -> &EventBus<S::Tx>{
+        &self.events
+    }
+
+    pub fn subscribe(&self) -> Receiver<ChainEvent<S::Tx>>{
+        self.events.subscribe()
+    }
+
+    /// Subscribe after replaying the active chain from `height` as `BlockConnected` events,
+    /// followed by the current tip.
+    pub fn subscribe_from(&self, height: usize) -> Receiver<ChainEvent<S::Tx>>{
+        let (tx, rx) = channel();
+        for (h, block) in self.chain.iter().enumerate().skip(height.max(self.pruned)){
+            tx.send(ChainEvent::BlockConnected{ height: h, block: Arc::new(block.clone()) }).unwrap();
+        }
+        tx.send(ChainEvent::TipChanged{ height: self.height(), hash: self.tip().hash() }).unwrap();
+        self.events.add(tx);
+        rx
+    }
+
+    pub fn params(&self) -> &ChainParams{
+        &self.params
+    }
+
+    /// Engine checking the seals of the blocks, chosen by `params().consensus`.
+    pub fn consensus(&self) -> &dyn Consensus{
+        self.consensus.as_ref()
+    }
+
+    /// Slots signed twice by a producer, seen in blocks or reported by peers.
+    /// The evidence is local to this chain and does not make any block invalid.
+    pub fn double_signs(&self) -> &[DoubleSign]{
+        &self.double_signs
+    }
+
+    /// Record evidence of a double signed slot received from elsewhere.
+    pub fn report_double_sign(&mut self, evidence: DoubleSign) -> Result<(), ChainError>{
+        evidence.verify(self.consensus.as_ref())?;
+        if !self.double_signs.iter().any(|ev| ev.slot == evidence.slot && ev.validator == evidence.validator){
+            self.double_signs.push(evidence);
+        }
+        Ok(())
+    }
+
+    /// Slot of the deepest final block, the chain can no longer change before it.
+    fn final_slot(&self) -> Option<u64>{
+        let height = self.height().checked_sub(self.consensus.finality_depth()?)?;
+        self.consensus.slot_owner(self.chain[height].header()).map(|(slot, _)| slot)
+    }
+
+    /// Forget the producers of the slots before the last final slot, once the tip moved.
+    fn forget_final_slots(&mut self){
+        if let Some(last) = self.final_slot(){
+            self.signed_slots.retain(|(slot, _), _| *slot >= last);
+        }
+    }
+
+    /// Remember the producer of a validly sealed header, keeps evidence if it already signed the slot.
+    /// Slots before the last final slot are ignored.
+    fn record_slot(&mut self, header: &BlockHeader){
+        let (slot, validator) = match self.consensus.slot_owner(header){
+            Some(owner) if self.consensus.check_seal(header).is_ok() => owner,
+            _ => return,
+        };
+        if self.final_slot().is_some_and(|last| slot < last){
+            return
+        }
+        match self.signed_slots.get(&(slot, validator)){
+            Some(first) if first.hash() != header.hash() => {
+                let evidence = DoubleSign{ slot, validator, first: first.clone(), second: header.clone() };
+                self.report_double_sign(evidence).expect("both seals were checked");
+            },
+            Some(_) => {},
+            None => { self.signed_slots.insert((slot, validator), header.clone()); },
         }
     }
 
-    pub fn push(&mut self, txs: impl IntoIterator<Item=SimpleTx>){
+    pub fn tip(&self) -> &Block<S::Tx>{
+        self.chain.last().unwrap()
+    }
 
+    /// Block at `height` of the active chain, `None` once it is pruned.
+    pub fn block(&self, height: usize) -> Option<&Block<S::Tx>>{
+        if height < self.pruned{
+            return None
+        }
+        self.chain.get(height)
+    }
+
+    /// Header at `height` of the active chain, pruned blocks keep it.
+    pub fn header(&self, height: usize) -> Option<&BlockHeader>{
+        self.chain.get(height).map(|b| b.header())
+    }
+
+    /// Data to disconnect the block at `height`, e.g. the outputs it spent, `None` once it is pruned.
+    pub fn undo(&self, height: usize) -> Option<&S::Undo>{
+        if height < self.pruned{
+            return None
+        }
+        self.undo.get(height)
+    }
+
+    /// Lowest height whose block is kept, 0 if nothing was pruned.
+    pub fn prune_height(&self) -> usize{
+        self.pruned
+    }
+
+    /// Drop the transactions and the undo data of all blocks but the last `keep`, the tip is always kept.
+    /// The active chain can no longer be reorganized below them. Returns the new prune height.
+    pub fn prune(&mut self, keep: usize) -> usize{
+        let target = (self.height() + 1).saturating_sub(keep.max(1));
+        for h in self.pruned..target{
+            *self.chain[h].txs_mut() = Vec::new();
+            self.undo[h] = S::Undo::default();
+        }
+        self.pruned = self.pruned.max(target);
+        self.pruned
     }
 
+    /// Height of the block with `hash`.
+    pub fn height_of(&self, hash: &[u8; 32]) -> Option<usize>{
+        self.heights.get(hash).cloned()
+    }
+
+    /// Height of the tip, genesis is 0.
+    pub fn height(&self) -> usize{
+        self.chain.len() - 1
+    }
+
+    /// Median timestamp of the last `MEDIAN_TIME_SPAN` blocks up to `height`.
+    pub fn median_time_past_at(&self, height: usize) -> u64{
+        let start = (height + 1).saturating_sub(MEDIAN_TIME_SPAN);
+        let ts: Vec<u64> = self.chain[start..=height].iter()
+            .map(|b| b.header().timestamp())
+            .collect();
+        median_time_past(&ts)
+    }
+
+    /// Median time past of the tip. Lock-time is evaluated against it.
+    pub fn median_time_past(&self) -> u64{
+        self.median_time_past_at(self.height())
+    }
+
+    /// Current time adjusted by the offsets of peers.
+    pub fn adjusted_time(&self) -> u64{
+        self.clock.now()
+    }
+
+    /// State after the tip.
+    pub fn state(&self) -> &S{
+        &self.state
+    }
+
+    /// Difficulty required for the next block.
+    /// It changes at the first block of every retarget interval.
+    pub fn next_bits(&self) -> u32{
+        let tip = self.tip().header();
+        let height = self.height() + 1;
+        let interval = self.params.retarget_interval;
+        if self.params.no_retargeting || !height.is_multiple_of(interval){
+            return tip.bits()
+        }
+        let first = self.chain[height - interval].header();
+        let actual = tip.timestamp().saturating_sub(first.timestamp());
+        pow::retarget(tip.bits(), actual, self.params.target_timespan(), self.params.pow_limit)
+    }
+
+    /// What the consensus engine sees of the tip.
+    fn header_context(&self) -> HeaderContext<'_>{
+        HeaderContext{ prev: self.tip().header(), bits: self.next_bits(), adjusted_time: self.adjusted_time() }
+    }
+}
+
+impl BlockChain{
+    /// Mine a block on the tip paying the subsidy and the fees of `txs` to `addr`.
+    /// The transactions are not validated, the fees are taken from the values of their inputs.
+    /// Blocks of a proof of stake chain are forged with `pos::Staker` instead.
+    pub fn create_block(&self, timestamp: u64, addr: String, txs: Vec<SimpleTx>) -> Result<Block, ChainError>{
+        if let ConsensusParams::ProofOfStake(_) = self.params.consensus{
+            return Err(ChainError::BadSeal)
+        }
+        let mut block = self.block_template(timestamp, addr, txs)?;
+        if !block.mine(self.params.pow_limit){
+            return Err(ChainError::HighHash)
+        }
+        Ok(block)
+    }
+
+    /// A block on the tip like `create_block`, still to be sealed.
+    pub fn block_template(&self, timestamp: u64, addr: String, txs: Vec<SimpleTx>) -> Result<Block, ChainError>{
+        let height = self.height() + 1;
+        let mut reward = self.params.block_subsidy(height);
+        for tx in txs.iter(){
+            let value_in = tx.value_in().ok_or(ChainError::ValueOutOfRange)?;
+            let value_out = tx.value_out().ok_or(ChainError::ValueOutOfRange)?;
+            let fee = value_in.checked_sub(&value_out).ok_or(ChainError::InsufficientFunds)?;
+            reward = reward.checked_add(&fee).ok_or(ChainError::ValueOutOfRange)?;
+        }
+        let coinbase = SimpleTx::coinbase(height as u32, reward, addr);
+        let mut block = Block::pack(self.tip().header(), timestamp, std::iter::once(coinbase).chain(txs));
+        block.header_mut().set_bits(self.next_bits());
+        Ok(block)
+    }
+
+    /// Contextual checks of a non-coinbase transaction to be mined at `height`, returns the fee.
+    /// `coin` looks up the unspent output referenced by an input.
+    pub(crate) fn check_tx(&self, tx: &SimpleTx, height: usize, coin: impl Fn(&OutPoint) -> Option<UtxoEntry<String, SimpleValue>>) -> Result<SimpleValue, ChainError>{
+        // BIP113: lock-time is compared with the median time past of the previous block.
+        let prev_mtp = self.median_time_past_at(height - 1);
+        self.check_tx_alone(tx, height, prev_mtp)?;
+        let fee = self.check_tx_coins(tx, height, prev_mtp, coin)?;
+        self.verify_scripts(tx)?;
+        Ok(fee)
+    }
+
+    /// Scripts and signatures unlocking the inputs, skipped for blocks assumed valid.
+    pub(crate) fn verify_scripts(&self, tx: &SimpleTx) -> Result<(), ChainError>{
+        for i in 0..tx.input.0.len(){
+            script::verify_input(tx, i)?;
+        }
+        Ok(())
+    }
+
+    /// Checks of a transaction that need neither the coins it spends nor the rest of its block,
+    /// so that they can run in parallel. Scripts are left to `verify_scripts`.
+    fn check_tx_alone(&self, tx: &SimpleTx, height: usize, prev_mtp: u64) -> Result<(), ChainError>{
+        if tx.input.0.is_empty(){
+            return Err(ChainError::NoInputs)
+        }
+        if !tx.check_witness(){
+            return Err(ChainError::BadWitness)
+        }
+        tx.value_out().ok_or(ChainError::ValueOutOfRange)?;
+        if !tx.is_coinbase() && !tx.is_final(height, prev_mtp){
+            return Err(ChainError::NonFinal)
+        }
+        Ok(())
+    }
+
+    /// Checks of a non-coinbase transaction against the coins it spends, returns the fee.
+    fn check_tx_coins(&self, tx: &SimpleTx, height: usize, prev_mtp: u64, coin: impl Fn(&OutPoint) -> Option<UtxoEntry<String, SimpleValue>>) -> Result<SimpleValue, ChainError>{
+        let mut seen = HashSet::with_capacity(tx.input.0.len());
+        let mut coin_heights = Vec::with_capacity(tx.input.0.len());
+        for txin in tx.input.0.iter(){
+            if !seen.insert(txin.prev_out){
+                return Err(ChainError::DoubleSpend(txin.prev_out))
+            }
+            let entry = coin(&txin.prev_out).ok_or(ChainError::MissingInput(txin.prev_out))?;
+            if entry.output.addr != txin.addr || entry.output.val != txin.val{
+                return Err(ChainError::InputMismatch(txin.prev_out))
+            }
+            if entry.coinbase && height - entry.height < self.params.coinbase_maturity{
+                return Err(ChainError::ImmatureCoinbase(txin.prev_out))
+            }
+            coin_heights.push(entry.height);
+        }
+        // the input values have been checked against the spent outputs
+        let value_in = tx.value_in().ok_or(ChainError::ValueOutOfRange)?;
+        let value_out = tx.value_out().ok_or(ChainError::ValueOutOfRange)?;
+        let fee = value_in.checked_sub(&value_out).ok_or(ChainError::InsufficientFunds)?;
+
+        let lp = tx.sequence_locks(&coin_heights, |h| self.median_time_past_at(h));
+        if !lp.is_satisfied(height, prev_mtp){
+            return Err(ChainError::SequenceLocked)
+        }
+        Ok(fee)
+    }
+}
+
+impl<S: StateModel> BlockChain<S>{
+    /// Add a block extending the active chain or a side branch.
+    /// When a side branch gets more work than the active chain it is connected instead,
+    /// if one of its blocks is invalid the active chain stays as it was.
+    pub fn add_block(&mut self, block: Block<S::Tx>) -> Result<(), ChainError>{
+        let hash = block.hash();
+        if self.side.contains_key(&hash) || self.height_of(&hash).is_some(){
+            return Err(ChainError::DuplicateBlock)
+        }
+        let prev = *block.header().prev_block();
+        if self.side.contains_key(&prev) || self.height_of(&prev).is_some(){
+            self.record_slot(block.header());
+        }
+        if prev == self.tip().hash(){
+            self.connect_block(block)?;
+            self.forget_final_slots();
+            self.headers.remove(&hash);
+            self.events.publish(ChainEvent::BlockConnected{
+                height: self.height(),
+                block: Arc::new(self.tip().clone()),
+            });
+            self.events.publish(ChainEvent::TipChanged{ height: self.height(), hash });
+            return Ok(())
+        }
+        if !self.side.contains_key(&prev) && self.height_of(&prev).is_none(){
+            return Err(ChainError::BadPrevBlock)
+        }
+        // the other checks need the state of the branch
+        let height = self.known_height(&prev).unwrap() + 1;
+        self.check_checkpoint(height, &hash)?;
+        self.consensus.check_seal(block.header())?;
+        if block.header().merkle_root() != &block.compute_merkle_root(){
+            return Err(ChainError::BadMerkleRoot)
+        }
+        // kept aside under its hash, the block must not stand for a valid block
+        if block.is_mutated(){
+            return Err(ChainError::MutatedBlock)
+        }
+        self.side.insert(hash, block);
+        self.headers.remove(&hash);
+        self.activate_branch(hash)
+    }
+
+    /// Accept a header before its block, e.g. during initial download.
+    /// Blocks below the assume-valid block are only known to be its ancestors through headers.
+    pub fn add_header(&mut self, header: BlockHeader) -> Result<(), ChainError>{
+        let hash = header.hash();
+        if self.known_height(&hash).is_some(){
+            return Ok(())
+        }
+        let height = self.known_height(header.prev_block()).ok_or(ChainError::BadPrevBlock)? + 1;
+        self.check_checkpoint(height, &hash)?;
+        self.consensus.check_seal(&header)?;
+        self.headers.insert(hash, (height, header));
+        if self.params.assume_valid == Some(hash){
+            self.mark_assumed_valid(hash);
+        }
+        Ok(())
+    }
+
+    /// Height of a block of the active chain or a side branch, or of a header.
+    fn known_height(&self, hash: &[u8; 32]) -> Option<usize>{
+        let mut cur = *hash;
+        let mut depth = 0;
+        loop{
+            if let Some((h, _)) = self.headers.get(&cur){
+                return Some(h + depth)
+            }
+            match self.side.get(&cur){
+                Some(b) => cur = *b.header().prev_block(),
+                None => return self.height_of(&cur).map(|h| h + depth),
+            }
+            depth += 1;
+        }
+    }
+
+    /// Refuse a block at `height` that differs from a checkpoint or forks below the highest
+    /// checkpoint of the active chain.
+    fn check_checkpoint(&self, height: usize, hash: &[u8; 32]) -> Result<(), ChainError>{
+        let checkpoints = &self.params.checkpoints;
+        if checkpoints.iter().any(|(h, cp)| *h == height && cp != hash){
+            return Err(ChainError::CheckpointMismatch{ height })
+        }
+        let last = checkpoints.iter().map(|(h, _)| *h).filter(|h| *h <= self.height()).max();
+        match last{
+            Some(last) if height <= last && self.chain[height].hash() != *hash => Err(ChainError::ForkBeforeCheckpoint{ height: last }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remember the blocks from `hash` back to the active chain.
+    fn mark_assumed_valid(&mut self, hash: [u8; 32]){
+        let mut cur = hash;
+        // the blocks before are connected already
+        while let Some(header) = self.headers.get(&cur).map(|(_, h)| h).or_else(|| self.side.get(&cur).map(|b| b.header())){
+            let prev = *header.prev_block();
+            self.assumed_valid.insert(cur);
+            cur = prev;
+        }
+    }
+
+    /// Switch to the branch ending at `hash` if it has more work than the active chain.
+    fn activate_branch(&mut self, hash: [u8; 32]) -> Result<(), ChainError>{
+        let mut branch = Vec::new();
+        let mut cur = hash;
+        while let Some(b) = self.side.get(&cur){
+            branch.push(cur);
+            cur = *b.header().prev_block();
+        }
+        branch.reverse();
+        let fork = match self.height_of(&cur){
+            Some(h) => h,
+            // an ancestor was invalid and dropped
+            None => return Ok(()),
+        };
+
+        let work = |header: &BlockHeader| self.consensus.block_work(header);
+        let branch_work = branch.iter().fold(0u128, |acc, h| acc.saturating_add(work(self.side[h].header())));
+        let active_work = self.chain[fork + 1..].iter().fold(0u128, |acc, b| acc.saturating_add(work(b.header())));
+        if branch_work <= active_work{
+            return Ok(())
+        }
+        // the blocks to disconnect need their undo data
+        if fork + 1 < self.pruned{
+            return Err(ChainError::ReorgTooDeep{ fork })
+        }
+        if let Some(depth) = self.consensus.finality_depth(){
+            if fork + depth < self.height(){
+                return Err(ChainError::ReorgTooDeep{ fork })
+            }
+        }
+
+        let mut old = Vec::new();
+        while self.height() > fork{
+            old.push(self.disconnect_tip());
+        }
+        for (i, h) in branch.iter().enumerate(){
+            let block = self.side.remove(h).unwrap();
+            if let Err(e) = self.connect_block(block){
+                for h in branch[i + 1..].iter(){
+                    self.side.remove(h);
+                }
+                while self.height() > fork{
+                    let b = self.disconnect_tip();
+                    self.side.insert(b.hash(), b);
+                }
+                for b in old.into_iter().rev(){
+                    self.connect_block(b).expect("the previous chain was valid");
+                }
+                return Err(e)
+            }
+        }
+
+        self.forget_final_slots();
+        // `old` starts with the previous tip
+        let old_tip = fork + old.len();
+        for (i, b) in old.into_iter().enumerate(){
+            self.side.insert(b.hash(), b.clone());
+            self.events.publish(ChainEvent::BlockDisconnected{ height: old_tip - i, block: Arc::new(b) });
+        }
+        for h in fork + 1..=self.height(){
+            self.events.publish(ChainEvent::BlockConnected{ height: h, block: Arc::new(self.chain[h].clone()) });
+        }
+        self.events.publish(ChainEvent::TipChanged{ height: self.height(), hash: self.tip().hash() });
+        Ok(())
+    }
+
+    /// Remove the tip from the active chain and revert its changes to the state.
+    fn disconnect_tip(&mut self) -> Block<S::Tx>{
+        let block = self.chain.pop().unwrap();
+        let undo = self.undo.pop().unwrap();
+        self.heights.remove(&block.hash());
+        self.state.disconnect(&block, undo);
+        block
+    }
+
+    /// Append a block to the tip after checking its header and transactions.
+    fn connect_block(&mut self, block: Block<S::Tx>) -> Result<(), ChainError>{
+        let header = block.header();
+        if header.prev_block() != &self.tip().hash(){
+            return Err(ChainError::BadPrevBlock)
+        }
+        let height = self.height() + 1;
+        self.check_checkpoint(height, &block.hash())?;
+        self.consensus.check_header(&self.header_context(), header)?;
+        header.check_timestamp(self.median_time_past(), self.adjusted_time())?;
+
+        // the model sees the chain before the block
+        let mut state = std::mem::take(&mut self.state);
+        let undo = state.connect(self, &block, height);
+        self.state = state;
+        self.undo.push(undo?);
+        self.heights.insert(block.hash(), self.chain.len());
+        self.chain.push(block);
+        Ok(())
+    }
+}
+
+impl BlockChain{
+    /// Check the transactions of `block` at `height` against `utxo` and apply them,
+    /// returns the outputs spent.
+    fn connect_txs(&self, utxo: &mut UtxoSet<String, SimpleValue>, block: &Block, height: usize) -> Result<BlockUndo, ChainError>{
+        let header = block.header();
+        // transactions are checked one by one on the thread pool, against the coins in order below
+        let prev_mtp = self.median_time_past();
+        let check_scripts = !self.assumed_valid.contains(&block.hash());
+        let check = || block.txs().par_iter().map(|tx| {
+            let res = self.check_tx_alone(tx, height, prev_mtp)
+                .and_then(|_| if check_scripts{ self.verify_scripts(tx) }else{ Ok(()) });
+            (tx.txid(), res)
+        }).collect::<Vec<_>>();
+        let checked = match self.pool{
+            Some(ref pool) => pool.install(check),
+            None => check(),
+        };
+        let root = mkt::build_tree(checked.iter().map(|(txid, _)| HashVal(*txid)).collect()).root().0;
+        if header.merkle_root() != &root{
+            return Err(ChainError::BadMerkleRoot)
+        }
+        let mut txids = HashSet::with_capacity(checked.len());
+        if !checked.iter().all(|(txid, _)| txids.insert(*txid)){
+            return Err(ChainError::MutatedBlock)
+        }
+
+        match block.txs().first(){
+            Some(cb) if cb.is_coinbase() => {
+                if cb.input.0[0].prev_out.index as usize != height{
+                    return Err(ChainError::BadCoinbaseHeight)
+                }
+            },
+            _ => return Err(ChainError::MissingCoinbase),
+        }
+
+        let commitment = block.txs().first()
+            .filter(|tx| tx.is_coinbase())
+            .and_then(|cb| cb.witness_commitment());
+        match commitment{
+            Some(root) if root != witness_root(block.txs()) => return Err(ChainError::BadWitnessCommitment),
+            None if block.txs().iter().any(|tx| tx.has_witness()) => return Err(ChainError::BadWitnessCommitment),
+            _ => {},
+        }
+
+        let mut created = HashMap::new();
+        let mut spent = HashSet::new();
+        let mut fees = SimpleValue::zero();
+        for (i, (tx, (txid, res))) in block.txs().iter().zip(checked).enumerate(){
+            res?;
+            if tx.is_coinbase(){
+                if i != 0{
+                    return Err(ChainError::UnexpectedCoinbase)
+                }
+            }else{
+                for txin in tx.input.0.iter(){
+                    if spent.contains(&txin.prev_out){
+                        return Err(ChainError::DoubleSpend(txin.prev_out))
+                    }
+                }
+                let fee = self.check_tx_coins(tx, height, prev_mtp, |op| {
+                    created.get(op).or_else(|| utxo.get(op)).cloned()
+                })?;
+                fees = fees.checked_add(&fee).ok_or(ChainError::ValueOutOfRange)?;
+                spent.extend(tx.input.0.iter().map(|txin| txin.prev_out));
+            }
+
+            for (j, out) in tx.output.0.iter().enumerate(){
+                if out.is_unspendable(){
+                    continue
+                }
+                created.insert(OutPoint::new(txid, j as u32), UtxoEntry{
+                    output: out.clone(),
+                    height,
+                    coinbase: tx.is_coinbase(),
+                });
+            }
+        }
+
+        // checked above, the coinbase value is in range
+        let reward = block.txs()[0].value_out().unwrap();
+        let max_reward = self.params.block_subsidy(height).checked_add(&fees).ok_or(ChainError::ValueOutOfRange)?;
+        if reward > max_reward{
+            return Err(ChainError::BadCoinbaseValue)
+        }
+
+        let mut undo = Vec::new();
+        for op in spent.iter(){
+            if created.remove(op).is_none(){
+                undo.extend(utxo.spend(op).map(|entry| (*op, entry)));
+            }
+        }
+        for (op, entry) in created{
+            utxo.insert(op, entry);
+        }
+        Ok(undo)
+    }
+}
+
+impl StateModel for UtxoSet<String, SimpleValue>{
+    type Tx = SimpleTx;
+    type Undo = BlockUndo;
+
+    fn connect(&mut self, chain: &BlockChain, block: &Block, height: usize) -> Result<BlockUndo, ChainError>{
+        chain.connect_txs(self, block, height)
+    }
+
+    /// Remove the outputs of `block` and restore those it spent.
+    fn disconnect(&mut self, block: &Block, undo: BlockUndo){
+        for tx in block.txs(){
+            let txid = tx.txid();
+            for j in 0..tx.output.0.len(){
+                self.spend(&OutPoint::new(txid, j as u32));
+            }
+        }
+        for (op, entry) in undo{
+            self.insert(op, entry);
+        }
+    }
 }
 
 type SimpleHash = mkt::HashVal;
-type SimpleTx = Transaction<String, SimpleValue>;
+pub type SimpleTx = Transaction<String, SimpleValue>;
+/// Outputs spent by a block, with their outpoints.
+pub type BlockUndo = Vec<(OutPoint, UtxoEntry<String, SimpleValue>)>;
 type SimpleChain = BlockChain;
 
-#[derive(Clone, Debug)]
+/// Satoshis per coin.
+pub const COIN: u64 = 100_000_000;
+/// 21 million coins.
+pub const MAX_MONEY: u64 = 21_000_000 * COIN;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct SimpleValue{
     pub val: u64, // v64
 }
@@ -92,6 +831,29 @@ impl CoinValue for SimpleValue{
         v.write_u64::<BigEndian>(self.val).unwrap();
         v
     }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self>{
+        if bytes.len() != 8{
+            return None
+        }
+        Some(SimpleValue::from(BigEndian::read_u64(bytes)))
+    }
+
+    fn zero() -> Self{
+        SimpleValue::from(0)
+    }
+
+    fn max_money() -> Self{
+        SimpleValue::from(MAX_MONEY)
+    }
+
+    fn checked_add(&self, other: &Self) -> Option<Self>{
+        self.val.checked_add(other.val).map(SimpleValue::from)
+    }
+
+    fn checked_sub(&self, other: &Self) -> Option<Self>{
+        self.val.checked_sub(other.val).map(SimpleValue::from)
+    }
 }
 
 impl From<u64> for SimpleValue{
@@ -118,15 +880,39 @@ impl SimpleTx{
 }
 
 
+/// Prefix of the address holding a witness commitment, followed by the hex root.
+pub const WITNESS_COMMITMENT_PREFIX: &str = "witness:";
+
 impl TxAddr for String{
     fn coin_base_addr() -> Self{
         "".to_string()
     }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self>{
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+
+    fn witness_commitment(root: &[u8; 32]) -> Self{
+        format!("{}{}", WITNESS_COMMITMENT_PREFIX, encode::to_hex(root))
+    }
+
+    fn as_witness_commitment(&self) -> Option<[u8; 32]>{
+        if !self.starts_with(WITNESS_COMMITMENT_PREFIX){
+            return None
+        }
+        let bytes = encode::from_hex(&self[WITNESS_COMMITMENT_PREFIX.len()..])?;
+        if bytes.len() != 32{
+            return None
+        }
+        let mut root = [0; 32];
+        root.copy_from_slice(&bytes);
+        Some(root)
+    }
 }
 
 #[test]
 fn bc_usage() {
-    let mut chain: SimpleChain = BlockChain::new();
+    let mut chain: SimpleChain = BlockChain::new(ChainParams::regtest());
     let txs:Vec<SimpleTx> = Vec::new();
 
     let addr_a = "Alice".to_string();
@@ -136,20 +922,24 @@ fn bc_usage() {
     let txs = vec![
         Transaction{
             input:InputTx(vec![
-                Trans{addr: addr_a.clone(), val: SimpleValue::from(10)},
+                TxIn::new(OutPoint::new([1; 32], 0), addr_a.clone(), SimpleValue::from(10)),
             ]),
             output:OutputTx(vec![
                 Trans{addr: addr_b.clone(), val: SimpleValue::from(5)}, 
                 Trans{addr: addr_a.clone(), val: SimpleValue::from(5)},
             ]),
+            lock_time: 0,
+            witness: vec![],
         }, 
         Transaction{
             input:InputTx(vec![
-                Trans{addr: addr_b.clone(), val: SimpleValue::from(2)},
+                TxIn::new(OutPoint::new([2; 32], 0), addr_b.clone(), SimpleValue::from(2)),
             ]),
             output:OutputTx(vec![
                 Trans{addr: addr_c.clone(), val: SimpleValue::from(2)}, 
             ]),
+            lock_time: 0,
+            witness: vec![],
         }
     ];
     // tx -> hash
@@ -176,6 +966,541 @@ fn bc_usage() {
 
 }   
 
+/// Regtest chain whose coinbase outputs can be spent in the next block.
+#[cfg(test)]
+pub(crate) fn test_chain(clock: Arc<dyn Clock>) -> BlockChain{
+    let mut params = ChainParams::regtest();
+    params.coinbase_maturity = 1;
+    BlockChain::with_clock(params, clock)
+}
+
+/// Pack `txs` as they are on the tip of `chain` and mine the block.
+#[cfg(test)]
+pub(crate) fn mine_block(chain: &BlockChain, ts: u64, txs: Vec<SimpleTx>) -> Block{
+    let mut b = Block::pack(chain.tip().header(), ts, txs.into_iter());
+    b.header_mut().set_bits(chain.next_bits());
+    assert!(b.mine(chain.params().pow_limit));
+    b
+}
+
+#[test]
+fn test_block_timestamp_rules() {
+    let g = ChainParams::regtest().genesis_timestamp;
+    let clock = Arc::new(clock::MockClock::new(g + 1_000_000));
+    let mut chain = test_chain(clock.clone());
+    let empty = |chain: &BlockChain, ts| chain.create_block(ts, "Alice".to_string(), vec![]).unwrap();
+
+    for i in 1..=11{
+        let b = empty(&chain, g + i * 100);
+        chain.add_block(b).unwrap();
+    }
+    // timestamps g, g + 100, ..., g + 1100 -> median of the last 11 is g + 600
+    assert_eq!(g + 600, chain.median_time_past());
+
+    let b = empty(&chain, g + 600);
+    assert_eq!(
+        Err(ChainError::TimeTooOld{ timestamp: g + 600, median_time_past: g + 600 }),
+        chain.add_block(b)
+    );
+
+    // an earlier timestamp than the tip is fine as long as it is after the median
+    let b = empty(&chain, g + 601);
+    chain.add_block(b).unwrap();
+
+    let too_new = g + 1_000_000 + block::MAX_FUTURE_BLOCK_TIME + 1;
+    let b = empty(&chain, too_new);
+    assert!(chain.add_block(b.clone()).is_err());
+
+    clock.advance(1);
+    chain.add_block(b).unwrap();
+
+    let orphan = Block::pack(ChainParams::testnet().genesis_block().header(), too_new, Vec::<SimpleTx>::new().into_iter());
+    assert_eq!(Err(ChainError::BadPrevBlock), chain.add_block(orphan));
+}
+
+#[test]
+fn test_block_rejects_non_final_tx() {
+    let g = ChainParams::regtest().genesis_timestamp;
+    let mut chain = test_chain(Arc::new(clock::MockClock::new(g + 1_000_000)));
+
+    let cb = SimpleTx::coinbase(1, 50.into(), "Alice".to_string());
+    let cb_id = cb.txid();
+    chain.add_block(mine_block(&chain, g + 100, vec![cb])).unwrap();
+
+    let spend = |lock_time, sequence| SimpleTx{
+        input: InputTx(vec![
+            TxIn::new(OutPoint::new(cb_id, 0), "Alice".to_string(), 50.into()).with_sequence(sequence)
+        ]),
+        output: OutputTx(vec![Trans{addr: "Bob".to_string(), val: 50.into()}]),
+        lock_time,
+        witness: vec![],
+    };
+    let block = |chain: &BlockChain, ts, tx| chain.create_block(ts, "Alice".to_string(), vec![tx]).unwrap();
+
+    // height lock: the block at height 2 may include lock_time 1 but not 2
+    let b = block(&chain, g + 200, spend(2, 0));
+    assert_eq!(Err(ChainError::NonFinal), chain.add_block(b));
+    // a final sequence turns the lock off
+    let b = block(&chain, g + 200, spend(2, SEQUENCE_FINAL));
+    assert!(chain.clone().add_block(b).is_ok());
+
+    // time lock is compared with the median time past, not the block timestamp
+    let b = block(&chain, g + 200, spend(g as u32 + 150, 0));
+    assert_eq!(Err(ChainError::NonFinal), chain.add_block(b));
+
+    // relative lock of 2 blocks on a coin mined at height 1
+    let b = block(&chain, g + 200, spend(0, 2));
+    assert_eq!(Err(ChainError::SequenceLocked), chain.add_block(b));
+    let b = chain.create_block(g + 300, "Alice".to_string(), vec![]).unwrap();
+    chain.add_block(b).unwrap();
+    let b = block(&chain, g + 400, spend(0, 2));
+    chain.add_block(b).unwrap();
+
+    assert!(chain.utxo().get(&OutPoint::new(cb_id, 0)).is_none());
+    // Bob and the coinbases of heights 2 and 3
+    assert_eq!(3, chain.utxo().len());
+}
+
+#[test]
+fn test_block_consensus_rules() {
+    let params = ChainParams::regtest();
+    let g = params.genesis_timestamp;
+    let mut chain = BlockChain::with_clock(params.clone(), Arc::new(clock::MockClock::new(g + 1_000_000)));
+
+    let b = chain.create_block(g + 100, "Alice".to_string(), vec![]).unwrap();
+    let cb = b.txs()[0].clone();
+    assert_eq!(params.block_subsidy(1), cb.value_out().unwrap());
+
+    // the genesis difficulty is kept on regtest
+    let mut bad = b.clone();
+    bad.header_mut().set_bits(0x1f7fffff);
+    assert!(bad.mine(params.pow_limit));
+    assert_eq!(Err(ChainError::BadDifficulty), chain.clone().add_block(bad));
+
+    let mut bad = b.clone();
+    while bad.check_proof_of_work(params.pow_limit){
+        let nonce = bad.header().nonce();
+        bad.header_mut().set_nonce(nonce + 1);
+    }
+    assert_eq!(Err(ChainError::HighHash), chain.clone().add_block(bad));
+
+    let mut bad = b.clone();
+    bad.txs_mut()[0].output.0[0].addr = "Mallory".to_string();
+    assert!(bad.mine(params.pow_limit));
+    assert_eq!(Err(ChainError::BadMerkleRoot), chain.clone().add_block(bad));
+
+    let mut too_much = cb.clone();
+    too_much.output.0[0].val = SimpleValue::from(params.initial_subsidy + 1);
+    assert_eq!(Err(ChainError::BadCoinbaseValue), chain.clone().add_block(mine_block(&chain, g + 100, vec![too_much])));
+    let wrong_height = SimpleTx::coinbase(2, 50.into(), "Alice".to_string());
+    assert_eq!(Err(ChainError::BadCoinbaseHeight), chain.clone().add_block(mine_block(&chain, g + 100, vec![wrong_height])));
+    assert_eq!(Err(ChainError::MissingCoinbase), chain.clone().add_block(mine_block(&chain, g + 100, vec![])));
+
+    chain.add_block(b).unwrap();
+
+    // coinbase outputs mature after 100 blocks
+    let op = OutPoint::new(cb.txid(), 0);
+    let spend = SimpleTx{
+        input: InputTx(vec![TxIn::new(op, "Alice".to_string(), params.block_subsidy(1))]),
+        output: OutputTx(vec![Trans{addr: "Bob".to_string(), val: params.block_subsidy(1)}]),
+        lock_time: 0,
+        witness: vec![],
+    };
+    assert_eq!(Err(ChainError::ImmatureCoinbase(op)), chain.check_tx(&spend, 2, |op| chain.utxo().get(op).cloned()));
+    for i in 2..=100{
+        let b = chain.create_block(g + 100 * i, "Bob".to_string(), vec![]).unwrap();
+        chain.add_block(b).unwrap();
+    }
+    assert!(chain.check_tx(&spend, 101, |op| chain.utxo().get(op).cloned()).is_ok());
+}
+
+#[test]
+fn test_retarget() {
+    let mut params = ChainParams::regtest();
+    params.no_retargeting = false;
+    params.retarget_interval = 4;
+    let g = params.genesis_timestamp;
+    let mut chain = BlockChain::with_clock(params.clone(), Arc::new(clock::MockClock::new(g + 1_000_000)));
+
+    // four times faster than expected
+    for i in 1..4{
+        assert_eq!(params.pow_limit, chain.next_bits());
+        let b = chain.create_block(g + i * 150, "Alice".to_string(), vec![]).unwrap();
+        chain.add_block(b).unwrap();
+    }
+    assert_eq!(0x201fffff, chain.next_bits());
+
+    let mut stale = mine_block(&chain, g + 600, vec![SimpleTx::coinbase(4, 50.into(), "Alice".to_string())]);
+    stale.header_mut().set_bits(params.pow_limit);
+    assert!(stale.mine(params.pow_limit));
+    assert_eq!(Err(ChainError::BadDifficulty), chain.clone().add_block(stale));
+
+    let b = chain.create_block(g + 600, "Alice".to_string(), vec![]).unwrap();
+    chain.add_block(b).unwrap();
+    assert_eq!(0x201fffff, chain.tip().header().bits());
+}
+
+#[test]
+fn test_simple_value_arithmetic() {
+    let a = SimpleValue::from(7);
+    let b = SimpleValue::from(5);
+    assert_eq!(Some(SimpleValue::from(12)), a.checked_add(&b));
+    assert_eq!(Some(SimpleValue::from(2)), a.checked_sub(&b));
+    assert_eq!(None, b.checked_sub(&a));
+    assert_eq!(None, SimpleValue::from(u64::MAX).checked_add(&b));
+    assert!(b < a);
+    assert!(SimpleValue::max_money().in_money_range());
+    assert!(!SimpleValue::from(MAX_MONEY + 1).in_money_range());
+
+    assert_eq!(Some(a.clone()), SimpleValue::from_bytes(&a.to_bytes()));
+    assert_eq!(None, SimpleValue::from_bytes(&[0; 7]));
+}
+
+#[test]
+fn test_tx_values_are_checked() {
+    let g = ChainParams::regtest().genesis_timestamp;
+    let mut chain = test_chain(Arc::new(clock::MockClock::new(g + 1_000_000)));
+    let cb = SimpleTx::coinbase(1, 50.into(), "Alice".to_string());
+    let cb_id = cb.txid();
+    chain.add_block(mine_block(&chain, g + 100, vec![cb])).unwrap();
+
+    let spend = |outs: Vec<u64>| SimpleTx{
+        input: InputTx(vec![TxIn::new(OutPoint::new(cb_id, 0), "Alice".to_string(), 50.into())]),
+        output: OutputTx(outs.into_iter().map(|v| Trans{addr: "Bob".to_string(), val: v.into()}).collect()),
+        lock_time: 0,
+        witness: vec![],
+    };
+    let coin = |op: &OutPoint| chain.utxo().get(op).cloned();
+
+    assert_eq!(Ok(SimpleValue::from(5)), chain.check_tx(&spend(vec![40, 5]), 2, coin));
+    assert_eq!(Err(ChainError::InsufficientFunds), chain.check_tx(&spend(vec![40, 11]), 2, coin));
+    // the sum wraps around to 49 in u64 arithmetic
+    assert_eq!(
+        Err(ChainError::ValueOutOfRange),
+        chain.check_tx(&spend(vec![u64::MAX, 50]), 2, coin)
+    );
+
+    let mut tx = spend(vec![50]);
+    tx.input.0[0].val = 49.into();
+    assert_eq!(Err(ChainError::InputMismatch(OutPoint::new(cb_id, 0))), chain.check_tx(&tx, 2, coin));
+}
+
+#[test]
+fn test_witness_commitment() {
+    let g = ChainParams::regtest().genesis_timestamp;
+    let mut chain = test_chain(Arc::new(clock::MockClock::new(g + 1_000_000)));
+    let cb = SimpleTx::coinbase(1, 50.into(), "Alice".to_string());
+    let cb_id = cb.txid();
+    chain.add_block(mine_block(&chain, g + 100, vec![cb])).unwrap();
+    assert_eq!(1, chain.utxo().len());
+
+    let mut tx = SimpleTx{
+        input: InputTx(vec![TxIn::new(OutPoint::new(cb_id, 0), "Alice".to_string(), 50.into())]),
+        output: OutputTx(vec![Trans{addr: "Bob".to_string(), val: 50.into()}]),
+        lock_time: 0,
+        witness: vec![vec![b"unlock".to_vec()]],
+    };
+    let cb = SimpleTx::coinbase(2, 50.into(), "Alice".to_string());
+
+    // pack adds the commitment to the coinbase
+    let b = mine_block(&chain, g + 200, vec![cb.clone(), tx.clone()]);
+    assert_eq!(Some(witness_root(b.txs())), b.txs()[0].witness_commitment());
+    assert!(chain.clone().add_block(b.clone()).is_ok());
+
+    // a block without the commitment
+    let mut missing = b.clone();
+    missing.txs_mut()[0] = cb;
+    missing.update_merkle_root();
+    assert!(missing.mine(chain.params().pow_limit));
+    assert_eq!(Err(ChainError::BadWitnessCommitment), chain.clone().add_block(missing));
+
+    // the witness changes after the block is packed
+    let mut malleated = b.clone();
+    malleated.txs_mut()[1].witness[0][0] = b"other".to_vec();
+    // the txid and so the merkle root stay the same
+    assert!(malleated.check_proof_of_work(chain.params().pow_limit));
+    assert_eq!(Err(ChainError::BadWitnessCommitment), chain.clone().add_block(malleated));
+
+    tx.witness = vec![vec![], vec![]];
+    assert_eq!(Err(ChainError::BadWitness), chain.check_tx(&tx, 2, |op| chain.utxo().get(op).cloned()));
+
+    chain.add_block(b).unwrap();
+    // the commitment output is not spendable
+    assert_eq!(2, chain.utxo().len());
+}
+
+#[test]
+fn test_reorg() {
+    let g = ChainParams::regtest().genesis_timestamp;
+    let mut chain = test_chain(Arc::new(clock::MockClock::new(g + 1_000_000)));
+    let cb = |height: u32, addr: &str| SimpleTx::coinbase(height, (50 * COIN).into(), addr.to_string());
+    let cb1 = cb(1, "Alice");
+    let coin = OutPoint::new(cb1.txid(), 0);
+    chain.add_block(mine_block(&chain, g + 100, vec![cb1])).unwrap();
+    let mut fork = chain.clone();
+
+    let events = chain.subscribe();
+    let spend = SimpleTx{
+        input: InputTx(vec![TxIn::new(coin, "Alice".to_string(), (50 * COIN).into())]),
+        output: OutputTx(vec![Trans{addr: "Bob".to_string(), val: (50 * COIN).into()}]),
+        lock_time: 0,
+        witness: vec![],
+    };
+    let b2 = mine_block(&chain, g + 200, vec![cb(2, "Alice"), spend]);
+    chain.add_block(b2.clone()).unwrap();
+    let mut main = chain.clone();
+    assert!(chain.utxo().get(&coin).is_none());
+
+    // a branch with as much work is kept aside
+    let f2 = mine_block(&fork, g + 200, vec![cb(2, "Carol")]);
+    fork.add_block(f2.clone()).unwrap();
+    chain.add_block(f2.clone()).unwrap();
+    assert_eq!(b2.hash(), chain.tip().hash());
+    assert_eq!(Err(ChainError::DuplicateBlock), chain.add_block(f2.clone()));
+
+    // and becomes the active chain once it has more
+    let f3 = mine_block(&fork, g + 300, vec![cb(3, "Carol")]);
+    chain.add_block(f3.clone()).unwrap();
+    assert_eq!(f3.hash(), chain.tip().hash());
+    assert_eq!(Some(2), chain.height_of(&f2.hash()));
+    assert_eq!(Some(3), chain.height_of(&f3.hash()));
+    assert_eq!(None, chain.height_of(&b2.hash()));
+    // the spent coin is back
+    assert!(chain.utxo().get(&coin).is_some());
+
+    let seen: Vec<String> = events.try_iter().map(|e| match e{
+        ChainEvent::BlockConnected{ height, block } => format!("connect {} {}", height, block.hash() == f2.hash()),
+        ChainEvent::BlockDisconnected{ height, block } => format!("disconnect {} {}", height, block.hash() == b2.hash()),
+        ChainEvent::TipChanged{ height, .. } => format!("tip {}", height),
+        _ => unreachable!(),
+    }).collect();
+    assert_eq!(vec![
+        "connect 2 false", "tip 2",
+        "disconnect 2 true", "connect 2 true", "connect 3 false", "tip 3",
+    ], seen);
+
+    // a longer branch with an invalid block leaves the active chain as it was
+    let b3 = mine_block(&main, g + 300, vec![cb(3, "Alice")]);
+    main.add_block(b3.clone()).unwrap();
+    chain.add_block(b3).unwrap();
+    let bad = mine_block(&main, g + 400, vec![SimpleTx::coinbase(4, (100 * COIN).into(), "Alice".to_string())]);
+    assert_eq!(Err(ChainError::BadCoinbaseValue), chain.add_block(bad));
+    assert_eq!(f3.hash(), chain.tip().hash());
+    assert!(chain.utxo().get(&coin).is_some());
+    assert!(events.try_recv().is_err());
+
+    // a subscriber catching up from height 2
+    let replay: Vec<usize> = chain.subscribe_from(2).try_iter().map(|e| match e{
+        ChainEvent::BlockConnected{ height, .. } => height,
+        ChainEvent::TipChanged{ height, .. } => height + 100,
+        _ => unreachable!(),
+    }).collect();
+    assert_eq!(vec![2, 3, 103], replay);
+}
+
+#[test]
+fn test_mutated_block() {
+    let g = ChainParams::regtest().genesis_timestamp;
+    let mut chain = test_chain(Arc::new(clock::MockClock::new(g + 1_000_000)));
+    let cb = |height: u32, addr: &str| SimpleTx::coinbase(height, (50 * COIN).into(), addr.to_string());
+    let cb1 = cb(1, "Alice");
+    let coin = OutPoint::new(cb1.txid(), 0);
+    chain.add_block(mine_block(&chain, g + 100, vec![cb1])).unwrap();
+    let pay = |prev_out: OutPoint, from: &str, to: &str| SimpleTx{
+        input: InputTx(vec![TxIn::new(prev_out, from.to_string(), (50 * COIN).into())]),
+        output: OutputTx(vec![Trans{addr: to.to_string(), val: (50 * COIN).into()}]),
+        lock_time: 0,
+        witness: vec![],
+    };
+    let first = pay(coin, "Alice", "Bob");
+    let second = pay(OutPoint::new(first.txid(), 0), "Bob", "Carol");
+    let b2 = mine_block(&chain, g + 200, vec![cb(2, "Alice"), first, second.clone()]);
+    // the last transaction twice, same root and hash
+    let mut txs = b2.txs().to_vec();
+    txs.push(second);
+    let mutated = Block::from_parts(b2.header().clone(), txs);
+    assert_eq!(b2.hash(), mutated.hash());
+
+    // on a side branch the mutated block would take the hash of the valid one
+    let mut fork = chain.clone();
+    fork.add_block(mine_block(&fork, g + 200, vec![cb(2, "Carol")])).unwrap();
+    assert_eq!(Err(ChainError::MutatedBlock), fork.add_block(mutated.clone()));
+    assert_eq!(Ok(()), fork.add_block(b2.clone()));
+
+    assert_eq!(Err(ChainError::MutatedBlock), chain.add_block(mutated));
+    assert_eq!(Ok(()), chain.add_block(b2.clone()));
+    assert_eq!(b2.hash(), chain.tip().hash());
+}
+
+#[test]
+fn test_checkpoints() {
+    let g = ChainParams::regtest().genesis_timestamp;
+    let clock = Arc::new(clock::MockClock::new(g + 1_000_000));
+    let cb = |height: u32, addr: &str| SimpleTx::coinbase(height, (50 * COIN).into(), addr.to_string());
+    let mut source = test_chain(clock.clone());
+    for h in 1..4{
+        source.add_block(mine_block(&source, g + 100 * h as u64, vec![cb(h, "Alice")])).unwrap();
+    }
+    let mut params = source.params().clone();
+    params.checkpoints = vec![(2, source.block(2).unwrap().hash())];
+    let mut chain = BlockChain::with_clock(params, clock.clone());
+    let genesis = chain.clone();
+    chain.add_block(source.block(1).unwrap().clone()).unwrap();
+
+    let f2 = mine_block(&chain, g + 250, vec![cb(2, "Carol")]);
+    assert_eq!(Err(ChainError::CheckpointMismatch{ height: 2 }), chain.add_header(f2.header().clone()));
+    assert_eq!(Err(ChainError::CheckpointMismatch{ height: 2 }), chain.add_block(f2));
+    chain.add_block(source.block(2).unwrap().clone()).unwrap();
+    let at2 = chain.clone();
+    chain.add_block(source.block(3).unwrap().clone()).unwrap();
+
+    // nothing forks below the checkpoint
+    let f1 = mine_block(&genesis, g + 150, vec![cb(1, "Carol")]);
+    assert_eq!(Err(ChainError::ForkBeforeCheckpoint{ height: 2 }), chain.add_header(f1.header().clone()));
+    assert_eq!(Err(ChainError::ForkBeforeCheckpoint{ height: 2 }), chain.add_block(f1));
+    // above it branches are kept as usual
+    chain.add_block(mine_block(&at2, g + 350, vec![cb(3, "Carol")])).unwrap();
+    assert_eq!(source.tip().hash(), chain.tip().hash());
+}
+
+#[test]
+fn test_assume_valid() {
+    let g = ChainParams::regtest().genesis_timestamp;
+    let clock = Arc::new(clock::MockClock::new(g + 1_000_000));
+    let mut params = test_chain(clock.clone()).params().clone();
+    let pow_limit = params.pow_limit;
+    let cb = |height: u32, addr: &str| SimpleTx::coinbase(height, (50 * COIN).into(), addr.to_string());
+    let mine_on = |prev: &Block, ts: u64, txs: Vec<SimpleTx>| {
+        let mut b = Block::pack(prev.header(), ts, txs.into_iter());
+        b.header_mut().set_bits(pow_limit);
+        assert!(b.mine(pow_limit));
+        b
+    };
+    let policy = script::Multisig::new(1, vec![script::SigningKey::from_seed(&[1; 32]).public_key()]).unwrap();
+    let cb1 = cb(1, &policy.address());
+    let b1 = mine_on(&params.genesis_block(), g + 100, vec![cb1.clone()]);
+    // signed by nobody
+    let spend = SimpleTx{
+        input: InputTx(vec![TxIn::new(OutPoint::new(cb1.txid(), 0), policy.address(), (50 * COIN).into())]),
+        output: OutputTx(vec![Trans{addr: "Bob".to_string(), val: (50 * COIN).into()}]),
+        lock_time: 0,
+        witness: vec![vec![vec![7; 64]]],
+    };
+    let b2 = mine_on(&b1, g + 200, vec![cb(2, "Alice"), spend.clone()]);
+    let b3 = mine_on(&b2, g + 300, vec![cb(3, "Alice")]);
+    let blocks = vec![b1.clone(), b2, b3];
+
+    let mut chain = BlockChain::with_clock(params.clone(), clock.clone());
+    chain.add_block(b1.clone()).unwrap();
+    assert_eq!(Err(ChainError::BadSignature), chain.add_block(blocks[1].clone()));
+
+    // the signatures are not checked below the assume-valid block
+    params.assume_valid = Some(blocks[2].hash());
+    let mut chain = BlockChain::with_clock(params.clone(), clock.clone());
+    for b in blocks.iter(){
+        chain.add_header(b.header().clone()).unwrap();
+    }
+    for b in blocks{
+        chain.add_block(b).unwrap();
+    }
+    assert_eq!(3, chain.height());
+
+    // but the structure and the coins are
+    let mut no_witness = spend.clone();
+    no_witness.witness.push(vec![]);
+    let mut overspend = spend;
+    overspend.output.0[0].val = (51 * COIN).into();
+    for (tx, err) in [(no_witness, ChainError::BadWitness), (overspend, ChainError::InsufficientFunds)]{
+        let b2 = mine_on(&b1, g + 200, vec![cb(2, "Alice"), tx]);
+        let b3 = mine_on(&b2, g + 300, vec![cb(3, "Alice")]);
+        params.assume_valid = Some(b3.hash());
+        let mut chain = BlockChain::with_clock(params.clone(), clock.clone());
+        for b in [&b1, &b2, &b3].iter(){
+            chain.add_header(b.header().clone()).unwrap();
+        }
+        chain.add_block(b1.clone()).unwrap();
+        assert_eq!(Err(err), chain.add_block(b2));
+    }
+}
+
+#[test]
+fn test_parallel_validation() {
+    let g = ChainParams::regtest().genesis_timestamp;
+    let mut chain = test_chain(Arc::new(clock::MockClock::new(g + 1_000_000)));
+    let mut cb = SimpleTx::coinbase(1, (50 * COIN).into(), "Alice".to_string());
+    cb.output.0 = (0..8).map(|_| Trans{addr: "Alice".to_string(), val: COIN.into()}).collect();
+    let cb_id = cb.txid();
+    chain.add_block(mine_block(&chain, g + 100, vec![cb])).unwrap();
+
+    let mut txs: Vec<SimpleTx> = (0..8).map(|i| SimpleTx{
+        input: InputTx(vec![TxIn::new(OutPoint::new(cb_id, i), "Alice".to_string(), COIN.into())]),
+        output: OutputTx(vec![Trans{addr: "Bob".to_string(), val: COIN.into()}]),
+        lock_time: 0,
+        witness: vec![],
+    }).collect();
+    let valid = chain.create_block(g + 200, "Miner".to_string(), txs.clone()).unwrap();
+    // the first error in block order is reported, whichever thread finds it
+    txs[2].output.0[0].val = (2 * COIN).into();
+    txs[5].lock_time = 1000;
+    txs[5].input.0[0] = txs[5].input.0[0].clone().with_sequence(0);
+    txs[6].witness = vec![vec![], vec![]];
+    let invalid = mine_block(&chain, g + 200, std::iter::once(SimpleTx::coinbase(2, COIN.into(), "Miner".to_string())).chain(txs).collect());
+    for &threads in [1, 4].iter(){
+        let mut chain = chain.clone();
+        chain.set_validation_threads(threads);
+        assert_eq!(Err(ChainError::InsufficientFunds), chain.add_block(invalid.clone()));
+        chain.add_block(valid.clone()).unwrap();
+    }
+}
+
+#[test]
+fn test_prune() {
+    let g = ChainParams::regtest().genesis_timestamp;
+    let mut chain = test_chain(Arc::new(clock::MockClock::new(g + 1_000_000)));
+    for i in 1..=6{
+        let block = chain.create_block(g + i * 100, "Alice".to_string(), vec![]).unwrap();
+        chain.add_block(block).unwrap();
+    }
+    let fork = chain.clone();
+    assert_eq!(0, chain.prune(10));
+    assert_eq!(4, chain.prune(3));
+    // pruning does not go back
+    assert_eq!(4, chain.prune(5));
+    assert!(chain.block(3).is_none() && chain.undo(3).is_none());
+    assert_eq!(fork.block(3).unwrap().hash(), chain.header(3).unwrap().hash());
+    assert_eq!(6, chain.utxo().len());
+    let events = chain.subscribe_from(1);
+    assert!(matches!(events.try_recv(), Ok(ChainEvent::BlockConnected{ height: 4, .. })));
+
+    // branches from the chain up to `height`
+    let branch = |height: usize, n: u64| {
+        let mut side = test_chain(Arc::new(clock::MockClock::new(g + 1_000_000)));
+        for h in 1..=height{
+            side.add_block(fork.block(h).unwrap().clone()).unwrap();
+        }
+        (1..=n).map(|i| {
+            let block = side.create_block(g + 1000 + i * 100, "Bob".to_string(), vec![]).unwrap();
+            side.add_block(block.clone()).unwrap();
+            block
+        }).collect::<Vec<_>>()
+    };
+    let mut blocks = branch(2, 5);
+    let last = blocks.pop().unwrap();
+    for b in blocks{
+        chain.add_block(b).unwrap();
+    }
+    // block 3 would have to be disconnected
+    assert_eq!(Err(ChainError::ReorgTooDeep{ fork: 2 }), chain.add_block(last));
+    assert_eq!(fork.tip().hash(), chain.tip().hash());
+
+    for b in branch(3, 4){
+        chain.add_block(b).unwrap();
+    }
+    assert_eq!(7, chain.height());
+    assert_eq!(7, chain.utxo().len());
+    assert!(chain.utxo().iter().any(|(_, e)| e.output.addr == "Bob"));
+}
+
 #[test]
 fn test_adding(){
     let p: u8 = 0b01100110;
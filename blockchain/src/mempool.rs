@@ -0,0 +1,584 @@
+//! Transactions waiting to be packed into a block.
+//!
+//! Transactions may spend the outputs of others in the pool. A transaction conflicting
+//! with the pool replaces the ones it conflicts with if they signal it (BIP125) and it pays
+//! more: a higher fee than all the transactions it evicts together, descendants included,
+//! by at least `MIN_RELAY_FEE_RATE` for its own size, and a higher fee rate than each of the
+//! conflicts.
+//!
+//! Blocks are filled by ancestor package: the fee rate of a transaction counts its parents
+//! still in the pool, so a child paying a high fee pulls in its parent (CPFP).
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::block::Block;
+use crate::error::ChainError;
+use crate::events::{ChainEvent, EventBus, EvictReason};
+use crate::transaction::{CoinValue, OutPoint};
+use crate::utxo::UtxoEntry;
+use crate::{BlockChain, SimpleTx, SimpleValue};
+
+/// Most transactions a replacement may evict, descendants included.
+pub const MAX_REPLACEMENT_EVICTIONS: usize = 100;
+/// Fee rate a replacement pays for its own relay on top of the fees it evicts,
+/// in satoshis per 1000 virtual bytes.
+pub const MIN_RELAY_FEE_RATE: u64 = 1000;
+
+#[derive(Clone, Debug, Default)]
+pub struct Mempool{
+    txs: HashMap<[u8; 32], SimpleTx>,
+    // outpoint -> txid of the spending transaction
+    spent: HashMap<OutPoint, [u8; 32]>,
+    events: Option<EventBus>,
+}
+
+/// Fee of a transaction whose inputs were checked, 0 if they were not.
+fn fee_of(tx: &SimpleTx) -> u64{
+    tx.value_in()
+        .and_then(|v| v.checked_sub(&tx.value_out()?))
+        .map_or(0, |fee| fee.val)
+}
+
+/// A transaction and its ancestors not chosen yet in `select`,
+/// ordered by fee rate then by lowest txid so that the order does not depend on the map.
+#[derive(PartialEq, Eq)]
+struct Candidate{
+    fee: u64,
+    vsize: usize,
+    txid: [u8; 32],
+}
+
+impl Ord for Candidate{
+    fn cmp(&self, other: &Candidate) -> Ordering{
+        (self.fee as u128 * other.vsize as u128).cmp(&(other.fee as u128 * self.vsize as u128))
+            .then_with(|| other.txid.cmp(&self.txid))
+    }
+}
+
+impl PartialOrd for Candidate{
+    fn partial_cmp(&self, other: &Candidate) -> Option<Ordering>{
+        Some(self.cmp(other))
+    }
+}
+
+impl Mempool{
+    pub fn new() -> Mempool{
+        Mempool::default()
+    }
+
+    /// A mempool publishing accepted and evicted transactions to `events`,
+    /// usually the bus of the chain.
+    pub fn with_events(events: EventBus) -> Mempool{
+        Mempool{ events: Some(events), ..Mempool::default() }
+    }
+
+    fn publish(&self, event: ChainEvent){
+        if let Some(events) = self.events.as_ref(){
+            events.publish(event);
+        }
+    }
+
+    pub fn len(&self) -> usize{
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.txs.is_empty()
+    }
+
+    pub fn contains(&self, txid: &[u8; 32]) -> bool{
+        self.txs.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &[u8; 32]) -> Option<&SimpleTx>{
+        self.txs.get(txid)
+    }
+
+    pub fn txs(&self) -> impl Iterator<Item=&SimpleTx>{
+        self.txs.values()
+    }
+
+    /// Every transaction of the pool, parents before their children.
+    pub fn sorted_txs(&self) -> Vec<&SimpleTx>{
+        let mut order = Vec::with_capacity(self.txs.len());
+        let mut seen = HashSet::with_capacity(self.txs.len());
+        for txid in self.txs.keys(){
+            self.add_ancestors(txid, &HashSet::new(), &mut seen, &mut order);
+        }
+        order.iter().map(|t| &self.txs[t]).collect()
+    }
+
+    /// Fee paid by a transaction of the pool.
+    pub fn fee(&self, txid: &[u8; 32]) -> Option<u64>{
+        self.txs.get(txid).map(fee_of)
+    }
+
+    /// Unspent output of the chain or of a transaction of the pool, to be spent at `height`.
+    fn coin(&self, chain: &BlockChain, op: &OutPoint, height: usize) -> Option<UtxoEntry<String, SimpleValue>>{
+        if let Some(entry) = chain.utxo().get(op){
+            return Some(entry.clone())
+        }
+        let output = self.txs.get(&op.txid)?.output.0.get(op.index as usize)?;
+        Some(UtxoEntry{ output: output.clone(), height, coinbase: false })
+    }
+
+    /// Transactions of the pool spending the outputs of `txid`.
+    fn children(&self, txid: &[u8; 32]) -> Vec<[u8; 32]>{
+        let n = self.txs.get(txid).map_or(0, |tx| tx.output.0.len());
+        (0..n).filter_map(|i| self.spent.get(&OutPoint::new(*txid, i as u32)).cloned()).collect()
+    }
+
+    /// `txids` and all the transactions of the pool spending their outputs, parents first.
+    fn with_descendants(&self, txids: &[[u8; 32]]) -> Vec<[u8; 32]>{
+        let mut seen: HashSet<[u8; 32]> = txids.iter().cloned().collect();
+        let mut all = txids.to_vec();
+        let mut i = 0;
+        while i < all.len(){
+            for child in self.children(&all[i]){
+                if seen.insert(child){
+                    all.push(child);
+                }
+            }
+            i += 1;
+        }
+        all
+    }
+
+    /// Accept a transaction that could be mined in the next block of `chain`.
+    /// Non-final transactions are rejected, so are the ones still locked by BIP68.
+    /// A conflict that is not a valid replacement is rejected as a double spend.
+    pub fn accept(&mut self, tx: SimpleTx, chain: &BlockChain) -> Result<[u8; 32], ChainError>{
+        let txid = self.insert(tx.clone(), chain)?;
+        self.publish(ChainEvent::TxAccepted{ tx: Arc::new(tx), height: chain.height() });
+        Ok(txid)
+    }
+
+    fn insert(&mut self, tx: SimpleTx, chain: &BlockChain) -> Result<[u8; 32], ChainError>{
+        let txid = tx.txid();
+        if self.contains(&txid){
+            return Err(ChainError::AlreadyInMempool)
+        }
+        if tx.is_coinbase(){
+            return Err(ChainError::UnexpectedCoinbase)
+        }
+        let mut conflicts = Vec::new();
+        for txin in tx.input.0.iter(){
+            if let Some(other) = self.spent.get(&txin.prev_out){
+                if !self.txs[other].signals_replacement(){
+                    return Err(ChainError::DoubleSpend(txin.prev_out))
+                }
+                if !conflicts.contains(other){
+                    conflicts.push(*other);
+                }
+            }
+        }
+        let evicted = self.with_descendants(&conflicts);
+        if let Some(txin) = tx.input.0.iter().find(|txin| evicted.contains(&txin.prev_out.txid)){
+            return Err(ChainError::MissingInput(txin.prev_out))
+        }
+
+        let height = chain.height() + 1;
+        let fee = chain.check_tx(&tx, height, |op| self.coin(chain, op, height))?.val;
+
+        if !conflicts.is_empty(){
+            let double_spend = ChainError::DoubleSpend(tx.input.0.iter()
+                .map(|txin| txin.prev_out)
+                .find(|op| self.spent.contains_key(op))
+                .unwrap());
+            if evicted.len() > MAX_REPLACEMENT_EVICTIONS{
+                return Err(double_spend)
+            }
+            let evicted_fees: u64 = evicted.iter().map(|t| fee_of(&self.txs[t])).sum();
+            // compared as fee * other vsize to avoid rounding
+            let vsize = tx.vsize() as u128;
+            let higher_rate = conflicts.iter().all(|t| {
+                let other = &self.txs[t];
+                fee as u128 * other.vsize() as u128 > fee_of(other) as u128 * vsize
+            });
+            // rule 4: the replacement pays for its own relay
+            let pays_relay = fee.checked_sub(evicted_fees)
+                .is_some_and(|extra| extra as u128 * 1000 >= MIN_RELAY_FEE_RATE as u128 * vsize);
+            if fee <= evicted_fees || !pays_relay || !higher_rate{
+                return Err(double_spend)
+            }
+            for t in evicted{
+                self.remove(&t);
+                self.publish(ChainEvent::TxEvicted{ txid: t, reason: EvictReason::Replaced });
+            }
+        }
+
+        for txin in tx.input.0.iter(){
+            self.spent.insert(txin.prev_out, txid);
+        }
+        self.txs.insert(txid, tx);
+        Ok(txid)
+    }
+
+    /// Add a transaction without checking it against a chain.
+    #[cfg(test)]
+    pub(crate) fn insert_unchecked(&mut self, tx: SimpleTx){
+        let txid = tx.txid();
+        for txin in tx.input.0.iter(){
+            self.spent.insert(txin.prev_out, txid);
+        }
+        self.txs.insert(txid, tx);
+    }
+
+    /// Remove a transaction, returns it if it was in the pool.
+    /// Transactions spending its outputs stay, see `remove_for_block`.
+    pub fn remove(&mut self, txid: &[u8; 32]) -> Option<SimpleTx>{
+        let tx = self.txs.remove(txid)?;
+        for txin in tx.input.0.iter(){
+            self.spent.remove(&txin.prev_out);
+        }
+        Some(tx)
+    }
+
+    /// Evict `txids` and their descendants.
+    fn evict(&mut self, txids: &[[u8; 32]], reason: EvictReason) -> Vec<SimpleTx>{
+        let mut evicted = Vec::new();
+        for txid in self.with_descendants(txids){
+            evicted.extend(self.remove(&txid));
+            self.publish(ChainEvent::TxEvicted{ txid, reason: reason.clone() });
+        }
+        evicted
+    }
+
+    /// Drop transactions mined in `block` and those conflicting with it, with their
+    /// descendants. Returns the conflicts.
+    pub fn remove_for_block(&mut self, block: &Block) -> Vec<SimpleTx>{
+        let mut conflicts = Vec::new();
+        for tx in block.txs(){
+            self.remove(&tx.txid());
+            for txin in tx.input.0.iter(){
+                if let Some(txid) = self.spent.get(&txin.prev_out).cloned(){
+                    conflicts.extend(self.evict(&[txid], EvictReason::Conflict));
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// Update the pool after a reorganization, once the connected blocks were removed with
+    /// `remove_for_block`. The transactions of the `disconnected` blocks, oldest first, return
+    /// to the pool if they are still valid, so that children waiting in the pool keep their
+    /// parents. Transactions that are no longer valid are then evicted with their descendants.
+    pub fn reorganize(&mut self, disconnected: &[Arc<Block>], chain: &BlockChain){
+        for block in disconnected{
+            for tx in block.txs().iter().filter(|tx| !tx.is_coinbase()){
+                // those mined again in the new branch fail as their inputs are spent
+                let _ = self.accept(tx.clone(), chain);
+            }
+        }
+        let height = chain.height() + 1;
+        let invalid: Vec<[u8; 32]> = self.txs.iter()
+            .filter(|(_, tx)| chain.check_tx(tx, height, |op| self.coin(chain, op, height)).is_err())
+            .map(|(txid, _)| *txid)
+            .collect();
+        for txid in invalid{
+            self.evict(&[txid], EvictReason::Reorg);
+        }
+    }
+
+    /// Transactions for a block of at most `max_vsize` virtual bytes, parents before their
+    /// children. The package of a transaction and its ancestors not yet chosen with the highest
+    /// fee rate is added first, packages that do not fit are skipped.
+    /// The packages of the descendants shrink as ancestors are chosen, their fee and size are
+    /// updated then instead of summed again for every pick.
+    pub fn select(&self, max_vsize: usize) -> Vec<SimpleTx>{
+        // fee and size of every candidate package, entries of the heap that differ are stale
+        let mut packages: HashMap<[u8; 32], (u64, usize)> = HashMap::with_capacity(self.txs.len());
+        let mut heap = BinaryHeap::with_capacity(self.txs.len());
+        let mut chosen: HashSet<[u8; 32]> = HashSet::new();
+        for txid in self.txs.keys(){
+            let package = self.package(txid, &chosen);
+            let fee = package.iter().map(|t| fee_of(&self.txs[t])).sum();
+            let vsize = package.iter().map(|t| self.txs[t].vsize()).sum();
+            packages.insert(*txid, (fee, vsize));
+            heap.push(Candidate{ fee, vsize, txid: *txid });
+        }
+
+        let mut selected = Vec::new();
+        let mut size = 0;
+        while let Some(best) = heap.pop(){
+            if packages.get(&best.txid) != Some(&(best.fee, best.vsize)){
+                continue
+            }
+            packages.remove(&best.txid);
+            if size + best.vsize > max_vsize{
+                continue
+            }
+            size += best.vsize;
+            for t in self.package(&best.txid, &chosen){
+                let (fee, vsize) = (fee_of(&self.txs[&t]), self.txs[&t].vsize());
+                for d in self.with_descendants(&[t]).into_iter().skip(1){
+                    if let Some(p) = packages.get_mut(&d){
+                        p.0 -= fee;
+                        p.1 -= vsize;
+                        heap.push(Candidate{ fee: p.0, vsize: p.1, txid: d });
+                    }
+                }
+                packages.remove(&t);
+                chosen.insert(t);
+                selected.push(self.txs[&t].clone());
+            }
+        }
+        selected
+    }
+
+    /// `txid` after its ancestors in the pool that are not in `chosen`, parents first.
+    fn package(&self, txid: &[u8; 32], chosen: &HashSet<[u8; 32]>) -> Vec<[u8; 32]>{
+        let mut package = Vec::new();
+        self.add_ancestors(txid, chosen, &mut HashSet::new(), &mut package);
+        package
+    }
+
+    fn add_ancestors(&self, txid: &[u8; 32], chosen: &HashSet<[u8; 32]>, seen: &mut HashSet<[u8; 32]>, package: &mut Vec<[u8; 32]>){
+        if chosen.contains(txid) || !seen.insert(*txid){
+            return
+        }
+        for txin in self.txs[txid].input.0.iter(){
+            if self.txs.contains_key(&txin.prev_out.txid){
+                self.add_ancestors(&txin.prev_out.txid, chosen, seen, package);
+            }
+        }
+        package.push(*txid);
+    }
+}
+
+#[cfg(test)]
+mod mempool_test{
+    use super::*;
+    use std::sync::Arc;
+    use crate::clock::MockClock;
+    use crate::transaction::*;
+
+    #[test]
+    fn test_reject_non_final() {
+        let g = crate::ChainParams::regtest().genesis_timestamp;
+        let mut chain = crate::test_chain(Arc::new(MockClock::new(g + 1_000_000)));
+        let cb = SimpleTx::coinbase(1, 50.into(), "Alice".to_string());
+        let cb_id = cb.txid();
+        chain.add_block(crate::mine_block(&chain, g + 100, vec![cb])).unwrap();
+
+        let spend = |lock_time, sequence| SimpleTx{
+            input: InputTx(vec![
+                TxIn::new(OutPoint::new(cb_id, 0), "Alice".to_string(), 50.into())
+                    .with_sequence(sequence)
+            ]),
+            output: OutputTx(vec![Trans{addr: "Bob".to_string(), val: 50.into()}]),
+            lock_time,
+            witness: vec![],
+        };
+
+        let mut pool = Mempool::new();
+        // the next block is at height 2
+        assert_eq!(Err(ChainError::NonFinal), pool.accept(spend(2, 0), &chain));
+        assert_eq!(Err(ChainError::SequenceLocked), pool.accept(spend(0, 2), &chain));
+
+        let txid = pool.accept(spend(1, 0), &chain).unwrap();
+        assert!(pool.contains(&txid));
+        assert_eq!(Err(ChainError::AlreadyInMempool), pool.accept(spend(1, 0), &chain));
+        assert_eq!(
+            Err(ChainError::DoubleSpend(OutPoint::new(cb_id, 0))),
+            pool.accept(spend(0, 1), &chain)
+        );
+
+        let block = chain.create_block(g + 200, "Alice".to_string(), vec![spend(0, 1)]).unwrap();
+        chain.add_block(block.clone()).unwrap();
+        pool.remove_for_block(&block);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_reorganize() {
+        let g = crate::ChainParams::regtest().genesis_timestamp;
+        let mut chain = crate::test_chain(Arc::new(MockClock::new(g + 1_000_000)));
+        let mut cb = SimpleTx::coinbase(1, 50.into(), "Alice".to_string());
+        cb.output.0.push(Trans{addr: "Alice".to_string(), val: 50.into()});
+        let cb_id = cb.txid();
+        chain.add_block(crate::mine_block(&chain, g + 100, vec![cb])).unwrap();
+        let mut fork = chain.clone();
+
+        let mut pool = Mempool::with_events(chain.events().clone());
+        let events = chain.subscribe();
+        let pay = |prev_out, val: u64, to: &str| SimpleTx{
+            input: InputTx(vec![TxIn::new(prev_out, "Alice".to_string(), val.into())]),
+            output: OutputTx(vec![Trans{addr: to.to_string(), val: val.into()}]),
+            lock_time: 0,
+            witness: vec![],
+        };
+        let to_bob = pay(OutPoint::new(cb_id, 0), 50, "Bob");
+        let b2 = crate::mine_block(&chain, g + 200, vec![SimpleTx::coinbase(2, 0.into(), "Alice".to_string()), to_bob.clone()]);
+        chain.add_block(b2.clone()).unwrap();
+        pool.remove_for_block(&b2);
+        // spends the output of a mined transaction
+        let mut from_bob = pay(OutPoint::new(to_bob.txid(), 0), 50, "Carol");
+        from_bob.input.0[0].addr = "Bob".to_string();
+        let to_dave = pay(OutPoint::new(cb_id, 1), 50, "Dave");
+        pool.accept(from_bob.clone(), &chain).unwrap();
+        pool.accept(to_dave.clone(), &chain).unwrap();
+
+        // a longer branch spends the coin of Dave's transaction to Erin
+        let f2 = crate::mine_block(&fork, g + 200, vec![
+            SimpleTx::coinbase(2, 0.into(), "Erin".to_string()),
+            pay(OutPoint::new(cb_id, 1), 50, "Erin"),
+        ]);
+        fork.add_block(f2.clone()).unwrap();
+        let f3 = crate::mine_block(&fork, g + 300, vec![SimpleTx::coinbase(3, 0.into(), "Erin".to_string())]);
+        chain.add_block(f2.clone()).unwrap();
+        chain.add_block(f3.clone()).unwrap();
+        events.try_iter().count();
+
+        pool.remove_for_block(&f2);
+        pool.remove_for_block(&f3);
+        pool.reorganize(&[Arc::new(b2)], &chain);
+        // the child of the disconnected transaction stays
+        assert_eq!(2, pool.len());
+        assert!(pool.contains(&to_bob.txid()));
+        assert!(pool.contains(&from_bob.txid()));
+
+        let evicted: Vec<_> = events.try_iter().filter_map(|e| match e{
+            ChainEvent::TxEvicted{ txid, reason } => Some((txid, reason)),
+            ChainEvent::TxAccepted{ tx, .. } => {
+                assert_eq!(to_bob.txid(), tx.txid());
+                None
+            },
+            _ => None,
+        }).collect();
+        assert_eq!(vec![(to_dave.txid(), EvictReason::Conflict)], evicted);
+    }
+
+    /// A chain whose coinbase pays 100000 to Alice `n` times.
+    fn setup(n: usize) -> (BlockChain, [u8; 32]){
+        let g = crate::ChainParams::regtest().genesis_timestamp;
+        let mut chain = crate::test_chain(Arc::new(MockClock::new(g + 1_000_000)));
+        let mut cb = SimpleTx::coinbase(1, 0.into(), "Alice".to_string());
+        cb.output.0 = (0..n).map(|_| Trans{addr: "Alice".to_string(), val: 100_000.into()}).collect();
+        let cb_id = cb.txid();
+        chain.add_block(crate::mine_block(&chain, g + 100, vec![cb])).unwrap();
+        (chain, cb_id)
+    }
+
+    fn pay(prev_out: OutPoint, from: &str, val_in: u64, val_out: u64, to: &str, sequence: u32) -> SimpleTx{
+        SimpleTx{
+            input: InputTx(vec![TxIn::new(prev_out, from.to_string(), val_in.into()).with_sequence(sequence)]),
+            output: OutputTx(vec![Trans{addr: to.to_string(), val: val_out.into()}]),
+            lock_time: 0,
+            witness: vec![],
+        }
+    }
+
+    #[test]
+    fn test_replace_by_fee() {
+        let (chain, cb_id) = setup(4);
+        let mut pool = Mempool::with_events(chain.events().clone());
+        let events = chain.subscribe();
+        let rbf = MAX_BIP125_RBF_SEQUENCE;
+
+        // no signal
+        let op = OutPoint::new(cb_id, 0);
+        pool.accept(pay(op, "Alice", 100_000, 90_000, "Bob", SEQUENCE_FINAL), &chain).unwrap();
+        assert_eq!(Err(ChainError::DoubleSpend(op)), pool.accept(pay(op, "Alice", 100_000, 50_000, "Carol", rbf), &chain));
+
+        let op = OutPoint::new(cb_id, 1);
+        let first = pay(op, "Alice", 100_000, 99_000, "Bob", rbf);
+        pool.accept(first.clone(), &chain).unwrap();
+        let child = pay(OutPoint::new(first.txid(), 0), "Bob", 99_000, 98_000, "Dave", SEQUENCE_FINAL);
+        pool.accept(child.clone(), &chain).unwrap();
+        assert_eq!(Some(1000), pool.fee(&child.txid()));
+        // more than the first transaction but not more than it and its child
+        assert_eq!(Err(ChainError::DoubleSpend(op)), pool.accept(pay(op, "Alice", 100_000, 98_000, "Carol", rbf), &chain));
+
+        let second = pay(op, "Alice", 100_000, 97_500, "Carol", rbf);
+        pool.accept(second.clone(), &chain).unwrap();
+        assert!(!pool.contains(&first.txid()) && !pool.contains(&child.txid()));
+        let evicted: Vec<_> = events.try_iter().filter_map(|e| match e{
+            ChainEvent::TxEvicted{ txid, reason } => Some((txid, reason)),
+            _ => None,
+        }).collect();
+        assert_eq!(vec![(first.txid(), EvictReason::Replaced), (child.txid(), EvictReason::Replaced)], evicted);
+
+        // a higher fee at a lower fee rate
+        let mut large = pay(op, "Alice", 100_000, 97_000, "Carol", rbf);
+        large.output.0.extend((0..20).map(|_| Trans{addr: "Carol".to_string(), val: 0.into()}));
+        assert!(3000 * second.vsize() < 2500 * large.vsize());
+        assert_eq!(Err(ChainError::DoubleSpend(op)), pool.accept(large, &chain));
+
+        // too many descendants to evict
+        let op = OutPoint::new(cb_id, 2);
+        let mut prev = pay(op, "Alice", 100_000, 99_990, "Bob", rbf);
+        pool.accept(prev.clone(), &chain).unwrap();
+        for _ in 0..MAX_REPLACEMENT_EVICTIONS{
+            let val = prev.output.0[0].val.val;
+            let next = pay(OutPoint::new(prev.txid(), 0), "Bob", val, val - 10, "Bob", SEQUENCE_FINAL);
+            pool.accept(next.clone(), &chain).unwrap();
+            prev = next;
+        }
+        assert_eq!(Err(ChainError::DoubleSpend(op)), pool.accept(pay(op, "Alice", 100_000, 0, "Carol", rbf), &chain));
+
+        // the replacement pays for its own size on top of the evicted fee
+        let op = OutPoint::new(cb_id, 3);
+        let first = pay(op, "Alice", 100_000, 99_000, "Bob", rbf);
+        pool.accept(first.clone(), &chain).unwrap();
+        let relay = MIN_RELAY_FEE_RATE * first.vsize() as u64 / 1000;
+        assert!(relay > 1);
+        let cheap = pay(op, "Alice", 100_000, 99_000 - relay + 1, "Dan", rbf);
+        assert_eq!(first.vsize(), cheap.vsize());
+        assert_eq!(Err(ChainError::DoubleSpend(op)), pool.accept(cheap, &chain));
+        pool.accept(pay(op, "Alice", 100_000, 99_000 - relay, "Dan", rbf), &chain).unwrap();
+        assert!(!pool.contains(&first.txid()));
+    }
+
+    #[test]
+    fn test_child_pays_for_parent() {
+        let (mut chain, cb_id) = setup(2);
+        let mut pool = Mempool::new();
+        let parent = pay(OutPoint::new(cb_id, 0), "Alice", 100_000, 99_900, "Bob", SEQUENCE_FINAL);
+        let other = pay(OutPoint::new(cb_id, 1), "Alice", 100_000, 99_000, "Carol", SEQUENCE_FINAL);
+        let child = pay(OutPoint::new(parent.txid(), 0), "Bob", 99_900, 89_900, "Dave", SEQUENCE_FINAL);
+        assert_eq!(Err(ChainError::MissingInput(child.input.0[0].prev_out)), pool.accept(child.clone(), &chain));
+        for tx in [&parent, &other, &child].iter(){
+            pool.accept((*tx).clone(), &chain).unwrap();
+        }
+
+        let txids = |txs: Vec<SimpleTx>| txs.iter().map(|tx| tx.txid()).collect::<Vec<_>>();
+        // the child lifts its parent above the other transaction
+        let selected = pool.select(usize::MAX);
+        assert_eq!(vec![parent.txid(), child.txid(), other.txid()], txids(selected.clone()));
+        let sorted: Vec<_> = pool.sorted_txs().into_iter().cloned().collect();
+        assert_eq!(3, sorted.len());
+        assert!(txids(sorted.clone()).iter().position(|t| *t == parent.txid()) < txids(sorted).iter().position(|t| *t == child.txid()));
+        // the package does not fit
+        let max = parent.vsize() + child.vsize() - 1;
+        assert_eq!(other.txid(), txids(pool.select(max))[0]);
+        assert!(!txids(pool.select(max)).contains(&child.txid()));
+
+        let g = chain.params().genesis_timestamp;
+        let block = chain.create_block(g + 200, "Miner".to_string(), selected).unwrap();
+        chain.add_block(block.clone()).unwrap();
+        pool.remove_for_block(&block);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_package_updates() {
+        let (chain, cb_id) = setup(2);
+        let mut pool = Mempool::new();
+        let mut parent = pay(OutPoint::new(cb_id, 0), "Alice", 100_000, 99_900, "Bob", SEQUENCE_FINAL);
+        parent.output.0 = (0..2).map(|_| Trans{addr: "Bob".to_string(), val: 49_950.into()}).collect();
+        let rich = pay(OutPoint::new(parent.txid(), 0), "Bob", 49_950, 39_950, "Carol", SEQUENCE_FINAL);
+        let poor = pay(OutPoint::new(parent.txid(), 1), "Bob", 49_950, 46_950, "Carol", SEQUENCE_FINAL);
+        let other = pay(OutPoint::new(cb_id, 1), "Alice", 100_000, 98_000, "Carol", SEQUENCE_FINAL);
+        for tx in [&parent, &rich, &poor, &other].iter(){
+            pool.accept((*tx).clone(), &chain).unwrap();
+        }
+        // with its parent the poor child pays less than the other transaction, alone it pays more
+        assert!(3100 * other.vsize() < 2000 * (parent.vsize() + poor.vsize()));
+        assert!(3000 * other.vsize() > 2000 * poor.vsize());
+        let txids: Vec<_> = pool.select(usize::MAX).iter().map(|tx| tx.txid()).collect();
+        assert_eq!(vec![parent.txid(), rich.txid(), poor.txid(), other.txid()], txids);
+    }
+}
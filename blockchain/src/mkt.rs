@@ -1,10 +1,10 @@
 use std::hash::Hasher;
 use merkletree::hash::{Algorithm, Hashable};
 use merkletree::merkle::{
-    //MerkleTree,
+    MerkleTree,
     Element,
 };
-//use merkletree::store::VecStore;
+use merkletree::store::VecStore;
 use sha2::Sha256;
 
 use digest::{Input, Reset, FixedOutput};
@@ -91,3 +91,41 @@ impl Algorithm<HashVal> for HashAlgorithm {
         self.0.reset();
     }
 }
+
+/// Merkle tree over the transaction hashes of a block.
+pub type BlockTree = MerkleTree<HashVal, HashAlgorithm, VecStore<HashVal>>;
+
+/// SHA-256 of `data`.
+pub fn sha256(data: &[u8]) -> [u8; 32]{
+    let mut sha = Sha256::default();
+    let mut h = [0; 32];
+    sha.input(data);
+    h.copy_from_slice(&sha.fixed_result());
+    h
+}
+
+/// SHA-256 applied twice, used for block hashes.
+pub fn sha256d(data: &[u8]) -> [u8; 32]{
+    sha256(&sha256(data))
+}
+
+/// Hash of a leaf of a `BlockTree`, the leaves are hashed again when the tree is built.
+pub fn hash_leaf(leaf: &HashVal) -> HashVal{
+    HashAlgorithm::new().leaf(leaf.clone())
+}
+
+/// Hash of an inner node of a `BlockTree`.
+pub fn hash_node(left: &HashVal, right: &HashVal) -> HashVal{
+    HashAlgorithm::new().node(left.clone(), right.clone(), 0)
+}
+
+/// Build a merkle tree from the hashes of transactions.
+/// `merkletree` only accepts a power of two leaves (at least 2), so the last leaf is repeated.
+/// Hence repeating the last leaf up to the next power of two keeps the root (CVE-2012-2459),
+/// see `Block::is_mutated`.
+pub fn build_tree(mut leaves: Vec<HashVal>) -> BlockTree{
+    let last = leaves.last().cloned().unwrap_or_default();
+    let n = leaves.len().max(2).next_power_of_two();
+    leaves.resize(n, last);
+    MerkleTree::new(leaves).unwrap()
+}
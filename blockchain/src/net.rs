@@ -0,0 +1,396 @@
+//! In-process network of fully connected peers.
+//!
+//! Messages go through a queue and are delivered by `run()`. Every message is serialized
+//! when it is sent, so the network can tell how many bytes a relay strategy costs.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::block::{Block, BlockTx};
+use crate::compact::{BlockTxn, CompactBlock, CompactError, GetBlockTxn, PartialBlock};
+use crate::mempool::Mempool;
+use crate::{BlockChain, ChainError, SimpleTx};
+
+/// How new blocks are announced to peers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Relay{
+    Full,
+    Compact,
+}
+
+/// Messages of peers whose blocks carry `T`, `SimpleTx` of the UTXO model by default.
+#[derive(Clone, Debug)]
+pub enum Message<T = SimpleTx>{
+    Tx(T),
+    Block(Block<T>),
+    CmpctBlock(CompactBlock<T>),
+    GetBlockTxn(GetBlockTxn),
+    BlockTxn(BlockTxn<T>),
+    /// Ask for a full block, e.g. after short ids collided.
+    GetBlock([u8; 32]),
+}
+
+impl<T: BlockTx> Message<T>{
+    pub fn command(&self) -> &'static str{
+        match self{
+            Message::Tx(_) => "tx",
+            Message::Block(_) => "block",
+            Message::CmpctBlock(_) => "cmpctblock",
+            Message::GetBlockTxn(_) => "getblocktxn",
+            Message::BlockTxn(_) => "blocktxn",
+            Message::GetBlock(_) => "getblock",
+        }
+    }
+
+    /// Payload on the wire.
+    pub fn to_bytes(&self) -> Vec<u8>{
+        match self{
+            Message::Tx(tx) => {
+                let mut buf = Vec::new();
+                tx.encode_into(&mut buf);
+                buf
+            },
+            Message::Block(b) => b.to_bytes(),
+            Message::CmpctBlock(c) => c.to_bytes(),
+            Message::GetBlockTxn(r) => r.to_bytes(),
+            Message::BlockTxn(r) => r.to_bytes(),
+            Message::GetBlock(h) => h.to_vec(),
+        }
+    }
+}
+
+/// Size of the header in front of every payload: magic, command, length and checksum.
+pub const MESSAGE_HEADER_LEN: usize = 24;
+
+/// Service bit of a peer serving every block of its chain.
+pub const NODE_NETWORK: u64 = 1;
+/// Service bit of a peer serving at least the last `NETWORK_LIMITED_BLOCKS` blocks (BIP159).
+pub const NODE_NETWORK_LIMITED: u64 = 1 << 10;
+pub const NETWORK_LIMITED_BLOCKS: usize = 288;
+
+/// Services to advertise for `chain`, a pruned chain does not serve old blocks.
+pub fn services(chain: &BlockChain) -> u64{
+    let kept = chain.height() + 1 - chain.prune_height();
+    if chain.prune_height() == 0{
+        NODE_NETWORK | NODE_NETWORK_LIMITED
+    }else if kept >= NETWORK_LIMITED_BLOCKS{
+        NODE_NETWORK_LIMITED
+    }else{
+        0
+    }
+}
+
+pub struct Peer{
+    pub chain: BlockChain,
+    pub mempool: Mempool,
+    // compact blocks waiting for transactions
+    partial: HashMap<[u8; 32], PartialBlock>,
+}
+
+impl Peer{
+    pub fn new(chain: BlockChain) -> Peer{
+        Peer{ chain, mempool: Mempool::new(), partial: HashMap::new() }
+    }
+
+    pub fn services(&self) -> u64{
+        services(&self.chain)
+    }
+}
+
+pub struct LocalNet{
+    peers: Vec<Peer>,
+    relay: Relay,
+    // (from, to, message)
+    queue: VecDeque<(usize, usize, Message)>,
+    bytes_sent: usize,
+    sent: HashMap<&'static str, usize>,
+    nonce: u64,
+}
+
+impl LocalNet{
+    pub fn new(chains: Vec<BlockChain>, relay: Relay) -> LocalNet{
+        LocalNet{
+            peers: chains.into_iter().map(Peer::new).collect(),
+            relay,
+            queue: VecDeque::new(),
+            bytes_sent: 0,
+            sent: HashMap::new(),
+            nonce: 0,
+        }
+    }
+
+    pub fn peer(&self, i: usize) -> &Peer{
+        &self.peers[i]
+    }
+
+    pub fn peer_mut(&mut self, i: usize) -> &mut Peer{
+        &mut self.peers[i]
+    }
+
+    /// Bytes of all messages sent so far, headers included.
+    pub fn bytes_sent(&self) -> usize{
+        self.bytes_sent
+    }
+
+    /// Number of messages sent with `command`.
+    pub fn sent(&self, command: &str) -> usize{
+        self.sent.get(command).cloned().unwrap_or(0)
+    }
+
+    pub fn reset_counters(&mut self){
+        self.bytes_sent = 0;
+        self.sent.clear();
+    }
+
+    /// Accept a transaction at peer `from` and relay it.
+    pub fn submit_tx(&mut self, from: usize, tx: SimpleTx) -> Result<[u8; 32], ChainError>{
+        let peer = &mut self.peers[from];
+        let txid = peer.mempool.accept(tx.clone(), &peer.chain)?;
+        self.broadcast(from, None, Message::Tx(tx));
+        Ok(txid)
+    }
+
+    /// Connect a block mined by peer `from` and announce it.
+    pub fn submit_block(&mut self, from: usize, block: Block) -> Result<(), ChainError>{
+        self.connect(from, None, block)
+    }
+
+    /// Deliver messages until the network is quiet.
+    pub fn run(&mut self){
+        while let Some((from, to, msg)) = self.queue.pop_front(){
+            self.handle(from, to, msg);
+        }
+    }
+
+    fn send(&mut self, from: usize, to: usize, msg: Message){
+        self.bytes_sent += MESSAGE_HEADER_LEN + msg.to_bytes().len();
+        *self.sent.entry(msg.command()).or_insert(0) += 1;
+        self.queue.push_back((from, to, msg));
+    }
+
+    fn broadcast(&mut self, from: usize, except: Option<usize>, msg: Message){
+        for to in 0..self.peers.len(){
+            if to != from && Some(to) != except{
+                self.send(from, to, msg.clone());
+            }
+        }
+    }
+
+    fn announce(&mut self, from: usize, except: Option<usize>, block: &Block){
+        let msg = match self.relay{
+            Relay::Full => Message::Block(block.clone()),
+            Relay::Compact => {
+                self.nonce += 1;
+                Message::CmpctBlock(CompactBlock::from_block(block, self.nonce))
+            },
+        };
+        self.broadcast(from, except, msg);
+    }
+
+    fn knows_block(&self, peer: usize, hash: &[u8; 32]) -> bool{
+        self.peers[peer].chain.height_of(hash).is_some()
+    }
+
+    fn connect(&mut self, at: usize, source: Option<usize>, block: Block) -> Result<(), ChainError>{
+        let peer = &mut self.peers[at];
+        peer.chain.add_block(block.clone())?;
+        peer.mempool.remove_for_block(&block);
+        self.announce(at, source, &block);
+        Ok(())
+    }
+
+    fn handle(&mut self, from: usize, to: usize, msg: Message){
+        match msg{
+            Message::Tx(tx) => {
+                let peer = &mut self.peers[to];
+                if peer.mempool.accept(tx.clone(), &peer.chain).is_ok(){
+                    self.broadcast(to, Some(from), Message::Tx(tx));
+                }
+            },
+            Message::Block(block) => {
+                if !self.knows_block(to, &block.hash()){
+                    let _ = self.connect(to, Some(from), block);
+                }
+            },
+            Message::CmpctBlock(cmpct) => {
+                let hash = cmpct.hash();
+                if self.knows_block(to, &hash) || self.peers[to].partial.contains_key(&hash){
+                    return
+                }
+                let partial = match PartialBlock::new(&cmpct, self.peers[to].mempool.txs()){
+                    Ok(p) => p,
+                    // the sender still has the full block
+                    Err(_) => return self.send(to, from, Message::GetBlock(hash)),
+                };
+                if partial.missing().is_empty(){
+                    self.finish(from, to, partial.into_block(), hash);
+                }else{
+                    let req = partial.request();
+                    self.peers[to].partial.insert(hash, partial);
+                    self.send(to, from, Message::GetBlockTxn(req));
+                }
+            },
+            Message::GetBlockTxn(req) => {
+                let peer = &self.peers[to];
+                let resp = peer.chain.height_of(&req.block_hash)
+                    .and_then(|h| peer.chain.block(h))
+                    .and_then(|b| req.answer(b));
+                if let Some(resp) = resp{
+                    self.send(to, from, Message::BlockTxn(resp));
+                }
+            },
+            Message::BlockTxn(resp) => {
+                let hash = resp.block_hash;
+                if let Some(partial) = self.peers[to].partial.remove(&hash){
+                    self.finish(from, to, partial.fill(resp), hash);
+                }
+            },
+            Message::GetBlock(hash) => {
+                let peer = &self.peers[to];
+                if let Some(block) = peer.chain.height_of(&hash).and_then(|h| peer.chain.block(h)){
+                    self.send(to, from, Message::Block(block.clone()));
+                }
+            },
+        }
+    }
+
+    fn finish(&mut self, from: usize, to: usize, rebuilt: Result<Block, CompactError>, hash: [u8; 32]){
+        match rebuilt{
+            Ok(block) => { let _ = self.connect(to, Some(from), block); },
+            // fall back to the full block
+            Err(_) => self.send(to, from, Message::GetBlock(hash)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod net_test{
+    use super::*;
+    use std::sync::Arc;
+    use crate::clock::MockClock;
+    use crate::transaction::*;
+    use crate::{ChainParams, COIN};
+
+    const PEERS: usize = 4;
+    const TXS: u32 = 20;
+
+    /// Peers sharing a chain with `TXS` coins of Alice, and the transactions spending them.
+    fn setup(relay: Relay) -> (LocalNet, Vec<SimpleTx>){
+        let g = ChainParams::regtest().genesis_timestamp;
+        let mut chain = crate::test_chain(Arc::new(MockClock::new(g + 1_000_000)));
+        let mut cb = SimpleTx::coinbase(1, COIN.into(), "Alice".to_string());
+        cb.output.0 = (0..TXS).map(|_| Trans{addr: "Alice".to_string(), val: COIN.into()}).collect();
+        let cb_id = cb.txid();
+        chain.add_block(crate::mine_block(&chain, g + 100, vec![cb])).unwrap();
+
+        let txs = (0..TXS).map(|i| SimpleTx{
+            input: InputTx(vec![TxIn::new(OutPoint::new(cb_id, i), "Alice".to_string(), COIN.into())]),
+            output: OutputTx(vec![Trans{addr: format!("Bob{}", i), val: (COIN - 1000).into()}]),
+            lock_time: 0,
+            witness: vec![],
+        }).collect();
+        (LocalNet::new(vec![chain; PEERS], relay), txs)
+    }
+
+    /// Relay the transactions, then a block including them. Returns the bytes spent on the block.
+    fn relay_block(net: &mut LocalNet, txs: Vec<SimpleTx>) -> usize{
+        let n = txs.len();
+        for tx in txs{
+            net.submit_tx(0, tx).unwrap();
+        }
+        net.run();
+        assert!((0..PEERS).all(|i| net.peer(i).mempool.len() == n));
+
+        net.reset_counters();
+        let chain = &net.peer(0).chain;
+        let txs = net.peer(0).mempool.txs().cloned().collect();
+        let block = chain.create_block(chain.tip().header().timestamp() + 100, "Miner".to_string(), txs).unwrap();
+        let hash = block.hash();
+        net.submit_block(0, block).unwrap();
+        net.run();
+        for i in 0..PEERS{
+            assert_eq!(hash, net.peer(i).chain.tip().hash());
+            assert!(net.peer(i).mempool.is_empty());
+        }
+        net.bytes_sent()
+    }
+
+    #[test]
+    fn test_compact_relay_bandwidth() {
+        let (mut net, txs) = setup(Relay::Full);
+        let full = relay_block(&mut net, txs);
+
+        let (mut net, txs) = setup(Relay::Compact);
+        let compact = relay_block(&mut net, txs);
+        assert_eq!(0, net.sent("getblocktxn"));
+        assert_eq!(0, net.sent("block"));
+        assert!(compact * 4 < full);
+    }
+
+    #[test]
+    fn test_request_missing_txs() {
+        let (mut net, mut txs) = setup(Relay::Compact);
+        // only the miner hears of the last transaction
+        let last = txs.pop().unwrap();
+        relay_block(&mut net, txs);
+
+        let peer = net.peer_mut(0);
+        peer.mempool.accept(last.clone(), &peer.chain).unwrap();
+        let chain = &net.peer(0).chain;
+        let block = chain.create_block(chain.tip().header().timestamp() + 100, "Miner".to_string(), vec![last]).unwrap();
+        let hash = block.hash();
+        net.reset_counters();
+        net.submit_block(0, block).unwrap();
+        net.run();
+
+        assert!((0..PEERS).all(|i| net.peer(i).chain.tip().hash() == hash));
+        // every peer but the miner lacked it
+        assert_eq!(PEERS - 1, net.sent("getblocktxn"));
+        assert_eq!(PEERS - 1, net.sent("blocktxn"));
+        assert_eq!(0, net.sent("block"));
+    }
+
+    #[test]
+    fn test_bad_compact_block() {
+        let (mut net, txs) = setup(Relay::Compact);
+        let chain = &net.peer(0).chain;
+        let block = chain.create_block(chain.tip().header().timestamp() + 100, "Miner".to_string(), txs).unwrap();
+        let hash = block.hash();
+        net.peer_mut(0).chain.add_block(block.clone()).unwrap();
+
+        // the coinbase is prefilled past the end of the block
+        let mut cmpct = CompactBlock::from_block(&block, 1);
+        cmpct.prefilled[0].index = cmpct.tx_count();
+        net.send(0, 1, Message::CmpctBlock(cmpct));
+        net.run();
+
+        assert_eq!(1, net.sent("getblock"));
+        assert_eq!(1, net.sent("block"));
+        assert!((0..PEERS).all(|i| net.peer(i).chain.tip().hash() == hash));
+    }
+
+    #[test]
+    fn test_account_messages() {
+        use crate::account::{self, AccountChain, AccountTx, Transfer};
+        use crate::script::SigningKey;
+
+        let alice = SigningKey::from_seed(&[1; 32]);
+        let params = ChainParams::regtest();
+        let g = params.genesis_timestamp;
+        let clock = Arc::new(MockClock::new(g + 1_000_000));
+        let chain = AccountChain::with_balances(params, clock, vec![(account::address(&alice.public_key()), 1000)]).unwrap();
+        let transfers: Vec<Transfer> = (0..3).map(|n| Transfer::new(&alice, "Bob", 10, 1, n)).collect();
+        let block = chain.create_block(g + 100, "Miner".to_string(), transfers).unwrap();
+
+        let msg = Message::Block(block.clone());
+        assert_eq!(Some(block.hash()), Block::<AccountTx>::from_bytes(&msg.to_bytes()).map(|b| b.hash()));
+
+        // a receiver knowing two of the transfers asks for the third
+        let cmpct = CompactBlock::from_block(&block, 1);
+        let msg = Message::CmpctBlock(cmpct.clone());
+        assert_eq!(Some(block.hash()), CompactBlock::<AccountTx>::from_bytes(&msg.to_bytes()).map(|c| c.hash()));
+        let partial = PartialBlock::new(&cmpct, block.txs()[1..3].iter()).unwrap();
+        assert_eq!(vec![3], partial.missing());
+        let resp = partial.request().answer(&block).unwrap();
+        assert_eq!(block.hash(), partial.fill(resp).unwrap().hash());
+    }
+}
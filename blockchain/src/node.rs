@@ -0,0 +1,699 @@
+//! A node keeping the chain and the mempool in a data directory.
+//!
+//! Layout of the data directory:
+//! - `network`: name of the network, written by `init`
+//! - `blocks/<height>.blk`: every block after genesis, as `Block::to_bytes`
+//! - `mempool.dat`: length-prefixed transactions waiting for a block
+//! - `fee_estimates.dat`: state of the fee estimator
+//!
+//! A pruned node, created by `init_pruned`, only keeps the files of the last blocks and adds:
+//! - `prune`: number of blocks to keep
+//! - `blocks/<height>.rev`: outputs spent by a kept block, to disconnect it in a reorganization
+//! - `headers/<height>.dat`: headers of the blocks pruned at once from `height` on
+//! - `chainstate.dat`: hash of a kept block then the UTXO set after it
+//!
+//! A pruned node saves the UTXO set every `prune` blocks, or when the block it was saved at
+//! is disconnected, and drops the files of the pruned blocks at that point. Block, undo, header
+//! and chainstate files are written through a renamed temporary file, so a crash leaves the old
+//! or the new content.
+//!
+//! Blocks are validated again when the directory is opened, a pruned node restores its state
+//! from these files instead and only validates the blocks above the saved UTXO set again.
+//! The mempool and the fee estimator follow the chain through its events.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+
+use crate::clock::{Clock, NetworkClock, SystemClock};
+use crate::encode::{self, Reader};
+use crate::events::ChainEvent;
+use crate::fees::{FeeEstimate, FeeEstimator};
+use crate::mempool::Mempool;
+use crate::net;
+use crate::params::{ChainParams, Network};
+use crate::transaction::{CoinValue, InputTx, OutPoint, OutputTx, Trans, TxIn};
+use crate::utxo::{UtxoEntry, UtxoSet};
+use crate::{Block, BlockChain, BlockHeader, BlockUndo, ChainError, SimpleTx, SimpleValue};
+
+#[derive(Debug)]
+pub enum NodeError{
+    Io(io::Error),
+    Chain(ChainError),
+    /// `init` on a directory that already holds a chain.
+    AlreadyInitialized,
+    /// The network file is missing or names an unknown network.
+    UnknownNetwork(String),
+    /// A data file cannot be decoded.
+    Corrupt(PathBuf),
+    /// Blocks are only mined on demand on regtest.
+    MiningNotAllowed,
+    /// The sender does not own enough mature coins.
+    NotEnoughCoins,
+}
+
+impl fmt::Display for NodeError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        match self{
+            NodeError::Io(e) => write!(f, "{}", e),
+            NodeError::Chain(e) => write!(f, "{}", e),
+            NodeError::AlreadyInitialized => write!(f, "data directory is already initialized"),
+            NodeError::UnknownNetwork(name) => write!(f, "unknown network {:?}", name),
+            NodeError::Corrupt(path) => write!(f, "corrupt data file {}", path.display()),
+            NodeError::MiningNotAllowed => write!(f, "mining on demand is only allowed on regtest"),
+            NodeError::NotEnoughCoins => write!(f, "not enough mature coins"),
+        }
+    }
+}
+
+impl std::error::Error for NodeError{}
+
+impl From<io::Error> for NodeError{
+    fn from(e: io::Error) -> NodeError{
+        NodeError::Io(e)
+    }
+}
+
+impl From<ChainError> for NodeError{
+    fn from(e: ChainError) -> NodeError{
+        NodeError::Chain(e)
+    }
+}
+
+pub struct Node{
+    dir: PathBuf,
+    /// Number of blocks kept by a pruned node.
+    prune: Option<usize>,
+    /// Height of the UTXO set in `chainstate.dat` of a pruned node.
+    flushed: usize,
+    /// Lowest height whose block file a pruned node keeps.
+    kept_from: usize,
+    chain: BlockChain,
+    mempool: Mempool,
+    fees: FeeEstimator,
+    events: Receiver<ChainEvent>,
+}
+
+impl Node{
+    /// Create a new chain of `network` in `dir`.
+    pub fn init(dir: impl AsRef<Path>, network: Network) -> Result<Node, NodeError>{
+        create(dir.as_ref(), network)?;
+        Node::open(dir)
+    }
+
+    /// Create a new chain of `network` in `dir` that only keeps the last `keep` blocks.
+    pub fn init_pruned(dir: impl AsRef<Path>, network: Network, keep: usize) -> Result<Node, NodeError>{
+        create(dir.as_ref(), network)?;
+        fs::create_dir_all(dir.as_ref().join("headers"))?;
+        fs::write(dir.as_ref().join("prune"), keep.max(1).to_string())?;
+        Node::open(dir)
+    }
+
+    pub fn open(dir: impl AsRef<Path>) -> Result<Node, NodeError>{
+        Node::open_with_clock(dir, Arc::new(NetworkClock::new(Arc::new(SystemClock))))
+    }
+
+    /// Open an initialized directory and replay its blocks and mempool.
+    pub fn open_with_clock(dir: impl AsRef<Path>, clock: Arc<dyn Clock>) -> Result<Node, NodeError>{
+        let dir = dir.as_ref().to_path_buf();
+        let name = fs::read_to_string(dir.join("network"))
+            .map_err(|_| NodeError::UnknownNetwork(String::new()))?;
+        let network = Network::from_name(name.trim())
+            .ok_or_else(|| NodeError::UnknownNetwork(name.trim().to_string()))?;
+
+        let params = ChainParams::for_network(network);
+        let path = dir.join("prune");
+        let prune = if path.exists(){
+            let keep = fs::read_to_string(&path)?.trim().parse().map_err(|_| NodeError::Corrupt(path))?;
+            Some(keep)
+        }else{
+            None
+        };
+        let (chain, flushed, kept_from) = match prune{
+            Some(keep) => restore_chain(&dir, params, clock, keep)?,
+            None => (replay_chain(&dir, params, clock)?, 0, 1),
+        };
+
+        let path = dir.join("fee_estimates.dat");
+        let fees = if path.exists(){
+            FeeEstimator::from_bytes(&fs::read(&path)?).ok_or(NodeError::Corrupt(path))?
+        }else{
+            FeeEstimator::new()
+        };
+
+        // blocks replayed above are already known to the estimator
+        let events = chain.subscribe();
+        let mempool = Mempool::with_events(chain.events().clone());
+        let mut node = Node{ dir, prune, flushed, kept_from, chain, mempool, fees, events };
+        let path = node.dir.join("mempool.dat");
+        if path.exists(){
+            let bytes = fs::read(&path)?;
+            let mut r = Reader::new(&bytes);
+            while !r.is_empty(){
+                let tx = r.read_var_bytes()
+                    .and_then(SimpleTx::from_bytes)
+                    .ok_or_else(|| NodeError::Corrupt(path.clone()))?;
+                // transactions may have become invalid, e.g. after a restart with a later clock
+                let _ = node.mempool.accept(tx, &node.chain);
+            }
+        }
+        node.process_events();
+        Ok(node)
+    }
+
+    pub fn chain(&self) -> &BlockChain{
+        &self.chain
+    }
+
+    pub fn mempool(&self) -> &Mempool{
+        &self.mempool
+    }
+
+    /// Number of blocks kept, `None` if the node keeps all of them.
+    pub fn prune(&self) -> Option<usize>{
+        self.prune
+    }
+
+    /// Service bits advertised to peers, see `net::services`.
+    pub fn services(&self) -> u64{
+        net::services(&self.chain)
+    }
+
+    /// Fee rate, in satoshis per 1000 virtual bytes, to confirm within `target` blocks.
+    pub fn estimate_fee(&self, target: usize) -> Option<FeeEstimate>{
+        self.fees.estimate(target)
+    }
+
+    /// Mine `n` blocks paying to `addr`, the first one takes the whole mempool.
+    /// Returns the hashes of the new blocks.
+    pub fn mine(&mut self, n: usize, addr: &str) -> Result<Vec<[u8; 32]>, NodeError>{
+        if self.chain.params().network != Network::Regtest{
+            return Err(NodeError::MiningNotAllowed)
+        }
+        let mut hashes = Vec::with_capacity(n);
+        for _ in 0..n{
+            let ts = self.chain.adjusted_time().max(self.chain.median_time_past() + 1);
+            let txs = self.mempool.select(usize::MAX);
+            let block = self.chain.create_block(ts, addr.to_string(), txs)?;
+            hashes.push(block.hash());
+            self.add_block(block)?;
+        }
+        self.save_mempool()?;
+        Ok(hashes)
+    }
+
+    /// Add a transaction to the mempool.
+    pub fn submit(&mut self, tx: SimpleTx) -> Result<[u8; 32], NodeError>{
+        let txid = self.mempool.accept(tx, &self.chain)?;
+        self.process_events();
+        self.save_mempool()?;
+        Ok(txid)
+    }
+
+    /// Add a block received from elsewhere, it may extend a side branch and trigger a reorganization.
+    pub fn submit_block(&mut self, block: Block) -> Result<(), NodeError>{
+        self.add_block(block)?;
+        self.save_mempool()
+    }
+
+    /// Subscribe to the events of the chain, replaying the active chain from `height`.
+    pub fn subscribe_from(&self, height: usize) -> Receiver<ChainEvent>{
+        self.chain.subscribe_from(height)
+    }
+
+    fn add_block(&mut self, block: Block) -> Result<(), NodeError>{
+        let old_height = self.chain.height();
+        self.chain.add_block(block)?;
+        let fork = self.process_events();
+        // rewrite the files of the blocks that changed and drop those above the new tip
+        for height in fork.min(old_height) + 1..=self.chain.height(){
+            write_atomic(&block_path(&self.dir, height), &self.chain.block(height).unwrap().to_bytes())?;
+        }
+        for height in self.chain.height() + 1..=old_height{
+            fs::remove_file(block_path(&self.dir, height))?;
+        }
+        if let Some(keep) = self.prune{
+            self.prune_files(fork.min(old_height), old_height, keep)?;
+        }
+        Ok(())
+    }
+
+    /// Write the undo data of the blocks above `fork`. Every `keep` blocks, or when the block
+    /// of the saved UTXO set was disconnected, save the UTXO set of the tip and drop the files
+    /// of the blocks that fell out of the last `keep`.
+    fn prune_files(&mut self, fork: usize, old_height: usize, keep: usize) -> Result<(), NodeError>{
+        for height in fork + 1..=self.chain.height(){
+            write_atomic(&undo_path(&self.dir, height), &encode_undo(self.chain.undo(height).unwrap()))?;
+        }
+        for height in self.chain.height() + 1..=old_height{
+            fs::remove_file(undo_path(&self.dir, height))?;
+        }
+        let pruned = self.chain.prune(keep);
+        if fork >= self.flushed && self.chain.height() < self.flushed + keep{
+            return Ok(())
+        }
+
+        // saved first, the files of the blocks above are needed until then
+        let mut state = self.chain.tip().hash().to_vec();
+        state.extend(self.chain.utxo().to_bytes());
+        write_atomic(&self.dir.join("chainstate.dat"), &state)?;
+        self.flushed = self.chain.height();
+        if pruned > self.kept_from{
+            let mut headers = Vec::new();
+            for height in self.kept_from..pruned{
+                headers.extend(self.chain.header(height).unwrap().to_bytes());
+            }
+            write_atomic(&headers_path(&self.dir, self.kept_from), &headers)?;
+            for height in self.kept_from..pruned{
+                fs::remove_file(block_path(&self.dir, height))?;
+                fs::remove_file(undo_path(&self.dir, height))?;
+            }
+            self.kept_from = pruned;
+        }
+        Ok(())
+    }
+
+    /// Apply the pending chain events to the mempool and the fee estimator.
+    /// Returns the lowest height whose block was disconnected, or the tip height.
+    fn process_events(&mut self) -> usize{
+        let mut fork = self.chain.height();
+        loop{
+            let mut connected = Vec::new();
+            let mut disconnected = Vec::new();
+            for event in self.events.try_iter(){
+                match event{
+                    ChainEvent::BlockConnected{ height, block } => {
+                        self.fees.block_connected(height, &block);
+                        connected.push(block);
+                    },
+                    ChainEvent::BlockDisconnected{ height, block } => {
+                        self.fees.block_disconnected(height);
+                        fork = fork.min(height - 1);
+                        disconnected.push(block);
+                    },
+                    ChainEvent::TxAccepted{ tx, height } => self.fees.track_tx(&tx, height),
+                    ChainEvent::TxEvicted{ txid, .. } => self.fees.untrack_tx(&txid),
+                    ChainEvent::TipChanged{ .. } => {},
+                }
+            }
+            if connected.is_empty() && disconnected.is_empty(){
+                return fork
+            }
+            for block in connected.iter(){
+                self.mempool.remove_for_block(block);
+            }
+            if !disconnected.is_empty(){
+                // published from the old tip down
+                disconnected.reverse();
+                self.mempool.reorganize(&disconnected, &self.chain);
+            }
+        }
+    }
+
+    /// Build a transaction paying `amount` from the coins of `from` to `to`.
+    /// The change goes back to `from`, coins spent in the mempool are skipped.
+    pub fn create_tx(&self, from: &str, to: &str, amount: u64, fee: u64) -> Result<SimpleTx, NodeError>{
+        let height = self.chain.height() + 1;
+        let maturity = self.chain.params().coinbase_maturity;
+        let pending: HashSet<OutPoint> = self.mempool.txs()
+            .flat_map(|tx| tx.input.0.iter().map(|txin| txin.prev_out))
+            .collect();
+        let mut coins: Vec<_> = self.chain.utxo().iter()
+            .filter(|(op, entry)| entry.output.addr == from && !pending.contains(op))
+            .filter(|(_, entry)| !entry.coinbase || height - entry.height >= maturity)
+            .collect();
+        // oldest first so that the result does not depend on the hash map order
+        coins.sort_by_key(|(op, entry)| (entry.height, op.txid, op.index));
+
+        let target = amount.checked_add(fee).ok_or(ChainError::ValueOutOfRange)?;
+        let mut inputs = Vec::new();
+        let mut total = 0u64;
+        for (op, entry) in coins{
+            if total >= target{
+                break
+            }
+            total += entry.output.val.val;
+            inputs.push(TxIn::new(*op, from.to_string(), entry.output.val.clone()));
+        }
+        if total < target{
+            return Err(NodeError::NotEnoughCoins)
+        }
+
+        let mut outputs = vec![Trans{addr: to.to_string(), val: SimpleValue::from(amount)}];
+        if total > target{
+            outputs.push(Trans{addr: from.to_string(), val: SimpleValue::from(total - target)});
+        }
+        Ok(SimpleTx{
+            input: InputTx(inputs),
+            output: OutputTx(outputs),
+            lock_time: 0,
+            witness: vec![],
+        })
+    }
+
+    /// Confirmed balance of every address.
+    pub fn balances(&self) -> BTreeMap<String, SimpleValue>{
+        let mut balances = BTreeMap::new();
+        for (_, entry) in self.chain.utxo().iter(){
+            let bal = balances.entry(entry.output.addr.clone()).or_insert_with(SimpleValue::zero);
+            *bal = bal.checked_add(&entry.output.val).unwrap_or_else(SimpleValue::max_money);
+        }
+        balances
+    }
+
+    /// A transaction of the mempool or the chain, with the height of its block.
+    pub fn find_tx(&self, txid: &[u8; 32]) -> Option<(SimpleTx, Option<usize>)>{
+        if let Some(tx) = self.mempool.get(txid){
+            return Some((tx.clone(), None))
+        }
+        (0..=self.chain.height()).rev().find_map(|h| {
+            let block = self.chain.block(h)?;
+            block.txs().iter().find(|tx| &tx.txid() == txid).map(|tx| (tx.clone(), Some(h)))
+        })
+    }
+
+    fn save_mempool(&self) -> Result<(), NodeError>{
+        let mut buf = Vec::new();
+        // parents first, so that their children are accepted again
+        for tx in self.mempool.sorted_txs(){
+            encode::write_var_bytes(&mut buf, &tx.to_witness_bytes());
+        }
+        fs::write(self.dir.join("mempool.dat"), buf)?;
+        fs::write(self.dir.join("fee_estimates.dat"), self.fees.to_bytes())?;
+        Ok(())
+    }
+}
+
+fn create(dir: &Path, network: Network) -> Result<(), NodeError>{
+    if dir.join("network").exists(){
+        return Err(NodeError::AlreadyInitialized)
+    }
+    fs::create_dir_all(dir.join("blocks"))?;
+    fs::write(dir.join("network"), network.name())?;
+    Ok(())
+}
+
+/// Validate the blocks of a full node again.
+fn replay_chain(dir: &Path, params: ChainParams, clock: Arc<dyn Clock>) -> Result<BlockChain, NodeError>{
+    let mut blocks = Vec::new();
+    loop{
+        let path = block_path(dir, blocks.len() + 1);
+        if !path.exists(){
+            break
+        }
+        blocks.push(Block::from_bytes(&fs::read(&path)?).ok_or(NodeError::Corrupt(path))?);
+    }
+    let mut chain = BlockChain::with_clock(params, clock);
+    // headers first, so that the ancestors of the assume-valid block are known as such
+    for block in blocks.iter(){
+        chain.add_header(block.header().clone())?;
+    }
+    for block in blocks{
+        chain.add_block(block)?;
+    }
+    Ok(chain)
+}
+
+/// Load the state of a pruned node and prune it to the last `keep` blocks.
+/// The blocks above the saved UTXO set are validated again, like those of a full node.
+/// Returns the chain, the height of the saved UTXO set and the lowest height with a block file.
+fn restore_chain(dir: &Path, params: ChainParams, clock: Arc<dyn Clock>, keep: usize) -> Result<(BlockChain, usize, usize), NodeError>{
+    let mut headers = Vec::new();
+    loop{
+        let path = headers_path(dir, headers.len() + 1);
+        if !path.exists(){
+            break
+        }
+        let bytes = fs::read(&path)?;
+        let mut r = Reader::new(&bytes);
+        while !r.is_empty(){
+            headers.push(BlockHeader::decode(&mut r).ok_or_else(|| NodeError::Corrupt(path.clone()))?);
+        }
+    }
+    let kept_from = headers.len() + 1;
+
+    let mut blocks = Vec::new();
+    loop{
+        let path = block_path(dir, kept_from + blocks.len());
+        if !path.exists(){
+            break
+        }
+        blocks.push(Block::from_bytes(&fs::read(&path)?).ok_or(NodeError::Corrupt(path))?);
+    }
+
+    let path = dir.join("chainstate.dat");
+    let (flushed, utxo) = if path.exists(){
+        let bytes = fs::read(&path)?;
+        let hash = Reader::new(&bytes).read_hash().ok_or_else(|| NodeError::Corrupt(path.clone()))?;
+        let utxo = UtxoSet::from_bytes(&bytes[32..]).ok_or_else(|| NodeError::Corrupt(path.clone()))?;
+        let i = blocks.iter().position(|b| b.hash() == hash).ok_or(NodeError::Corrupt(path))?;
+        (kept_from + i, utxo)
+    }else if headers.is_empty(){
+        (0, UtxoSet::new())
+    }else{
+        return Err(NodeError::Corrupt(path))
+    };
+
+    let rest = blocks.split_off(flushed + 1 - kept_from);
+    let mut kept = Vec::with_capacity(blocks.len());
+    for (height, block) in (kept_from..).zip(blocks){
+        let path = undo_path(dir, height);
+        let undo = decode_undo(&fs::read(&path)?).ok_or(NodeError::Corrupt(path))?;
+        kept.push((block, undo));
+    }
+    let mut chain = BlockChain::restore(params, clock, headers, kept, utxo)?;
+    for block in rest.iter(){
+        chain.add_header(block.header().clone())?;
+    }
+    for block in rest{
+        chain.add_block(block)?;
+    }
+    chain.prune(keep);
+    Ok((chain, flushed, kept_from))
+}
+
+/// Replace the file at `path`, a crash leaves either the old or the new content.
+fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()>{
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+fn headers_path(dir: &Path, height: usize) -> PathBuf{
+    dir.join("headers").join(format!("{:08}.dat", height))
+}
+
+fn block_path(dir: &Path, height: usize) -> PathBuf{
+    dir.join("blocks").join(format!("{:08}.blk", height))
+}
+
+fn undo_path(dir: &Path, height: usize) -> PathBuf{
+    dir.join("blocks").join(format!("{:08}.rev", height))
+}
+
+fn encode_undo(undo: &[(OutPoint, UtxoEntry<String, SimpleValue>)]) -> Vec<u8>{
+    let mut buf = Vec::new();
+    encode::write_varint(&mut buf, undo.len() as u64);
+    for (op, entry) in undo{
+        entry.encode(op, &mut buf);
+    }
+    buf
+}
+
+fn decode_undo(bytes: &[u8]) -> Option<BlockUndo>{
+    let mut r = Reader::new(bytes);
+    let n = r.read_count(47)?;
+    let undo = (0..n).map(|_| UtxoEntry::decode(&mut r)).collect::<Option<Vec<_>>>()?;
+    if r.is_empty() { Some(undo) } else { None }
+}
+
+#[cfg(test)]
+mod node_test{
+    use super::*;
+
+    #[test]
+    fn test_mine_send_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut node = Node::init(dir.path(), Network::Regtest).unwrap();
+        assert!(matches!(Node::init(dir.path(), Network::Regtest), Err(NodeError::AlreadyInitialized)));
+
+        let maturity = node.chain().params().coinbase_maturity;
+        node.mine(maturity - 1, "Alice").unwrap();
+        // the first coinbase is not mature yet
+        assert!(matches!(node.create_tx("Alice", "Bob", 1, 0), Err(NodeError::NotEnoughCoins)));
+        node.mine(1, "Carol").unwrap();
+
+        let tx = node.create_tx("Alice", "Bob", 10 * crate::COIN, 1000).unwrap();
+        let txid = node.submit(tx).unwrap();
+        assert_eq!(Some(None), node.find_tx(&txid).map(|(_, h)| h));
+
+        // the mempool survives a restart
+        let mut node = Node::open(dir.path()).unwrap();
+        assert!(node.mempool().contains(&txid));
+        node.mine(1, "Carol").unwrap();
+        assert!(node.mempool().is_empty());
+
+        let node = Node::open(dir.path()).unwrap();
+        assert_eq!(maturity + 1, node.chain().height());
+        // a single transaction is not enough history
+        assert_eq!(None, node.estimate_fee(1));
+        assert_eq!(maturity + 1, node.fees.best_height());
+        assert_eq!(Some(Some(maturity + 1)), node.find_tx(&txid).map(|(_, h)| h));
+        let balances = node.balances();
+        let coin = crate::COIN;
+        assert_eq!(SimpleValue::from(10 * coin), balances["Bob"]);
+        // one coinbase was spent, 40 coins minus the fee came back as change
+        assert_eq!(SimpleValue::from((maturity as u64 - 2) * 50 * coin + 40 * coin - 1000), balances["Alice"]);
+        assert_eq!(SimpleValue::from(100 * coin + 1000), balances["Carol"]);
+    }
+
+    #[test]
+    fn test_submit_longer_branch() {
+        let (dir, other) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let mut node = Node::init(dir.path(), Network::Regtest).unwrap();
+        let mut miner = Node::init(other.path(), Network::Regtest).unwrap();
+        node.mine(2, "Alice").unwrap();
+        let hashes = miner.mine(3, "Bob").unwrap();
+
+        let events = node.subscribe_from(3);
+        for height in 1..=3{
+            node.submit_block(miner.chain().block(height).unwrap().clone()).unwrap();
+        }
+        assert_eq!(hashes[2], node.chain().tip().hash());
+        assert_eq!(3, node.fees.best_height());
+        let disconnected = events.try_iter()
+            .filter(|e| matches!(e, ChainEvent::BlockDisconnected{ .. }))
+            .count();
+        assert_eq!(2, disconnected);
+
+        // the files follow the active chain
+        let node = Node::open(dir.path()).unwrap();
+        assert_eq!(hashes[2], node.chain().tip().hash());
+        assert!(!node.balances().contains_key("Alice"));
+    }
+
+    /// `n` blocks paying to `addr` on top of the block of `node` at `fork`.
+    fn branch(node: &Node, fork: usize, n: usize, addr: &str) -> Vec<Block>{
+        let mut chain = BlockChain::new(node.chain().params().clone());
+        for height in 1..=fork{
+            chain.add_block(node.chain().block(height).unwrap().clone()).unwrap();
+        }
+        (0..n).map(|_| {
+            let ts = chain.adjusted_time().max(chain.median_time_past() + 1);
+            let block = chain.create_block(ts, addr.to_string(), vec![]).unwrap();
+            chain.add_block(block.clone()).unwrap();
+            block
+        }).collect()
+    }
+
+    #[test]
+    fn test_pruned_node() {
+        let (dir, other) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let mut miner = Node::init(other.path(), Network::Regtest).unwrap();
+        let mut node = Node::init_pruned(dir.path(), Network::Regtest, 5).unwrap();
+        let maturity = miner.chain().params().coinbase_maturity;
+        miner.mine(maturity + 1, "Alice").unwrap();
+        let tx = miner.create_tx("Alice", "Bob", 10 * crate::COIN, 1000).unwrap();
+        let txid = miner.submit(tx).unwrap();
+        miner.mine(3, "Alice").unwrap();
+        let tip = maturity + 4;
+        for height in 1..=tip{
+            node.submit_block(miner.chain().block(height).unwrap().clone()).unwrap();
+        }
+
+        assert_eq!(Some(5), node.prune());
+        assert_eq!(tip - 4, node.chain().prune_height());
+        assert!(node.chain().block(tip - 5).is_none());
+        assert!(node.chain().header(tip - 5).is_some());
+        // the files are dropped every 5 blocks, when the UTXO set is saved
+        assert_eq!(tip - 4, node.flushed);
+        assert_eq!(tip - 8, node.kept_from);
+        assert!(!block_path(dir.path(), tip - 9).exists());
+        assert!(block_path(dir.path(), tip - 8).exists());
+        assert!(headers_path(dir.path(), tip - 13).exists());
+        // a write cut short leaves a temporary file behind, not a truncated block
+        fs::write(block_path(dir.path(), tip + 1).with_extension("tmp"), [0; 3]).unwrap();
+        // the blocks above the saved UTXO set are connected again
+        let reopened = Node::open(dir.path()).unwrap();
+        assert_eq!(node.chain().tip().hash(), reopened.chain().tip().hash());
+        assert_eq!(tip - 4, reopened.chain().prune_height());
+        assert_eq!(node.balances(), reopened.balances());
+        // too few blocks to advertise even the limited service
+        assert_eq!(0, node.services());
+        assert_eq!(net::NODE_NETWORK | net::NODE_NETWORK_LIMITED, miner.services());
+
+        // disconnect the block spending to Bob
+        let fork = maturity + 1;
+        let carol = branch(&miner, fork, 4, "Carol");
+        for block in carol.iter(){
+            node.submit_block(block.clone()).unwrap();
+            miner.submit_block(block.clone()).unwrap();
+        }
+        assert_eq!(tip + 1, node.chain().height());
+        assert!(!node.balances().contains_key("Bob"));
+        assert!(node.mempool().contains(&txid));
+
+        let mut node = Node::open(dir.path()).unwrap();
+        assert_eq!(tip + 1, node.chain().height());
+        assert_eq!(tip - 3, node.chain().prune_height());
+        assert_eq!(node.balances(), Node::open(dir.path()).unwrap().balances());
+        assert_eq!(SimpleValue::from(4 * 50 * crate::COIN), node.balances()["Carol"]);
+
+        // the undo data below the last 5 blocks is gone
+        let hash = node.chain().tip().hash();
+        let blocks = branch(&miner, fork - 3, tip - fork + 5, "Dave");
+        let res: Vec<_> = blocks.into_iter().map(|b| node.submit_block(b)).collect();
+        assert!(matches!(res.last(), Some(Err(NodeError::Chain(ChainError::ReorgTooDeep{ fork: f }))) if *f == fork - 3));
+        assert_eq!(hash, node.chain().tip().hash());
+        assert_eq!(hash, Node::open(dir.path()).unwrap().chain().tip().hash());
+
+        // the block of the saved UTXO set is disconnected, so it is saved again
+        assert_eq!(tip + 1, node.flushed);
+        for block in branch(&miner, tip - 1, 3, "Erin"){
+            node.submit_block(block).unwrap();
+        }
+        assert_eq!(tip + 2, node.flushed);
+        let reopened = Node::open(dir.path()).unwrap();
+        assert_eq!(node.chain().tip().hash(), reopened.chain().tip().hash());
+        assert_eq!(node.balances(), reopened.balances());
+        assert!(reopened.balances().contains_key("Erin"));
+    }
+
+    #[test]
+    fn test_replay_checks_stored_scripts() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut node = Node::init(dir.path(), Network::Regtest).unwrap();
+        let policy = crate::script::Multisig::new(1, vec![crate::script::SigningKey::from_seed(&[1; 32]).public_key()]).unwrap();
+        node.mine(node.chain().params().coinbase_maturity, &policy.address()).unwrap();
+        let cb = node.chain().block(1).unwrap().txs()[0].clone();
+        // signed by nobody
+        let spend = SimpleTx{
+            input: InputTx(vec![TxIn::new(OutPoint::new(cb.txid(), 0), policy.address(), cb.output.0[0].val.clone())]),
+            output: OutputTx(vec![Trans{addr: "Bob".to_string(), val: cb.output.0[0].val.clone()}]),
+            lock_time: 0,
+            witness: vec![vec![vec![7; 64]]],
+        };
+        let chain = node.chain();
+        let ts = chain.adjusted_time().max(chain.median_time_past() + 1);
+        let block = chain.create_block(ts, "Alice".to_string(), vec![spend]).unwrap();
+        assert_eq!(Err(ChainError::BadSignature), chain.clone().add_block(block.clone()));
+
+        // a block file is not trusted more than a block from a peer
+        let height = chain.height() + 1;
+        fs::write(block_path(dir.path(), height), block.to_bytes()).unwrap();
+        assert!(matches!(Node::open(dir.path()), Err(NodeError::Chain(ChainError::BadSignature))));
+    }
+
+    #[test]
+    fn test_mining_needs_regtest() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut node = Node::init(dir.path(), Network::Mainnet).unwrap();
+        assert!(matches!(node.mine(1, "Alice"), Err(NodeError::MiningNotAllowed)));
+    }
+}
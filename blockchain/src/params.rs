@@ -0,0 +1,224 @@
+//! Parameters that differ between networks.
+//!
+//! `mainnet` and `testnet` are meant to be long running, `regtest` has trivial difficulty
+//! and no retargeting so that tests can mine blocks instantly.
+
+use crate::block::Block;
+use crate::consensus::ConsensusParams;
+use crate::encode;
+use crate::{SimpleTx, SimpleValue, COIN};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Network{
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+impl Network{
+    pub fn name(&self) -> &'static str{
+        match self{
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Regtest => "regtest",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Network>{
+        match name{
+            "mainnet" => Some(Network::Mainnet),
+            "testnet" => Some(Network::Testnet),
+            "regtest" => Some(Network::Regtest),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ChainParams{
+    pub network: Network,
+    /// Start of every p2p message and data file, tells networks apart.
+    pub magic: [u8; 4],
+    pub genesis_timestamp: u64,
+    /// Receiver of the genesis coinbase. The genesis outputs are not spendable.
+    pub genesis_addr: String,
+    /// Engine deciding who may add blocks.
+    pub consensus: ConsensusParams,
+    /// Difficulty of the genesis block and the lowest difficulty allowed.
+    pub pow_limit: u32,
+    /// Blocks between two difficulty adjustments.
+    pub retarget_interval: usize,
+    /// Expected seconds between two blocks.
+    pub target_spacing: u64,
+    /// Keep the difficulty of genesis forever.
+    pub no_retargeting: bool,
+    /// Coinbase reward of the first blocks, in satoshis.
+    pub initial_subsidy: u64,
+    /// The reward halves every this many blocks.
+    pub subsidy_halving_interval: usize,
+    /// Confirmations before a coinbase output may be spent.
+    pub coinbase_maturity: usize,
+    /// Hashes of the blocks at these heights. Blocks forking below the highest checkpoint
+    /// of the active chain are refused.
+    pub checkpoints: Vec<(usize, [u8; 32])>,
+    /// The scripts of this block and of its ancestors are not checked once its header is known.
+    pub assume_valid: Option<[u8; 32]>,
+    /// Prefix of addresses paying to a public key hash.
+    pub pubkey_prefix: &'static str,
+    /// Prefix of addresses paying to a script hash.
+    pub script_prefix: &'static str,
+}
+
+impl ChainParams{
+    pub fn mainnet() -> ChainParams{
+        ChainParams{
+            network: Network::Mainnet,
+            magic: [0xd3, 0x5a, 0xc7, 0xe1],
+            genesis_timestamp: 1_585_699_200, // 2020-04-01
+            genesis_addr: "Tsumida".to_string(),
+            consensus: ConsensusParams::ProofOfWork,
+            pow_limit: 0x1e00ffff,
+            retarget_interval: 2016,
+            target_spacing: 10 * 60,
+            no_retargeting: false,
+            initial_subsidy: 50 * COIN,
+            subsidy_halving_interval: 210_000,
+            coinbase_maturity: 100,
+            checkpoints: Vec::new(),
+            assume_valid: None,
+            pubkey_prefix: "1",
+            script_prefix: "3",
+        }
+    }
+
+    pub fn testnet() -> ChainParams{
+        ChainParams{
+            network: Network::Testnet,
+            magic: [0xd3, 0x5a, 0xc7, 0xe2],
+            genesis_timestamp: 1_585_699_201,
+            pow_limit: 0x1f00ffff,
+            retarget_interval: 144,
+            pubkey_prefix: "m",
+            script_prefix: "2",
+            ..ChainParams::mainnet()
+        }
+    }
+
+    pub fn regtest() -> ChainParams{
+        ChainParams{
+            network: Network::Regtest,
+            magic: [0xd3, 0x5a, 0xc7, 0xe3],
+            genesis_timestamp: 1_585_699_202,
+            pow_limit: 0x207fffff,
+            retarget_interval: 144,
+            no_retargeting: true,
+            subsidy_halving_interval: 150,
+            pubkey_prefix: "r",
+            script_prefix: "s",
+            ..ChainParams::mainnet()
+        }
+    }
+
+    pub fn for_network(network: Network) -> ChainParams{
+        match network{
+            Network::Mainnet => ChainParams::mainnet(),
+            Network::Testnet => ChainParams::testnet(),
+            Network::Regtest => ChainParams::regtest(),
+        }
+    }
+
+    /// Seconds a retarget interval is expected to take.
+    pub fn target_timespan(&self) -> u64{
+        self.retarget_interval as u64 * self.target_spacing
+    }
+
+    /// Coinbase reward of the block at `height`, without fees.
+    pub fn block_subsidy(&self, height: usize) -> SimpleValue{
+        let halvings = height / self.subsidy_halving_interval;
+        if halvings >= 64{
+            return SimpleValue::from(0)
+        }
+        SimpleValue::from(self.initial_subsidy >> halvings)
+    }
+
+    pub fn genesis_block(&self) -> Block{
+        let coinbase = SimpleTx::coinbase(0, self.block_subsidy(0), self.genesis_addr.clone());
+        Block::genesis_block(self.genesis_timestamp, self.pow_limit, coinbase)
+    }
+
+    /// Address paying to the hash of a public key.
+    pub fn pubkey_address(&self, hash: &[u8; 20]) -> String{
+        format!("{}{}", self.pubkey_prefix, encode::to_hex(hash))
+    }
+
+    /// Address paying to the hash of a script.
+    pub fn script_address(&self, hash: &[u8; 20]) -> String{
+        format!("{}{}", self.script_prefix, encode::to_hex(hash))
+    }
+
+    /// Hash of a pay-to-pubkey-hash address of this network.
+    pub fn parse_pubkey_address(&self, addr: &str) -> Option<[u8; 20]>{
+        parse_hash_address(self.pubkey_prefix, addr)
+    }
+
+    /// Hash of a pay-to-script-hash address of this network.
+    pub fn parse_script_address(&self, addr: &str) -> Option<[u8; 20]>{
+        parse_hash_address(self.script_prefix, addr)
+    }
+}
+
+fn parse_hash_address(prefix: &str, addr: &str) -> Option<[u8; 20]>{
+    if !addr.starts_with(prefix) || addr.len() != prefix.len() + 40{
+        return None
+    }
+    let bytes = encode::from_hex(&addr[prefix.len()..])?;
+    let mut hash = [0; 20];
+    hash.copy_from_slice(&bytes);
+    Some(hash)
+}
+
+#[cfg(test)]
+mod params_test{
+    use super::*;
+    use crate::pow;
+
+    #[test]
+    fn test_subsidy_schedule() {
+        let params = ChainParams::regtest();
+        assert_eq!(SimpleValue::from(50 * COIN), params.block_subsidy(0));
+        assert_eq!(SimpleValue::from(50 * COIN), params.block_subsidy(149));
+        assert_eq!(SimpleValue::from(25 * COIN), params.block_subsidy(150));
+        assert_eq!(SimpleValue::from(0), params.block_subsidy(150 * 64));
+    }
+
+    #[test]
+    fn test_genesis() {
+        let params = ChainParams::mainnet();
+        let genesis = params.genesis_block();
+        assert_eq!(&[0; 32], genesis.header().prev_block());
+        assert_eq!(params.genesis_timestamp, genesis.header().timestamp());
+        assert_eq!(1, genesis.txs().len());
+        assert!(genesis.txs()[0].is_coinbase());
+        // the genesis blocks of the networks differ
+        assert_ne!(genesis.hash(), ChainParams::testnet().genesis_block().hash());
+        assert_ne!(genesis.hash(), ChainParams::regtest().genesis_block().hash());
+        // and so do their magic bytes, which are not bitcoin's
+        assert_ne!(params.magic, ChainParams::testnet().magic);
+        assert_ne!(params.magic, ChainParams::regtest().magic);
+        assert_ne!(ChainParams::testnet().magic, ChainParams::regtest().magic);
+        assert_ne!([0xf9, 0xbe, 0xb4, 0xd9], params.magic);
+        // regtest accepts about every second hash
+        assert!(pow::bits_to_target(ChainParams::regtest().pow_limit).unwrap()[0] == 0x7f);
+    }
+
+    #[test]
+    fn test_addresses() {
+        let params = ChainParams::testnet();
+        let addr = params.pubkey_address(&[0xab; 20]);
+        assert!(addr.starts_with("m"));
+        assert_eq!(Some([0xab; 20]), params.parse_pubkey_address(&addr));
+        assert_eq!(None, params.parse_script_address(&addr));
+        assert_eq!(None, ChainParams::mainnet().parse_pubkey_address(&addr));
+        assert_eq!(None, params.parse_pubkey_address("Alice"));
+    }
+}
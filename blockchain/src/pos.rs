@@ -0,0 +1,506 @@
+//! A simple proof of stake.
+//!
+//! Time is divided into slots of `slot_duration` seconds counted from the genesis timestamp.
+//! For every slot each validator evaluates its VRF on the randomness of the previous block
+//! and the slot number. It leads the slot if the output is below a threshold growing with
+//! its share of the stake, as in Ouroboros Praos: `1 - (1 - active_slots) ^ share`.
+//! The threshold is computed in fixed point, so that every node agrees on the leaders.
+//! Some slots have no leader, some several; the longest chain wins.
+//!
+//! The leader seals its block with the VRF proof and a signature of the header, the nonce
+//! is not used. Blocks may not be forged for slots that have not started yet.
+//! The chain never reorganizes below `finality_depth` blocks.
+//!
+//! A validator signing two blocks for one slot can be proven to have done so with a
+//! `DoubleSign`. The evidence is not committed in blocks, so every node may know of
+//! different evidence: it is kept for reporting and does not change which blocks are valid.
+//! Validators and their stakes are fixed by the chain parameters.
+
+use schnorrkel::vrf::{VRFInOut, VRFOutput, VRFProof};
+use schnorrkel::{signing_context, ExpansionMode, Keypair, MiniSecretKey, PublicKey, Signature};
+
+use crate::block::{Block, BlockHeader, StakeSeal};
+use crate::consensus::{Consensus, ConsensusParams, HeaderContext};
+use crate::error::ChainError;
+use crate::mkt;
+use crate::params::ChainParams;
+use crate::{BlockChain, SimpleTx};
+
+const SEAL_CONTEXT: &[u8] = b"bchain header";
+const VRF_CONTEXT: &[u8] = b"bchain slot";
+
+/// Seconds a slot may start ahead of the local clock.
+pub const MAX_CLOCK_DRIFT: u64 = 5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Validator{
+    pub public_key: [u8; 32],
+    pub stake: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StakeParams{
+    /// Seconds of a slot.
+    pub slot_duration: u64,
+    /// Chance that a slot has a leader if a single validator holds all the stake, in millionths.
+    pub active_slots: u32,
+    /// Blocks this deep in the active chain are final.
+    pub finality_depth: usize,
+    pub validators: Vec<Validator>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ProofOfStake{
+    genesis_timestamp: u64,
+    params: StakeParams,
+    total_stake: u64,
+}
+
+impl ProofOfStake{
+    pub fn new(chain: &ChainParams, params: StakeParams) -> ProofOfStake{
+        let total_stake = params.validators.iter().map(|v| v.stake).sum();
+        ProofOfStake{ genesis_timestamp: chain.genesis_timestamp, params, total_stake }
+    }
+
+    /// The engine of a proof of stake chain.
+    pub fn from_params(chain: &ChainParams) -> Option<ProofOfStake>{
+        match chain.consensus{
+            ConsensusParams::ProofOfStake(ref stake) => Some(ProofOfStake::new(chain, stake.clone())),
+            ConsensusParams::ProofOfWork => None,
+        }
+    }
+
+    /// Slot of a timestamp, `None` before genesis.
+    pub fn slot_at(&self, timestamp: u64) -> Option<u64>{
+        timestamp.checked_sub(self.genesis_timestamp).map(|t| t / self.params.slot_duration)
+    }
+
+    /// First second of `slot`, the timestamp of the blocks forged for it.
+    pub fn slot_start(&self, slot: u64) -> u64{
+        self.genesis_timestamp + slot * self.params.slot_duration
+    }
+
+    pub fn stake_of(&self, validator: &[u8; 32]) -> u64{
+        self.params.validators.iter()
+            .filter(|v| &v.public_key == validator)
+            .map(|v| v.stake)
+            .sum()
+    }
+
+    /// VRF values below this make a validator with `stake` the leader of a slot.
+    pub fn threshold(&self, stake: u64) -> u64{
+        if self.total_stake == 0{
+            return 0
+        }
+        let f = ONE * self.params.active_slots.min(1_000_000) as u128 / 1_000_000;
+        if f == ONE{
+            return if stake == 0 { 0 } else { u64::MAX }
+        }
+        // (1 - f) ^ share = e ^ -(share * -ln(1 - f))
+        let c = neg_ln(ONE - f);
+        let total = self.total_stake as u128;
+        let x = c / total * stake as u128 + c % total * stake as u128 / total;
+        let phi = ONE - exp_neg(x);
+        (phi << (64 - FRAC_BITS)).min(u64::MAX as u128) as u64
+    }
+}
+
+/// Fractional bits of the fixed point numbers, products of two numbers up to 1 fit a `u128`.
+const FRAC_BITS: u32 = 62;
+const ONE: u128 = 1 << FRAC_BITS;
+
+fn mul(a: u128, b: u128) -> u128{
+    (a * b) >> FRAC_BITS
+}
+
+/// `-ln(x)` for `0 < x <= 1`.
+fn neg_ln(x: u128) -> u128{
+    // x = y / 2^k with 1/2 <= y <= 1
+    let (mut y, mut k) = (x, 0);
+    while y < ONE / 2{
+        y <<= 1;
+        k += 1;
+    }
+    k * neg_ln_series(ONE / 2) + neg_ln_series(y)
+}
+
+/// `-ln(x)` for `1/2 <= x <= 1`, as the sum of `(1 - x)^n / n`.
+fn neg_ln_series(x: u128) -> u128{
+    let z = ONE - x;
+    let (mut sum, mut p) = (0, z);
+    for n in 1..=FRAC_BITS as u128{
+        sum += p / n;
+        p = mul(p, z);
+    }
+    sum
+}
+
+/// `e^-x` for `x >= 0`.
+fn exp_neg(x: u128) -> u128{
+    // e^-x = (e^-1)^n * e^-frac
+    let e = exp_neg_series(ONE);
+    let mut res = exp_neg_series(x & (ONE - 1));
+    for _ in 0..x >> FRAC_BITS{
+        res = mul(res, e);
+        if res == 0{
+            break
+        }
+    }
+    res
+}
+
+/// `e^-x` for `0 <= x <= 1`, as the sum of `(-x)^k / k!`.
+fn exp_neg_series(x: u128) -> u128{
+    let (mut sum, mut term) = (ONE, ONE);
+    for k in 1..=24{
+        term = mul(term, x) / k;
+        if k.is_multiple_of(2){
+            sum += term;
+        }else{
+            sum -= term;
+        }
+    }
+    sum
+}
+
+/// Randomness a block passes on to the election of the next slot.
+/// Blocks without a seal, such as genesis, use their hash.
+pub fn randomness(header: &BlockHeader) -> [u8; 32]{
+    match header.seal(){
+        Some(seal) => mkt::sha256(&seal.vrf_output),
+        None => header.hash(),
+    }
+}
+
+/// Input of the VRF for `slot` after `prev`.
+pub fn slot_input(prev: &BlockHeader, slot: u64) -> [u8; 32]{
+    let mut buf = randomness(prev).to_vec();
+    buf.extend(&slot.to_be_bytes());
+    mkt::sha256(&buf)
+}
+
+fn leader_value(inout: &VRFInOut) -> u64{
+    u64::from_be_bytes(inout.make_bytes::<[u8; 8]>(b"leader"))
+}
+
+impl Consensus for ProofOfStake{
+    fn check_seal(&self, header: &BlockHeader) -> Result<(), ChainError>{
+        let seal = header.seal().ok_or(ChainError::BadSeal)?;
+        if self.stake_of(&seal.validator) == 0{
+            return Err(ChainError::BadSeal)
+        }
+        self.slot_at(header.timestamp()).ok_or(ChainError::BadSlot)?;
+        let key = PublicKey::from_bytes(&seal.validator).map_err(|_| ChainError::BadSeal)?;
+        let sig = Signature::from_bytes(&seal.signature).map_err(|_| ChainError::BadSeal)?;
+        key.verify(signing_context(SEAL_CONTEXT).bytes(&header.signing_bytes()), &sig)
+            .map_err(|_| ChainError::BadSeal)
+    }
+
+    fn check_header(&self, ctx: &HeaderContext, header: &BlockHeader) -> Result<(), ChainError>{
+        self.check_seal(header)?;
+        let seal = header.seal().unwrap();
+        let prev = ctx.prev;
+        let slot = self.slot_at(header.timestamp()).unwrap();
+        if slot <= self.slot_at(prev.timestamp()).unwrap_or(0){
+            return Err(ChainError::BadSlot)
+        }
+        let max = ctx.adjusted_time.saturating_add(MAX_CLOCK_DRIFT);
+        if header.timestamp() > max{
+            return Err(ChainError::TimeTooNew{ timestamp: header.timestamp(), max })
+        }
+        // not used for the election, but part of the header all the same
+        if header.bits() != ctx.bits{
+            return Err(ChainError::BadDifficulty)
+        }
+
+        let key = PublicKey::from_bytes(&seal.validator).map_err(|_| ChainError::BadSeal)?;
+        let output = VRFOutput::from_bytes(&seal.vrf_output).map_err(|_| ChainError::BadSeal)?;
+        let proof = VRFProof::from_bytes(&seal.vrf_proof).map_err(|_| ChainError::BadSeal)?;
+        let (inout, _) = key.vrf_verify(signing_context(VRF_CONTEXT).bytes(&slot_input(prev, slot)), &output, &proof)
+            .map_err(|_| ChainError::BadSeal)?;
+        if leader_value(&inout) >= self.threshold(self.stake_of(&seal.validator)){
+            return Err(ChainError::NotSlotLeader)
+        }
+        Ok(())
+    }
+
+    /// Every block counts the same, the longest chain wins.
+    fn block_work(&self, _header: &BlockHeader) -> u128{
+        1
+    }
+
+    fn finality_depth(&self) -> Option<usize>{
+        Some(self.params.finality_depth)
+    }
+
+    fn slot_owner(&self, header: &BlockHeader) -> Option<(u64, [u8; 32])>{
+        Some((self.slot_at(header.timestamp())?, header.seal()?.validator))
+    }
+}
+
+/// The keys of a validator, forging blocks for the slots it leads.
+pub struct Staker{
+    keypair: Keypair,
+}
+
+impl Staker{
+    /// Keys derived from a secret seed.
+    pub fn from_seed(seed: &[u8; 32]) -> Staker{
+        let secret = MiniSecretKey::from_bytes(seed).expect("32 bytes");
+        Staker{ keypair: secret.expand_to_keypair(ExpansionMode::Uniform) }
+    }
+
+    pub fn public_key(&self) -> [u8; 32]{
+        self.keypair.public.to_bytes()
+    }
+
+    fn prove(&self, prev: &BlockHeader, slot: u64) -> (VRFInOut, VRFProof){
+        let (inout, proof, _) = self.keypair.vrf_sign(signing_context(VRF_CONTEXT).bytes(&slot_input(prev, slot)));
+        (inout, proof)
+    }
+
+    /// Whether this validator leads `slot` on top of the tip of `chain`.
+    pub fn leads(&self, chain: &BlockChain, slot: u64) -> bool{
+        let pos = match ProofOfStake::from_params(chain.params()){
+            Some(pos) => pos,
+            None => return false,
+        };
+        let (inout, _) = self.prove(chain.tip().header(), slot);
+        leader_value(&inout) < pos.threshold(pos.stake_of(&self.public_key()))
+    }
+
+    /// A block for `slot` on the tip of `chain`, `None` if this validator does not lead it.
+    /// It pays the subsidy and the fees of `txs` to `addr`.
+    pub fn forge(&self, chain: &BlockChain, slot: u64, addr: String, txs: Vec<SimpleTx>) -> Result<Option<Block>, ChainError>{
+        let pos = ProofOfStake::from_params(chain.params()).ok_or(ChainError::BadSeal)?;
+        if !self.leads(chain, slot){
+            return Ok(None)
+        }
+        let mut block = chain.block_template(pos.slot_start(slot), addr, txs)?;
+        let (inout, proof) = self.prove(chain.tip().header(), slot);
+        self.seal(block.header_mut(), inout.to_output().to_bytes(), proof.to_bytes());
+        Ok(Some(block))
+    }
+
+    /// Seal `header` with a VRF output and proof, and sign it.
+    pub fn seal(&self, header: &mut BlockHeader, vrf_output: [u8; 32], vrf_proof: [u8; 64]){
+        header.set_seal(StakeSeal{ validator: self.public_key(), vrf_output, vrf_proof, signature: [0; 64] });
+        let sig = self.keypair.sign(signing_context(SEAL_CONTEXT).bytes(&header.signing_bytes()));
+        let mut seal = header.seal().unwrap().clone();
+        seal.signature = sig.to_bytes();
+        header.set_seal(seal);
+    }
+}
+
+#[cfg(test)]
+mod pos_test{
+    use super::*;
+    use std::sync::Arc;
+    use crate::clock::MockClock;
+    use crate::consensus::DoubleSign;
+
+    fn stakers() -> Vec<Staker>{
+        (1..=3).map(|i| Staker::from_seed(&[i; 32])).collect()
+    }
+
+    fn pos_chain(stakers: &[Staker]) -> BlockChain{
+        let mut params = ChainParams::regtest();
+        params.consensus = ConsensusParams::ProofOfStake(StakeParams{
+            slot_duration: 10,
+            active_slots: 500_000,
+            finality_depth: 10,
+            validators: stakers.iter().zip([10, 20, 70].iter())
+                .map(|(s, &stake)| Validator{ public_key: s.public_key(), stake })
+                .collect(),
+        });
+        let g = params.genesis_timestamp;
+        BlockChain::with_clock(params, Arc::new(MockClock::new(g + 1_000_000)))
+    }
+
+    fn next_slot(chain: &BlockChain) -> u64{
+        let pos = ProofOfStake::from_params(chain.params()).unwrap();
+        pos.slot_at(chain.tip().header().timestamp()).unwrap() + 1
+    }
+
+    /// First slot after the tip led by `staker`.
+    fn led_slot(chain: &BlockChain, staker: &Staker) -> u64{
+        (next_slot(chain)..).find(|&slot| staker.leads(chain, slot)).unwrap()
+    }
+
+    #[test]
+    fn test_threshold() {
+        let validators = [10, 20, 70].iter().enumerate()
+            .map(|(i, &stake)| Validator{ public_key: [i as u8; 32], stake })
+            .collect();
+        let pos = ProofOfStake::new(&ChainParams::regtest(), StakeParams{
+            slot_duration: 10,
+            active_slots: 500_000,
+            finality_depth: 10,
+            validators,
+        });
+        // the same on every platform, within 2^-58 of 1 - 0.5 ^ share
+        assert_eq!(0, pos.threshold(0));
+        assert_eq!(1_235_323_266_502_455_180, pos.threshold(10));
+        assert_eq!(2_387_920_629_362_261_736, pos.threshold(20));
+        assert_eq!(7_091_441_118_334_073_028, pos.threshold(70));
+        assert_eq!(9_223_372_036_854_775_768, pos.threshold(100));
+
+        let mut params = pos.params.clone();
+        params.active_slots = 999_999;
+        assert_eq!(2_380_301_632_270_403_820, ProofOfStake::new(&ChainParams::regtest(), params.clone()).threshold(1));
+        params.active_slots = 1_000_000;
+        let always = ProofOfStake::new(&ChainParams::regtest(), params);
+        assert_eq!((0, u64::MAX), (always.threshold(0), always.threshold(1)));
+    }
+
+    #[test]
+    fn test_forge_and_check() {
+        let stakers = stakers();
+        let mut chain = pos_chain(&stakers);
+        let pos = ProofOfStake::from_params(chain.params()).unwrap();
+        assert!(pos.threshold(10) < pos.threshold(70));
+        assert_eq!(Some(ChainError::BadSeal), chain.create_block(chain.tip().header().timestamp() + 1, "Alice".to_string(), vec![]).err());
+
+        let mut leaders = vec![0; stakers.len()];
+        let mut slot = 1;
+        while chain.height() < 30{
+            for (i, staker) in stakers.iter().enumerate(){
+                if let Some(block) = staker.forge(&chain, slot, format!("v{}", i), vec![]).unwrap(){
+                    assert_eq!(0, block.header().nonce());
+                    chain.add_block(block).unwrap();
+                    leaders[i] += 1;
+                    break
+                }
+            }
+            slot += 1;
+        }
+        // the largest stake leads the most
+        assert!(leaders[2] > leaders[0]);
+
+        // a validator not leading the slot
+        let slot = next_slot(&chain);
+        let (i, staker) = stakers.iter().enumerate().find(|(_, s)| !s.leads(&chain, slot)).unwrap();
+        assert!(staker.forge(&chain, slot, "v".to_string(), vec![]).unwrap().is_none());
+        let mut block = chain.block_template(pos.slot_start(slot), format!("v{}", i), vec![]).unwrap();
+        let (inout, proof) = staker.prove(chain.tip().header(), slot);
+        staker.seal(block.header_mut(), inout.to_output().to_bytes(), proof.to_bytes());
+        assert_eq!(Err(ChainError::NotSlotLeader), chain.add_block(block));
+
+        // the signature covers the header
+        let slot = led_slot(&chain, &stakers[2]);
+        let block = stakers[2].forge(&chain, slot, "v2".to_string(), vec![]).unwrap().unwrap();
+        let mut tampered = block.clone();
+        tampered.header_mut().set_bits(1);
+        assert_eq!(Err(ChainError::BadSeal), chain.add_block(tampered));
+        // so does the VRF proof, for the slot and the previous block
+        let outsider = Staker::from_seed(&[9; 32]);
+        let mut stolen = block.clone();
+        outsider.seal(stolen.header_mut(), block.header().seal().unwrap().vrf_output, block.header().seal().unwrap().vrf_proof);
+        assert_eq!(Err(ChainError::BadSeal), chain.add_block(stolen));
+
+        let bytes = block.to_bytes();
+        assert_eq!(Some(block.hash()), Block::<SimpleTx>::from_bytes(&bytes).map(|b| b.hash()));
+        chain.add_block(block.clone()).unwrap();
+
+        // the next block may not reuse the slot
+        let mut again = chain.block_template(pos.slot_start(slot), "v2".to_string(), vec![]).unwrap();
+        let (inout, proof) = stakers[2].prove(chain.tip().header(), slot);
+        stakers[2].seal(again.header_mut(), inout.to_output().to_bytes(), proof.to_bytes());
+        assert_eq!(Err(ChainError::BadSlot), chain.add_block(again));
+    }
+
+    #[test]
+    fn test_double_sign() {
+        let stakers = stakers();
+        let mut chain = pos_chain(&stakers);
+        let fresh = chain.clone();
+        let cheat = &stakers[2];
+        let slot = led_slot(&chain, cheat);
+        let first = cheat.forge(&chain, slot, "Alice".to_string(), vec![]).unwrap().unwrap();
+        let second = cheat.forge(&chain, slot, "Bob".to_string(), vec![]).unwrap().unwrap();
+        chain.add_block(first.clone()).unwrap();
+        assert!(chain.double_signs().is_empty());
+        // kept on a side branch, but the evidence is recorded
+        chain.add_block(second.clone()).unwrap();
+        assert_eq!(first.hash(), chain.tip().hash());
+        assert_eq!(1, chain.double_signs().len());
+        let evidence = chain.double_signs()[0].clone();
+        assert_eq!((slot, cheat.public_key()), (evidence.slot, evidence.validator));
+        assert!(evidence.verify(chain.consensus()).is_ok());
+
+        // evidence can be passed to other nodes
+        let mut other = fresh;
+        other.add_block(first.clone()).unwrap();
+        let same = DoubleSign{ second: first.header().clone(), ..evidence.clone() };
+        assert_eq!(Err(ChainError::BadEvidence), other.report_double_sign(same));
+        other.report_double_sign(evidence).unwrap();
+        assert_eq!(1, other.double_signs().len());
+
+        // it is not committed in blocks, so later blocks of the cheater stay valid everywhere
+        let later = led_slot(&chain, cheat);
+        let block = cheat.forge(&chain, later, "Alice".to_string(), vec![]).unwrap().unwrap();
+        chain.add_block(block.clone()).unwrap();
+        other.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn test_header_checks() {
+        let stakers = stakers();
+        let mut chain = pos_chain(&stakers);
+        let pos = ProofOfStake::from_params(chain.params()).unwrap();
+        let staker = &stakers[2];
+
+        // a slot that has not started yet
+        let now = pos.slot_at(chain.adjusted_time()).unwrap();
+        let slot = (now + 2..).find(|&slot| staker.leads(&chain, slot)).unwrap();
+        let block = staker.forge(&chain, slot, "v2".to_string(), vec![]).unwrap().unwrap();
+        assert_eq!(
+            Err(ChainError::TimeTooNew{ timestamp: pos.slot_start(slot), max: chain.adjusted_time() + MAX_CLOCK_DRIFT }),
+            chain.add_block(block),
+        );
+
+        // the target is still checked
+        let slot = led_slot(&chain, staker);
+        let mut block = staker.forge(&chain, slot, "v2".to_string(), vec![]).unwrap().unwrap();
+        block.header_mut().set_bits(chain.next_bits() - 1);
+        let (inout, proof) = staker.prove(chain.tip().header(), slot);
+        staker.seal(block.header_mut(), inout.to_output().to_bytes(), proof.to_bytes());
+        assert_eq!(Err(ChainError::BadDifficulty), chain.add_block(block));
+    }
+
+    /// Forge `n` blocks on `chain`, the first validator leading a slot forges it.
+    fn extend(chain: &mut BlockChain, stakers: &[Staker], n: usize, addr: &str) -> Vec<Block>{
+        let mut blocks = Vec::new();
+        let mut slot = next_slot(chain);
+        while blocks.len() < n{
+            if let Some(staker) = stakers.iter().find(|s| s.leads(chain, slot)){
+                let block = staker.forge(chain, slot, addr.to_string(), vec![]).unwrap().unwrap();
+                chain.add_block(block.clone()).unwrap();
+                blocks.push(block);
+            }
+            slot += 1;
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_finality() {
+        let stakers = stakers();
+        let mut chain = pos_chain(&stakers);
+        let mut fork = chain.clone();
+        extend(&mut chain, &stakers, 12, "Alice");
+        // slots of final blocks are forgotten
+        let last = chain.final_slot().unwrap();
+        assert!(chain.signed_slots.keys().all(|(slot, _)| *slot >= last));
+        assert!(chain.signed_slots.len() < 12);
+
+        // a longer branch forking below the final blocks is not followed
+        let branch = extend(&mut fork, &stakers, 13, "Bob");
+        let tip = chain.tip().hash();
+        for block in branch[..12].iter(){
+            chain.add_block(block.clone()).unwrap();
+        }
+        assert_eq!(Err(ChainError::ReorgTooDeep{ fork: 0 }), chain.add_block(branch[12].clone()));
+        assert_eq!(tip, chain.tip().hash());
+    }
+}
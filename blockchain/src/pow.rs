@@ -0,0 +1,192 @@
+//! Proof of work.
+//!
+//! The target is stored in the header as `bits` in Bitcoin's compact form:
+//! the highest byte is the length `e` of the target in bytes and the low 23 bits
+//! the mantissa `m`, `target = m * 256^(e - 3)`.
+//! A hash, read as a big-endian number, must not be greater than the target.
+
+/// Target as a big-endian 256 bit number, `None` for a negative or overflowing target.
+pub fn bits_to_target(bits: u32) -> Option<[u8; 32]>{
+    let exp = (bits >> 24) as usize;
+    let mant = bits & 0x007f_ffff;
+    if bits & 0x0080_0000 != 0{
+        return None
+    }
+    let mut target = [0u8; 32];
+    if exp <= 3{
+        let m = mant >> (8 * (3 - exp));
+        target[29..].copy_from_slice(&m.to_be_bytes()[1..]);
+    }else{
+        if exp > 32{
+            return None
+        }
+        let m = mant.to_be_bytes();
+        let start = 32 - exp;
+        for (i, &b) in m[1..].iter().enumerate(){
+            target[start + i] = b;
+        }
+    }
+    Some(target)
+}
+
+/// Compact form of a target, the inverse of `bits_to_target`.
+pub fn target_to_bits(target: &[u8; 32]) -> u32{
+    let first = match target.iter().position(|&b| b != 0){
+        Some(i) => i,
+        None => return 0,
+    };
+    let mut exp = (32 - first) as u32;
+    let mut mant = 0u32;
+    for i in 0..3{
+        mant <<= 8;
+        if let Some(&b) = target.get(first + i){
+            mant |= b as u32;
+        }
+    }
+    if exp < 3{
+        mant >>= 8 * (3 - exp);
+    }
+    // the sign bit must stay clear
+    if mant & 0x0080_0000 != 0{
+        mant >>= 8;
+        exp += 1;
+    }
+    (exp << 24) | mant
+}
+
+/// Whether `hash` meets the target `bits`, which may not be easier than `pow_limit`.
+pub fn check_proof_of_work(hash: &[u8; 32], bits: u32, pow_limit: u32) -> bool{
+    let (target, limit) = match (bits_to_target(bits), bits_to_target(pow_limit)){
+        (Some(t), Some(l)) => (t, l),
+        _ => return false,
+    };
+    if target == [0; 32] || target > limit{
+        return false
+    }
+    hash <= &target
+}
+
+/// Expected number of hashes to find a block with `bits`, `2^256 / (target + 1)`.
+/// Targets below `2^128` saturate, no chain gets anywhere near them.
+pub fn block_work(bits: u32) -> u128{
+    let t = match bits_to_target(bits){
+        Some(t) => t,
+        None => return 0,
+    };
+    let mut hi = [0u8; 16];
+    let mut lo = [0u8; 16];
+    hi.copy_from_slice(&t[..16]);
+    lo.copy_from_slice(&t[16..]);
+    let (hi, lo) = (u128::from_be_bytes(hi), u128::from_be_bytes(lo));
+    if hi == 0{
+        return u128::MAX
+    }
+    // divisor target + 1, at least 2^128 so the quotient fits
+    let (dl, carry) = lo.overflowing_add(1);
+    let dh = match hi.checked_add(carry as u128){
+        Some(dh) => dh,
+        None => return 1,
+    };
+    // long division of 2^256, the remainder keeps 257 bits with `top`
+    let (mut rh, mut rl, mut q) = (0u128, 1u128, 0u128);
+    for _ in 0..256{
+        let top = rh >> 127 == 1;
+        rh = (rh << 1) | (rl >> 127);
+        rl <<= 1;
+        q <<= 1;
+        if top || (rh, rl) >= (dh, dl){
+            let (l, borrow) = rl.overflowing_sub(dl);
+            rh = rh.wrapping_sub(dh).wrapping_sub(borrow as u128);
+            rl = l;
+            q |= 1;
+        }
+    }
+    q
+}
+
+/// New target after a retarget interval that took `actual_timespan` seconds instead of
+/// `target_timespan`. The adjustment is limited to a factor of 4 in either direction.
+pub fn retarget(bits: u32, actual_timespan: u64, target_timespan: u64, pow_limit: u32) -> u32{
+    let actual = actual_timespan.max(target_timespan / 4).min(target_timespan * 4);
+    let exp = (bits >> 24) as i64;
+    let mant = (bits & 0x007f_ffff) as u128;
+
+    // keep 4 more bytes of precision before dividing
+    let mut value = (mant << 32) * actual as u128 / target_timespan as u128;
+    let mut exp = exp - 4;
+    while value > 0x007f_ffff{
+        value >>= 8;
+        exp += 1;
+    }
+    while exp < 3 && value != 0{
+        value >>= 8;
+        exp += 1;
+    }
+    if value == 0 || exp <= 0{
+        return pow_limit
+    }
+    let bits = ((exp as u32) << 24) | value as u32;
+    match (bits_to_target(bits), bits_to_target(pow_limit)){
+        (Some(t), Some(l)) if t <= l => bits,
+        _ => pow_limit,
+    }
+}
+
+#[cfg(test)]
+mod pow_test{
+    use super::*;
+
+    #[test]
+    fn test_bits_to_target() {
+        let t = bits_to_target(0x1d00ffff).unwrap();
+        assert_eq!([0, 0, 0, 0, 0xff, 0xff, 0], t[..7]);
+        assert!(t[7..].iter().all(|&b| b == 0));
+        assert_eq!(0x1d00ffff, target_to_bits(&t));
+
+        let t = bits_to_target(0x207fffff).unwrap();
+        assert_eq!([0x7f, 0xff, 0xff, 0], t[..4]);
+        assert_eq!(0x207fffff, target_to_bits(&t));
+
+        assert_eq!(None, bits_to_target(0x04923456));
+        assert_eq!(None, bits_to_target(0x2200ffff));
+        assert_eq!(0x03123456, target_to_bits(&bits_to_target(0x03123456).unwrap()));
+    }
+
+    #[test]
+    fn test_check_proof_of_work() {
+        let limit = 0x1f00ffff;
+        let mut hash = [0u8; 32];
+        hash[2] = 0xff;
+        hash[3] = 0xff;
+        assert!(check_proof_of_work(&hash, limit, limit));
+        hash[4] = 1;
+        assert!(!check_proof_of_work(&hash, limit, limit));
+        // easier than the limit
+        assert!(!check_proof_of_work(&[0; 32], 0x2000ffff, limit));
+    }
+
+    #[test]
+    fn test_block_work() {
+        assert_eq!(2, block_work(0x207fffff));
+        // the work of the genesis block of Bitcoin
+        assert_eq!(0x1_0001_0001, block_work(0x1d00ffff));
+        // half the target, twice the work
+        let ratio = block_work(0x1e00ffff) as f64 / block_work(0x1e01fffe) as f64;
+        assert!((ratio - 2.0).abs() < 1e-4);
+        assert_eq!(0, block_work(0x04923456));
+        assert_eq!(u128::MAX, block_work(0x1000ffff));
+    }
+
+    #[test]
+    fn test_retarget() {
+        let limit = 0x1f00ffff;
+        let bits = 0x1e00ffff;
+        assert_eq!(bits, retarget(bits, 1000, 1000, limit));
+        // twice as slow -> twice the target
+        assert_eq!(0x1e01fffe, retarget(bits, 2000, 1000, limit));
+        // clamped to a factor of 4
+        assert_eq!(0x1d3fffc0, retarget(bits, 1, 1000, limit));
+        // never easier than the limit
+        assert_eq!(limit, retarget(0x1f00ffff, 4000, 1000, limit));
+    }
+}
@@ -0,0 +1,320 @@
+//! Deterministic random chains to test consensus invariants.
+//!
+//! A scenario runs several miners, each a `BlockChain` extending its own branch with
+//! random spends of its coins. Miners fork by copying another miner, so branches share a
+//! prefix and spend the same coins differently. Their blocks are delivered to a node which
+//! reorganizes to the branch with the most work. Some blocks spend an output twice and must
+//! be rejected without changing the miner.
+//!
+//! After every step the node is checked:
+//! - the coins add up to the subsidies of the blocks of the active chain
+//! - the UTXO set is the one of a node replaying the active chain from genesis
+//! - no output is spent twice along the active chain
+//!
+//! The same seed always gives the same chains.
+//!
+//! The cargo-fuzz targets in `fuzz/` decode blocks, headers and transactions, validate blocks
+//! on top of a scenario and run scenarios seeded by the input, e.g. `cargo fuzz run scenario`.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::clock::MockClock;
+use crate::transaction::{InputTx, OutPoint, OutputTx, Trans, TxIn};
+use crate::{Block, BlockChain, ChainError, ChainParams, SimpleTx};
+
+const ADDRS: [&str; 3] = ["Alice", "Bob", "Carol"];
+const MAX_MINERS: usize = 4;
+
+/// A broken invariant, or a block handled against expectations.
+#[derive(Debug, PartialEq)]
+pub enum Violation{
+    /// The coinbase at `height` claims more than the subsidy and the fees, or the coins of the
+    /// chain at the tip `height` differ from the outputs created and not spent.
+    Supply{ height: usize, expected: u64, actual: u64 },
+    /// The UTXO set differs from a replay of the chain up to `height`.
+    UtxoMismatch{ height: usize },
+    /// An output spent twice along the active chain, or never created.
+    DoubleSpend(OutPoint),
+    /// A block built by a miner was refused.
+    Rejected(ChainError),
+    /// A block spending an output twice was accepted.
+    Accepted([u8; 32]),
+}
+
+/// What happened during a scenario.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats{
+    pub blocks: usize,
+    pub forks: usize,
+    /// Blocks disconnected from the active chain of the node.
+    pub disconnected: usize,
+    /// Invalid blocks refused by a miner.
+    pub rejected: usize,
+}
+
+/// SplitMix64, enough to drive the choices of a scenario.
+struct Rng(u64);
+
+impl Rng{
+    fn next(&mut self) -> u64{
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// In `0..n`, `n` is not 0.
+    fn below(&mut self, n: usize) -> usize{
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// Regtest with mature coinbases after one block and a halving every 10 blocks,
+/// so that a short scenario spends coinbases and crosses halvings.
+pub fn params() -> ChainParams{
+    let mut params = ChainParams::regtest();
+    params.coinbase_maturity = 1;
+    params.subsidy_halving_interval = 10;
+    params
+}
+
+pub struct Scenario{
+    rng: Rng,
+    node: BlockChain,
+    miners: Vec<BlockChain>,
+    stats: Stats,
+}
+
+impl Scenario{
+    pub fn new(seed: u64) -> Scenario{
+        let params = params();
+        // far enough ahead that no block is too new
+        let clock = Arc::new(MockClock::new(params.genesis_timestamp + 1_000_000_000));
+        let node = BlockChain::with_clock(params, clock);
+        let miners = vec![node.clone(), node.clone()];
+        Scenario{ rng: Rng(seed), node, miners, stats: Stats::default() }
+    }
+
+    pub fn node(&self) -> &BlockChain{
+        &self.node
+    }
+
+    pub fn stats(&self) -> &Stats{
+        &self.stats
+    }
+
+    /// Run `steps` random steps, then deliver every branch. Stops at the first violation.
+    pub fn run(&mut self, steps: usize) -> Result<&Stats, Violation>{
+        for _ in 0..steps{
+            self.step()?;
+        }
+        for i in 0..self.miners.len(){
+            self.deliver(i)?;
+        }
+        Ok(&self.stats)
+    }
+
+    /// Mine, fork or deliver a branch to the node, then check the node.
+    pub fn step(&mut self) -> Result<(), Violation>{
+        let i = self.rng.below(self.miners.len());
+        match self.rng.below(20){
+            0..=9 => self.mine(i)?,
+            10..=12 => self.fork(i),
+            _ => self.deliver(i)?,
+        }
+        check_invariants(&self.node)
+    }
+
+    fn mine(&mut self, i: usize) -> Result<(), Violation>{
+        let mut txs = Vec::new();
+        let mut coins = self.coins(i);
+        for _ in 0..self.rng.below(4){
+            if coins.is_empty(){
+                break
+            }
+            let n = 1 + self.rng.below(2.min(coins.len()));
+            let inputs: Vec<_> = (0..n).map(|_| coins.swap_remove(self.rng.below(coins.len()))).collect();
+            txs.push(self.spend(inputs));
+        }
+
+        let ts = self.miners[i].tip().header().timestamp() + 1 + self.rng.below(600) as u64;
+        let addr = ADDRS[self.rng.below(ADDRS.len())].to_string();
+        // now and then spend an output of the block a second time
+        if !txs.is_empty() && self.rng.below(10) == 0{
+            let mut again = self.spend(vec![txs[0].input.0[0].clone()]);
+            again.output.0[0].addr = "Mallory".to_string();
+            txs.push(again);
+            let miner = &mut self.miners[i];
+            let block = miner.create_block(ts, addr, txs).map_err(Violation::Rejected)?;
+            let tip = miner.tip().hash();
+            if miner.add_block(block.clone()).is_ok() || miner.tip().hash() != tip{
+                return Err(Violation::Accepted(block.hash()))
+            }
+            self.stats.rejected += 1;
+            return Ok(())
+        }
+        let block = self.miners[i].create_block(ts, addr, txs).map_err(Violation::Rejected)?;
+        self.miners[i].add_block(block).map_err(Violation::Rejected)?;
+        self.stats.blocks += 1;
+        Ok(())
+    }
+
+    /// Mature coins of miner `i`, in a fixed order.
+    fn coins(&self, i: usize) -> Vec<TxIn<String, crate::SimpleValue>>{
+        let miner = &self.miners[i];
+        let height = miner.height() + 1;
+        let maturity = miner.params().coinbase_maturity;
+        let mut coins: Vec<_> = miner.utxo().iter()
+            .filter(|(_, e)| !e.coinbase || height - e.height >= maturity)
+            .map(|(op, e)| TxIn::new(*op, e.output.addr.clone(), e.output.val.clone()))
+            .collect();
+        coins.sort_by_key(|txin| txin.prev_out);
+        coins
+    }
+
+    /// Pay the value of `inputs` to up to 3 outputs, keeping a random fee.
+    fn spend(&mut self, inputs: Vec<TxIn<String, crate::SimpleValue>>) -> SimpleTx{
+        let total: u64 = inputs.iter().map(|txin| txin.val.val).sum();
+        let fee = self.rng.next() % (total / 10 + 1);
+        let n = 1 + self.rng.below(3);
+        let mut left = total - fee;
+        let mut outputs = Vec::with_capacity(n);
+        for k in 0..n{
+            let val = if k + 1 == n { left } else { self.rng.next() % (left + 1) };
+            left -= val;
+            outputs.push(Trans{ addr: ADDRS[self.rng.below(ADDRS.len())].to_string(), val: val.into() });
+        }
+        SimpleTx{ input: InputTx(inputs), output: OutputTx(outputs), lock_time: 0, witness: vec![] }
+    }
+
+    /// A new miner starting from the tip of miner `i`.
+    fn fork(&mut self, i: usize){
+        let miner = self.miners[i].clone();
+        if self.miners.len() < MAX_MINERS{
+            self.miners.push(miner);
+        }else{
+            let j = (i + 1 + self.rng.below(MAX_MINERS - 1)) % MAX_MINERS;
+            self.miners[j] = miner;
+        }
+        self.stats.forks += 1;
+    }
+
+    /// Send the branch of miner `i` to the node.
+    fn deliver(&mut self, i: usize) -> Result<(), Violation>{
+        let events = self.node.subscribe();
+        let miner = &self.miners[i];
+        for h in 1..=miner.height(){
+            match self.node.add_block(miner.block(h).unwrap().clone()){
+                Ok(()) | Err(ChainError::DuplicateBlock) => {},
+                Err(e) => return Err(Violation::Rejected(e)),
+            }
+        }
+        self.stats.disconnected += events.try_iter()
+            .filter(|e| matches!(e, crate::ChainEvent::BlockDisconnected{ .. }))
+            .count();
+        Ok(())
+    }
+}
+
+/// Check the supply, the UTXO set and the spends of the active chain of `chain`.
+/// A coinbase may claim less than the subsidy and the fees, the rest is never created.
+pub fn check_invariants(chain: &BlockChain) -> Result<(), Violation>{
+    let height = chain.height();
+    // value of every spendable output
+    let mut created: HashMap<OutPoint, u64> = HashMap::new();
+    let mut spent = HashSet::new();
+    let mut replay = BlockChain::with_clock(chain.params().clone(), Arc::new(MockClock::new(u64::MAX / 2)));
+    for h in 1..=height{
+        let block: &Block = chain.block(h).ok_or(Violation::UtxoMismatch{ height })?;
+        let mut fees = 0u64;
+        let mut claimed = 0u64;
+        for tx in block.txs(){
+            let value_out = tx.output.0.iter().fold(0u64, |sum, out| sum.saturating_add(out.val.val));
+            if tx.is_coinbase(){
+                claimed = value_out;
+            }else{
+                let mut value_in = 0u64;
+                for txin in tx.input.0.iter(){
+                    match created.get(&txin.prev_out){
+                        Some(val) if spent.insert(txin.prev_out) => value_in = value_in.saturating_add(*val),
+                        _ => return Err(Violation::DoubleSpend(txin.prev_out)),
+                    }
+                }
+                fees = fees.saturating_add(value_in.saturating_sub(value_out));
+            }
+            let txid = tx.txid();
+            for (j, out) in tx.output.0.iter().enumerate().filter(|(_, out)| !out.is_unspendable()){
+                created.insert(OutPoint::new(txid, j as u32), out.val.val);
+            }
+        }
+        let expected = chain.params().block_subsidy(h).val.saturating_add(fees);
+        if claimed > expected{
+            return Err(Violation::Supply{ height: h, expected, actual: claimed })
+        }
+        replay.add_block(block.clone()).map_err(|_| Violation::UtxoMismatch{ height })?;
+    }
+
+    let expected: u64 = created.iter().filter(|(op, _)| !spent.contains(*op)).map(|(_, val)| val).sum();
+    let actual: u64 = chain.utxo().iter().map(|(_, e)| e.output.val.val).sum();
+    if expected != actual{
+        return Err(Violation::Supply{ height, expected, actual })
+    }
+    if replay.utxo().to_bytes() != chain.utxo().to_bytes(){
+        return Err(Violation::UtxoMismatch{ height })
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod scenario_test{
+    use super::*;
+
+    #[test]
+    fn test_random_chains() {
+        let mut total = Stats::default();
+        for seed in 1..=4{
+            let mut scenario = Scenario::new(seed);
+            let stats = scenario.run(40).unwrap().clone();
+            assert!(scenario.node().height() > 0);
+            total.forks += stats.forks;
+            total.disconnected += stats.disconnected;
+            total.rejected += stats.rejected;
+        }
+        // every kind of event shows up
+        assert!(total.forks > 0 && total.disconnected > 0 && total.rejected > 0, "{:?}", total);
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let mut a = Scenario::new(7);
+        let mut b = Scenario::new(7);
+        a.run(20).unwrap();
+        b.run(20).unwrap();
+        assert_eq!(a.node().tip().hash(), b.node().tip().hash());
+        assert_eq!(a.stats(), b.stats());
+    }
+
+    #[test]
+    fn test_detects_violations() {
+        let mut scenario = Scenario::new(3);
+        scenario.run(10).unwrap();
+        // a chain whose coins were changed behind its back
+        let mut chain = scenario.node().clone();
+        let (op, entry) = chain.utxo().iter().next().map(|(op, e)| (*op, e.clone())).unwrap();
+        chain.state.spend(&op);
+        assert!(matches!(check_invariants(&chain), Err(Violation::Supply{ .. })));
+        let mut moved = entry.clone();
+        moved.output.addr = "Mallory".to_string();
+        chain.state.insert(op, moved);
+        assert_eq!(Err(Violation::UtxoMismatch{ height: chain.height() }), check_invariants(&chain));
+
+        // a miner may leave part of the subsidy unclaimed
+        let mut chain = scenario.node().clone();
+        let cb = SimpleTx::coinbase(chain.height() as u32 + 1, 1.into(), "Miner".to_string());
+        let block = crate::mine_block(&chain, chain.tip().header().timestamp() + 1, vec![cb]);
+        chain.add_block(block).unwrap();
+        assert_eq!(Ok(()), check_invariants(&chain));
+    }
+}
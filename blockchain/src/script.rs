@@ -0,0 +1,695 @@
+//! Spending policies and partially signed transactions.
+//!
+//! Besides plain addresses, outputs can be locked to a policy:
+//! - `multisig:<hex policy>`: bare m-of-n multisig, the policy is visible in the output
+//! - `p2sh:<hex sha256 of the policy>`: pay to script hash, the policy is revealed when spent
+//! - `htlc:<hex contract>`: hashed time-locked contract, see `Htlc`
+//!
+//! A policy is encoded as `m | n | n public keys`. The witness of an input spending it holds
+//! one item per key, a signature of the key or empty, at least `m` of them signed.
+//! A pay to script hash input has the policy as the last item.
+//!
+//! Signatures are schnorrkel signatures of `sighash`, which commits to the txid and so to the
+//! addresses and values of all inputs, and to the index of the input.
+//!
+//! A `Psbt` carries an unsigned transaction and the signatures collected so far between the
+//! signers, `finalize` turns it into the witnesses.
+
+use std::collections::BTreeMap;
+
+use byteorder::{BigEndian, WriteBytesExt};
+use schnorrkel::{signing_context, ExpansionMode, Keypair, MiniSecretKey, PublicKey, Signature};
+
+use crate::encode::{self, Reader};
+use crate::error::ChainError;
+use crate::mkt;
+use crate::transaction::{InputTx, OutPoint, OutputTx, Trans, TxIn, LOCKTIME_THRESHOLD, SEQUENCE_FINAL};
+use crate::{SimpleTx, SimpleValue};
+
+pub const MULTISIG_PREFIX: &str = "multisig:";
+pub const P2SH_PREFIX: &str = "p2sh:";
+pub const HTLC_PREFIX: &str = "htlc:";
+/// Largest number of keys of a policy.
+pub const MAX_MULTISIG_KEYS: usize = 20;
+
+const TX_CONTEXT: &[u8] = b"bchain tx";
+const PSBT_MAGIC: &[u8] = b"psbt";
+
+/// Signatures of at least `m` of the keys are needed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Multisig{
+    m: usize,
+    keys: Vec<[u8; 32]>,
+}
+
+impl Multisig{
+    /// `None` unless `1 <= m <= keys.len() <= MAX_MULTISIG_KEYS` and the keys differ,
+    /// a key given twice would sign twice.
+    pub fn new(m: usize, keys: Vec<[u8; 32]>) -> Option<Multisig>{
+        if m == 0 || m > keys.len() || keys.len() > MAX_MULTISIG_KEYS{
+            return None
+        }
+        if keys.iter().enumerate().any(|(i, key)| keys[..i].contains(key)){
+            return None
+        }
+        Some(Multisig{ m, keys })
+    }
+
+    pub fn threshold(&self) -> usize{
+        self.m
+    }
+
+    pub fn keys(&self) -> &[[u8; 32]]{
+        &self.keys
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut buf = vec![self.m as u8, self.keys.len() as u8];
+        for key in self.keys.iter(){
+            buf.extend(key);
+        }
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Multisig>{
+        let mut r = Reader::new(bytes);
+        let m = r.read_u8()? as usize;
+        let n = r.read_u8()? as usize;
+        let keys = (0..n).map(|_| r.read_hash()).collect::<Option<Vec<_>>>()?;
+        if !r.is_empty(){
+            return None
+        }
+        Multisig::new(m, keys)
+    }
+
+    /// SHA-256 of the policy, committed by its pay to script hash address.
+    pub fn hash(&self) -> [u8; 32]{
+        mkt::sha256(&self.to_bytes())
+    }
+
+    /// Bare multisig address.
+    pub fn address(&self) -> String{
+        format!("{}{}", MULTISIG_PREFIX, encode::to_hex(&self.to_bytes()))
+    }
+
+    /// Pay to script hash address.
+    pub fn p2sh_address(&self) -> String{
+        format!("{}{}", P2SH_PREFIX, encode::to_hex(&self.hash()))
+    }
+
+    /// Check the witness items of an input, one per key.
+    pub fn verify(&self, msg: &[u8; 32], sigs: &[Vec<u8>]) -> Result<(), ChainError>{
+        if sigs.len() != self.keys.len(){
+            return Err(ChainError::BadScript)
+        }
+        let mut valid = 0;
+        for (key, sig) in self.keys.iter().zip(sigs).filter(|(_, sig)| !sig.is_empty()){
+            if !check_signature(key, msg, sig){
+                return Err(ChainError::BadSignature)
+            }
+            valid += 1;
+        }
+        if valid < self.m{
+            return Err(ChainError::BadSignature)
+        }
+        Ok(())
+    }
+}
+
+/// Hashed time-locked contract, the building block of atomic swaps.
+///
+/// The recipient claims the coin with its signature and a preimage of `hash`, the witness
+/// is `signature | preimage`. Once `timeout` is reached, a height or a timestamp like
+/// `lock_time`, the sender takes it back with a transaction whose `lock_time` is at least
+/// `timeout`, the witness is its signature alone.
+/// Encoded as `hash | recipient | sender | timeout`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Htlc{
+    pub hash: [u8; 32],
+    pub recipient: [u8; 32],
+    pub sender: [u8; 32],
+    pub timeout: u32,
+}
+
+impl Htlc{
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut buf = self.hash.to_vec();
+        buf.extend(&self.recipient);
+        buf.extend(&self.sender);
+        buf.write_u32::<BigEndian>(self.timeout).unwrap();
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Htlc>{
+        let mut r = Reader::new(bytes);
+        let htlc = Htlc{ hash: r.read_hash()?, recipient: r.read_hash()?, sender: r.read_hash()?, timeout: r.read_u32()? };
+        if r.is_empty() { Some(htlc) } else { None }
+    }
+
+    pub fn address(&self) -> String{
+        format!("{}{}", HTLC_PREFIX, encode::to_hex(&self.to_bytes()))
+    }
+
+    fn verify(&self, tx: &SimpleTx, index: usize, items: &[Vec<u8>]) -> Result<(), ChainError>{
+        let msg = sighash(tx, index);
+        match items{
+            [sig, preimage] => {
+                if mkt::sha256(preimage) != self.hash{
+                    return Err(ChainError::BadScript)
+                }
+                if !check_signature(&self.recipient, &msg, sig){
+                    return Err(ChainError::BadSignature)
+                }
+            },
+            [sig] => {
+                // `lock_time` is only enforced if the input is not final
+                let same_kind = (tx.lock_time < LOCKTIME_THRESHOLD) == (self.timeout < LOCKTIME_THRESHOLD);
+                if !same_kind || tx.lock_time < self.timeout || tx.input.0[index].sequence == SEQUENCE_FINAL{
+                    return Err(ChainError::BadScript)
+                }
+                if !check_signature(&self.sender, &msg, sig){
+                    return Err(ChainError::BadSignature)
+                }
+            },
+            _ => return Err(ChainError::BadScript),
+        }
+        Ok(())
+    }
+
+    /// A transaction spending the contract output `prev_out` of value `val` to `to`.
+    fn spend(&self, prev_out: OutPoint, val: u64, to: &str, fee: u64, lock_time: u32, sequence: u32) -> SimpleTx{
+        SimpleTx{
+            input: InputTx(vec![TxIn::new(prev_out, self.address(), SimpleValue::from(val)).with_sequence(sequence)]),
+            output: OutputTx(vec![Trans{ addr: to.to_string(), val: SimpleValue::from(val.saturating_sub(fee)) }]),
+            lock_time,
+            witness: vec![],
+        }
+    }
+
+    /// Claim by the recipient, revealing `preimage`.
+    pub fn claim(&self, prev_out: OutPoint, val: u64, to: &str, fee: u64, key: &SigningKey, preimage: &[u8]) -> SimpleTx{
+        let mut tx = self.spend(prev_out, val, to, fee, 0, SEQUENCE_FINAL);
+        tx.witness = vec![vec![key.sign(&tx, 0).to_vec(), preimage.to_vec()]];
+        tx
+    }
+
+    /// Refund to the sender, it can be mined once the timeout is passed.
+    pub fn refund(&self, prev_out: OutPoint, val: u64, to: &str, fee: u64, key: &SigningKey) -> SimpleTx{
+        let mut tx = self.spend(prev_out, val, to, fee, self.timeout, SEQUENCE_FINAL - 1);
+        tx.witness = vec![vec![key.sign(&tx, 0).to_vec()]];
+        tx
+    }
+
+    /// The preimage revealed by a transaction claiming this contract, e.g. on the other chain of a swap.
+    pub fn preimage_in(&self, tx: &SimpleTx) -> Option<Vec<u8>>{
+        let addr = self.address();
+        tx.input.0.iter().zip(tx.witness.iter())
+            .filter(|(txin, _)| txin.addr == addr)
+            .find_map(|(_, items)| match items.as_slice(){
+                [_, preimage] if mkt::sha256(preimage) == self.hash => Some(preimage.clone()),
+                _ => None,
+            })
+    }
+}
+
+/// What an address is locked to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Lock{
+    Multisig(Multisig),
+    ScriptHash([u8; 32]),
+    Htlc(Htlc),
+}
+
+impl Lock{
+    /// `None` for plain addresses, an error for a malformed policy address.
+    pub fn of(addr: &str) -> Result<Option<Lock>, ChainError>{
+        if let Some(hex) = addr.strip_prefix(MULTISIG_PREFIX){
+            return encode::from_hex(hex)
+                .and_then(|b| Multisig::from_bytes(&b))
+                .map(|p| Some(Lock::Multisig(p)))
+                .ok_or(ChainError::BadScript)
+        }
+        if let Some(hex) = addr.strip_prefix(P2SH_PREFIX){
+            let bytes = encode::from_hex(hex).filter(|b| b.len() == 32).ok_or(ChainError::BadScript)?;
+            let mut hash = [0; 32];
+            hash.copy_from_slice(&bytes);
+            return Ok(Some(Lock::ScriptHash(hash)))
+        }
+        if let Some(hex) = addr.strip_prefix(HTLC_PREFIX){
+            return encode::from_hex(hex)
+                .and_then(|b| Htlc::from_bytes(&b))
+                .map(|htlc| Some(Lock::Htlc(htlc)))
+                .ok_or(ChainError::BadScript)
+        }
+        Ok(None)
+    }
+}
+
+/// Message signed for input `index` of `tx`: SHA-256 of `txid | index`.
+pub fn sighash(tx: &SimpleTx, index: usize) -> [u8; 32]{
+    let mut buf = tx.txid().to_vec();
+    buf.write_u32::<BigEndian>(index as u32).unwrap();
+    mkt::sha256(&buf)
+}
+
+pub(crate) fn check_signature(key: &[u8; 32], msg: &[u8; 32], sig: &[u8]) -> bool{
+    match (PublicKey::from_bytes(key), Signature::from_bytes(sig)){
+        (Ok(key), Ok(sig)) => key.verify(signing_context(TX_CONTEXT).bytes(msg), &sig).is_ok(),
+        _ => false,
+    }
+}
+
+/// Check the witness of input `index` against the address it spends.
+/// Inputs of plain addresses need no signature.
+pub fn verify_input(tx: &SimpleTx, index: usize) -> Result<(), ChainError>{
+    let items = tx.witness.get(index).map(|w| w.as_slice()).unwrap_or(&[]);
+    let msg = sighash(tx, index);
+    match Lock::of(&tx.input.0[index].addr)?{
+        None => Ok(()),
+        Some(Lock::Multisig(policy)) => policy.verify(&msg, items),
+        Some(Lock::ScriptHash(hash)) => {
+            let (last, sigs) = items.split_last().ok_or(ChainError::BadScript)?;
+            let policy = Multisig::from_bytes(last).ok_or(ChainError::BadScript)?;
+            if policy.hash() != hash{
+                return Err(ChainError::BadScript)
+            }
+            policy.verify(&msg, sigs)
+        },
+        Some(Lock::Htlc(htlc)) => htlc.verify(tx, index, items),
+    }
+}
+
+/// A key signing inputs.
+pub struct SigningKey{
+    keypair: Keypair,
+}
+
+impl SigningKey{
+    /// Keys derived from a secret seed.
+    pub fn from_seed(seed: &[u8; 32]) -> SigningKey{
+        let secret = MiniSecretKey::from_bytes(seed).expect("32 bytes");
+        SigningKey{ keypair: secret.expand_to_keypair(ExpansionMode::Uniform) }
+    }
+
+    pub fn public_key(&self) -> [u8; 32]{
+        self.keypair.public.to_bytes()
+    }
+
+    /// Signature of input `index` of `tx`.
+    pub fn sign(&self, tx: &SimpleTx, index: usize) -> [u8; 64]{
+        self.sign_hash(&sighash(tx, index))
+    }
+
+    /// Signature of a message hash, e.g. of an account transfer.
+    pub fn sign_hash(&self, msg: &[u8; 32]) -> [u8; 64]{
+        self.keypair.sign(signing_context(TX_CONTEXT).bytes(msg)).to_bytes()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PsbtError{
+    /// The transactions to combine differ.
+    TxMismatch,
+    /// The input at this index lacks its policy or enough signatures.
+    Incomplete(usize),
+}
+
+/// Policy and signatures collected for an input.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PsbtInput{
+    /// Known from a bare multisig address, added with `add_policy` for pay to script hash.
+    pub policy: Option<Multisig>,
+    /// Signatures by public key.
+    pub sigs: BTreeMap<[u8; 32], [u8; 64]>,
+}
+
+/// A transaction waiting for the signatures of its inputs.
+#[derive(Clone, Debug)]
+pub struct Psbt{
+    tx: SimpleTx,
+    inputs: Vec<PsbtInput>,
+}
+
+impl Psbt{
+    /// Start from `tx`, its witnesses are dropped.
+    pub fn new(mut tx: SimpleTx) -> Result<Psbt, ChainError>{
+        tx.witness.clear();
+        let inputs = tx.input.0.iter().map(|txin| {
+            let policy = match Lock::of(&txin.addr)?{
+                Some(Lock::Multisig(policy)) => Some(policy),
+                _ => None,
+            };
+            Ok(PsbtInput{ policy, sigs: BTreeMap::new() })
+        }).collect::<Result<Vec<_>, ChainError>>()?;
+        Ok(Psbt{ tx, inputs })
+    }
+
+    pub fn tx(&self) -> &SimpleTx{
+        &self.tx
+    }
+
+    pub fn inputs(&self) -> &[PsbtInput]{
+        &self.inputs
+    }
+
+    /// Reveal `policy` for the pay to script hash inputs locked to it.
+    /// Returns the number of inputs it was added to.
+    pub fn add_policy(&mut self, policy: &Multisig) -> usize{
+        let addr = policy.p2sh_address();
+        let mut added = 0;
+        for (txin, input) in self.tx.input.0.iter().zip(self.inputs.iter_mut()){
+            if txin.addr == addr && input.policy.is_none(){
+                input.policy = Some(policy.clone());
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// Sign every input whose policy has the key of `key`, returns the number of inputs signed.
+    pub fn sign(&mut self, key: &SigningKey) -> usize{
+        let public = key.public_key();
+        let mut signed = 0;
+        for (i, input) in self.inputs.iter_mut().enumerate(){
+            if input.policy.as_ref().is_some_and(|p| p.keys().contains(&public)){
+                input.sigs.insert(public, key.sign(&self.tx, i));
+                signed += 1;
+            }
+        }
+        signed
+    }
+
+    /// Merge the policies and signatures of another copy of the same transaction.
+    pub fn combine(&mut self, other: &Psbt) -> Result<(), PsbtError>{
+        if self.tx.txid() != other.tx.txid(){
+            return Err(PsbtError::TxMismatch)
+        }
+        for (input, theirs) in self.inputs.iter_mut().zip(other.inputs.iter()){
+            if input.policy.is_none(){
+                input.policy = theirs.policy.clone();
+            }
+            input.sigs.extend(theirs.sigs.iter().map(|(k, s)| (*k, *s)));
+        }
+        Ok(())
+    }
+
+    /// Whether every input can be finalized.
+    pub fn is_complete(&self) -> bool{
+        (0..self.inputs.len()).all(|i| self.witness(i).is_ok())
+    }
+
+    /// Witness of input `index` from the first `m` valid signatures in the order of the keys.
+    fn witness(&self, index: usize) -> Result<Vec<Vec<u8>>, PsbtError>{
+        let input = &self.inputs[index];
+        let lock = Lock::of(&self.tx.input.0[index].addr).map_err(|_| PsbtError::Incomplete(index))?;
+        match lock{
+            None => return Ok(vec![]),
+            // contracts are spent with their own templates
+            Some(Lock::Htlc(_)) => return Err(PsbtError::Incomplete(index)),
+            _ => {},
+        }
+        let policy = input.policy.as_ref().ok_or(PsbtError::Incomplete(index))?;
+        let msg = sighash(&self.tx, index);
+        let mut count = 0;
+        let mut items: Vec<Vec<u8>> = policy.keys().iter().map(|key| {
+            match input.sigs.get(key){
+                Some(sig) if count < policy.threshold() && check_signature(key, &msg, sig) => {
+                    count += 1;
+                    sig.to_vec()
+                },
+                _ => vec![],
+            }
+        }).collect();
+        if count < policy.threshold(){
+            return Err(PsbtError::Incomplete(index))
+        }
+        if let Some(Lock::ScriptHash(_)) = lock{
+            items.push(policy.to_bytes());
+        }
+        Ok(items)
+    }
+
+    /// The signed transaction.
+    pub fn finalize(&self) -> Result<SimpleTx, PsbtError>{
+        let witness = (0..self.inputs.len()).map(|i| self.witness(i)).collect::<Result<Vec<_>, _>>()?;
+        let mut tx = self.tx.clone();
+        if witness.iter().any(|w| !w.is_empty()){
+            tx.witness = witness;
+        }
+        Ok(tx)
+    }
+
+    /// `"psbt" | tx | per input: policy or empty | count | (public key | signature)`
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut buf = PSBT_MAGIC.to_vec();
+        encode::write_var_bytes(&mut buf, &self.tx.to_bytes());
+        for input in self.inputs.iter(){
+            encode::write_var_bytes(&mut buf, &input.policy.as_ref().map(|p| p.to_bytes()).unwrap_or_default());
+            encode::write_varint(&mut buf, input.sigs.len() as u64);
+            for (key, sig) in input.sigs.iter(){
+                buf.extend(key);
+                buf.extend(&sig[..]);
+            }
+        }
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Psbt>{
+        let mut r = Reader::new(bytes);
+        if r.read_bytes(PSBT_MAGIC.len())? != PSBT_MAGIC{
+            return None
+        }
+        let tx = SimpleTx::from_bytes(r.read_var_bytes()?)?;
+        let mut inputs = Vec::with_capacity(tx.input.0.len());
+        for _ in 0..tx.input.0.len(){
+            let policy = match r.read_var_bytes()?{
+                [] => None,
+                b => Some(Multisig::from_bytes(b)?),
+            };
+            let n = r.read_count(96)?;
+            let mut sigs = BTreeMap::new();
+            for _ in 0..n{
+                let key = r.read_hash()?;
+                let mut sig = [0; 64];
+                sig.copy_from_slice(r.read_bytes(64)?);
+                sigs.insert(key, sig);
+            }
+            inputs.push(PsbtInput{ policy, sigs });
+        }
+        if !r.is_empty() || tx.has_witness(){
+            return None
+        }
+        Some(Psbt{ tx, inputs })
+    }
+}
+
+#[cfg(test)]
+mod script_test{
+    use super::*;
+    use std::sync::Arc;
+    use crate::clock::MockClock;
+    use crate::transaction::{InputTx, OutPoint, OutputTx, Trans, TxIn};
+    use crate::{mine_block, test_chain, BlockChain, ChainParams, COIN};
+
+    fn keys() -> Vec<SigningKey>{
+        (1..=3).map(|i| SigningKey::from_seed(&[i; 32])).collect()
+    }
+
+    /// A chain whose first coinbase pays 50 coins to `addr`, and a transaction spending it.
+    fn setup(addr: &str) -> (BlockChain, SimpleTx){
+        let g = ChainParams::regtest().genesis_timestamp;
+        let mut chain = test_chain(Arc::new(MockClock::new(g + 1_000_000)));
+        let cb = SimpleTx::coinbase(1, (50 * COIN).into(), addr.to_string());
+        let op = OutPoint::new(cb.txid(), 0);
+        chain.add_block(mine_block(&chain, g + 100, vec![cb])).unwrap();
+        let tx = SimpleTx{
+            input: InputTx(vec![TxIn::new(op, addr.to_string(), (50 * COIN).into())]),
+            output: OutputTx(vec![Trans{addr: "Bob".to_string(), val: (49 * COIN).into()}]),
+            lock_time: 0,
+            witness: vec![],
+        };
+        (chain, tx)
+    }
+
+    /// Check `tx` for the next block of `chain`.
+    fn check(chain: &BlockChain, tx: &SimpleTx) -> Result<(), ChainError>{
+        chain.check_tx(tx, chain.height() + 1, |op| chain.utxo().get(op).cloned()).map(|_| ())
+    }
+
+    /// Mine `txs` in the next block of `chain`.
+    fn mine(chain: &mut BlockChain, txs: Vec<SimpleTx>){
+        let ts = chain.tip().header().timestamp() + 10;
+        let block = chain.create_block(ts, "Miner".to_string(), txs).unwrap();
+        chain.add_block(block).unwrap();
+    }
+
+    /// A chain where `from` locked 49 coins in `htlc`, and the output holding them.
+    fn lock(from: &str, htlc: &Htlc) -> (BlockChain, OutPoint){
+        let (mut chain, mut tx) = setup(from);
+        tx.output.0[0].addr = htlc.address();
+        let op = OutPoint::new(tx.txid(), 0);
+        mine(&mut chain, vec![tx]);
+        (chain, op)
+    }
+
+    #[test]
+    fn test_policy_encoding() {
+        let keys: Vec<_> = keys().iter().map(|k| k.public_key()).collect();
+        assert!(Multisig::new(0, keys.clone()).is_none());
+        assert!(Multisig::new(4, keys.clone()).is_none());
+        assert!(Multisig::new(2, vec![keys[0], keys[1], keys[0]]).is_none());
+        let policy = Multisig::new(2, keys).unwrap();
+        let mut twice = policy.to_bytes();
+        twice.truncate(2 + 32 * 2);
+        twice.extend(&policy.keys()[0]);
+        assert_eq!(None, Multisig::from_bytes(&twice));
+        assert_eq!(Some(policy.clone()), Multisig::from_bytes(&policy.to_bytes()));
+        assert_eq!(Ok(Some(Lock::Multisig(policy.clone()))), Lock::of(&policy.address()));
+        assert_eq!(Ok(Some(Lock::ScriptHash(policy.hash()))), Lock::of(&policy.p2sh_address()));
+        assert_eq!(Ok(None), Lock::of("Alice"));
+        assert_eq!(Err(ChainError::BadScript), Lock::of("p2sh:00"));
+    }
+
+    #[test]
+    fn test_multisig_offline_signing() {
+        let keys = keys();
+        let policy = Multisig::new(2, keys.iter().map(|k| k.public_key()).collect()).unwrap();
+        let (mut chain, tx) = setup(&policy.address());
+        assert_eq!(Err(ChainError::BadScript), check(&chain, &tx));
+
+        // two signers sign their copies offline
+        let unsigned = Psbt::new(tx.clone()).unwrap();
+        let mut first = Psbt::from_bytes(&unsigned.to_bytes()).unwrap();
+        assert_eq!(1, first.sign(&keys[0]));
+        assert!(!first.is_complete());
+        assert_eq!(Some(PsbtError::Incomplete(0)), first.finalize().err());
+        let mut second = unsigned.clone();
+        second.sign(&keys[2]);
+
+        let mut combined = Psbt::from_bytes(&first.to_bytes()).unwrap();
+        combined.combine(&second).unwrap();
+        assert!(combined.is_complete());
+        let signed = combined.finalize().unwrap();
+        assert_eq!(tx.txid(), signed.txid());
+        assert_eq!(Ok(()), check(&chain, &signed));
+
+        // one signature is not enough, a bad one is rejected
+        let mut one = signed.clone();
+        one.witness[0][2].clear();
+        assert_eq!(Err(ChainError::BadSignature), check(&chain, &one));
+        let mut forged = signed.clone();
+        forged.witness[0][0][0] ^= 1;
+        assert_eq!(Err(ChainError::BadSignature), check(&chain, &forged));
+        // signatures of another input do not count
+        let mut moved = signed.clone();
+        moved.witness[0][0] = keys[0].sign(&tx, 1).to_vec();
+        assert_eq!(Err(ChainError::BadSignature), check(&chain, &moved));
+
+        let mut other = tx.clone();
+        other.output.0[0].addr = "Carol".to_string();
+        assert_eq!(Err(PsbtError::TxMismatch), combined.combine(&Psbt::new(other).unwrap()));
+
+        let g = chain.params().genesis_timestamp;
+        let block = chain.create_block(g + 200, "Miner".to_string(), vec![signed]).unwrap();
+        chain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn test_pay_to_script_hash() {
+        let keys = keys();
+        let policy = Multisig::new(2, keys.iter().map(|k| k.public_key()).collect()).unwrap();
+        let (chain, tx) = setup(&policy.p2sh_address());
+
+        let mut psbt = Psbt::new(tx).unwrap();
+        // the policy is not known from the address
+        assert_eq!(0, psbt.sign(&keys[0]));
+        assert_eq!(1, psbt.add_policy(&policy));
+        psbt.sign(&keys[0]);
+        psbt.sign(&keys[1]);
+        psbt.sign(&keys[2]);
+        let signed = psbt.finalize().unwrap();
+        // only the threshold is used, followed by the policy
+        assert_eq!(4, signed.witness[0].len());
+        assert!(signed.witness[0][2].is_empty());
+        assert_eq!(Ok(()), check(&chain, &signed));
+
+        let other = Multisig::new(1, vec![keys[0].public_key()]).unwrap();
+        let mut wrong = signed.clone();
+        wrong.witness[0] = vec![keys[0].sign(&signed, 0).to_vec(), other.to_bytes()];
+        assert_eq!(Err(ChainError::BadScript), check(&chain, &wrong));
+        let mut missing = signed;
+        missing.witness.clear();
+        assert_eq!(Err(ChainError::BadScript), check(&chain, &missing));
+    }
+
+    #[test]
+    fn test_htlc_atomic_swap() {
+        let (alice, bob) = (SigningKey::from_seed(&[1; 32]), SigningKey::from_seed(&[2; 32]));
+        let secret = b"known to Alice only".to_vec();
+        let hash = mkt::sha256(&secret);
+        // Alice pays Bob on chain A, Bob pays Alice on chain B with a shorter timeout,
+        // so that Bob still has time to claim once Alice revealed the secret
+        let on_a = Htlc{ hash, recipient: bob.public_key(), sender: alice.public_key(), timeout: 20 };
+        let on_b = Htlc{ hash, recipient: alice.public_key(), sender: bob.public_key(), timeout: 10 };
+        assert_eq!(Some(on_a.clone()), Htlc::from_bytes(&on_a.to_bytes()));
+        assert_eq!(Ok(Some(Lock::Htlc(on_a.clone()))), Lock::of(&on_a.address()));
+        let (mut chain_a, op_a) = lock("Alice", &on_a);
+        let (mut chain_b, op_b) = lock("Bob", &on_b);
+
+        // neither a wrong secret nor an early refund works
+        let guess = on_b.claim(op_b, 49 * COIN, "Alice", COIN, &alice, b"guess");
+        assert_eq!(Err(ChainError::BadScript), check(&chain_b, &guess));
+        let early = on_b.refund(op_b, 49 * COIN, "Bob", COIN, &bob);
+        assert_eq!(Err(ChainError::NonFinal), check(&chain_b, &early));
+        let stolen = on_b.claim(op_b, 49 * COIN, "Bob", COIN, &bob, &secret);
+        assert_eq!(Err(ChainError::BadSignature), check(&chain_b, &stolen));
+
+        // Alice claims on chain B, revealing the secret
+        let claim_b = on_b.claim(op_b, 49 * COIN, "Alice", COIN, &alice, &secret);
+        assert_eq!(Psbt::new(claim_b.clone()).unwrap().finalize().err(), Some(PsbtError::Incomplete(0)));
+        mine(&mut chain_b, vec![claim_b]);
+
+        // Bob reads it from chain B and claims on chain A
+        let block = chain_b.block(chain_b.height()).unwrap();
+        let revealed = block.txs().iter().find_map(|tx| on_b.preimage_in(tx)).unwrap();
+        assert_eq!(secret, revealed);
+        let claim_a = on_a.claim(op_a, 49 * COIN, "Bob", COIN, &bob, &revealed);
+        mine(&mut chain_a, vec![claim_a]);
+
+        let paid = |chain: &BlockChain, addr: &str| chain.utxo().iter()
+            .filter(|(_, e)| e.output.addr == addr)
+            .map(|(_, e)| e.output.val.val)
+            .sum::<u64>();
+        assert_eq!(48 * COIN, paid(&chain_a, "Bob"));
+        assert_eq!(48 * COIN, paid(&chain_b, "Alice"));
+        assert_eq!(0, paid(&chain_a, &on_a.address()) + paid(&chain_b, &on_b.address()));
+    }
+
+    #[test]
+    fn test_htlc_refund() {
+        let (alice, bob) = (SigningKey::from_seed(&[1; 32]), SigningKey::from_seed(&[2; 32]));
+        let secret = b"never revealed".to_vec();
+        let htlc = Htlc{ hash: mkt::sha256(&secret), recipient: bob.public_key(), sender: alice.public_key(), timeout: 5 };
+        let (mut chain, op) = lock("Alice", &htlc);
+
+        let refund = htlc.refund(op, 49 * COIN, "Alice", COIN, &alice);
+        while chain.height() < htlc.timeout as usize{
+            assert_eq!(Err(ChainError::NonFinal), check(&chain, &refund));
+            mine(&mut chain, vec![]);
+        }
+        // the lock-time is only enforced with a non-final sequence, only the sender refunds
+        let mut final_seq = refund.clone();
+        final_seq.input.0[0].sequence = SEQUENCE_FINAL;
+        assert_eq!(Err(ChainError::BadScript), check(&chain, &final_seq));
+        let mut short = htlc.spend(op, 49 * COIN, "Alice", COIN, htlc.timeout - 1, SEQUENCE_FINAL - 1);
+        short.witness = vec![vec![alice.sign(&short, 0).to_vec()]];
+        assert_eq!(Err(ChainError::BadScript), check(&chain, &short));
+        let by_bob = htlc.refund(op, 49 * COIN, "Bob", COIN, &bob);
+        assert_eq!(Err(ChainError::BadSignature), check(&chain, &by_bob));
+
+        assert_eq!(Ok(()), check(&chain, &refund));
+        mine(&mut chain, vec![refund]);
+        let late = htlc.claim(op, 49 * COIN, "Bob", COIN, &bob, &secret);
+        assert_eq!(Err(ChainError::MissingInput(op)), check(&chain, &late));
+    }
+}
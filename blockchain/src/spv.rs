@@ -0,0 +1,501 @@
+//! Bloom filtered light clients in the style of BIP37.
+//!
+//! A light client loads a `FilterLoad` holding its addresses and unspent outpoints into a full
+//! node. For every block the node answers with a `MerkleBlock`, the header and a partial merkle
+//! tree covering the matching transactions, followed by these transactions.
+//! When `BloomUpdate::All` is set, the node adds the outpoint of every matching output to the
+//! filter, so a later transaction spending it matches too.
+//! Filters hash in `u64` whatever the platform, each hash step below 64 and used once.
+//!
+//! The partial tree follows the shape of `mkt::build_tree`: the last transaction is repeated
+//! up to a power of two leaves. The `BlockChecker` of the `merkle_tree` crate hashes hex strings
+//! and cannot check a block header, so the tree is walked here with `mkt::hash_node`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use bloom_filter::BloomFilter;
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::block::{Block, BlockHeader};
+use crate::consensus::{self, Consensus};
+use crate::encode::{self, Reader};
+use crate::mkt::{self, HashVal};
+use crate::{ChainError, ChainParams, OutPoint, SimpleTx, SimpleValue, Trans};
+
+/// Largest filter a node accepts, in bytes.
+pub const MAX_FILTER_BYTES: usize = 36_000;
+/// Most hash functions of a filter.
+pub const MAX_HASH_STEPS: usize = 50;
+/// Largest element of a `filteradd`.
+pub const MAX_FILTER_ADD: usize = 520;
+
+/// How a node updates a loaded filter with the transactions it matched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BloomUpdate{
+    None,
+    /// Insert the outpoint of every matching output.
+    All,
+}
+
+/// Bytes of an outpoint inserted into filters: the txid then the index.
+pub fn outpoint_key(op: &OutPoint) -> Vec<u8>{
+    let mut key = op.txid.to_vec();
+    key.write_u32::<BigEndian>(op.index).unwrap();
+    key
+}
+
+/// The `filterload` message, and the filter a node keeps for the peer that sent it.
+#[derive(Clone, Debug)]
+pub struct FilterLoad{
+    pub filter: BloomFilter,
+    pub update: BloomUpdate,
+}
+
+impl FilterLoad{
+    /// An empty filter, `None` if it exceeds the limits of a node.
+    pub fn new(bit_size: usize, steps: &[usize], update: BloomUpdate) -> Option<FilterLoad>{
+        let bytes = bit_size.div_ceil(8);
+        if bytes == 0 || bytes > MAX_FILTER_BYTES || steps.len() > MAX_HASH_STEPS{
+            return None
+        }
+        let filter = BloomFilter::from_bits(bit_size, steps, vec![0; bytes])?;
+        Some(FilterLoad{ filter, update })
+    }
+
+    pub fn insert(&mut self, data: &[u8]) -> &mut Self{
+        self.filter.insert(data);
+        self
+    }
+
+    /// Handle a `filteradd` message.
+    pub fn add(&mut self, data: &[u8]) -> Result<(), ChainError>{
+        if data.len() > MAX_FILTER_ADD{
+            return Err(ChainError::BadFilter)
+        }
+        self.filter.insert(data);
+        Ok(())
+    }
+
+    /// Whether `tx` is relevant to the peer: its txid, an output address, a spent outpoint or
+    /// an input address is in the filter. Matching outputs are added per `update`.
+    pub fn matches(&mut self, tx: &SimpleTx) -> bool{
+        let txid = tx.txid();
+        let mut found = self.filter.contains(&txid);
+        for (i, out) in tx.output.0.iter().enumerate(){
+            if self.filter.contains(out.addr.as_bytes()){
+                found = true;
+                if self.update == BloomUpdate::All{
+                    self.filter.insert(&outpoint_key(&OutPoint::new(txid, i as u32)));
+                }
+            }
+        }
+        if found || tx.is_coinbase(){
+            return found
+        }
+        tx.input.0.iter().any(|txin| {
+            self.filter.contains(&outpoint_key(&txin.prev_out)) || self.filter.contains(txin.addr.as_bytes())
+        })
+    }
+
+    /// The merkle block of `block` and the transactions it covers, in block order.
+    /// Fails if the block has no transactions.
+    pub fn filter_block(&mut self, block: &Block) -> Result<(MerkleBlock, Vec<SimpleTx>), ChainError>{
+        let matches: Vec<bool> = block.txs().iter().map(|tx| self.matches(tx)).collect();
+        let txids: Vec<[u8; 32]> = block.txs().iter().map(|tx| tx.txid()).collect();
+        let txs = block.txs().iter()
+            .zip(matches.iter())
+            .filter(|(_, &m)| m)
+            .map(|(tx, _)| tx.clone())
+            .collect();
+        Ok((MerkleBlock::new(block.header().clone(), &txids, &matches)?, txs))
+    }
+
+    /// Bit size, hash steps, bits and the update flag.
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut buf = Vec::new();
+        encode::write_varint(&mut buf, self.filter.bit_size() as u64);
+        encode::write_varint(&mut buf, self.filter.hash_steps().len() as u64);
+        for &step in self.filter.hash_steps(){
+            encode::write_varint(&mut buf, step as u64);
+        }
+        encode::write_var_bytes(&mut buf, self.filter.bits());
+        buf.push(match self.update{
+            BloomUpdate::None => 0,
+            BloomUpdate::All => 1,
+        });
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<FilterLoad>{
+        let mut r = Reader::new(bytes);
+        let bit_size = r.read_varint()? as usize;
+        let n = r.read_count(1)?;
+        if n > MAX_HASH_STEPS{
+            return None
+        }
+        let mut steps = Vec::with_capacity(n);
+        for _ in 0..n{
+            steps.push(r.read_varint()? as usize);
+        }
+        if bit_size == 0 || bit_size > MAX_FILTER_BYTES * 8{
+            return None
+        }
+        let bits = r.read_var_bytes()?;
+        if bits.len() != bit_size.div_ceil(8){
+            return None
+        }
+        let update = match r.read_u8()?{
+            0 => BloomUpdate::None,
+            1 => BloomUpdate::All,
+            _ => return None,
+        };
+        if !r.is_empty(){
+            return None
+        }
+        let filter = BloomFilter::from_bits(bit_size, &steps, bits.to_vec())?;
+        Some(FilterLoad{ filter, update })
+    }
+}
+
+/// A header and the part of its merkle tree that proves the matching transactions.
+#[derive(Clone, Debug)]
+pub struct MerkleBlock{
+    pub header: BlockHeader,
+    pub total_txs: u32,
+    /// Hashes of the nodes where the walk stops, in depth first order. Leaves are txids.
+    pub hashes: Vec<[u8; 32]>,
+    /// One bit per visited node, least significant bit first: set if a match is below it.
+    pub flags: Vec<u8>,
+}
+
+/// Leaves of the tree and its height, with the padding of `mkt::build_tree`.
+fn tree_shape(total_txs: usize) -> (usize, usize){
+    let width = total_txs.max(2).next_power_of_two();
+    (width, width.trailing_zeros() as usize)
+}
+
+impl MerkleBlock{
+    /// Partial tree of a block with `txids`, covering those flagged in `matches`.
+    /// Fails if there are no txids or a flag is missing for one.
+    pub fn new(header: BlockHeader, txids: &[[u8; 32]], matches: &[bool]) -> Result<MerkleBlock, ChainError>{
+        if txids.is_empty() || txids.len() != matches.len(){
+            return Err(ChainError::BadMerkleBlock)
+        }
+        let mut mb = MerkleBlock{ header, total_txs: txids.len() as u32, hashes: Vec::new(), flags: Vec::new() };
+        let (_, height) = tree_shape(txids.len());
+        let mut bits = Vec::new();
+        mb.build(height, 0, txids, matches, &mut bits);
+        mb.flags = vec![0; bits.len().div_ceil(8)];
+        for (i, &bit) in bits.iter().enumerate(){
+            if bit{
+                mb.flags[i / 8] |= 1 << (i % 8);
+            }
+        }
+        Ok(mb)
+    }
+
+    fn node_hash(height: usize, pos: usize, txids: &[[u8; 32]]) -> HashVal{
+        if height == 0{
+            mkt::hash_leaf(&HashVal(txids[pos.min(txids.len() - 1)]))
+        }else{
+            let left = MerkleBlock::node_hash(height - 1, pos * 2, txids);
+            let right = MerkleBlock::node_hash(height - 1, pos * 2 + 1, txids);
+            mkt::hash_node(&left, &right)
+        }
+    }
+
+    fn build(&mut self, height: usize, pos: usize, txids: &[[u8; 32]], matches: &[bool], bits: &mut Vec<bool>){
+        let start = (pos << height).min(matches.len());
+        let end = ((pos + 1) << height).min(matches.len());
+        let parent_of_match = matches[start..end].iter().any(|&m| m);
+        bits.push(parent_of_match);
+        if height == 0{
+            self.hashes.push(txids[pos.min(txids.len() - 1)]);
+        }else if !parent_of_match{
+            self.hashes.push(MerkleBlock::node_hash(height, pos, txids).0);
+        }else{
+            self.build(height - 1, pos * 2, txids, matches, bits);
+            self.build(height - 1, pos * 2 + 1, txids, matches, bits);
+        }
+    }
+
+    pub fn hash(&self) -> [u8; 32]{
+        self.header.hash()
+    }
+
+    /// Txids of the matching transactions with their index in the block.
+    /// Fails unless the tree is well formed, fully used and commits to the merkle root.
+    pub fn extract_matches(&self) -> Result<Vec<(u32, [u8; 32])>, ChainError>{
+        let (width, height) = tree_shape(self.total_txs as usize);
+        if self.total_txs == 0 || self.hashes.len() > width{
+            return Err(ChainError::BadMerkleBlock)
+        }
+        let mut walk = Walk{ mb: self, bit: 0, hash: 0, matches: Vec::new() };
+        let root = walk.visit(height, 0).ok_or(ChainError::BadMerkleBlock)?;
+        if walk.hash != self.hashes.len() || walk.bit.div_ceil(8) != self.flags.len(){
+            return Err(ChainError::BadMerkleBlock)
+        }
+        if &root.0 != self.header.merkle_root(){
+            return Err(ChainError::BadMerkleRoot)
+        }
+        Ok(walk.matches)
+    }
+
+    /// Header, transaction count, hashes and flag bytes.
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut buf = self.header.to_bytes();
+        buf.write_u32::<BigEndian>(self.total_txs).unwrap();
+        encode::write_varint(&mut buf, self.hashes.len() as u64);
+        for h in self.hashes.iter(){
+            buf.extend(h);
+        }
+        encode::write_var_bytes(&mut buf, &self.flags);
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<MerkleBlock>{
+        let mut r = Reader::new(bytes);
+        let header = BlockHeader::decode(&mut r)?;
+        let total_txs = r.read_u32()?;
+        let n = r.read_count(32)?;
+        let mut hashes = Vec::with_capacity(n);
+        for _ in 0..n{
+            hashes.push(r.read_hash()?);
+        }
+        let flags = r.read_var_bytes()?.to_vec();
+        if !r.is_empty(){
+            return None
+        }
+        Some(MerkleBlock{ header, total_txs, hashes, flags })
+    }
+}
+
+/// Depth first walk over the partial tree of a merkle block.
+struct Walk<'a>{
+    mb: &'a MerkleBlock,
+    bit: usize,
+    hash: usize,
+    matches: Vec<(u32, [u8; 32])>,
+}
+
+impl<'a> Walk<'a>{
+    fn visit(&mut self, height: usize, pos: usize) -> Option<HashVal>{
+        let flag = (self.mb.flags.get(self.bit / 8)? >> (self.bit % 8)) & 1 == 1;
+        self.bit += 1;
+        if height == 0 || !flag{
+            let h = HashVal(*self.mb.hashes.get(self.hash)?);
+            self.hash += 1;
+            if height > 0{
+                return Some(h)
+            }
+            if flag{
+                // padding leaves repeat the last transaction and cannot match
+                if pos >= self.mb.total_txs as usize{
+                    return None
+                }
+                self.matches.push((pos as u32, h.0));
+            }
+            return Some(mkt::hash_leaf(&h))
+        }
+        let left = self.visit(height - 1, pos * 2)?;
+        let right = self.visit(height - 1, pos * 2 + 1)?;
+        Some(mkt::hash_node(&left, &right))
+    }
+}
+
+/// A wallet following the chain through merkle blocks, without the UTXO set.
+/// Headers are checked against their seal and their parent, difficulty changes are not.
+pub struct LightClient{
+    consensus: Arc<dyn Consensus>,
+    headers: Vec<BlockHeader>,
+    addrs: Vec<String>,
+    coins: HashMap<OutPoint, Trans<String, SimpleValue>>,
+}
+
+impl LightClient{
+    /// A client at the genesis block of `params`, watching `addrs`.
+    pub fn new(params: &ChainParams, addrs: Vec<String>) -> LightClient{
+        LightClient{
+            consensus: consensus::engine(params),
+            headers: vec![params.genesis_block().header().clone()],
+            addrs,
+            coins: HashMap::new(),
+        }
+    }
+
+    pub fn tip(&self) -> &BlockHeader{
+        self.headers.last().unwrap()
+    }
+
+    pub fn height(&self) -> usize{
+        self.headers.len() - 1
+    }
+
+    /// Unspent outputs paying the watched addresses.
+    pub fn coins(&self) -> &HashMap<OutPoint, Trans<String, SimpleValue>>{
+        &self.coins
+    }
+
+    pub fn balance(&self) -> u64{
+        self.coins.values().map(|out| out.val.val).sum()
+    }
+
+    /// A filter holding the watched addresses and the outpoints of the known coins.
+    pub fn filter_load(&self, bit_size: usize, steps: &[usize]) -> Option<FilterLoad>{
+        let mut load = FilterLoad::new(bit_size, steps, BloomUpdate::All)?;
+        for addr in self.addrs.iter(){
+            load.insert(addr.as_bytes());
+        }
+        for op in self.coins.keys(){
+            load.insert(&outpoint_key(op));
+        }
+        Some(load)
+    }
+
+    /// Connect the header of a merkle block and apply the transactions sent with it, one for
+    /// every matched txid in block order.
+    /// Returns the txids proven to be in the block, false positives of the filter included.
+    pub fn process(&mut self, mb: &MerkleBlock, txs: &[SimpleTx]) -> Result<Vec<[u8; 32]>, ChainError>{
+        if mb.header.prev_block() != &self.tip().hash(){
+            return Err(ChainError::BadPrevBlock)
+        }
+        self.consensus.check_seal(&mb.header)?;
+        let matched: Vec<[u8; 32]> = mb.extract_matches()?.into_iter().map(|(_, txid)| txid).collect();
+        if txs.len() != matched.len() || txs.iter().zip(matched.iter()).any(|(tx, txid)| &tx.txid() != txid){
+            return Err(ChainError::BadMerkleBlock)
+        }
+
+        for tx in txs{
+            let txid = tx.txid();
+            if !tx.is_coinbase(){
+                for txin in tx.input.0.iter(){
+                    self.coins.remove(&txin.prev_out);
+                }
+            }
+            for (i, out) in tx.output.0.iter().enumerate(){
+                if self.addrs.contains(&out.addr){
+                    self.coins.insert(OutPoint::new(txid, i as u32), out.clone());
+                }
+            }
+        }
+        self.headers.push(mb.header.clone());
+        Ok(matched)
+    }
+}
+
+#[cfg(test)]
+mod spv_test{
+    use super::*;
+    use std::sync::Arc;
+    use crate::clock::MockClock;
+    use crate::transaction::*;
+    use crate::COIN;
+
+    fn pay(from: OutPoint, from_addr: &str, to: &str, val: u64) -> SimpleTx{
+        SimpleTx{
+            input: InputTx(vec![TxIn::new(from, from_addr.to_string(), val.into())]),
+            output: OutputTx(vec![Trans{addr: to.to_string(), val: (val - 1000).into()}]),
+            lock_time: 0,
+            witness: vec![],
+        }
+    }
+
+    #[test]
+    fn test_partial_tree() {
+        let g = ChainParams::regtest().genesis_timestamp;
+        let chain = crate::test_chain(Arc::new(MockClock::new(g + 1_000_000)));
+        assert!(MerkleBlock::new(chain.tip().header().clone(), &[], &[]).is_err());
+        for n in 1..12u8{
+            let txids: Vec<[u8; 32]> = (0..n).map(|i| mkt::sha256(&[i])).collect();
+            let mut header = chain.tip().header().clone();
+            header.set_merkle_root(mkt::build_tree(txids.iter().map(|h| HashVal(*h)).collect()).root().0);
+            assert!(MerkleBlock::new(header.clone(), &txids, &[]).is_err());
+            for pick in 0..n as usize{
+                let matches: Vec<bool> = (0..n as usize).map(|i| i == pick || i + 1 == n as usize).collect();
+                let mb = MerkleBlock::new(header.clone(), &txids, &matches).unwrap();
+                let mut expected = vec![(pick as u32, txids[pick])];
+                if pick + 1 != n as usize{
+                    expected.push((n as u32 - 1, txids[n as usize - 1]));
+                }
+                assert_eq!(Ok(expected), mb.extract_matches());
+                assert_eq!(mb.to_bytes(), MerkleBlock::from_bytes(&mb.to_bytes()).unwrap().to_bytes());
+
+                let mut bad = mb.clone();
+                bad.hashes[0][0] ^= 1;
+                assert!(bad.extract_matches().is_err());
+                let mut bad = mb.clone();
+                bad.flags.push(0);
+                assert_eq!(Err(ChainError::BadMerkleBlock), bad.extract_matches());
+            }
+        }
+    }
+
+    #[test]
+    fn test_light_client() {
+        let params = ChainParams::regtest();
+        let g = params.genesis_timestamp;
+        let mut chain = crate::test_chain(Arc::new(MockClock::new(g + 1_000_000)));
+        let mut client = LightClient::new(&params, vec!["Carol".to_string()]);
+        let load = client.filter_load(1 << 12, &[2, 3, 5, 7]).unwrap();
+        // the node keeps the filter it received
+        let mut filter = FilterLoad::from_bytes(&load.to_bytes()).unwrap();
+        let load_bytes = |bit_size: usize, steps: &[usize], len: usize|{
+            let mut bytes = Vec::new();
+            encode::write_varint(&mut bytes, bit_size as u64);
+            encode::write_varint(&mut bytes, steps.len() as u64);
+            for &step in steps{
+                encode::write_varint(&mut bytes, step as u64);
+            }
+            encode::write_var_bytes(&mut bytes, &vec![0; len]);
+            bytes.push(0);
+            bytes
+        };
+        for &(bit_size, len) in &[(MAX_FILTER_BYTES * 8 + 1, MAX_FILTER_BYTES + 1), (1 << 12, 10), (usize::MAX, 0)]{
+            assert!(FilterLoad::from_bytes(&load_bytes(bit_size, &[2], len)).is_none());
+        }
+        // steps k and k + 64 are the same hash function
+        assert!(FilterLoad::from_bytes(&load_bytes(1 << 12, &[2, 3], 512)).is_some());
+        assert!(FilterLoad::from_bytes(&load_bytes(1 << 12, &[2, 2], 512)).is_none());
+        assert!(FilterLoad::from_bytes(&load_bytes(1 << 12, &[2, 66], 512)).is_none());
+
+        let mut cb = SimpleTx::coinbase(1, COIN.into(), "Alice".to_string());
+        cb.output.0 = (0..8).map(|i| Trans{addr: format!("Alice{}", i), val: COIN.into()}).collect();
+        let cb_id = cb.txid();
+        let b1 = crate::mine_block(&chain, g + 100, vec![cb]);
+        chain.add_block(b1.clone()).unwrap();
+        let (mb, txs) = filter.filter_block(&b1).unwrap();
+        assert!(txs.is_empty());
+        client.process(&mb, &txs).unwrap();
+
+        // Carol is paid, then spends the coin in the same block
+        let mut txs: Vec<SimpleTx> = (0..6).map(|i| pay(OutPoint::new(cb_id, i), &format!("Alice{}", i), "Bob", COIN)).collect();
+        let to_carol = pay(OutPoint::new(cb_id, 6), "Alice6", "Carol", COIN);
+        let spend = pay(OutPoint::new(to_carol.txid(), 0), "Carol", "Dave", COIN - 1000);
+        txs.insert(0, SimpleTx::coinbase(2, COIN.into(), "Miner".to_string()));
+        txs.insert(3, to_carol.clone());
+        txs.push(spend.clone());
+        let b2 = crate::mine_block(&chain, g + 200, txs);
+        let (mb, txs) = filter.filter_block(&b2).unwrap();
+        assert_eq!(vec![to_carol.txid(), spend.txid()], txs.iter().map(|tx| tx.txid()).collect::<Vec<_>>());
+        // the outpoint of Carol's output was added to the filter
+        assert!(filter.filter.contains(&outpoint_key(&OutPoint::new(to_carol.txid(), 0))));
+        let mb = MerkleBlock::from_bytes(&mb.to_bytes()).unwrap();
+        assert!(mb.to_bytes().len() < b2.to_bytes().len());
+
+        // transactions outside of the proof are refused, and so are missing ones
+        assert_eq!(Err(ChainError::BadMerkleBlock), client.process(&mb, &[b2.txs()[1].clone()]));
+        assert_eq!(Err(ChainError::BadMerkleBlock), client.process(&mb, &txs[..1]));
+        assert_eq!(Err(ChainError::BadMerkleBlock), client.process(&mb, &[txs[1].clone(), txs[0].clone()]));
+        let matched = client.process(&mb, &txs).unwrap();
+        assert_eq!(vec![to_carol.txid(), spend.txid()], matched);
+        assert_eq!(2, client.height());
+        // Carol's coin was spent in the same block
+        assert_eq!(0, client.balance());
+        assert!(client.coins().is_empty());
+
+        let mut forged = mb.clone();
+        forged.header.set_merkle_root([0; 32]);
+        assert_eq!(Err(ChainError::BadPrevBlock), client.process(&forged, &[]));
+    }
+}
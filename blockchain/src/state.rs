@@ -0,0 +1,25 @@
+//! The state a chain keeps, built by applying its blocks in order.
+//!
+//! `BlockChain` handles the headers, branches, reorganizations and events the same way for
+//! every state model and leaves the transactions to a `StateModel`: the UTXO set, whose
+//! blocks carry `SimpleTx`, or the accounts of `account::AccountState`.
+
+use std::fmt;
+
+use crate::block::{Block, BlockTx};
+use crate::error::ChainError;
+use crate::BlockChain;
+
+pub trait StateModel: Clone + Default + fmt::Debug + Send + Sync + Sized{
+    /// Transactions of the blocks.
+    type Tx: BlockTx + Send + Sync;
+    /// What is needed to disconnect a block again, e.g. the outputs it spent.
+    type Undo: Clone + Default + fmt::Debug + Send + Sync;
+
+    /// Check the transactions of `block`, the next block of `chain` at `height`, and apply them.
+    /// The header was checked already. On error the state is unchanged.
+    fn connect(&mut self, chain: &BlockChain<Self>, block: &Block<Self::Tx>, height: usize) -> Result<Self::Undo, ChainError>;
+
+    /// Revert `block`, the last block applied, with the data returned when it was connected.
+    fn disconnect(&mut self, block: &Block<Self::Tx>, undo: Self::Undo);
+}
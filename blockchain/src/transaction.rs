@@ -1,11 +1,70 @@
+use byteorder::{WriteBytesExt, BigEndian};
 
-pub trait CoinValue: Clone{
+use crate::encode::{self, Reader};
+use crate::mkt;
+
+/// `lock_time` below this is a block height, otherwise a unix timestamp.
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+/// Inputs with this sequence neither lock the transaction nor take part in relative lock-time.
+pub const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+/// BIP125: a transaction with an input sequence up to this value can be replaced in the mempool.
+pub const MAX_BIP125_RBF_SEQUENCE: u32 = 0xffff_fffd;
+/// BIP68: relative lock-time is disabled for an input with this bit set.
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+/// BIP68: relative lock-time is in units of 512 seconds if set, otherwise in blocks.
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+/// BIP68: the bits of the sequence that hold the relative lock-time.
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000_ffff;
+/// BIP68: time-based relative lock-times are shifted by this many bits (512 seconds).
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+/// Written in place of the input count to mark a transaction serialized with witness data.
+/// A transaction always has inputs so the count is never 0.
+pub const WITNESS_MARKER: u8 = 0x00;
+pub const WITNESS_FLAG: u8 = 0x01;
+
+/// Unlocking data of an input, e.g. a signature and a public key.
+pub type Witness = Vec<Vec<u8>>;
+
+pub trait CoinValue: Clone + Ord{
     fn default_value() -> Self;
     fn to_bytes(&self) -> Vec<u8>;
+    /// Decode a value written by `to_bytes()`.
+    fn from_bytes(bytes: &[u8]) -> Option<Self>;
+    fn zero() -> Self;
+    /// Total money supply. No amount, nor the sum of amounts in a transaction, may exceed it.
+    fn max_money() -> Self;
+    /// `None` on overflow.
+    fn checked_add(&self, other: &Self) -> Option<Self>;
+    /// `None` if `other` is larger.
+    fn checked_sub(&self, other: &Self) -> Option<Self>;
+
+    fn in_money_range(&self) -> bool{
+        *self <= Self::max_money()
+    }
 }
 
-pub trait TxAddr: Clone{
+/// Sum of `values`, `None` if it overflows or leaves the money range.
+pub fn checked_sum<'a, V: CoinValue + 'a>(values: impl IntoIterator<Item=&'a V>) -> Option<V>{
+    let mut total = V::zero();
+    for v in values{
+        if !v.in_money_range(){
+            return None
+        }
+        total = total.checked_add(v)?;
+        if !total.in_money_range(){
+            return None
+        }
+    }
+    Some(total)
+}
+
+pub trait TxAddr: Clone + PartialEq{
     fn coin_base_addr() -> Self;
+    /// Decode an address from its `as_ref()` bytes.
+    fn from_bytes(bytes: &[u8]) -> Option<Self>;
+    /// Address of the unspendable coinbase output that commits to the witness root of a block.
+    fn witness_commitment(root: &[u8; 32]) -> Self;
+    fn as_witness_commitment(&self) -> Option<[u8; 32]>;
 }
 
 /// 交易，也就是转账。在Input中，就表
@@ -16,10 +75,87 @@ pub struct Trans<A: TxAddr + AsRef<[u8]>, V: CoinValue >{
     pub val: V,
 }
 
-impl<A: TxAddr + AsRef<[u8]>, V: CoinValue > Trans<A, V>{}
+impl<A: TxAddr + AsRef<[u8]>, V: CoinValue > Trans<A, V>{
+    /// Outputs that can never be spent and are not added to the UTXO set.
+    pub fn is_unspendable(&self) -> bool{
+        self.addr.as_witness_commitment().is_some()
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>){
+        encode::write_var_bytes(buf, self.addr.as_ref());
+        encode::write_var_bytes(buf, &self.val.to_bytes());
+    }
+
+    pub(crate) fn decode(r: &mut Reader) -> Option<Trans<A, V>>{
+        Some(Trans{
+            addr: A::from_bytes(r.read_var_bytes()?)?,
+            val: V::from_bytes(r.read_var_bytes()?)?,
+        })
+    }
+}
+
+/// Reference to an output of a previous transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OutPoint{
+    pub txid: [u8; 32],
+    pub index: u32,
+}
+
+impl OutPoint{
+    pub fn new(txid: [u8; 32], index: u32) -> OutPoint{
+        OutPoint{ txid, index }
+    }
+
+    /// The outpoint of a coinbase input. It spends nothing,
+    /// `index` holds the height of the block so that coinbase txids are unique.
+    pub fn coinbase(height: u32) -> OutPoint{
+        OutPoint{ txid: [0; 32], index: height }
+    }
+
+    pub fn is_coinbase(&self) -> bool{
+        self.txid == [0; 32]
+    }
+}
+
+/// An input spends the output `prev_out`, whose address and value are repeated in `addr` and `val`.
+#[derive(Clone, Debug)]
+pub struct TxIn<A: TxAddr + AsRef<[u8]>, V: CoinValue >{
+    pub prev_out: OutPoint,
+    pub addr: A,
+    pub val: V,
+    pub sequence: u32,
+}
+
+impl<A: TxAddr + AsRef<[u8]>, V: CoinValue > TxIn<A, V>{
+    pub fn new(prev_out: OutPoint, addr: A, val: V) -> TxIn<A, V>{
+        TxIn{ prev_out, addr, val, sequence: SEQUENCE_FINAL }
+    }
+
+    pub fn with_sequence(mut self, sequence: u32) -> TxIn<A, V>{
+        self.sequence = sequence;
+        self
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>){
+        buf.extend(&self.prev_out.txid);
+        buf.write_u32::<BigEndian>(self.prev_out.index).unwrap();
+        encode::write_var_bytes(buf, self.addr.as_ref());
+        encode::write_var_bytes(buf, &self.val.to_bytes());
+        buf.write_u32::<BigEndian>(self.sequence).unwrap();
+    }
+
+    fn decode(r: &mut Reader) -> Option<TxIn<A, V>>{
+        Some(TxIn{
+            prev_out: OutPoint::new(r.read_hash()?, r.read_u32()?),
+            addr: A::from_bytes(r.read_var_bytes()?)?,
+            val: V::from_bytes(r.read_var_bytes()?)?,
+            sequence: r.read_u32()?,
+        })
+    }
+}
 
 #[derive(Clone, Debug)]
-pub struct InputTx<A, V>(pub Vec<Trans<A, V>>) 
+pub struct InputTx<A, V>(pub Vec<TxIn<A, V>>) 
     where A: TxAddr + AsRef<[u8]>, V: CoinValue ;
 
 #[derive(Clone, Debug)]
@@ -30,36 +166,341 @@ pub struct OutputTx<A, V>(pub Vec<Trans<A, V>>)
 pub struct Transaction<A: TxAddr + AsRef<[u8]>, V: CoinValue >{
     pub input: InputTx<A, V>,
     pub output: OutputTx<A, V>,
+    /// Block height or timestamp before which the transaction can not be mined.
+    pub lock_time: u32,
+    /// Either empty or one witness per input. Not covered by the txid.
+    pub witness: Vec<Witness>,
     // sig_script
     // pub_script
 }
 
+/// Earliest block a transaction may be included in under BIP68.
+/// The block must be higher than `height` and its median time past later than `time`.
+/// -1 means no constraint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LockPoints{
+    pub height: i64,
+    pub time: i64,
+}
+
+impl LockPoints{
+    /// `height` is the height of the block to include the transaction in,
+    /// `prev_median_time_past` the median time past of its parent.
+    pub fn is_satisfied(&self, height: usize, prev_median_time_past: u64) -> bool{
+        self.height < height as i64 && self.time < prev_median_time_past as i64
+    }
+}
+
 impl<A: TxAddr + AsRef<[u8]>, V: CoinValue > Transaction<A, V>{
-    pub fn coinbase(coin_val: V, recv_addr: A) -> Transaction<A, V>{
+    pub fn coinbase(height: u32, coin_val: V, recv_addr: A) -> Transaction<A, V>{
         Transaction{
             input: InputTx(
-                vec![Trans{addr: A::coin_base_addr(), val: coin_val.clone()}]
+                vec![TxIn::new(OutPoint::coinbase(height), A::coin_base_addr(), coin_val.clone())]
             ),
             output: OutputTx(
                 vec![Trans{addr: recv_addr, val: coin_val}]
             ),
+            lock_time: 0,
+            witness: Vec::new(),
         }
     }
 
+    pub fn is_coinbase(&self) -> bool{
+        self.input.0.len() == 1 && self.input.0[0].prev_out.is_coinbase()
+    }
+
+    /// BIP125: whether the transaction agrees to be replaced by a conflicting one paying more.
+    pub fn signals_replacement(&self) -> bool{
+        self.input.0.iter().any(|txin| txin.sequence <= MAX_BIP125_RBF_SEQUENCE)
+    }
+
+    pub fn has_witness(&self) -> bool{
+        self.witness.iter().any(|w| !w.is_empty())
+    }
+
+    /// Witness data must be absent or given for every input.
+    pub fn check_witness(&self) -> bool{
+        self.witness.is_empty() || self.witness.len() == self.input.0.len()
+    }
+
+    /// Serialization without witness data, the txid is computed over it:
+    /// `n_in | inputs | n_out | outputs | lock_time`
     pub fn to_bytes(&self) -> Vec<u8>{
         let mut bytes = Vec::new();
+        self.encode(&mut bytes, false);
+        bytes
+    }
 
+    /// Full serialization, the wtxid is computed over it. A transaction with witness data gets
+    /// `WITNESS_MARKER | WITNESS_FLAG` before the inputs and the witnesses before `lock_time`,
+    /// any other transaction is serialized the same way as by `to_bytes()`.
+    pub fn to_witness_bytes(&self) -> Vec<u8>{
+        let mut bytes = Vec::new();
+        self.encode(&mut bytes, self.has_witness());
+        bytes
+    }
 
-        for trans in self.input.0.iter()
-                        .chain(self.output.0.iter())
-        {
-            bytes.extend(trans.addr.as_ref());
-            bytes.extend(trans.val.to_bytes());
+    pub fn encode(&self, buf: &mut Vec<u8>, with_witness: bool){
+        if with_witness{
+            buf.push(WITNESS_MARKER);
+            buf.push(WITNESS_FLAG);
         }
-        bytes
+        encode::write_varint(buf, self.input.0.len() as u64);
+        for txin in self.input.0.iter(){
+            txin.encode(buf);
+        }
+        encode::write_varint(buf, self.output.0.len() as u64);
+        for trans in self.output.0.iter(){
+            trans.encode(buf);
+        }
+        if with_witness{
+            for i in 0..self.input.0.len(){
+                let items = self.witness.get(i).map(|w| w.as_slice()).unwrap_or(&[]);
+                encode::write_varint(buf, items.len() as u64);
+                for item in items{
+                    encode::write_var_bytes(buf, item);
+                }
+            }
+        }
+        buf.write_u32::<BigEndian>(self.lock_time).unwrap();
+    }
+
+    /// Decode either serialization.
+    pub fn decode(r: &mut Reader) -> Option<Transaction<A, V>>{
+        let mut n_in = r.read_count(42)?;
+        let with_witness = n_in == 0;
+        if with_witness{
+            if r.read_u8()? != WITNESS_FLAG{
+                return None
+            }
+            n_in = r.read_count(42)?;
+        }
+        let input = (0..n_in).map(|_| TxIn::decode(r)).collect::<Option<Vec<_>>>()?;
+        let n_out = r.read_count(2)?;
+        let output = (0..n_out).map(|_| Trans::decode(r)).collect::<Option<Vec<_>>>()?;
+
+        let mut witness = Vec::new();
+        if with_witness{
+            for _ in 0..n_in{
+                let n = r.read_count(1)?;
+                let items = (0..n)
+                    .map(|_| r.read_var_bytes().map(|b| b.to_vec()))
+                    .collect::<Option<Vec<_>>>()?;
+                witness.push(items);
+            }
+        }
+        let tx = Transaction{
+            input: InputTx(input),
+            output: OutputTx(output),
+            lock_time: r.read_u32()?,
+            witness,
+        };
+        // the marker must not be used without witness data
+        if with_witness && !tx.has_witness(){
+            return None
+        }
+        Some(tx)
+    }
+
+    /// Decode a whole buffer, trailing bytes are an error.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Transaction<A, V>>{
+        let mut r = Reader::new(bytes);
+        let tx = Transaction::decode(&mut r)?;
+        if r.is_empty() { Some(tx) } else { None }
+    }
+
+    /// Sum of the outputs, `None` if it is out of the money range.
+    pub fn value_out(&self) -> Option<V>{
+        checked_sum(self.output.0.iter().map(|out| &out.val))
+    }
+
+    /// Sum of the values claimed by the inputs, `None` if it is out of the money range.
+    pub fn value_in(&self) -> Option<V>{
+        checked_sum(self.input.0.iter().map(|txin| &txin.val))
+    }
+
+    /// SHA-256 of `to_bytes()`.
+    pub fn txid(&self) -> [u8; 32]{
+        mkt::sha256(&self.to_bytes())
+    }
+
+    /// SHA-256 of `to_witness_bytes()`, equal to the txid without witness data.
+    pub fn wtxid(&self) -> [u8; 32]{
+        mkt::sha256(&self.to_witness_bytes())
+    }
+
+    /// Virtual size: witness bytes count a quarter of the other bytes, as in BIP141.
+    pub fn vsize(&self) -> usize{
+        let base = self.to_bytes().len();
+        let total = self.to_witness_bytes().len();
+        (base * 3 + total).div_ceil(4)
+    }
+
+    /// Witness root committed by a coinbase.
+    pub fn witness_commitment(&self) -> Option<[u8; 32]>{
+        self.output.0.iter().rev().find_map(|out| out.addr.as_witness_commitment())
+    }
+
+    /// Replace the witness commitment of a coinbase.
+    pub fn set_witness_commitment(&mut self, root: &[u8; 32]){
+        self.output.0.retain(|out| !out.is_unspendable());
+        self.output.0.push(Trans{ addr: A::witness_commitment(root), val: V::zero() });
+    }
+
+    /// Whether the transaction may be included in a block at `height`.
+    /// `block_time` is the median time past of the previous block (BIP113).
+    pub fn is_final(&self, height: usize, block_time: u64) -> bool{
+        if self.lock_time == 0{
+            return true
+        }
+        let limit = if self.lock_time < LOCKTIME_THRESHOLD{
+            height as u64
+        }else{
+            block_time
+        };
+        if (self.lock_time as u64) < limit{
+            return true
+        }
+        self.input.0.iter().all(|txin| txin.sequence == SEQUENCE_FINAL)
+    }
+
+    /// BIP68 relative lock-times of the inputs.
+    /// `coin_heights[i]` is the height of the block that created the output spent by input `i`,
+    /// `median_time_past(h)` the median time past of the block at height `h`.
+    pub fn sequence_locks(&self, coin_heights: &[usize], median_time_past: impl Fn(usize) -> u64) -> LockPoints{
+        let mut lp = LockPoints{ height: -1, time: -1 };
+        if self.is_coinbase(){
+            return lp
+        }
+        for (txin, &coin_height) in self.input.0.iter().zip(coin_heights.iter()){
+            let seq = txin.sequence;
+            if seq & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0{
+                continue
+            }
+            let value = (seq & SEQUENCE_LOCKTIME_MASK) as i64;
+            if seq & SEQUENCE_LOCKTIME_TYPE_FLAG != 0{
+                // time is counted from the parent of the block containing the coin
+                let coin_time = median_time_past(coin_height.saturating_sub(1)) as i64;
+                lp.time = lp.time.max(coin_time + (value << SEQUENCE_LOCKTIME_GRANULARITY) - 1);
+            }else{
+                lp.height = lp.height.max(coin_height as i64 + value - 1);
+            }
+        }
+        lp
     }
 }
 
+#[cfg(test)]
+mod transaction_test{
+    use super::*;
+    use crate::{SimpleTx, SimpleValue};
+
+    fn spend(sequence: u32, lock_time: u32) -> SimpleTx{
+        Transaction{
+            input: InputTx(vec![
+                TxIn::new(OutPoint::new([1; 32], 0), "Alice".to_string(), 10.into())
+                    .with_sequence(sequence),
+            ]),
+            output: OutputTx(vec![Trans{addr: "Bob".to_string(), val: 10.into()}]),
+            lock_time,
+            witness: vec![],
+        }
+    }
 
+    #[test]
+    fn test_serialize_without_witness() {
+        let tx = spend(7, 100);
+        let bytes = tx.to_bytes();
+        assert_eq!(bytes, tx.to_witness_bytes());
+        assert_eq!(tx.txid(), tx.wtxid());
 
+        let decoded = SimpleTx::from_bytes(&bytes).unwrap();
+        assert_eq!(bytes, decoded.to_bytes());
+        assert!(decoded.witness.is_empty());
 
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(SimpleTx::from_bytes(&trailing).is_none());
+        assert!(SimpleTx::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn test_witness_is_segregated() {
+        let plain = spend(7, 100);
+        let mut tx = plain.clone();
+        tx.witness = vec![vec![b"sig".to_vec(), b"pubkey".to_vec()]];
+
+        assert_eq!(plain.txid(), tx.txid());
+        assert_ne!(tx.txid(), tx.wtxid());
+
+        let bytes = tx.to_witness_bytes();
+        assert_eq!(&[WITNESS_MARKER, WITNESS_FLAG], &bytes[..2]);
+        let decoded = SimpleTx::from_bytes(&bytes).unwrap();
+        assert_eq!(tx.witness, decoded.witness);
+        assert_eq!(tx.wtxid(), decoded.wtxid());
+
+        // changing the witness changes the wtxid only
+        let mut malleated = tx.clone();
+        malleated.witness[0][0] = b"other sig".to_vec();
+        assert_eq!(tx.txid(), malleated.txid());
+        assert_ne!(tx.wtxid(), malleated.wtxid());
+
+        // a marker without any witness data is rejected
+        let mut empty = plain.clone();
+        empty.witness = vec![vec![]];
+        let mut buf = Vec::new();
+        empty.encode(&mut buf, true);
+        assert!(SimpleTx::from_bytes(&buf).is_none());
+    }
+
+    #[test]
+    fn test_value_out() {
+        let mut tx = spend(0, 0);
+        assert_eq!(Some(10.into()), tx.value_out());
+
+        tx.output.0.push(Trans{addr: "Carol".to_string(), val: SimpleValue::max_money()});
+        assert_eq!(None, tx.value_out());
+
+        // wraps around u64 without the money range check
+        tx.output.0[0].val = u64::MAX.into();
+        tx.output.0[1].val = 2.into();
+        assert_eq!(None, tx.value_out());
+    }
+
+    #[test]
+    fn test_is_final() {
+        assert!(spend(0, 0).is_final(1, 0));
+
+        // height based
+        let tx = spend(0, 100);
+        assert!(!tx.is_final(100, 0));
+        assert!(tx.is_final(101, 0));
+        // a final sequence disables lock_time
+        assert!(spend(SEQUENCE_FINAL, 100).is_final(100, 0));
+
+        // time based, compared with the median time past
+        let tx = spend(0, LOCKTIME_THRESHOLD + 10);
+        assert!(!tx.is_final(1, (LOCKTIME_THRESHOLD + 10) as u64));
+        assert!(tx.is_final(1, (LOCKTIME_THRESHOLD + 11) as u64));
+    }
+
+    #[test]
+    fn test_sequence_locks() {
+        let mtp = |h: usize| 1000 * h as u64;
+
+        let lp = spend(SEQUENCE_FINAL, 0).sequence_locks(&[5], mtp);
+        assert_eq!(LockPoints{ height: -1, time: -1 }, lp);
+
+        // 3 blocks after the coin at height 5
+        let lp = spend(3, 0).sequence_locks(&[5], mtp);
+        assert_eq!(LockPoints{ height: 7, time: -1 }, lp);
+        assert!(!lp.is_satisfied(7, 0));
+        assert!(lp.is_satisfied(8, 0));
+
+        // 2 * 512 seconds after the median time past of block 4
+        let lp = spend(SEQUENCE_LOCKTIME_TYPE_FLAG | 2, 0).sequence_locks(&[5], mtp);
+        assert_eq!(LockPoints{ height: -1, time: 4000 + 1024 - 1 }, lp);
+        assert!(!lp.is_satisfied(100, 5023));
+        assert!(lp.is_satisfied(100, 5024));
+    }
+}
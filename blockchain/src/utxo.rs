@@ -0,0 +1,171 @@
+//! Set of unspent transaction outputs, the state of the chain.
+
+use std::collections::HashMap;
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::encode::{self, Reader};
+use crate::transaction::{CoinValue, OutPoint, Trans, Transaction, TxAddr};
+
+/// An unspent output and where it was created.
+#[derive(Clone, Debug)]
+pub struct UtxoEntry<A: TxAddr + AsRef<[u8]>, V: CoinValue >{
+    pub output: Trans<A, V>,
+    /// Height of the block containing the transaction.
+    pub height: usize,
+    pub coinbase: bool,
+}
+
+impl<A: TxAddr + AsRef<[u8]>, V: CoinValue > UtxoEntry<A, V>{
+    /// `txid | index | output | height | coinbase`
+    pub fn encode(&self, op: &OutPoint, buf: &mut Vec<u8>){
+        buf.extend(&op.txid);
+        buf.write_u32::<BigEndian>(op.index).unwrap();
+        self.output.encode(buf);
+        buf.write_u64::<BigEndian>(self.height as u64).unwrap();
+        buf.push(self.coinbase as u8);
+    }
+
+    pub fn decode(r: &mut Reader) -> Option<(OutPoint, UtxoEntry<A, V>)>{
+        let op = OutPoint::new(r.read_hash()?, r.read_u32()?);
+        let output = Trans::decode(r)?;
+        let height = r.read_u64()? as usize;
+        let coinbase = match r.read_u8()?{
+            0 => false,
+            1 => true,
+            _ => return None,
+        };
+        Some((op, UtxoEntry{ output, height, coinbase }))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct UtxoSet<A: TxAddr + AsRef<[u8]>, V: CoinValue >{
+    map: HashMap<OutPoint, UtxoEntry<A, V>>,
+}
+
+impl<A: TxAddr + AsRef<[u8]>, V: CoinValue > Default for UtxoSet<A, V>{
+    fn default() -> UtxoSet<A, V>{
+        UtxoSet::new()
+    }
+}
+
+impl<A: TxAddr + AsRef<[u8]>, V: CoinValue > UtxoSet<A, V>{
+    pub fn new() -> UtxoSet<A, V>{
+        UtxoSet{
+            map: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, op: &OutPoint) -> Option<&UtxoEntry<A, V>>{
+        self.map.get(op)
+    }
+
+    pub fn contains(&self, op: &OutPoint) -> bool{
+        self.map.contains_key(op)
+    }
+
+    pub fn len(&self) -> usize{
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.map.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&OutPoint, &UtxoEntry<A, V>)>{
+        self.map.iter()
+    }
+
+    pub fn insert(&mut self, op: OutPoint, entry: UtxoEntry<A, V>){
+        self.map.insert(op, entry);
+    }
+
+    /// Add all outputs of `tx`, mined at `height`.
+    pub fn add_tx(&mut self, tx: &Transaction<A, V>, height: usize){
+        let txid = tx.txid();
+        let coinbase = tx.is_coinbase();
+        for (i, out) in tx.output.0.iter().enumerate(){
+            if out.is_unspendable(){
+                continue
+            }
+            self.insert(OutPoint::new(txid, i as u32), UtxoEntry{
+                output: out.clone(),
+                height,
+                coinbase,
+            });
+        }
+    }
+
+    /// Remove a spent output.
+    pub fn spend(&mut self, op: &OutPoint) -> Option<UtxoEntry<A, V>>{
+        self.map.remove(op)
+    }
+
+    /// Count followed by the entries ordered by outpoint.
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut entries: Vec<_> = self.map.iter().collect();
+        entries.sort_by_key(|(op, _)| **op);
+        let mut buf = Vec::new();
+        encode::write_varint(&mut buf, entries.len() as u64);
+        for (op, entry) in entries{
+            entry.encode(op, &mut buf);
+        }
+        buf
+    }
+
+    /// Decode a whole buffer, an outpoint given twice is an error.
+    pub fn from_bytes(bytes: &[u8]) -> Option<UtxoSet<A, V>>{
+        let mut r = Reader::new(bytes);
+        let n = r.read_count(47)?;
+        let mut set = UtxoSet::new();
+        for _ in 0..n{
+            let (op, entry) = UtxoEntry::decode(&mut r)?;
+            if set.map.insert(op, entry).is_some(){
+                return None
+            }
+        }
+        if r.is_empty() { Some(set) } else { None }
+    }
+}
+
+#[cfg(test)]
+mod utxo_test{
+    use super::*;
+    use crate::{SimpleTx, SimpleValue};
+
+    #[test]
+    fn test_add_and_spend() {
+        let mut set = UtxoSet::new();
+        let tx = SimpleTx::coinbase(3, 50.into(), "Alice".to_string());
+        set.add_tx(&tx, 3);
+
+        let op = OutPoint::new(tx.txid(), 0);
+        assert_eq!(1, set.len());
+        assert_eq!(3, set.get(&op).unwrap().height);
+        assert!(set.get(&op).unwrap().coinbase);
+
+        assert!(set.spend(&op).is_some());
+        assert!(set.spend(&op).is_none());
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn test_encoding() {
+        let mut set = UtxoSet::new();
+        set.add_tx(&SimpleTx::coinbase(3, 50.into(), "Alice".to_string()), 3);
+        set.add_tx(&SimpleTx::coinbase(4, 25.into(), "Bob".to_string()), 4);
+        let bytes = set.to_bytes();
+        let decoded = UtxoSet::<String, SimpleValue>::from_bytes(&bytes).unwrap();
+        assert_eq!(bytes, decoded.to_bytes());
+        assert_eq!(2, decoded.len());
+
+        assert!(UtxoSet::<String, SimpleValue>::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        // an outpoint given twice
+        let (op, entry) = decoded.iter().next().unwrap();
+        let mut twice = vec![2];
+        entry.encode(op, &mut twice);
+        entry.encode(op, &mut twice);
+        assert!(UtxoSet::<String, SimpleValue>::from_bytes(&twice).is_none());
+    }
+}
@@ -1,12 +1,13 @@
 
 /// Simple hash function.
 /// reference: https://www.cnblogs.com/zhoug2020/p/6984177.html
-pub fn simple_hasher(key:&[u8], step: usize) -> usize{
+/// 以u64计算，各平台上结果相同。`step`需小于64。
+pub fn simple_hasher(key:&[u8], step: usize) -> u64{
     let p = 16777619;
-    let mut hash = 2166136261usize;
+    let mut hash = 2166136261u64;
 
     for &b in key{
-        hash = (hash ^ b as usize).wrapping_mul(p);
+        hash = (hash ^ b as u64).wrapping_mul(p);
     }
 
     hash = hash.wrapping_add(hash.rotate_left(13));
@@ -27,4 +28,11 @@ fn overflow() {
     eprintln!("{}", simple_hasher("string".as_bytes(), 5));
     eprintln!("{}", simple_hasher("string".as_bytes(), 7));
     eprintln!("{}", simple_hasher("string".as_bytes(), 11)); */
-}
\ No newline at end of file
+}
+
+#[test]
+fn fixed_width() {
+    // 32位平台上相同
+    assert_eq!(18005217428461805635, simple_hasher("string".as_bytes(), 2));
+    assert_eq!(13962647297521698528, simple_hasher("string".as_bytes(), 3));
+}
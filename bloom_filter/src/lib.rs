@@ -95,6 +95,7 @@ impl BloomFilterBuilder{
 }
 
 /// BloomFilter.
+#[derive(Clone, Debug)]
 pub struct BloomFilter{
     steps: Vec<usize>,
     bv: MyBitVec,
@@ -109,11 +110,41 @@ impl BloomFilter{
             bv: MyBitVec::new(1 << 23), // 1 MB
         }
     }
+    /// 由`bits()`等恢复一个过滤器，例如从网络上收到的过滤器。
+    /// 步长须小于64且互不相同：步长k与k+64是同一个hash函数。
+    pub fn from_bits(bit_size: usize, steps: &[usize], bits: Vec<u8>) -> Option<BloomFilter>{
+        // 先检查长度，不按收到的bit_size分配内存
+        if steps.is_empty() || bits.len() != bit_size.div_ceil(8){
+            return None
+        }
+        if steps.iter().enumerate().any(|(i, &s)| s >= 64 || steps[..i].contains(&s)){
+            return None
+        }
+        Some(BloomFilter{
+            steps: steps.to_vec(),
+            num: 0,
+            bv: MyBitVec{ bit_size, bit_vec: bits },
+        })
+    }
+
+    pub fn bit_size(&self) -> usize{
+        self.bv.bit_size()
+    }
+
+    pub fn hash_steps(&self) -> &[usize]{
+        &self.steps
+    }
+
+    /// 内部的bit数组。
+    pub fn bits(&self) -> &[u8]{
+        &self.bv.bit_vec
+    }
+
     pub fn insert(&mut self, key: &[u8]) -> &mut Self{
-        let limit = self.bv.bit_size();
+        let limit = self.bv.bit_size() as u64;
         //let mut tmp = Vec::new();
         for &step in &self.steps{
-            let p = simple_hasher(key, step) % limit;
+            let p = (simple_hasher(key, step) % limit) as usize;
             //tmp.push(p);
             self.bv.set(p);
         }
@@ -124,11 +155,11 @@ impl BloomFilter{
     }
     
     pub fn contains(&self, key: &[u8]) -> bool{
-        let limit = self.bv.bit_size();
+        let limit = self.bv.bit_size() as u64;
         //let mut tmp = Vec::new();
         let mut res = true;
         for &step in &self.steps{
-            let p = simple_hasher(key, step) % limit;
+            let p = (simple_hasher(key, step) % limit) as usize;
             //tmp.push(p);
             res &= self.bv.contains(p);
         }
@@ -175,6 +206,24 @@ mod bf_test {
         assert!(!bf.contains("helloworld".as_bytes()));
     }
 
+    #[test]
+    fn bf_from_bits() {
+        let mut bf = BloomFilterBuilder::new()
+            .set_bit_size(50)
+            .set_hash_steps(&[2, 3, 5, 7])
+            .build().unwrap();
+        bf.insert("string".as_bytes());
+
+        let copy = BloomFilter::from_bits(bf.bit_size(), bf.hash_steps(), bf.bits().to_vec()).unwrap();
+        assert!(copy.contains("string".as_bytes()));
+        assert!(!copy.contains("helloworld".as_bytes()));
+        assert!(BloomFilter::from_bits(50, &[2], vec![0; 6]).is_none());
+        assert!(BloomFilter::from_bits(50, &[], vec![0; 7]).is_none());
+        assert!(BloomFilter::from_bits(usize::MAX, &[2], vec![0; 7]).is_none());
+        assert!(BloomFilter::from_bits(50, &[2, 3, 2], vec![0; 7]).is_none());
+        assert!(BloomFilter::from_bits(50, &[2, 64], vec![0; 7]).is_none());
+    }
+
     #[test]
     fn bf_bench_insertion() {
         let key1 = "abcdefghijklmnopqrstuvwxyz".to_string(); // 26 Byte
@@ -1,4 +1,5 @@
 
+#[derive(Clone, Debug)]
 pub struct MyBitVec{
     // length in bit.
     pub bit_size: usize,
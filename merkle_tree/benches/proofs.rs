@@ -0,0 +1,38 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use merkle_tree::{sha256, MerkleTree};
+
+const TXS: u32 = 4096;
+
+/// `k` tx-nodes spread over the tree, as the matches of a light client filter.
+fn picked(txs: &[[u8; 32]], k: usize) -> Vec<[u8; 32]>{
+    txs.iter().step_by(txs.len() / k).take(k).cloned().collect()
+}
+
+fn bench_proofs(c: &mut Criterion) {
+    let txs: Vec<[u8; 32]> = (0..TXS).map(|i| sha256(&i.to_be_bytes())).collect();
+    let mt = MerkleTree::create(txs.clone());
+    let root = mt.get_root_hash().unwrap();
+    for &k in [1, 8, 64, 512].iter(){
+        let hs = picked(&txs, k);
+        let singles: Vec<_> = hs.iter().map(|h| mt.gen_block_checker(*h).unwrap()).collect();
+        let multi = mt.gen_multi_proof(&hs).unwrap();
+        // the sizes go in the ids, next to the validation times
+        let singles_len: usize = singles.iter().map(|p| p.to_bytes().len()).sum();
+        let multi_len = multi.to_bytes().len();
+
+        c.bench_function(&format!("{} single proofs of {}, {} bytes", k, TXS, singles_len), |b| b.iter(|| {
+            singles.iter().zip(hs.iter()).all(|(p, h)| p.validate(h, &root))
+        }));
+        c.bench_function(&format!("one proof of {} in {}, {} bytes", k, TXS, multi_len), |b| b.iter(|| {
+            multi.validate_all(&hs, &root)
+        }));
+    }
+}
+
+criterion_group!{
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = bench_proofs
+}
+criterion_main!(benches);
@@ -1,10 +1,21 @@
 //! Implementations of MerkleTree(MT) and SortedMerkleTree(SMT).
 //! Use at least 2 tx-node(hashes of transactions) to build a MT or SMT.
 //! 
-//! MT and SMT use `mysha_256::SHA256` for internal hashing to compute the hash of transactions, 
-//! but it's ok to use other hash function to compute hashes as input of `create()`.
+//! Nodes are `Digest`s, fixed-size byte arrays such as `[u8; 32]`. MT and SMT are generic
+//! over the `Hasher` used for internal hashing, `Sha256` by default, `DoubleSha256`,
+//! `Blake2b` and `Keccak256` are offered too. It's ok to use other hash function to compute
+//! hashes as input of `create()`, as long as they have the size of the hasher output.
+//! 
+//! Inner nodes hash the raw bytes of their children, `Mode::LegacyHex` hashes their
+//! hex strings instead, which gives the roots of the former `String` trees.
+//! `Mode::DomainSeparated` prefixes leaves with 0x00 and inner nodes with 0x01 as RFC 6962,
+//! so that an inner node cannot be passed off as a leaf (second preimage).
 //! 
 //! Both MT and SMT offer `gen_block_checker()` method to generate a `BlockChecker` for SPV.
+//! `gen_multi_proof()` proves several tx-nodes in one `BlockChecker`, sharing the nodes
+//! their paths have in common, see `benches/proofs.rs` for the sizes.
+//! A `BlockChecker` is sent as `to_bytes()`, in the style of Bitcoin's `merkleblock`, and
+//! `from_bytes()` rejects malformed proofs.
 //! 
 //! # MerkleTree
 //! `MerkleTree` allows appending tx-nodes and simultaneously 
@@ -12,15 +23,14 @@
 //! 
 //! The order of tx-nodes is determinated by user.
 //! ```
-//! use mysha_256::sha256::SHA256;
-//! use merkle_tree::MerkleTree;
+//! use merkle_tree::{sha256, MerkleTree};
 //! 
 //! let txs = vec!["abc", "abcd", "abcde"]
 //!     .into_iter()
-//!     .map(|b| SHA256::new(b.as_bytes()).cal_sha_256())
+//!     .map(|b| sha256(b.as_bytes()))
 //!     .collect();
 //! let mut mt = MerkleTree::create(txs);
-//! mt.append(SHA256::new("abcdef".as_bytes()).cal_sha_256());
+//! mt.append(sha256("abcdef".as_bytes()));
 //! ```
 //! 
 //! # SortedMerkleTree
@@ -28,53 +38,258 @@
 //! so it doesn't offer `append()`.
 //! 
 //! `SortedMerkleTree` sorts tx-nodes first and then compute the merkle root.
-//! The order of tx-nodes is determinated by `String`.
+//! The order of tx-nodes is determinated by the bytes of the digests.
 //! ```
-//! use mysha_256::sha256::SHA256;
-//! use merkle_tree::SortedMerkleTree;
+//! use merkle_tree::{sha256, SortedMerkleTree};
 //! 
 //! let txs = vec!["abc", "abcd", "abcde"]
 //!     .into_iter()
-//!     .map(|b| SHA256::new(b.as_bytes()).cal_sha_256())
+//!     .map(|b| sha256(b.as_bytes()))
 //!     .collect();
 //! let smt = SortedMerkleTree::create(txs);
-//! smt.append(SHA256::new("abcdef".as_bytes()).cal_sha_256());
+//! ```
+//! 
+//! # Hashers
+//! ```
+//! use merkle_tree::{Blake2b, Digest, Hasher, MerkleTree, Mode};
+//! 
+//! let txs = vec!["abc", "abcd", "abcde"]
+//!     .into_iter()
+//!     .map(|b| Blake2b::hash(b.as_bytes()))
+//!     .collect();
+//! let mt: MerkleTree<Blake2b> = MerkleTree::with_mode(txs, Mode::DomainSeparated);
+//! assert_eq!(128, mt.get_root_hash().unwrap().to_hex().len());
 //! ```
 //! 
 //! [Impl reference](https://www.jianshu.com/p/bfe990be3a21)
 //! 
 //! 
+use std::fmt;
+use std::hash::Hash;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use blake2::Blake2b as Blake2b512;
+use sha3::Keccak256 as Keccak;
 use mysha_256::sha_256::SHA256;
-static NO_SLIBLING: &'static str = "";
 
+/// A fixed-size hash value, the node of a merkle tree.
+pub trait Digest: Copy + Eq + Ord + Hash + fmt::Debug + AsRef<[u8]>{
+    /// Size in bytes.
+    const LEN: usize;
+
+    /// `None` if `bytes` is not `LEN` bytes long.
+    fn from_slice(bytes: &[u8]) -> Option<Self>;
+
+    fn to_hex(&self) -> String{
+        self.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn from_hex(s: &str) -> Option<Self>{
+        if s.len() != 2 * Self::LEN || !s.is_ascii(){
+            return None
+        }
+        let bytes = (0..s.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Self::from_slice(&bytes)
+    }
+}
+
+macro_rules! impl_digest {
+    ($($n:expr),+) => {
+        $(
+            impl Digest for [u8; $n]{
+                const LEN: usize = $n;
+
+                fn from_slice(bytes: &[u8]) -> Option<Self>{
+                    if bytes.len() != $n{
+                        return None
+                    }
+                    let mut h = [0; $n];
+                    h.copy_from_slice(bytes);
+                    Some(h)
+                }
+            }
+        )+
+    };
+}
+
+impl_digest!(32, 64);
+
+/// Hash function of a merkle tree.
+pub trait Hasher{
+    type Output: Digest;
+
+    fn hash(data: &[u8]) -> Self::Output;
+}
+
+/// SHA-256, by `mysha_256`.
+pub struct Sha256;
+
+/// SHA-256 applied twice, as the merkle trees of Bitcoin.
+pub struct DoubleSha256;
+
+/// BLAKE2b with a 64 bytes output.
+pub struct Blake2b;
+
+/// Keccak-256, as Ethereum (not the final SHA3-256 padding).
+pub struct Keccak256;
+
+impl Hasher for Sha256{
+    type Output = [u8; 32];
+
+    fn hash(data: &[u8]) -> [u8; 32]{
+        sha256(data)
+    }
+}
+
+impl Hasher for DoubleSha256{
+    type Output = [u8; 32];
+
+    fn hash(data: &[u8]) -> [u8; 32]{
+        sha256(&sha256(data))
+    }
+}
+
+impl Hasher for Blake2b{
+    type Output = [u8; 64];
+
+    fn hash(data: &[u8]) -> [u8; 64]{
+        use blake2::Digest as _;
+        <[u8; 64]>::from_slice(&Blake2b512::digest(data)).unwrap()
+    }
+}
+
+impl Hasher for Keccak256{
+    type Output = [u8; 32];
+
+    fn hash(data: &[u8]) -> [u8; 32]{
+        use sha3::Digest as _;
+        <[u8; 32]>::from_slice(&Keccak::digest(data)).unwrap()
+    }
+}
+
+/// SHA-256 of `data`.
+pub fn sha256(data: &[u8]) -> [u8; 32]{
+    SHA256::new(data).digest()
+}
+
+/// RFC 6962 prefix of the hash of a leaf.
+const LEAF_PREFIX: u8 = 0x00;
+/// RFC 6962 prefix of the hash of an inner node.
+const NODE_PREFIX: u8 = 0x01;
+
+/// What the hash of an inner node is computed over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode{
+    /// The bytes of the left child followed by the right one.
+    Binary,
+    /// The concatenated lowercase hex strings of the children, as the former `String` trees.
+    LegacyHex,
+    /// As `Binary` after 0x01, the tx-nodes are hashed again after 0x00 (RFC 6962).
+    DomainSeparated,
+}
+
+/// Hash of the inner node over `l` and `r`, a last node without sibling is hashed on its own.
+fn hash_node<H: Hasher>(mode: Mode, l: &H::Output, r: Option<&H::Output>) -> H::Output{
+    match mode{
+        Mode::Binary | Mode::DomainSeparated => {
+            let mut buf = Vec::with_capacity(1 + 2 * H::Output::LEN);
+            if mode == Mode::DomainSeparated{
+                buf.push(NODE_PREFIX);
+            }
+            buf.extend_from_slice(l.as_ref());
+            if let Some(r) = r{
+                buf.extend_from_slice(r.as_ref());
+            }
+            H::hash(&buf)
+        },
+        Mode::LegacyHex => {
+            let mut s = l.to_hex();
+            if let Some(r) = r{
+                s += &r.to_hex();
+            }
+            H::hash(s.as_bytes())
+        },
+    }
+}
+
+/// The level 0 node of the tx-node `h`.
+fn hash_leaf<H: Hasher>(mode: Mode, h: &H::Output) -> H::Output{
+    match mode{
+        Mode::DomainSeparated => {
+            let mut buf = Vec::with_capacity(1 + H::Output::LEN);
+            buf.push(LEAF_PREFIX);
+            buf.extend_from_slice(h.as_ref());
+            H::hash(&buf)
+        },
+        _ => *h,
+    }
+}
+
+
+/// Nodes from the tx-nodes at `indexes` up to below the root, in reverse preorder so that
+/// `get_flags()` and `get_blocks()` meet the last one first.
+fn proof_path(top_level: usize, indexes: &[usize]) -> Vec<(usize, usize)>{
+    let mut path: Vec<(usize, usize)> = indexes.iter()
+        .flat_map(|&i| (0..top_level - 1).map(move |l| (l, i >> l)))
+        .collect();
+    // preorder: by the first tx-node below, parents first
+    path.sort_by_key(|&(l, i)| (i << l, std::cmp::Reverse(l)));
+    path.dedup();
+    path.reverse();
+    path
+}
 
 /// `MerkleTree` allow appending new transactions and simultaneously 
 /// update the merkle root hash in O(logN), where N is the number of nodes.
 /// The order of tx-nodes is determinated by user.
 /// # Example
 /// ```
-/// use mysha_256::sha_256::SHA256;
-/// use merkle_tree::MerkleTree;
+/// use merkle_tree::{sha256, MerkleTree};
 /// 
 /// let txs = vec!["abc", "abcd", "abcde"]
 ///     .into_iter()
-///     .map(|b| SHA256::new(b.as_bytes()).cal_sha_256())
+///     .map(|b| sha256(b.as_bytes()))
 ///     .collect();
 /// let mut mt = MerkleTree::create(txs);
-/// mt.append(SHA256::new("abcdef".as_bytes()).cal_sha_256());
+/// mt.append(sha256("abcdef".as_bytes()));
 /// ```
-pub struct MerkleTree{
-    tree: Vec<Vec<String>>,
+pub struct MerkleTree<H: Hasher = Sha256>{
+    tree: Vec<Vec<H::Output>>,
+    mode: Mode,
 }
 
 impl MerkleTree{
+    /// Create MerkleTree from non-empty hash sequence using sha-256..
+    /// # Example 
+    /// ```
+    /// use merkle_tree::{sha256, MerkleTree};
+    /// 
+    /// let case = vec!["abc", "abcd", "abcde"]
+    ///     .into_iter()
+    ///     .map(|b| sha256(b.as_bytes()))
+    ///     .collect();
+    /// let mt = MerkleTree::create(case);
+    /// ```
+    pub fn create(txs: Vec<[u8; 32]>) -> MerkleTree{
+        MerkleTree::with_mode(txs, Mode::Binary)
+    }
+
+    /// Create a MerkleTree hashing the hex strings of the nodes, as the former `String` trees.
+    pub fn create_legacy(txs: Vec<[u8; 32]>) -> MerkleTree{
+        MerkleTree::with_mode(txs, Mode::LegacyHex)
+    }
+}
+
+impl<H: Hasher> MerkleTree<H>{
     fn build(&mut self){
         let mut n = self.tree[0].len();
         while n > 1{
             let mut nodes = Vec::with_capacity((n+1) >> 1);
             for ck in self.tree.last().unwrap().chunks(2){
-                let h = SHA256::new(ck.join("").as_bytes()).cal_sha_256();
-                //println!("ck = {:?}, h = {}", ck, &h);
+                let h = hash_node::<H>(self.mode, &ck[0], ck.get(1));
                 nodes.push(h);
             }
             self.tree.push(nodes);
@@ -83,25 +298,21 @@ impl MerkleTree{
         assert_eq!(1, self.tree.last().unwrap().len());
     }
 
-    fn get_slibling_hash(&self, row: usize, index: usize) -> &str{
+    fn get_slibling_hash(&self, row: usize, index: usize) -> Option<&H::Output>{
         // index row is ok
         let n = self.tree[row].len();
         if index >= n{
-            NO_SLIBLING
+            None
         }else{
             if index % 2 == 0{ // left, 0 <= index < len()
-                if index + 1 < n{ 
-                    &self.tree[row][index+1]
-                }else{
-                    NO_SLIBLING
-                }
+                self.tree[row].get(index+1)
             }else{ // right, index >= 1
-                &self.tree[row][index-1]
+                Some(&self.tree[row][index-1])
             }
         }
     }
 
-    fn update_tree(&mut self, h: String){
+    fn update_tree(&mut self, h: H::Output){
         let mut index = self.tree[0].len() - 1;
         let mut current_hash = h;
         let top_level = self.tree.len() - 1;
@@ -111,15 +322,15 @@ impl MerkleTree{
 
             let slib_hash = self.get_slibling_hash(level_index, index);
             if index % 2 == 0 { // left
-                current_hash = SHA256::new((current_hash + slib_hash).as_bytes()).cal_sha_256();
+                current_hash = hash_node::<H>(self.mode, &current_hash, slib_hash);
             }else{
-                current_hash = SHA256::new((slib_hash.to_string() + &current_hash).as_bytes()).cal_sha_256();
+                current_hash = hash_node::<H>(self.mode, slib_hash.unwrap(), Some(&current_hash));
             }
 
             if p_index >= self.tree[level_index+1].len(){
-                self.tree[level_index+1].push(current_hash.clone());
+                self.tree[level_index+1].push(current_hash);
             }else{
-                self.tree[level_index+1][p_index] = current_hash.clone();
+                self.tree[level_index+1][p_index] = current_hash;
             }
             index = self.tree[level_index+1].len() - 1;
             level_index += 1;
@@ -128,54 +339,34 @@ impl MerkleTree{
         let top_len = self.tree.last().unwrap().len();
         if top_len > 1{ 
             assert_eq!(2, top_len);
-            self.tree.push(
-                vec![
-                    SHA256::new(self.tree.last()
-                    .unwrap()
-                    .join("")
-                    .as_bytes())
-                    .cal_sha_256()
-                ]
-            );
+            let top = self.tree.last().unwrap();
+            self.tree.push(vec![hash_node::<H>(self.mode, &top[0], top.get(1))]);
         }
     }
 
-    /// Get blocks.
-    fn get_blocks(&self, path: &Vec<(usize, usize)>) -> (Vec<String>, usize){
-        // left node to hashes, right node to rhashes, 
+    /// Hashes of the nodes flagged 0 by `get_flags()` and of the tx-nodes of `path`, in preorder.
+    fn get_blocks(&self, path: &Vec<(usize, usize)>) -> Vec<H::Output>{
         let top_level = self.tree.len();
         assert!(top_level > 1);
-        let mut hashes = Vec::with_capacity(path.len() + 1); // 完全二叉树 n0 = n2 + 1
-        let mut rhashes = Vec::new(); // right node's hash
-
-        // left node to hashes, right node to rhashes, 
-        for &pos in path.iter().rev(){
-            println!("proc {:?}", pos);
-            match (MerkleTree::is_tx_node(pos.0), pos){
-                (true, (l, i)) => { // tx node => edge.
-                    // tx节点的兄弟都要push进去
-                    if i % 2 == 0{ 
-                        rhashes.push(self.get_slibling_hash(l, i).to_string());  
-                        hashes.push(self.tree[l][i].clone());
-                    }else{
-                        rhashes.push(self.tree[l][i].clone());
-                        hashes.push(self.get_slibling_hash(l, i).to_string());
-                    }
-                },
-                (false, (l, i)) => {
-                    if i % 2 == 0{ // 左节点需要做计算, 因此右节点push
-                        rhashes.push(self.get_slibling_hash(l, i).to_string());  
-                    }else{
-                        hashes.push(self.get_slibling_hash(l, i).to_string());
-                    }
-                },
+        let mut path = path.clone();
+        let mut blocks = Vec::with_capacity(path.len() + 1);
+        let mut st: Vec<(usize, usize)> = vec![(top_level-2, 1), (top_level-2, 0)];
+        while let Some((l, i)) = st.pop(){
+            let on_path = path.last() == Some(&(l, i));
+            if on_path{
+                path.pop();
+            }
+            if on_path && l > 0{
+                let lens = self.tree[l-1].len();
+                let q = i << 1;
+                if q + 1 < lens {   st.push((l-1, q+1)); }
+                st.push((l-1, q));
+            }else{
+                // sibling of the path or a tx-node of it
+                blocks.push(self.tree[l][i]);
             }
         }
-        let rhash_index = hashes.len();
-        while let Some(h) = rhashes.pop(){
-            hashes.push(h);
-        }
-        (hashes, rhash_index)
+        blocks
     }
 
     fn get_flags(&self, path: &Vec<(usize, usize)>) -> Vec<u8>{
@@ -193,7 +384,6 @@ impl MerkleTree{
         st.push((top_level-2, 0)); 
         //println!("{:?}", path);
         while let Some((l, i)) = st.pop(){
-            println!("st={:?}", &st);
             if let Some((pl, pi)) = path.last(){
                 if *pl == l && *pi == i{ // match nodes in path.
                     // txnode filter match 
@@ -214,78 +404,50 @@ impl MerkleTree{
             }else{
                 flags.push(0);
             }
-            println!("st={:?}, {:?}", &st, &flags);
         }
         //println!("{:?}", &flags);
         flags
     }
 
-    /// Create MerkleTree from non-empty hash sequence using sha-256..
-    /// # Example 
-    /// ```
-    /// use mysha_256::sha_256::SHA256;
-    /// use merkle_tree::MerkleTree;
-    /// 
-    /// let case = vec!["abc", "abcd", "abcde"]
-    ///     .into_iter()
-    ///     .map(|b| SHA256::new(b.as_bytes()).cal_sha_256())
-    ///     .collect();
-    /// let mt = MerkleTree::create(case);
-    /// ```
-    pub fn create(txs: Vec<String>) -> MerkleTree{
+    /// Create a MerkleTree hashing with `H` as `mode`.
+    pub fn with_mode(txs: Vec<H::Output>, mode: Mode) -> MerkleTree<H>{
         assert!(txs.len() >= 2);
+        let txs = txs.iter().map(|h| hash_leaf::<H>(mode, h)).collect();
         // if txs is sorted and then we can perform Inclusive Verifacation in O(logN).
         // But it will slow append(), remove(), update() due to re-sort and O(N) re-hash.
         // txs.sort();
         let mut mt = MerkleTree{
             tree: vec![txs],
+            mode,
         };
         mt.build();
         assert!(mt.tree.len() >= 1);
         mt      
     }
 
-    // TODO
-    pub fn gen_block_checker(&self, h: String) -> Option<BlockChecker>{
-        let t = &h;
-        let mut id = None;
-        for (i, tx) in self.tree[0].iter().enumerate(){
-            if tx == t{
-                id = Some(i);
-                break
-            }
-        }
-        if id.is_none(){
-            return None
-        }
-        let mut index = id.unwrap();
-        let mut level = 0;
-        let top_level = self.tree.len();
-        assert!(top_level >= 1);
-        let mut path = Vec::with_capacity(top_level);
-        
-        // get paths.
-        while level < top_level - 1{
-            path.push((level, index)); // index % 2 == 0 --> left
-            index = index >> 1;
-            level += 1;
-        }
-        for p in path.iter(){
-            println!("(level={}, index={})", p.0, p.1);
-        }
-        let (blocks, rhash_index) = self.get_blocks(&path);
+    pub fn gen_block_checker(&self, h: H::Output) -> Option<BlockChecker<H>>{
+        self.gen_multi_proof(&[h])
+    }
+
+    /// Generate one `BlockChecker` proving all the tx-nodes of `hs`, the nodes shared by
+    /// their paths are sent once. `None` if one of them is not in the tree.
+    pub fn gen_multi_proof(&self, hs: &[H::Output]) -> Option<BlockChecker<H>>{
+        let indexes = hs.iter()
+            .map(|h| {
+                let t = hash_leaf::<H>(self.mode, h);
+                self.tree[0].iter().position(|tx| *tx == t)
+            })
+            .collect::<Option<Vec<usize>>>()?;
+        let path = proof_path(self.tree.len(), &indexes);
+        let blocks = self.get_blocks(&path);
         let flags: Vec<u8> = self.get_flags(&path);
 
-        for (i, e) in blocks.iter().enumerate(){
-            println!("{}-{}", i, e);
-        }
-        println!("flags = {:?}", flags);
-        
         Some(
             BlockChecker{
-                rtx_index: rhash_index,
+                leaves: self.tree[0].len(),
                 blocks: blocks,
                 flags: flags,
+                mode: self.mode,
             }
         )
     }
@@ -294,7 +456,7 @@ impl MerkleTree{
         for (row_i, row) in self.tree.iter().enumerate(){
             println!("level - {}", row_i);
             for (i, hs) in row.iter().enumerate(){
-                println!("i={}, hash={}",i, hs);
+                println!("i={}, hash={}",i, hs.to_hex());
             }
             println!();
         }
@@ -314,14 +476,19 @@ impl MerkleTree{
         self.tree.len() == level + 1
     }
 
-    pub fn append(&mut self, h: String){
-        self.tree[0].push(h.clone());
+    pub fn get_mode(&self) -> Mode{
+        self.mode
+    }
+
+    pub fn append(&mut self, h: H::Output){
+        let h = hash_leaf::<H>(self.mode, &h);
+        self.tree[0].push(h);
         self.update_tree(h);
     }
 
     /// Get hash of  merkle root.
-    pub fn get_root_hash(&self) -> Option<String>{
-        Some(self.tree.last().unwrap()[0].clone())
+    pub fn get_root_hash(&self) -> Option<H::Output>{
+        Some(self.tree.last().unwrap()[0])
     }
 }
 
@@ -342,28 +509,28 @@ mod test_merkle_tree{
         ];
 
         // the first level hash.
-        let hss:Vec<String> = case.into_iter()
-            .map(|b| SHA256::new(b.as_bytes()).cal_sha_256())
+        let hss:Vec<[u8; 32]> = case.into_iter()
+            .map(|b| sha256(b.as_bytes()))
             .collect();
         let mt = MerkleTree::create(hss);
 
         assert_eq!(
-            SHA256::new("abcd".as_bytes()).cal_sha_256(),
+            Some(&sha256("abcd".as_bytes())),
             mt.get_slibling_hash(0, 0),
         );
 
         assert_eq!(
-            SHA256::new("abc".as_bytes()).cal_sha_256(),
+            Some(&sha256("abc".as_bytes())),
             mt.get_slibling_hash(0, 1),
         );
 
         assert_eq!(
-            NO_SLIBLING,
+            None,
             mt.get_slibling_hash(0, 6),
         );
 
         assert_eq!(
-            NO_SLIBLING,
+            None,
             mt.get_slibling_hash(0, 1024),
         );
     }
@@ -372,7 +539,7 @@ mod test_merkle_tree{
     #[should_panic]
     fn test_panic_get_slib_hash(){
         MerkleTree::create(
-            vec!["36bbe50ed96841d10443bcb670d6554f0a34b761be67ec9c4a8ad2c0c44ca42c".to_string()]
+            vec![<[u8; 32]>::from_hex("36bbe50ed96841d10443bcb670d6554f0a34b761be67ec9c4a8ad2c0c44ca42c").unwrap()]
         ).get_slibling_hash(1024, 1);
     }
 
@@ -384,9 +551,9 @@ mod test_merkle_tree{
             "abcdefg", "abcdefgh",
             "abcdefghi",
         ].into_iter()
-        .map(|b| SHA256::new(b.as_bytes()).cal_sha_256())
+        .map(|b| sha256(b.as_bytes()))
         .collect();
-        let mt = MerkleTree::create(case);
+        let mt: MerkleTree = MerkleTree::create(case);
         let path = vec![(0, 2), (1, 1), (2, 0)];
         assert_eq!(
             vec![1, 1, 0, 1, 1, 0, 0],
@@ -412,34 +579,105 @@ mod test_merkle_tree{
             "abcdefgh",
             "abcdefghi",
         ].into_iter()
-        .map(|b| SHA256::new(b.as_bytes()).cal_sha_256())
+        .map(|b| sha256(b.as_bytes()))
         .collect(); 
 
-        let mt2 = MerkleTree::create(case2);
+        let mt2: MerkleTree = MerkleTree::create_legacy(case2);
         assert_eq!(
             4, 
             mt2.get_height()
         );
         assert_eq!(
             "e239682fd4c8efb7a008f355d8c9858a2b458fee1e4ba1bc86dd032fe745d204",
-            mt2.get_root_hash().unwrap()
+            mt2.get_root_hash().unwrap().to_hex()
         );
     }
 
+    #[test]
+    fn test_binary_nodes() {
+        let (a, b, c) = (sha256(b"abc"), sha256(b"abcd"), sha256(b"abcde"));
+        let mt = MerkleTree::create(vec![a, b, c]);
+        let mut ab = a.to_vec();
+        ab.extend_from_slice(&b);
+        // the last node is hashed on its own
+        let mut root = sha256(&ab).to_vec();
+        root.extend_from_slice(&sha256(&c));
+        assert_eq!(Some(sha256(&root)), mt.get_root_hash());
+
+        let legacy = MerkleTree::create_legacy(vec![a, b, c]);
+        assert_ne!(mt.get_root_hash(), legacy.get_root_hash());
+        let ab = sha256((a.to_hex() + &b.to_hex()).as_bytes());
+        let c = sha256(c.to_hex().as_bytes());
+        assert_eq!(Some(sha256((ab.to_hex() + &c.to_hex()).as_bytes())), legacy.get_root_hash());
+
+        assert_eq!(Some(a), <[u8; 32]>::from_hex(&a.to_hex()));
+        assert_eq!(None, <[u8; 32]>::from_hex("abcd"));
+        assert_eq!(None, <[u8; 32]>::from_hex(&"zz".repeat(32)));
+    }
+
+    #[test]
+    fn test_hashers() {
+        assert_eq!(
+            "4f8b42c22dd3729b519ba6f68d2da7cc5b2d606d05daed5ad5128cc03e6c6358",
+            DoubleSha256::hash(b"abc").to_hex()
+        );
+        assert_eq!(
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470",
+            Keccak256::hash(b"").to_hex()
+        );
+        assert_eq!(
+            "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1\
+             7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923",
+            Blake2b::hash(b"abc").to_hex()
+        );
+
+        let txs: Vec<[u8; 64]> = ["abc", "abcd", "abcde"].iter().map(|b| Blake2b::hash(b.as_bytes())).collect();
+        let mut mt: MerkleTree<Blake2b> = MerkleTree::with_mode(txs[..2].to_vec(), Mode::Binary);
+        mt.append(txs[2]);
+        let mut ab = txs[0].to_vec();
+        ab.extend_from_slice(&txs[1]);
+        let mut root = Blake2b::hash(&ab).to_vec();
+        root.extend_from_slice(&Blake2b::hash(&txs[2]));
+        assert_eq!(Some(Blake2b::hash(&root)), mt.get_root_hash());
+    }
+
+    #[test]
+    fn test_domain_separation() {
+        let txs: Vec<[u8; 32]> = ["abc", "abcd", "abcde", "abcdef"].iter().map(|b| sha256(b.as_bytes())).collect();
+        // without prefixes the inner nodes pass for tx-nodes of a tree with the same root
+        let mt = MerkleTree::create(txs.clone());
+        let forged = MerkleTree::create(mt.tree[1].clone());
+        assert_eq!(mt.get_root_hash(), forged.get_root_hash());
+
+        let mt: MerkleTree = MerkleTree::with_mode(txs.clone(), Mode::DomainSeparated);
+        let forged: MerkleTree = MerkleTree::with_mode(mt.tree[1].clone(), Mode::DomainSeparated);
+        assert_ne!(mt.get_root_hash(), forged.get_root_hash());
+
+        let leaf = |h: &[u8; 32]| sha256(&[&[0u8][..], h].concat());
+        let node = |l: &[u8; 32], r: &[u8; 32]| sha256(&[&[1u8][..], l, r].concat());
+        let root = node(&node(&leaf(&txs[0]), &leaf(&txs[1])), &node(&leaf(&txs[2]), &leaf(&txs[3])));
+        assert_eq!(Some(root), mt.get_root_hash());
+
+        let mut appended: MerkleTree = MerkleTree::with_mode(txs[..2].to_vec(), Mode::DomainSeparated);
+        appended.append(txs[2]);
+        appended.append(txs[3]);
+        assert_eq!(Some(root), appended.get_root_hash());
+    }
+
     #[test]
     fn test_append() {
         let case2 = vec!["abc", "abcd", "abcde",]
         .into_iter()
-        .map(|b| SHA256::new(b.as_bytes()).cal_sha_256())
+        .map(|b| sha256(b.as_bytes()))
         .collect();
 
-        let mut mt = MerkleTree::create(case2);
+        let mut mt: MerkleTree = MerkleTree::create(case2);
         assert_eq!(3, mt.get_height());
 
-        mt.append(SHA256::new("abcdef".as_bytes()).cal_sha_256());
+        mt.append(sha256("abcdef".as_bytes()));
         assert_eq!(3, mt.get_height());
 
-        mt.append(SHA256::new("abcdefg".as_bytes()).cal_sha_256());
+        mt.append(sha256("abcdefg".as_bytes()));
         assert_eq!(4, mt.get_height());
 
         assert_eq!(
@@ -447,7 +685,7 @@ mod test_merkle_tree{
             MerkleTree::create(
                 vec!["abc", "abcd", "abcde", "abcdef", "abcdefg"]
                 .into_iter()
-                .map(|b| SHA256::new(b.as_bytes()).cal_sha_256())
+                .map(|b| sha256(b.as_bytes()))
                 .collect()
             ).get_root_hash()
         )
@@ -457,32 +695,54 @@ mod test_merkle_tree{
 /// Differs from `MerkleTree`, `SortedMerkleTree` disallow modifiaction 
 /// so it doesn't offer `.append()`.
 /// `SortedMerkleTree` sorts tx-nodes first and then compute the merkle root.
-/// The order of tx-nodes is determinated by `String`.
+/// The order of tx-nodes is determinated by the bytes of the digests.
 /// 
 /// # Example
 /// ```
-/// use mysha_256::SHA256;
-/// use merkle_tree::SortedMerkleTree;
+/// use merkle_tree::{sha256, SortedMerkleTree};
 /// let txs = vec!["abc", "abcd", "abcde"]
 ///     .into_iter()
-///     .map(|b| SHA256::new(b.as_bytes()).cal_sha_256())
+///     .map(|b| sha256(b.as_bytes()))
 ///     .collect();
 /// let smt = SortedMerkleTree::create(txs);
-/// mt.append(SHA256::new("abcdef".as_bytes()).cal_sha_256());
 /// ```
-pub struct SortedMerkleTree{
-    tree: Vec<Vec<String>>,
+pub struct SortedMerkleTree<H: Hasher = Sha256>{
+    tree: Vec<Vec<H::Output>>,
+    mode: Mode,
 }
 
 
 impl SortedMerkleTree{
+    /// Create a SortedMerkleTree from non-empty hash sequence using sha-256. 
+    /// Hashes of Transactions will be sorted before further construction.
+    /// # Example 
+    /// ```
+    /// use merkle_tree::{sha256, SortedMerkleTree};
+    /// 
+    /// let case = vec!["abc", "abcd", "abcde"]
+    ///     .into_iter()
+    ///     .map(|b| sha256(b.as_bytes()))
+    ///     .collect();
+    /// let mt = SortedMerkleTree::create(case);
+    /// 
+    /// ```
+    pub fn create(txs: Vec<[u8; 32]>) -> SortedMerkleTree{
+        SortedMerkleTree::with_mode(txs, Mode::Binary)
+    }
+
+    /// Create a SortedMerkleTree hashing the hex strings of the nodes, as the former `String` trees.
+    pub fn create_legacy(txs: Vec<[u8; 32]>) -> SortedMerkleTree{
+        SortedMerkleTree::with_mode(txs, Mode::LegacyHex)
+    }
+}
+
+impl<H: Hasher> SortedMerkleTree<H>{
     fn build(&mut self){
         let mut n = self.tree[0].len();
         while n > 1{
             let mut nodes = Vec::with_capacity((n+1) >> 1);
             for ck in self.tree.last().unwrap().chunks(2){
-                let h = SHA256::new(ck.join("").as_bytes()).cal_sha_256();
-                //println!("ck = {:?}, h = {}", ck, &h);
+                let h = hash_node::<H>(self.mode, &ck[0], ck.get(1));
                 nodes.push(h);
             }
             self.tree.push(nodes);
@@ -491,60 +751,29 @@ impl SortedMerkleTree{
         assert_eq!(1, self.tree.last().unwrap().len());
     }
 
-    fn get_slibling_hash(&self, row: usize, index: usize) -> &str{
-        // index row is ok
-        let n = self.tree[row].len();
-        if index >= n{
-            NO_SLIBLING
-        }else{
-            if index % 2 == 0{ // left, 0 <= index < len()
-                if index + 1 < n{ 
-                    &self.tree[row][index+1]
-                }else{
-                    NO_SLIBLING
-                }
-            }else{ // right, index >= 1
-                &self.tree[row][index-1]
-            }
-        }
-    }
-
-    /// Get blocks.
-    fn get_blocks(&self, path: &Vec<(usize, usize)>) -> (Vec<String>, usize){
-        // left node to hashes, right node to rhashes, 
+    /// Hashes of the nodes flagged 0 by `get_flags()` and of the tx-nodes of `path`, in preorder.
+    fn get_blocks(&self, path: &Vec<(usize, usize)>) -> Vec<H::Output>{
         let top_level = self.tree.len();
         assert!(top_level > 1);
-        let mut hashes = Vec::with_capacity(path.len() + 1); // 完全二叉树 n0 = n2 + 1
-        let mut rhashes = Vec::new(); // right node's hash
-
-        // left node to hashes, right node to rhashes, 
-        for &pos in path.iter().rev(){
-            //println!("proc {:?}", pos);
-            match (MerkleTree::is_tx_node(pos.0), pos){
-                (true, (l, i)) => { // tx node => edge.
-                    // tx节点的兄弟都要push进去
-                    if i % 2 == 0{ 
-                        rhashes.push(self.get_slibling_hash(l, i).to_string());  
-                        hashes.push(self.tree[l][i].clone());
-                    }else{
-                        rhashes.push(self.tree[l][i].clone());
-                        hashes.push(self.get_slibling_hash(l, i).to_string());
-                    }
-                },
-                (false, (l, i)) => {
-                    if i % 2 == 0{ // 左节点需要做计算, 因此右节点push
-                        rhashes.push(self.get_slibling_hash(l, i).to_string());  
-                    }else{
-                        hashes.push(self.get_slibling_hash(l, i).to_string());
-                    }
-                },
+        let mut path = path.clone();
+        let mut blocks = Vec::with_capacity(path.len() + 1);
+        let mut st: Vec<(usize, usize)> = vec![(top_level-2, 1), (top_level-2, 0)];
+        while let Some((l, i)) = st.pop(){
+            let on_path = path.last() == Some(&(l, i));
+            if on_path{
+                path.pop();
+            }
+            if on_path && l > 0{
+                let lens = self.tree[l-1].len();
+                let q = i << 1;
+                if q + 1 < lens {   st.push((l-1, q+1)); }
+                st.push((l-1, q));
+            }else{
+                // sibling of the path or a tx-node of it
+                blocks.push(self.tree[l][i]);
             }
         }
-        let rhash_index = hashes.len();
-        while let Some(h) = rhashes.pop(){
-            hashes.push(h);
-        }
-        (hashes, rhash_index)
+        blocks
     }
 
     fn get_flags(&self, path: &Vec<(usize, usize)>) -> Vec<u8>{
@@ -562,7 +791,6 @@ impl SortedMerkleTree{
         st.push((top_level-2, 0)); 
         //println!("{:?}", path);
         while let Some((l, i)) = st.pop(){
-            println!("st={:?}", &st);
             if let Some((pl, pi)) = path.last(){
                 if *pl == l && *pi == i{ // match nodes in path.
                     // txnode filter match 
@@ -583,73 +811,50 @@ impl SortedMerkleTree{
             }else{
                 flags.push(0);
             }
-            println!("st={:?}, {:?}", &st, &flags);
         }
         //println!("{:?}", &flags);
         flags
     }
 
-    /// Create a SortedMerkleTree from non-empty hash sequence using sha-256. 
-    /// Hashes of Transactions will be sorted before further construction.
-    /// # Example 
-    /// ```
-    /// use mysha_256::sha_256::SHA256;
-    /// use merkle_tree::SortedMerkleTree;
-    /// 
-    /// let case = vec!["abc", "abcd", "abcde"]
-    ///     .into_iter()
-    ///     .map(|b| SHA256::new(b.as_bytes()).cal_sha_256())
-    ///     .collect();
-    /// let mt = SortedMerkleTree::create(case);
-    /// 
-    /// ```
-    pub fn create(mut txs: Vec<String>) -> SortedMerkleTree{
+    /// Create a SortedMerkleTree hashing with `H` as `mode`.
+    pub fn with_mode(txs: Vec<H::Output>, mode: Mode) -> SortedMerkleTree<H>{
         assert!(txs.len() >= 2);
+        let mut txs: Vec<_> = txs.iter().map(|h| hash_leaf::<H>(mode, h)).collect();
         // if txs is sorted and then we can perform Inclusive Verifacation in O(logN).
         // But it will slow append(), remove(), update() due to re-sort and O(N) re-hash.
         txs.sort();
         let mut smt = SortedMerkleTree{
             tree: vec![txs],
+            mode,
         };
         smt.build();
         assert!(smt.tree.len() >= 1);
         smt      
     }
 
-    // TODO
-    pub fn gen_block_checker(&self, h: String) -> Option<BlockChecker>{
-        let t = &h;
-        let mut id = None;
-        for (i, tx) in self.tree[0].iter().enumerate(){
-            if tx == t{
-                id = Some(i);
-                break
-            }
-        }
-        if id.is_none(){
-            return None
-        }
-        let mut index = id.unwrap();
-        let mut level = 0;
-        let top_level = self.tree.len();
-        assert!(top_level >= 1);
-        let mut path = Vec::with_capacity(top_level);
-        
-        // get paths.
-        while level < top_level - 1{
-            path.push((level, index)); // index % 2 == 0 --> left
-            index = index >> 1;
-            level += 1;
-        }
-        
-        let (blocks, rhash_index) = self.get_blocks(&path);
+    pub fn gen_block_checker(&self, h: H::Output) -> Option<BlockChecker<H>>{
+        self.gen_multi_proof(&[h])
+    }
+
+    /// Generate one `BlockChecker` proving all the tx-nodes of `hs`, the nodes shared by
+    /// their paths are sent once. `None` if one of them is not in the tree.
+    pub fn gen_multi_proof(&self, hs: &[H::Output]) -> Option<BlockChecker<H>>{
+        let indexes = hs.iter()
+            .map(|h| {
+                let t = hash_leaf::<H>(self.mode, h);
+                self.tree[0].iter().position(|tx| *tx == t)
+            })
+            .collect::<Option<Vec<usize>>>()?;
+        let path = proof_path(self.tree.len(), &indexes);
+        let blocks = self.get_blocks(&path);
         let flags: Vec<u8> = self.get_flags(&path);
 
         Some(
             BlockChecker{
-                rtx_index: rhash_index,
+                leaves: self.tree[0].len(),
                 blocks: blocks,
                 flags: flags,
+                mode: self.mode,
             }
         )
     }
@@ -658,7 +863,7 @@ impl SortedMerkleTree{
         for (row_i, row) in self.tree.iter().enumerate(){
             println!("level - {}", row_i);
             for (i, hs) in row.iter().enumerate(){
-                println!("i={}, hash={:?}",i, hs.chars().take(8).collect::<String>());
+                println!("i={}, hash={:?}",i, hs.to_hex().chars().take(8).collect::<String>());
             }
             println!();
         }
@@ -678,9 +883,13 @@ impl SortedMerkleTree{
         self.tree.len()
     }
 
+    pub fn get_mode(&self) -> Mode{
+        self.mode
+    }
+
     /// Get hash of  merkle root.
-    pub fn get_root_hash(&self) -> Option<String>{
-        Some(self.tree.last().unwrap()[0].clone())
+    pub fn get_root_hash(&self) -> Option<H::Output>{
+        Some(self.tree.last().unwrap()[0])
     }
 }
 
@@ -699,12 +908,12 @@ mod test_smt{
             "abcdefgh",
             "abcdefghi",
         ].iter()
-        .map(|b| SHA256::new(b.as_bytes()).cal_sha_256())
-        .collect::<Vec<String>>();
+        .map(|b| sha256(b.as_bytes()))
+        .collect::<Vec<[u8; 32]>>();
 
         println!("Sorted Merkle Tree, input:");
         for (i, c) in case.iter().enumerate(){
-            println!("{}-{}", i, c);
+            println!("{}-{}", i, c.to_hex());
         }
         println!();
 
@@ -713,47 +922,218 @@ mod test_smt{
     }
 }
 
+/// Highest tree accepted in a proof.
+pub const MAX_PROOF_HEIGHT: usize = 32;
+
+/// Why a `BlockChecker` is malformed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProofError{
+    /// The bytes end early or a count is not canonical.
+    Truncated,
+    /// Bytes left after the proof.
+    TrailingBytes,
+    /// Fewer than 2 tx-nodes, or more hashes than tx-nodes.
+    BadCount,
+    /// The tree of the tx-nodes is higher than `MAX_PROOF_HEIGHT`.
+    TooDeep,
+    /// The flags or the hashes run out before the tree is complete.
+    Incomplete,
+    UnusedHashes,
+    UnusedFlags,
+}
+
+impl fmt::Display for ProofError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        match self{
+            ProofError::Truncated => write!(f, "proof is truncated"),
+            ProofError::TrailingBytes => write!(f, "bytes left after the proof"),
+            ProofError::BadCount => write!(f, "bad number of tx-nodes or hashes"),
+            ProofError::TooDeep => write!(f, "tree is too deep"),
+            ProofError::Incomplete => write!(f, "flags or hashes run out"),
+            ProofError::UnusedHashes => write!(f, "hashes left unused"),
+            ProofError::UnusedFlags => write!(f, "flags left unused"),
+        }
+    }
+}
+
+impl std::error::Error for ProofError{}
+
 /// BlockChecker is generated by full node and using for SPV.
-pub struct BlockChecker{
-    // There are only two tx-node in blocks
-    // and rtx_index is the index of the right tx-node.
-    pub rtx_index: usize, 
+///
+/// The partial tree is walked in preorder from the root as Bitcoin's `merkleblock`: a node
+/// flagged 1 is above a proven tx-node, or is one, its children follow. A node flagged 0
+/// and a proven tx-node take the next hash of `blocks`.
+pub struct BlockChecker<H: Hasher = Sha256>{
+    /// Number of tx-nodes of the tree.
+    pub leaves: usize,
     pub flags: Vec<u8>,
-    pub blocks: Vec<String>,
+    pub blocks: Vec<H::Output>,
+    /// Hashing of the tree that generated the checker.
+    pub mode: Mode,
+}
+
+/// Cursor over the flags and hashes of a `BlockChecker`.
+struct Walk<'a, H: Hasher>{
+    checker: &'a BlockChecker<H>,
+    flag: usize,
+    block: usize,
+    matched: Vec<H::Output>,
+}
+
+impl<'a, H: Hasher> Walk<'a, H>{
+    fn node(&mut self, level: usize, index: usize) -> Result<H::Output, ProofError>{
+        let flag = *self.checker.flags.get(self.flag).ok_or(ProofError::Incomplete)?;
+        self.flag += 1;
+        if level == 0 || flag == 0{
+            let h = *self.checker.blocks.get(self.block).ok_or(ProofError::Incomplete)?;
+            self.block += 1;
+            if level == 0 && flag != 0{
+                self.matched.push(h);
+            }
+            return Ok(h)
+        }
+        let l = self.node(level - 1, index << 1)?;
+        let r = if (index << 1) + 1 < self.checker.width(level - 1){
+            Some(self.node(level - 1, (index << 1) + 1)?)
+        }else{
+            None
+        };
+        Ok(hash_node::<H>(self.checker.mode, &l, r.as_ref()))
+    }
 }
 
-impl BlockChecker{
+impl<H: Hasher> BlockChecker<H>{
+    /// Number of nodes at `level`, the tx-nodes are level 0.
+    fn width(&self, level: usize) -> usize{
+        if level >= 64{
+            return 1
+        }
+        ((self.leaves - 1) >> level) + 1
+    }
+
+    /// Walk the partial tree, returns the root and the walk holding the proven level 0 nodes.
+    fn walk(&self) -> Result<(H::Output, Walk<'_, H>), ProofError>{
+        if self.leaves < 2 || self.blocks.len() > self.leaves{
+            return Err(ProofError::BadCount)
+        }
+        let mut height = 0;
+        while self.width(height) > 1{
+            height += 1;
+        }
+        if height > MAX_PROOF_HEIGHT{
+            return Err(ProofError::TooDeep)
+        }
+        let mut walk = Walk{ checker: self, flag: 0, block: 0, matched: Vec::new() };
+        let root = walk.node(height, 0)?;
+        if walk.block != self.blocks.len(){
+            return Err(ProofError::UnusedHashes)
+        }
+        Ok((root, walk))
+    }
+
+    /// The merkle root and the proven tx-nodes (their leaf hashes in `Mode::DomainSeparated`).
+    pub fn extract(&self) -> Result<(H::Output, Vec<H::Output>), ProofError>{
+        let (root, walk) = self.walk()?;
+        if walk.flag != self.flags.len(){
+            return Err(ProofError::UnusedFlags)
+        }
+        Ok((root, walk.matched))
+    }
+
     /// Verify that whether h is in the branch of the merkle tree.
-    pub fn validate(&self, h: &String, root_hash: &String) -> bool{
-        if !self.blocks.contains(&h){
-            return false;
+    pub fn validate(&self, h: &H::Output, root_hash: &H::Output) -> bool{
+        self.validate_all(std::slice::from_ref(h), root_hash)
+    }
+
+    /// Verify that all of `hs` are in the branches of the merkle tree, e.g. for a proof
+    /// of `gen_multi_proof()`.
+    pub fn validate_all(&self, hs: &[H::Output], root_hash: &H::Output) -> bool{
+        match self.extract(){
+            Ok((root, matched)) => root == *root_hash
+                && hs.iter().all(|h| matched.contains(&hash_leaf::<H>(self.mode, h))),
+            Err(_) => false,
         }
+    }
 
-        let leng = self.flags.len();
-        let mut st:Vec<String> = Vec::with_capacity(leng);
-        let mut j = 0;
-        for &flag in self.flags[1..].iter(){
-            if flag == 0 || j == self.rtx_index - 1 || j == self.rtx_index{
-                if let Some(hs) = self.blocks.get(j){
-                    st.push(hs.clone());
-                    j += 1;
-                }else{
-                    break
-                }
-            }
-            if j > self.rtx_index{
-                // two tx node has been pushed.
-                assert!(st.len() >= 2);
-                let r = st.pop().unwrap();
-                let l = st.pop().unwrap();
-                let new_hash = SHA256::new((l + &r).as_bytes()).cal_sha_256();
-                // println!("validate: {}", &new_hash);
-                st.push(new_hash);
+    /// `leaves | hash count | hashes | flag bytes count | flags`, counts are varints and
+    /// flags are packed 8 per byte, the first in the lowest bit.
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut buf = Vec::with_capacity(12 + self.blocks.len() * H::Output::LEN + self.flags.len() / 8);
+        write_varint(&mut buf, self.leaves as u64);
+        write_varint(&mut buf, self.blocks.len() as u64);
+        for h in self.blocks.iter(){
+            buf.extend_from_slice(h.as_ref());
+        }
+        let mut bits = vec![0u8; self.flags.len().div_ceil(8)];
+        for (i, &flag) in self.flags.iter().enumerate(){
+            if flag != 0{
+                bits[i / 8] |= 1 << (i % 8);
             }
         }
-        assert_eq!(1, st.len());
+        write_varint(&mut buf, bits.len() as u64);
+        buf.extend(bits);
+        buf
+    }
 
-        root_hash == st.get(0).unwrap()
+    /// Decode a checker of a tree hashed as `mode`. The partial tree must use every hash and
+    /// every flag, only the bits filling the last flag byte may be left, as zeros.
+    pub fn from_bytes(bytes: &[u8], mode: Mode) -> Result<BlockChecker<H>, ProofError>{
+        let mut r = bytes;
+        let leaves = read_varint(&mut r)?;
+        let n = read_varint(&mut r)?;
+        if n > leaves{
+            return Err(ProofError::BadCount)
+        }
+        if n.saturating_mul(H::Output::LEN as u64) > r.len() as u64{
+            return Err(ProofError::Truncated)
+        }
+        let (hashes, rest) = r.split_at(n as usize * H::Output::LEN);
+        let blocks = hashes.chunks(H::Output::LEN).map(|h| H::Output::from_slice(h).unwrap()).collect();
+        r = rest;
+        let nbytes = read_varint(&mut r)?;
+        if nbytes != r.len() as u64{
+            return Err(if nbytes > r.len() as u64 { ProofError::Truncated } else { ProofError::TrailingBytes })
+        }
+        let flags = (0..r.len() * 8).map(|i| (r[i / 8] >> (i % 8)) & 1).collect();
+        let leaves = if leaves > usize::MAX as u64 { usize::MAX } else { leaves as usize };
+        let mut checker = BlockChecker{ leaves, flags, blocks, mode };
+        let used = checker.walk()?.1.flag;
+        if used.div_ceil(8) != r.len() || checker.flags[used..].iter().any(|&f| f != 0){
+            return Err(ProofError::UnusedFlags)
+        }
+        checker.flags.truncate(used);
+        Ok(checker)
+    }
+}
+
+/// Canonical varint, as `blockchain::encode`.
+fn write_varint(buf: &mut Vec<u8>, n: u64){
+    if n < 0xfd{
+        buf.push(n as u8);
+    }else if n <= 0xffff{
+        buf.push(0xfd);
+        buf.write_u16::<BigEndian>(n as u16).unwrap();
+    }else if n <= 0xffff_ffff{
+        buf.push(0xfe);
+        buf.write_u32::<BigEndian>(n as u32).unwrap();
+    }else{
+        buf.push(0xff);
+        buf.write_u64::<BigEndian>(n).unwrap();
+    }
+}
+
+fn read_varint(r: &mut &[u8]) -> Result<u64, ProofError>{
+    let (&first, rest) = r.split_first().ok_or(ProofError::Truncated)?;
+    *r = rest;
+    let (n, min) = match first{
+        0xfd => (r.read_u16::<BigEndian>().map(u64::from), 0xfd),
+        0xfe => (r.read_u32::<BigEndian>().map(u64::from), 0x1_0000),
+        0xff => (r.read_u64::<BigEndian>(), 0x1_0000_0000),
+        n => (Ok(n as u64), 0),
+    };
+    match n{
+        Ok(n) if n >= min => Ok(n),
+        _ => Err(ProofError::Truncated),
     }
 }
 
@@ -773,24 +1153,163 @@ mod test_bc{
             "abcdefgh",
             "abcdefghi",
         ].iter()
-        .map(|b| SHA256::new(b.as_bytes()).cal_sha_256())
-        .collect::<Vec<String>>();
+        .map(|b| sha256(b.as_bytes()))
+        .collect::<Vec<[u8; 32]>>();
         let mt = SortedMerkleTree::create(case);
         let rh = mt.get_root_hash().unwrap();
         // mt.print_tree_short();
 
 
-        let h = SHA256::new("abc".as_bytes()).cal_sha_256();
-        let bc = mt.gen_block_checker(h.clone()).unwrap();
+        let h = sha256("abc".as_bytes());
+        let bc = mt.gen_block_checker(h).unwrap();
         assert_eq!(
             true, bc.validate(&h, &rh)
         );
 
-        let h = SHA256::new("abcd".as_bytes()).cal_sha_256();
-        let bc = mt.gen_block_checker(h.clone()).unwrap();
+        let h = sha256("abcd".as_bytes());
+        let bc = mt.gen_block_checker(h).unwrap();
         assert_eq!(
             true, bc.validate(&h, &rh)
         );
+        assert_eq!(
+            false, bc.validate(&sha256("abcde".as_bytes()), &rh)
+        );
 
+        // the former String trees
+        let legacy = SortedMerkleTree::create_legacy(mt.tree[0].clone());
+        let bc = legacy.gen_block_checker(h).unwrap();
+        assert_eq!(
+            true, bc.validate(&h, &legacy.get_root_hash().unwrap())
+        );
+        assert_eq!(
+            false, bc.validate(&h, &rh)
+        );
+
+        let txs: Vec<[u8; 64]> = ["abc", "abcd", "abcde", "abcdef"].iter().map(|b| Blake2b::hash(b.as_bytes())).collect();
+        let smt: SortedMerkleTree<Blake2b> = SortedMerkleTree::with_mode(txs.clone(), Mode::DomainSeparated);
+        let rh = smt.get_root_hash().unwrap();
+        for h in txs.iter(){
+            let bc = smt.gen_block_checker(*h).unwrap();
+            assert_eq!(
+                true, bc.validate(h, &rh)
+            );
+        }
+        // a leaf is not a tx-node
+        let leaf = smt.tree[0][0];
+        assert!(smt.gen_block_checker(leaf).is_none());
+        assert_eq!(
+            false, smt.gen_block_checker(txs[0]).unwrap().validate(&leaf, &rh)
+        );
+    }
+
+    #[test]
+    fn test_every_leaf() {
+        for n in 2..=17{
+            let txs: Vec<[u8; 32]> = (0..n).map(|i: u32| sha256(&i.to_be_bytes())).collect();
+            let mut mt = MerkleTree::create(txs[..2].to_vec());
+            for h in txs[2..].iter(){
+                mt.append(*h);
+            }
+            let rh = mt.get_root_hash().unwrap();
+            assert_eq!(Some(rh), MerkleTree::create(txs.clone()).get_root_hash());
+            for h in txs.iter(){
+                let bc = mt.gen_block_checker(*h).unwrap();
+                assert!(bc.validate(h, &rh), "{} of {}", h.to_hex(), n);
+                assert_eq!(Ok((rh, vec![*h])), bc.extract());
+            }
+        }
+    }
+
+    #[test]
+    fn test_multi_proof() {
+        let txs: Vec<[u8; 32]> = (0..13u32).map(|i| sha256(&i.to_be_bytes())).collect();
+        let mt = MerkleTree::create(txs.clone());
+        let rh = mt.get_root_hash().unwrap();
+        let picked = [txs[9], txs[2], txs[3], txs[12], txs[2]];
+        let bc = mt.gen_multi_proof(&picked).unwrap();
+        assert!(bc.validate_all(&picked, &rh));
+        assert!(bc.validate(&txs[12], &rh));
+        assert!(!bc.validate_all(&[txs[2], txs[4]], &rh));
+        let (_, matched) = bc.extract().unwrap();
+        assert_eq!(vec![txs[2], txs[3], txs[9], txs[12]], matched);
+
+        // siblings and common ancestors are sent once
+        let singles: usize = [2, 3, 9, 12].iter().map(|&i| mt.gen_block_checker(txs[i]).unwrap().to_bytes().len()).sum();
+        let bytes = bc.to_bytes();
+        assert!(bytes.len() < singles / 2, "{} {}", bytes.len(), singles);
+        let decoded: BlockChecker = BlockChecker::from_bytes(&bytes, Mode::Binary).unwrap();
+        assert!(decoded.validate_all(&picked, &rh));
+
+        assert!(mt.gen_multi_proof(&[txs[0], sha256(b"missing")]).is_none());
+        let all = mt.gen_multi_proof(&txs).unwrap();
+        assert_eq!(txs.len(), all.blocks.len());
+        assert!(all.validate_all(&txs, &rh));
+
+        let smt: SortedMerkleTree<Keccak256> = SortedMerkleTree::with_mode(
+            (0..6u32).map(|i| Keccak256::hash(&i.to_be_bytes())).collect(), Mode::DomainSeparated);
+        let picked: Vec<_> = (1..4u32).map(|i| Keccak256::hash(&i.to_be_bytes())).collect();
+        let bc = smt.gen_multi_proof(&picked).unwrap();
+        assert!(bc.validate_all(&picked, &smt.get_root_hash().unwrap()));
+    }
+
+    #[test]
+    fn test_proof_bytes() {
+        let txs: Vec<[u8; 32]> = (0..7u32).map(|i| sha256(&i.to_be_bytes())).collect();
+        let mt = MerkleTree::create(txs.clone());
+        let rh = mt.get_root_hash().unwrap();
+        let bc = mt.gen_block_checker(txs[2]).unwrap();
+        let bytes = bc.to_bytes();
+        // 7 tx-nodes, 4 hashes, 7 flags in one byte
+        assert_eq!(1 + 1 + 4 * 32 + 1 + 1, bytes.len());
+        assert_eq!(0b0011011, bytes[bytes.len() - 1]);
+        let decoded: BlockChecker = BlockChecker::from_bytes(&bytes, Mode::Binary).unwrap();
+        assert_eq!(bc.flags, decoded.flags);
+        assert!(decoded.validate(&txs[2], &rh));
+        // the mode is not part of the proof
+        let other: BlockChecker = BlockChecker::from_bytes(&bytes, Mode::LegacyHex).unwrap();
+        assert!(!other.validate(&txs[2], &rh));
+
+        let decode = |b: &[u8]| BlockChecker::<Sha256>::from_bytes(b, Mode::Binary).err();
+        assert_eq!(Some(ProofError::Truncated), decode(&bytes[..bytes.len() - 1]));
+        assert_eq!(Some(ProofError::Truncated), decode(&bytes[..40]));
+        assert_eq!(Some(ProofError::TrailingBytes), decode(&[&bytes[..], &[0]].concat()));
+
+        // a hash more
+        let mut extra = vec![7, 5];
+        extra.extend_from_slice(&bytes[2..2 + 4 * 32]);
+        extra.extend_from_slice(&[0; 32]);
+        extra.extend_from_slice(&bytes[2 + 4 * 32..]);
+        assert_eq!(Some(ProofError::UnusedHashes), decode(&extra));
+        // a flag set after the last one used, or a flag byte more
+        let mut flags = bytes.clone();
+        *flags.last_mut().unwrap() |= 0x80;
+        assert_eq!(Some(ProofError::UnusedFlags), decode(&flags));
+        let mut flags = bytes[..bytes.len() - 2].to_vec();
+        flags.extend_from_slice(&[2, 0b0011011, 0]);
+        assert_eq!(Some(ProofError::UnusedFlags), decode(&flags));
+        // a flag more needs hashes under the last node
+        let mut flags = bytes.clone();
+        *flags.last_mut().unwrap() = 0b1011011;
+        assert_eq!(Some(ProofError::Incomplete), decode(&flags));
+        // without the flag of the tx-node the proof is well formed but proves nothing
+        *flags.last_mut().unwrap() = 0b0001011;
+        let nothing: BlockChecker = BlockChecker::from_bytes(&flags, Mode::Binary).unwrap();
+        assert_eq!(Ok((rh, vec![])), nothing.extract());
+        assert!(!nothing.validate(&txs[2], &rh));
+
+        let mut deep = vec![0xff];
+        deep.extend_from_slice(&(1u64 << 40).to_be_bytes());
+        deep.extend_from_slice(&bytes[1..]);
+        assert_eq!(Some(ProofError::TooDeep), decode(&deep));
+        assert_eq!(Some(ProofError::BadCount), decode(&[&[1][..], &bytes[1..]].concat()));
+        assert_eq!(Some(ProofError::BadCount), decode(&[3, 4]));
+        // not canonical
+        assert_eq!(Some(ProofError::Truncated), decode(&[&[0xfd, 0, 7][..], &bytes[1..]].concat()));
+
+        // a checker built by hand is checked the same way, without panicking
+        let mut broken = decoded;
+        broken.blocks.pop();
+        assert_eq!(Err(ProofError::Incomplete), broken.extract());
+        assert!(!broken.validate(&txs[2], &rh));
     }
 }
\ No newline at end of file
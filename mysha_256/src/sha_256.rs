@@ -75,15 +75,24 @@ impl SHA256{
     }
 
     pub fn cal_sha_256(&mut self) -> String{
+        self.digest().iter()
+            .map(|e| format!("{:02x}", e))
+            .collect::<String>()
+    }
+
+    /// 以字节形式返回摘要。
+    pub fn digest(&mut self) -> [u8; 32]{
         let mut dige: [u32; 8] = [
             0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 
             0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
         ];
         self.compact_iteration(&mut dige);
         // digital signature = dige[0] append dige[1] ...
-        dige.iter()
-            .map(|e| format!("{:08x}", e))
-            .collect::<String>()
+        let mut res = [0u8; 32];
+        for (bytes, e) in res.chunks_mut(4).zip(dige.iter()){
+            bytes.copy_from_slice(&e.to_be_bytes());
+        }
+        res
     }
 
     fn cal_padding(&self) -> usize{
@@ -236,6 +245,13 @@ mod test_mysha_256{
         );
     }
 
+    #[test]
+    fn test_digest() {
+        let d = SHA256::new("abc".as_bytes()).digest();
+        assert_eq!(d[..4], [0xba, 0x78, 0x16, 0xbf]);
+        assert_eq!(d[28..], [0xf2, 0x00, 0x15, 0xad]);
+    }
+
     #[test]
     fn test_modular_32_add() {
         assert_eq!(